@@ -0,0 +1,253 @@
+use anyhow::{anyhow, Context};
+use clap::parser::ValueSource;
+use clap::ArgAction;
+use serde_yaml::Value;
+use std::env::VarError;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use wstunnel::tunnel::expand_vars;
+
+/// Options of a command read from a yaml file. Keys are the names of the command line options, i.e:
+///
+/// remote_addr: wss://wstunnel.example.com
+/// http_upgrade_credentials: "${WSTUNNEL_CREDENTIALS}"
+/// websocket_mask_frame: false
+/// local_to_remote:
+///   - tcp://1212:google.com:443
+///   - udp://1212:1.1.1.1:53
+///
+/// ${VAR} in the values is replaced by the content of the environment variable VAR, to keep secrets out of the file.
+/// An option given on the command line takes precedence over the file, lists included: the ones of the file are then
+/// ignored instead of combined. Options that can also be set by an environment variable are taken from the file first.
+///
+/// The file maps to the options of the command line, it is not deserialized into WsClientConfig or WsServerConfig:
+/// they hold runtime state (TLS configurations, DNS resolver, metrics, clock) that the command line builds
+pub struct ConfigFile {
+    options: serde_yaml::Mapping,
+}
+
+impl ConfigFile {
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path).with_context(|| format!("cannot open config file {}", path.display()))?;
+        let options = serde_yaml::from_reader(BufReader::new(file))
+            .with_context(|| format!("invalid config file {}", path.display()))?;
+
+        Ok(Self { options })
+    }
+
+    /// Command line arguments equivalent to the options of the file, going through the same parsing and validation.
+    /// Options for which is_set returns true are skipped, the ones the command line already sets.
+    /// Fails on keys that are not options of the command, to catch typos
+    pub fn to_args(
+        &self,
+        command: &clap::Command,
+        is_set: impl Fn(&str) -> bool,
+        lookup_var: impl Fn(&str) -> Result<String, VarError>,
+    ) -> anyhow::Result<Vec<OsString>> {
+        let mut args = vec![];
+        for (key, value) in &self.options {
+            let key = key
+                .as_str()
+                .ok_or_else(|| anyhow!("invalid option {:?}, it must be a string", key))?;
+            let name = key.replace('-', "_");
+            let arg = command
+                .get_arguments()
+                .find(|arg| arg.get_id() == name.as_str() && !matches!(name.as_str(), "config" | "help" | "version"))
+                .ok_or_else(|| anyhow!("unknown option {} for {}", key, command.get_name()))?;
+            if is_set(arg.get_id().as_str()) {
+                continue;
+            }
+
+            let values = match value {
+                Value::Sequence(values) => values.iter().collect(),
+                value => vec![value],
+            };
+            for value in values {
+                let value = match value {
+                    Value::Bool(value) if matches!(arg.get_action(), ArgAction::SetTrue) => {
+                        if *value {
+                            args.push(format!("--{}", arg.get_long().unwrap_or_default()).into());
+                        }
+                        continue;
+                    }
+                    Value::Bool(value) => value.to_string(),
+                    Value::Number(value) => value.to_string(),
+                    Value::String(value) => expand_vars(value, &lookup_var)
+                        .with_context(|| format!("invalid value for option {}", key))?
+                        .into_owned(),
+                    _ => {
+                        return Err(anyhow!(
+                            "invalid value for option {}, it must be a string, a number or a bool",
+                            key
+                        ))
+                    }
+                };
+
+                match arg.get_long() {
+                    Some(long) => args.push(format!("--{}={}", long, value).into()),
+                    None => args.push(value.into()),
+                }
+            }
+        }
+
+        Ok(args)
+    }
+}
+
+/// Insert the options of the file given with --config to the subcommand just after it,
+/// except the ones the command line already sets
+pub fn expand_config_file_arg(
+    mut args: Vec<OsString>,
+    command: &clap::Command,
+    lookup_var: impl Fn(&str) -> Result<String, VarError>,
+) -> anyhow::Result<Vec<OsString>> {
+    let Some((subcommand_ix, subcommand)) = args
+        .iter()
+        .enumerate()
+        .skip(1)
+        .find_map(|(ix, arg)| Some((ix, command.find_subcommand(arg)?)))
+    else {
+        return Ok(args);
+    };
+    if subcommand.get_arguments().all(|arg| arg.get_id() != "config") {
+        return Ok(args);
+    }
+
+    let mut config_path = None;
+    for (ix, arg) in args.iter().enumerate().skip(subcommand_ix + 1) {
+        let Some(arg) = arg.to_str() else {
+            continue;
+        };
+        if arg == "--config" {
+            config_path = args.get(ix + 1).cloned();
+        } else if let Some(path) = arg.strip_prefix("--config=") {
+            config_path = Some(path.into());
+        }
+    }
+    let Some(config_path) = config_path else {
+        return Ok(args);
+    };
+
+    // Only to know what the command line sets, the full validation is done once the options of the file are added
+    let cli_matches = command.clone().ignore_errors(true).try_get_matches_from(&args).ok();
+    let cli_matches = cli_matches
+        .as_ref()
+        .and_then(|matches| matches.subcommand_matches(subcommand.get_name()));
+    let is_set =
+        |id: &str| cli_matches.is_some_and(|matches| matches.value_source(id) == Some(ValueSource::CommandLine));
+
+    let file_args = ConfigFile::from_file(Path::new(&config_path))?.to_args(subcommand, is_set, lookup_var)?;
+    args.splice(subcommand_ix + 1..subcommand_ix + 1, file_args);
+
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{Arg, Command};
+
+    fn command() -> Command {
+        Command::new("wstunnel").subcommand(
+            Command::new("client")
+                .arg(Arg::new("remote_addr"))
+                .arg(
+                    Arg::new("local_to_remote")
+                        .short('L')
+                        .long("local-to-remote")
+                        .action(ArgAction::Append),
+                )
+                .arg(Arg::new("server_connect_timeout_sec").long("server-connect-timeout-sec"))
+                .arg(
+                    Arg::new("websocket_mask_frame")
+                        .long("websocket-mask-frame")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(Arg::new("config").long("config")),
+        )
+    }
+
+    fn lookup_var(var: &str) -> Result<String, VarError> {
+        match var {
+            "WSTUNNEL_TEST_CONFIG_SECRET" => Ok("s3cr3t".to_string()),
+            _ => Err(VarError::NotPresent),
+        }
+    }
+
+    fn expand(args: &[&str]) -> anyhow::Result<Vec<OsString>> {
+        expand_config_file_arg(args.iter().map(OsString::from).collect(), &command(), lookup_var)
+    }
+
+    #[test]
+    fn test_config_file_to_args() {
+        let path = std::env::temp_dir().join(format!("wstunnel_config_{}.yaml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+remote_addr: wss://${WSTUNNEL_TEST_CONFIG_SECRET}.example.com
+server-connect-timeout-sec: 5
+websocket_mask_frame: true
+local_to_remote:
+  - tcp://1212:google.com:443
+  - udp://1212:1.1.1.1:53
+"#,
+        )
+        .unwrap();
+        let path_arg = path.to_str().unwrap();
+
+        let args = expand(&["wstunnel", "client", "--config", path_arg]).unwrap();
+        assert_eq!(
+            args,
+            vec![
+                "wstunnel",
+                "client",
+                "wss://s3cr3t.example.com",
+                "--server-connect-timeout-sec=5",
+                "--websocket-mask-frame",
+                "--local-to-remote=tcp://1212:google.com:443",
+                "--local-to-remote=udp://1212:1.1.1.1:53",
+                "--config",
+                path_arg,
+            ]
+        );
+
+        // The command line takes precedence, lists and positional arguments included
+        let args = expand(&[
+            "wstunnel",
+            "client",
+            "--config",
+            path_arg,
+            "-L",
+            "tcp://1:a.com:1",
+            "--server-connect-timeout-sec",
+            "1",
+            "ws://other.example.com",
+        ])
+        .unwrap();
+        assert_eq!(
+            args,
+            vec![
+                "wstunnel",
+                "client",
+                "--websocket-mask-frame",
+                "--config",
+                path_arg,
+                "-L",
+                "tcp://1:a.com:1",
+                "--server-connect-timeout-sec",
+                "1",
+                "ws://other.example.com",
+            ]
+        );
+
+        std::fs::write(&path, "websocket_mask_frames: true\n").unwrap();
+        assert!(expand(&["wstunnel", "client", "--config", path_arg]).is_err());
+
+        std::fs::write(&path, "remote_addr: ws://${WSTUNNEL_TEST_CONFIG_NOT_SET}\n").unwrap();
+        assert!(expand(&["wstunnel", "client", "--config", path_arg]).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
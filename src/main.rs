@@ -1,40 +1,54 @@
-mod embedded_certificate;
-mod protocols;
-mod restrictions;
-mod tunnel;
-
-use crate::protocols::dns::DnsResolver;
-use crate::protocols::tls;
-use crate::restrictions::types::RestrictionsRules;
-use crate::tunnel::client::{TlsClientConfig, WsClient, WsClientConfig};
-use crate::tunnel::connectors::{Socks5TunnelConnector, TcpTunnelConnector, UdpTunnelConnector};
-use crate::tunnel::listeners::{
-    new_stdio_listener, new_udp_listener, HttpProxyTunnelListener, Socks5TunnelListener, TcpTunnelListener,
-};
-use crate::tunnel::server::{TlsServerConfig, WsServer, WsServerConfig};
-use crate::tunnel::{to_host_port, RemoteAddr, TransportAddr, TransportScheme};
+#[cfg(feature = "config-file")]
+mod config_file;
+
 use base64::Engine;
+#[cfg(feature = "config-file")]
+use clap::CommandFactory;
 use clap::Parser;
 use hyper::header::HOST;
 use hyper::http::{HeaderName, HeaderValue};
 use log::debug;
 use parking_lot::{Mutex, RwLock};
-use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::fmt::Debug;
 use std::io;
 use std::io::ErrorKind;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::select;
-use tokio_rustls::rustls::pki_types::DnsName;
-use tracing::{error, info};
-use tracing_subscriber::filter::Directive;
-use tracing_subscriber::EnvFilter;
+use tokio::task::JoinSet;
+use tokio_rustls::rustls::pki_types::{DnsName, ServerName};
+use tokio_rustls::rustls::RootCertStore;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
 use url::{Host, Url};
+use wstunnel::protocols::dns::DnsResolver;
+#[cfg(feature = "geoip")]
+use wstunnel::protocols::geoip::GeoIpDatabase;
+use wstunnel::protocols::tcp::{ConnectFailureBehavior, TcpSocketOptions};
+use wstunnel::protocols::tls;
+use wstunnel::protocols::tls::TlsVersion;
+use wstunnel::restrictions::types::RestrictionsRules;
+use wstunnel::tunnel::client::{
+    ReconnectBackoff, RemoteSelection, SaturationPolicy, TlsClientConfig, WsClient, WsClientConfigBuilder,
+};
+use wstunnel::tunnel::clock::TokioClock;
+use wstunnel::tunnel::connectors::{Socks5TunnelConnector, TcpTunnelConnector, UdpTunnelConnector};
+use wstunnel::tunnel::jwt::{JwtAlgorithm, JwtKey, JwtValidity};
+use wstunnel::tunnel::listeners::{
+    new_stdio_listener, new_udp_listener, HttpProxyTunnelListener, Socks5TunnelListener, TcpTunnelListener,
+};
+use wstunnel::tunnel::logging::LogConfig;
+use wstunnel::tunnel::server::{SubjectLimit, TlsServerConfig, WsServer, WsServerConfig};
+use wstunnel::tunnel::{
+    expand_env_vars, to_host_port, Http2MultiplexConfig, RemoteAddr, TransportAddr, TransportScheme, TunnelPriority,
+    JWT_HEADER_PREFIX, JWT_KEYS, MIN_COPY_BUFFER_SIZE,
+};
+use wstunnel::{embedded_certificate, protocols, LocalProtocol};
 
 const DEFAULT_CLIENT_UPGRADE_PATH_PREFIX: &str = "v1";
 
@@ -87,9 +101,16 @@ struct Client {
     /// 'tcp://1212:google.com:443'      =>       listen locally on tcp on port 1212 and forward to google.com on port 443
     /// 'tcp://2:n.lan:4?proxy_protocol' =>       listen locally on tcp on port 2 and forward to n.lan on port 4
     ///                                           Send a proxy protocol header v2 when establishing connection to n.lan
+    ///                                           with the address of the local peer as source. Servers still accepting the default --jwt-secret
+    ///                                           cannot trust it, and announce the address of the client instead
+    /// 'tcp://2:n.lan:4?expect_proxy_protocol' => listen locally on tcp on port 2 and forward to n.lan on port 4
+    ///                                           Expect a proxy protocol header (v1 or v2) from the local peers (i.e: behind a load balancer),
+    ///                                           the address it announces is used as the source of the tunnel
+    /// 'tcp://1212?original_dst'        =>       listen locally on tcp on port 1212 and forward to the original destination of the connections
+    ///                                           redirected to it by iptables/ip6tables REDIRECT. linux only
     ///
     /// 'udp://1212:1.1.1.1:53'          =>       listen locally on udp on port 1212 and forward to cloudflare dns 1.1.1.1 on port 53
-    /// 'udp://1212:1.1.1.1:53?timeout_sec=10'    timeout_sec on udp force close the tunnel after 10sec. Set it to 0 to disable the timeout [default: 30]
+    /// 'udp://1212:1.1.1.1:53?timeout_sec=10'    timeout_sec on udp close the session after 10sec without packets from the peer. Set it to 0 to disable the timeout [default: 30]
     ///
     /// 'socks5://[::1]:1212'            =>       listen locally with socks5 on port 1212 and forward dynamically requested tunnel
     /// 'socks5://[::1]:1212?login=admin&password=admin' => listen locally with socks5 on port 1212 and only accept connection with login=admin and password=admin
@@ -104,6 +125,18 @@ struct Client {
     /// 'stdio://google.com:443'         =>       listen for data from stdio, mainly for `ssh -o ProxyCommand="wstunnel client -L stdio://%h:%p ws://localhost:8080" my-server`
     ///
     /// 'unix:///tmp/wstunnel.sock:g.com:443' =>  listen for data from unix socket of path /tmp/wstunnel.sock and forward to g.com:443
+    ///
+    /// 'tcp+unix://2375:/var/run/docker.sock' => listen locally on tcp on port 2375 and forward to the unix socket /var/run/docker.sock of the server
+    ///                                           The server must explicitly allow the Unix protocol in its restrictions
+    ///
+    /// 'tcp+exec://2222:n.lan:22'       =>       listen locally on tcp on port 2222 and forward to the stdio of the --exec-command of the server,
+    ///                                           run with n.lan and 22 as its {host} and {port}. The server must explicitly allow the Exec protocol
+    ///
+    /// 'tcp+tls://8080:n.lan:443'       =>       listen locally on tcp on port 8080 and forward to n.lan on port 443, the server doing
+    ///                                           the TLS handshake with n.lan. Useful to reach a TLS only service with a plaintext client
+    ///
+    /// 'tcp://2222:n.lan:22?priority=interactive' => any tunnel accepts a priority of interactive, normal (default) or bulk
+    ///                                           Busy tunnels share the uplink 16:4:1 between interactive, normal and bulk ones
     #[arg(short='L', long, value_name = "{tcp,udp,socks5,stdio,unix}://[BIND:]PORT:HOST:PORT", value_parser = parse_tunnel_arg, verbatim_doc_comment)]
     local_to_remote: Vec<LocalToRemote>,
 
@@ -114,6 +147,7 @@ struct Client {
     /// 'socks5://[::1]:1212'            =>     listen on server for incoming socks5 request on port 1212 and forward dynamically request from local machine (login/password is supported)
     /// 'http://[::1]:1212'         =>     listen on server for incoming http proxy request on port 1212 and forward dynamically request from local machine (login/password is supported)
     /// 'unix://wstunnel.sock:g.com:443' =>     listen on server for incoming data from unix socket of path wstunnel.sock and forward to g.com:443 from local machine
+    /// 'tcp://127.0.0.1:0:g.com:443'    =>     listen on server only on its loopback, on an ephemeral port. The port picked by the server is logged by the client
     #[arg(short='R', long, value_name = "{tcp,udp,socks5,unix}://[BIND:]PORT:HOST:PORT", value_parser = parse_tunnel_arg, verbatim_doc_comment)]
     remote_to_local: Vec<LocalToRemote>,
 
@@ -122,6 +156,42 @@ struct Client {
     #[arg(long, value_name = "INT", verbatim_doc_comment)]
     socket_so_mark: Option<u32>,
 
+    /// When a hostname resolves to several addresses (i.e: IPv6 and IPv4), delay before trying the next address
+    /// while the previous connection attempt is still pending (Happy Eyeballs, RFC 8305).
+    /// Apply to the connection to the wstunnel server and to the local destinations of the reverse tunnels.
+    /// The addresses are tried in the order of --dns-resolver-prefer-ipv4, IPv6 and IPv4 alternating
+    #[arg(long, value_name = "ms", default_value = "250", value_parser = parse_duration_ms, verbatim_doc_comment)]
+    happy_eyeballs_delay_ms: Duration,
+
+    /// Number of the addresses of a hostname tried at once, the first one to connect wins and the others are closed.
+    /// The next addresses are tried after the happy eyeballs delay, or as soon as an attempt fails.
+    /// The address that connected is then tried first, alone, on the next connections to the hostname for a minute
+    #[arg(long, value_name = "INT", default_value = "1", value_parser = clap::value_parser!(u16).range(1..), verbatim_doc_comment)]
+    connect_parallelism: u16,
+
+    /// Disable Nagle's algorithm (TCP_NODELAY) on the tcp sockets carrying the tunnels traffic.
+    /// Enabled by default to not add latency to interactive traffic (i.e: ssh), set to false to favor throughput
+    #[arg(long, value_name = "BOOL", default_value = "true", action = clap::ArgAction::Set, verbatim_doc_comment)]
+    tcp_nodelay: bool,
+
+    /// Idle time before sending TCP keepalive probes on the tcp sockets carrying the tunnels traffic.
+    /// TCP keepalive allows to detect dead peers at the kernel level, it complements but does not replace the websocket ping
+    #[arg(long, value_name = "seconds", default_value = "60", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    tcp_keepalive_idle_sec: Duration,
+
+    /// Interval between TCP keepalive probes
+    #[arg(long, value_name = "seconds", default_value = "10", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    tcp_keepalive_interval_sec: Duration,
+
+    /// Number of unanswered TCP keepalive probes before considering the peer dead. Not supported on windows and openbsd
+    #[arg(long, value_name = "INT", default_value = "3", verbatim_doc_comment)]
+    tcp_keepalive_retries: u32,
+
+    /// Size of the queue of the pending connections of the local tcp and tproxy+tcp listeners,
+    /// the kernel may cap it (i.e: net.core.somaxconn on linux)
+    #[arg(long, value_name = "INT", default_value = "1024", verbatim_doc_comment)]
+    listen_backlog: u32,
+
     /// Client will maintain a pool of open connection to the server, in order to speed up the connection process.
     /// This option set the maximum number of connection that will be kept open.
     /// This is useful if you plan to create/destroy a lot of tunnel (i.e: with socks5 to navigate with a browser)
@@ -133,6 +203,77 @@ struct Client {
     #[arg(long, value_name = "DURATION_IN_SECONDS", default_value = "300", value_parser = parse_duration_sec, verbatim_doc_comment)]
     connection_retry_max_backoff_sec: Duration,
 
+    /// Maximum time in seconds to get a tunnel up with the server, dns + tcp + tls + http upgrade combined.
+    /// Protects against servers/middleboxes that accept the connection but never answer the upgrade request.
+    /// Reverse tunnels (-R) retry after it expires
+    #[arg(long, value_name = "DURATION_IN_SECONDS", default_value = "30", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    server_connect_timeout_sec: Duration,
+
+    /// Maximum time in seconds to open the tcp connection to the server (or to the http proxy), per address tried.
+    /// Keep it short to quickly fail over to the next address/server
+    #[arg(long, value_name = "DURATION_IN_SECONDS", default_value = "10", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    tcp_connect_timeout_sec: Duration,
+
+    /// Maximum time in seconds for the TLS handshake with the server, once the tcp connection is open
+    #[arg(long, value_name = "DURATION_IN_SECONDS", default_value = "10", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    tls_handshake_timeout_sec: Duration,
+
+    /// Number of connections to the local endpoint of a tcp reverse tunnel (-R tcp://) to open in advance,
+    /// while waiting for the server to hand over a client. Each tunnel then starts with a warm connection
+    /// instead of dialing the endpoint only once the server answered. The endpoint resolution is cached as well
+    /// Pooled connections closed by the endpoint meanwhile are discarded. 0 to disable
+    #[arg(long, value_name = "INT", default_value = "0", verbatim_doc_comment)]
+    reverse_tunnel_connector_pool_size: usize,
+
+    /// Receive all the connections of a reverse tcp tunnel (-R tcp://) through a single tunnel kept open to the server,
+    /// instead of waiting for each of them with a new tunnel. The server pushes every accepted connection as a new flow,
+    /// without the client having to dial it again, which saves a round trip and a tunnel per connection.
+    /// Servers of older versions ignore it and keep one connection per tunnel
+    #[arg(long, default_value = "false", verbatim_doc_comment)]
+    reverse_tunnel_multiplex: bool,
+
+    /// Initial delay in seconds before trying to reconnect a reverse tunnel (-R) to the server after a failure
+    /// The delay grows by --reverse-tunnel-reconnect-multiplier after each consecutive failure, up to --reverse-tunnel-reconnect-max-delay-sec
+    /// and is reset to this value once the tunnel is up again
+    #[arg(long, value_name = "DURATION_IN_SECONDS", default_value = "1", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    reverse_tunnel_reconnect_initial_delay_sec: Duration,
+
+    /// Maximum delay in seconds between two reconnection attempts of a reverse tunnel (-R)
+    #[arg(long, value_name = "DURATION_IN_SECONDS", default_value = "60", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    reverse_tunnel_reconnect_max_delay_sec: Duration,
+
+    /// Factor by which the reconnect delay of a reverse tunnel (-R) is multiplied after each consecutive failure
+    #[arg(long, value_name = "FLOAT", default_value = "2.0", verbatim_doc_comment)]
+    reverse_tunnel_reconnect_multiplier: f64,
+
+    /// Fraction of randomness applied to the reconnect delay of a reverse tunnel (-R), between 0.0 and 1.0
+    /// i.e: 0.1 means the delay is randomly adjusted by +/- 10%, to avoid all tunnels reconnecting at the same time
+    #[arg(long, value_name = "FLOAT", default_value = "0.1", verbatim_doc_comment)]
+    reverse_tunnel_reconnect_jitter: f64,
+
+    /// Stop a reverse tunnel (-R) after this many failures in a row to connect to the server or to the local endpoint,
+    /// instead of retrying forever. The counter is reset each time the tunnel is up.
+    /// Once all the tunnels are stopped, wstunnel exits with an error, to let a supervisor tell a blip from a server gone.
+    /// Each attempt to reach the server lasts up to --connection-retry-max-backoff-sec, lower it as well to fail fast
+    #[arg(long, value_name = "INT", value_parser = clap::value_parser!(u32).range(1..), verbatim_doc_comment)]
+    reverse_tunnel_max_reconnect_attempts: Option<u32>,
+
+    /// Maximum number of tunnels that can be running at the same time for each -L listener.
+    /// By default, there is no limit
+    #[arg(long, value_name = "INT", verbatim_doc_comment)]
+    max_concurrent_tunnels: Option<NonZeroUsize>,
+
+    /// Number of tunnels to the server to establish in advance for each -L tcp:// listener, without proxy protocol.
+    /// A new local connection then grabs an idle tunnel instead of waiting for the tcp + tls + upgrade handshakes.
+    /// Idle tunnels are kept alive with pings, and replaced in the background once used or dead.
+    /// Be aware that the server connects to the destination as soon as a tunnel is established. 0 to disable
+    #[arg(long, value_name = "INT", default_value = "0", verbatim_doc_comment)]
+    prewarm_pool_size: usize,
+
+    /// What to do with new connections when --max-concurrent-tunnels is reached
+    #[arg(long, value_enum, default_value = "queue", verbatim_doc_comment)]
+    when_saturated: SaturationPolicy,
+
     /// Domain name that will be used as SNI during TLS handshake
     /// Warning: If you are behind a CDN (i.e: Cloudflare) you must set this domain also in the http HOST header.
     ///          or it will be flagged as fishy and your request rejected
@@ -144,11 +285,48 @@ struct Client {
     #[arg(long, verbatim_doc_comment)]
     tls_sni_disable: bool,
 
-    /// Enable TLS certificate verification.
-    /// Disabled by default. The client will happily connect to any server with self-signed certificate.
-    #[arg(long, verbatim_doc_comment)]
+    /// Verify the TLS certificate of the server. Enabled by default
+    /// Use --tls-verify-certificate=false to connect to any server, i.e: with a self-signed certificate.
+    /// A warning is logged for each connection made without verification, do not use it that way in production
+    #[arg(long, value_name = "BOOL", default_value = "true", default_missing_value = "true", num_args = 0..=1, require_equals = true, action = clap::ArgAction::Set, verbatim_doc_comment)]
     tls_verify_certificate: bool,
 
+    /// Hostname the server certificate must be valid for, when the certificate is verified.
+    /// By default, the certificate is verified against the SNI (--tls-sni-override) or the host of the server url.
+    /// Useful when the SNI sent differs from the name of the certificate, or when SNI is disabled
+    #[arg(long, value_name = "HOSTNAME", value_parser = parse_tls_verify_hostname, verbatim_doc_comment)]
+    tls_verify_hostname: Option<ServerName<'static>>,
+
+    /// Pin the public key of the server certificate. Can be specified multiple times
+    /// The pin is the base64 of the sha256 of the certificate SubjectPublicKeyInfo, with an optional sha256/ prefix.
+    /// When set, the connection is only accepted if the server certificate matches one of the pins, even if not signed by a trusted CA.
+    /// Cannot be combined with --tls-verify-certificate=false
+    /// i.e: openssl x509 -in cert.pem -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64
+    #[arg(long, value_name = "BASE64_SHA256", verbatim_doc_comment)]
+    tls_certificate_pin: Vec<String>,
+
+    /// Protocols to offer with ALPN during the TLS handshake, by order of preference. Can be specified multiple times
+    /// By default http/1.1 is offered for wss:// and h2 for https://, the list must still contain it.
+    /// Useful when a CDN in front of the server expects other protocols to be offered as well.
+    /// When set, the connection is rejected if the server does not negotiate the protocol of the transport
+    /// i.e: --tls-alpn-protocol h2 --tls-alpn-protocol http/1.1
+    #[arg(long, value_name = "PROTOCOL", verbatim_doc_comment)]
+    tls_alpn_protocol: Vec<String>,
+
+    /// Oldest TLS version to accept when connecting to the server, 1.2 or 1.3.
+    /// Set it to 1.3 to refuse to downgrade to TLS 1.2, the connection fails if the server does not support 1.3
+    #[arg(long, value_name = "VERSION", default_value = "1.2", verbatim_doc_comment)]
+    tls_min_version: TlsVersion,
+
+    /// Path to a PEM bundle of CA certificates used to verify the server certificate, instead of the system ones.
+    /// The file can contain multiple certificates
+    #[arg(long, value_name = "FILE_PATH", verbatim_doc_comment)]
+    tls_root_ca: Option<PathBuf>,
+
+    /// Trust the system CA certificates in addition to the ones of --tls-root-ca
+    #[arg(long, requires = "tls_root_ca", verbatim_doc_comment)]
+    tls_root_ca_with_system_roots: bool,
+
     /// If set, will use this http proxy to connect to the server
     #[arg(
         short = 'p',
@@ -172,6 +350,18 @@ struct Client {
     )]
     http_proxy_password: Option<String>,
 
+    /// Comma separated list of hosts that must be reached directly, without going through the http proxy.
+    /// An entry matches the host and all its subdomains, ip addresses must match exactly and '*' disables the proxy
+    /// i.e: --no-proxy 'localhost,.corp.example.com,10.0.0.1'
+    #[arg(
+        long,
+        value_name = "HOSTS",
+        value_delimiter = ',',
+        verbatim_doc_comment,
+        env = "NO_PROXY"
+    )]
+    no_proxy: Vec<String>,
+
     /// Use a specific prefix that will show up in the http path during the upgrade request.
     /// Useful if you need to route requests server side but don't have vhosts
     /// When using mTLS this option overrides the default behavior of using the common name of the
@@ -190,26 +380,202 @@ struct Client {
     #[arg(long, value_name = "USER[:PASS]", value_parser = parse_http_credentials, verbatim_doc_comment)]
     http_upgrade_credentials: Option<HeaderValue>,
 
+    /// Pass a shared secret token in an `Authorization: Bearer` header during the upgrade request.
+    /// To use with a server started with the same --http-upgrade-bearer-token
+    #[arg(
+        long,
+        value_name = "TOKEN",
+        value_parser = parse_http_bearer_token,
+        conflicts_with = "http_upgrade_credentials",
+        verbatim_doc_comment,
+        env = "WSTUNNEL_HTTP_UPGRADE_BEARER_TOKEN"
+    )]
+    http_upgrade_bearer_token: Option<HeaderValue>,
+
+    /// Secret signing the tunnel information sent to the server, instead of the built-in one.
+    /// The server must accept it with its own --jwt-secret
+    #[arg(long, value_name = "SECRET", verbatim_doc_comment, env = "WSTUNNEL_JWT_SECRET")]
+    jwt_secret: Option<String>,
+
+    /// Sign the tunnel information with an asymmetric algorithm instead of a shared secret.
+    /// The server verifies it with the public key given to its --jwt-public-key
+    #[arg(long, value_name = "ALGORITHM", requires = "jwt_private_key", verbatim_doc_comment)]
+    jwt_algorithm: Option<JwtAlgorithm>,
+
+    /// PEM file of the private key signing the tunnel information with --jwt-algorithm (PKCS#8, or PKCS#1 for RSA).
+    /// It only signs, the information sent back by the server is verified with --jwt-server-public-key
+    #[arg(
+        long,
+        value_name = "FILE_PATH",
+        requires = "jwt_algorithm",
+        conflicts_with = "jwt_secret",
+        verbatim_doc_comment
+    )]
+    jwt_private_key: Option<PathBuf>,
+
+    /// PEM file of the public key of the server's --jwt-private-key, verifying the information it sends back
+    /// for the reverse socks5/http proxy tunnels. Can be given several times to rotate the key
+    #[arg(long, value_name = "FILE_PATH", requires = "jwt_algorithm", verbatim_doc_comment)]
+    jwt_server_public_key: Vec<PathBuf>,
+
+    /// The tunnel information sent to the server expires after this amount of seconds,
+    /// so a captured upgrade request cannot be replayed past it
+    #[arg(long, value_name = "DURATION_IN_SECONDS", default_value = "60", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    jwt_ttl_sec: Duration,
+
+    /// Audience of the tunnel information sent to the server, it must match the server --jwt-audience
+    #[arg(long, value_name = "AUDIENCE", verbatim_doc_comment)]
+    jwt_audience: Option<String>,
+
     /// Frequency at which the client will send websocket ping to the server.
     #[arg(long, value_name = "seconds", default_value = "30", value_parser = parse_duration_sec, verbatim_doc_comment)]
     websocket_ping_frequency_sec: Option<Duration>,
 
-    /// Enable the masking of websocket frames. Default is false
-    /// Enable this option only if you use unsecure (non TLS) websocket server, and you see some issues. Otherwise, it is just overhead.
+    /// Only send a ping when no data has been sent to the server for --websocket-ping-frequency-sec.
+    /// Busy tunnels don't need pings to prove they are alive, while idle ones still keep their NAT mapping open
     #[arg(long, default_value = "false", verbatim_doc_comment)]
+    websocket_adaptive_ping: bool,
+
+    /// Close the tunnel if a websocket ping does not get its pong from the server within this amount of seconds.
+    /// Detects a dead tunnel (i.e: silent NAT drop) long before a TCP error would. Disabled by default.
+    /// The round trip time of the pings is measured either way, and logged at debug level.
+    /// Also applies to the pings of the raw transport (tcp:// and tls://)
+    #[arg(long, value_name = "DURATION_IN_SECONDS", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    websocket_pong_timeout_sec: Option<Duration>,
+
+    /// Close the tunnel once this many websocket pings in a row did not get their pong from the server.
+    /// Reverse tunnels then reconnect. Disabled by default, can be combined with --websocket-pong-timeout-sec
+    #[arg(long, value_name = "INT", verbatim_doc_comment)]
+    missed_pong_limit: Option<usize>,
+
+    /// Mask the websocket frames sent to the server, as RFC 6455 requires from clients. Enabled by default
+    /// Set to false to avoid the masking overhead, i.e: with a TLS websocket server on a trusted network.
+    /// Note: Without masking the client is not spec compliant. This is safe when talking to a wstunnel server,
+    ///       but some middleboxes may reject unmasked frames.
+    #[arg(long, value_name = "BOOL", default_value = "true", action = clap::ArgAction::Set, verbatim_doc_comment)]
     websocket_mask_frame: bool,
 
+    /// Websocket subprotocol to request in the Sec-WebSocket-Protocol header of the upgrade request, instead of v1.
+    /// For proxies enforcing a subprotocol policy. The connection is aborted if the server does not accept this exact one.
+    /// Requires a server recent enough to echo back the requested subprotocol
+    #[arg(long, value_name = "PROTOCOL", value_parser = parse_websocket_subprotocol, verbatim_doc_comment)]
+    websocket_subprotocol: Option<String>,
+
+    /// With the http2 transport, carry the tunnels as concurrent streams of shared connections to the server,
+    /// instead of opening a new connection for each of them. Saves the tcp + tls handshakes and helps with proxies limiting
+    /// the number of connections. A new connection is opened once an existing one carries --http2-multiplex-max-streams tunnels.
+    /// The server should be started with --http2-multiplex too, for a stalled tunnel to not block the uploads of the others
+    #[arg(long, default_value = "false", verbatim_doc_comment)]
+    http2_multiplex: bool,
+
+    /// With --http2-multiplex, number of tunnels carried by a connection to the server before opening a new one
+    #[arg(long, value_name = "INT", default_value = "100", value_parser = clap::value_parser!(u32).range(1..), verbatim_doc_comment)]
+    http2_multiplex_max_streams: u32,
+
+    /// With --http2-multiplex, http2 flow control window of each tunnel. It bounds the data in flight of a tunnel,
+    /// and the window of a whole connection is this times --http2-multiplex-max-streams, for a stalled tunnel to never starve the others
+    #[arg(long, value_name = "BYTES", default_value = "2097152", value_parser = clap::value_parser!(u32).range(65535..=2147483647), verbatim_doc_comment)]
+    http2_multiplex_stream_window: u32,
+
+    /// With the http2 transport, fall back to the websocket transport when http2 does not go through to the server.
+    /// i.e: a middlebox strips ALPN, or resets the connection during the http2 handshake.
+    /// The tunnel is then made with a new connection offering only http/1.1, and the next ones to this server
+    /// go straight to websocket for 5 minutes before http2 is tried again
+    #[arg(long, default_value = "false", verbatim_doc_comment)]
+    transport_fallback: bool,
+
+    /// Close a tunnel if no data has been transferred in either direction for this amount of seconds.
+    /// Useful to clean up half-open connections (i.e: NAT timeout, dead peer). By default, tunnels are never closed for inactivity
+    #[arg(long, value_name = "DURATION_IN_SECONDS", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    idle_timeout_sec: Option<Duration>,
+
+    /// How a local connection is closed when its tunnel cannot be established, i.e: the server cannot reach the
+    /// destination. With abrupt, the tcp connection is reset for its peer to fail right away instead of seeing an
+    /// empty response. For reverse tunnels, the server is told with a close frame and applies its own setting
+    #[arg(long, value_name = "BEHAVIOR", default_value = "graceful", verbatim_doc_comment)]
+    connect_failure_behavior: ConnectFailureBehavior,
+
+    /// Opaque value sent with every tunnel, i.e: a tenant or user id. The server logs it and its restrictions
+    /// can match it with !Metadata, to attribute the tunnels without a separate channel.
+    /// Anyone knowing the secret can send any metadata, the server restrictions ignore it with the default --jwt-secret
+    #[arg(long, value_name = "STRING", verbatim_doc_comment)]
+    tunnel_metadata: Option<String>,
+
+    /// Close a tunnel once it has been opened for this amount of seconds, even if data is still flowing.
+    /// Useful to enforce the rotation of the sessions, or to not pin a server during rolling restarts. By default, tunnels live forever
+    #[arg(long, value_name = "DURATION_IN_SECONDS", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    max_tunnel_duration_sec: Option<Duration>,
+
+    /// Maximum throughput in bytes per second of each tunnel, applied independently to each direction.
+    /// The transfer is paced regularly rather than stopped once a burst is done. By default, tunnels are not limited
+    #[arg(long, value_name = "BYTES_PER_SECOND", value_parser = clap::value_parser!(u64).range(1..), verbatim_doc_comment)]
+    max_bytes_per_sec: Option<u64>,
+
+    /// Maximum throughput in bytes per second towards the server, shared by all the tunnels together.
+    /// Tunnels get their turn in order, so a long transfer does not starve the new tunnels. By default, there is no limit
+    #[arg(long, value_name = "BYTES_PER_SECOND", value_parser = clap::value_parser!(u64).range(1..), verbatim_doc_comment)]
+    max_egress_bytes_per_sec: Option<u64>,
+
+    /// Maximum throughput in bytes per second from the server, shared by all the tunnels together. By default, there is no limit
+    #[arg(long, value_name = "BYTES_PER_SECOND", value_parser = clap::value_parser!(u64).range(1..), verbatim_doc_comment)]
+    max_ingress_bytes_per_sec: Option<u64>,
+
+    /// On shutdown (ctrl+c), stop accepting new connections and wait up to this amount of seconds
+    /// for the tunnels in flight to finish. Tunnels still open after this delay are forcibly closed
+    #[arg(long, value_name = "DURATION_IN_SECONDS", default_value = "10", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    shutdown_grace_period_sec: Duration,
+
+    /// Size in bytes of the buffer used to read data from local connections, before sending it to the server.
+    /// A bigger buffer helps to saturate links with a high latency, a smaller one saves memory when there are many tunnels.
+    /// Memory used is roughly copy_buffer_size * 2 * number of concurrent tunnels. Minimum is 4096 (4KiB).
+    /// Default is 1048576 (1MiB) for http2, to keep enough data in flight for its flow control, and 65536 (64KiB) otherwise.
+    /// For websocket, this is only the initial size, the buffer grows when a read fills it entirely.
+    /// UDP tunnels always use at least 65536 (64KiB), to be able to receive a whole datagram
+    #[arg(long, value_name = "BYTES", value_parser = parse_copy_buffer_size, verbatim_doc_comment)]
+    copy_buffer_size: Option<usize>,
+
     /// Send custom headers in the upgrade request
-    /// Can be specified multiple time
+    /// Can be specified multiple time, a header given multiple times is sent multiple times.
+    /// A `Host` header replaces the one derived from the server url, without changing where the client connects to.
+    /// i.e: for domain fronting: -H 'Host: origin.example.com' --tls-sni-override cdn.example.com wss://203.0.113.10
+    /// ${VAR} in the value is replaced by the content of the environment variable VAR, i.e: -H 'Authorization: Bearer ${TOKEN}'
     #[arg(short='H', long, value_name = "HEADER_NAME: HEADER_VALUE", value_parser = parse_http_headers, verbatim_doc_comment)]
     http_headers: Vec<(HeaderName, HeaderValue)>,
 
     /// Send custom headers in the upgrade request reading them from a file.
     /// It overrides http_headers specified from command line.
-    /// File is read everytime and file format must contain lines with `HEADER_NAME: HEADER_VALUE`
+    /// File is read everytime and file format must contain lines with `HEADER_NAME: HEADER_VALUE`. ${VAR} is expanded too
     #[arg(long, value_name = "FILE_PATH", verbatim_doc_comment)]
     http_headers_file: Option<PathBuf>,
 
+    /// User-Agent header to send in the upgrade request. None is sent by default
+    /// i.e: --user-agent 'Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/126.0.0.0 Safari/537.36'
+    #[arg(long, value_name = "USER_AGENT", verbatim_doc_comment)]
+    user_agent: Option<HeaderValue>,
+
+    /// Comma separated list of header names, to send the headers of the upgrade request in this order.
+    /// The headers not listed are sent after, the listed ones that are not part of the request are skipped.
+    /// Combined with -H and --user-agent, allows the request to look like the one of a browser.
+    /// Header names are always lowercase with the http2 transport, and with websocket as hyper does not keep their case
+    /// i.e: --http-headers-order 'host,connection,pragma,cache-control,user-agent,upgrade,origin,sec-websocket-version,sec-websocket-key'
+    #[arg(long, value_name = "HEADER_NAMES", value_delimiter = ',', verbatim_doc_comment)]
+    http_headers_order: Vec<HeaderName>,
+
+    /// Read the options from a yaml file, with the option names as keys. Unknown options are rejected.
+    /// An option set on the command line replaces the one of the file, lists included.
+    /// The file takes precedence over the environment variables of the options (i.e: WSTUNNEL_JWT_SECRET).
+    /// ${VAR} in the values is replaced by the content of the environment variable VAR
+    /// i.e:
+    ///   remote_addr: wss://wstunnel.example.com
+    ///   http_upgrade_credentials: "${WSTUNNEL_CREDENTIALS}"
+    ///   websocket_mask_frame: false
+    ///   local_to_remote:
+    ///     - tcp://1212:google.com:443
+    ///     - udp://1212:1.1.1.1:53
+    #[cfg(feature = "config-file")]
+    #[arg(long, value_name = "FILE_PATH", verbatim_doc_comment)]
+    config: Option<PathBuf>,
+
     /// Address of the wstunnel server
     /// You can either use websocket or http2 as transport protocol. Use websocket if you are unsure.
     /// Example: For websocket with TLS wss://wstunnel.example.com or without ws://wstunnel.example.com
@@ -221,9 +587,27 @@ struct Client {
     ///   - if you have wstunnel behind a reverse proxy, most of them (i.e: nginx) are going to turn http2 request into http1
     ///     This is not going to work, because http1 does not support streaming naturally
     ///   - The only way to make it works with http2 is to have wstunnel directly exposed to the internet without any reverse proxy in front of it
-    #[arg(value_name = "ws[s]|http[s]://wstunnel.server.com[:port]", value_parser = parse_server_url, verbatim_doc_comment)]
+    ///
+    /// For trusted links, tcp://wstunnel.example.com:port or tls://wstunnel.example.com:port tunnel without any http upgrade.
+    /// The server must be started with the same scheme. It does not go through http proxies, CDN or load balancers,
+    /// only through ones doing CONNECT. It has ping frames like websocket, sent every --websocket-ping-frequency-sec
+    #[arg(value_name = "ws[s]|http[s]|tcp|tls://wstunnel.server.com[:port]", value_parser = parse_server_url, verbatim_doc_comment)]
     remote_addr: Url,
 
+    /// Address of a fallback wstunnel server, used if the main one is not reachable. Can be specified multiple times
+    /// Servers are tried in order and the client sticks to the last one that worked.
+    /// They must use the same transport protocol (ws, wss, http, https, tcp, tls) as the main server, and share its tls and http options
+    #[arg(long, value_name = "ws[s]|http[s]://wstunnel.server.com[:port]", value_parser = parse_server_url, verbatim_doc_comment)]
+    fallback_server: Vec<Url>,
+
+    /// How to choose the server to connect to, when --fallback-server is used
+    #[arg(long, value_enum, default_value = "failover", verbatim_doc_comment)]
+    remote_selection: RemoteSelection,
+
+    /// Once a server has been found unreachable, it is only tried after the other ones for this amount of seconds
+    #[arg(long, value_name = "DURATION_IN_SECONDS", default_value = "30", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    remote_cooldown_sec: Duration,
+
     /// [Optional] Certificate (pem) to present to the server when connecting over TLS (HTTPS).
     /// Used when the server requires clients to authenticate themselves with a certificate (i.e. mTLS).
     /// Unless overridden, the HTTP upgrade path will be configured to be the common name (CN) of the certificate.
@@ -237,6 +621,7 @@ struct Client {
     tls_private_key: Option<PathBuf>,
 
     /// Dns resolver to use to lookup ips of domain name. Can be specified multiple time
+    /// It is used to resolve the wstunnel server and the destinations of reverse tunnels. Answers are cached respecting their TTL
     /// Example:
     ///  dns://1.1.1.1 for using udp
     ///  dns+https://1.1.1.1?sni=cloudflare-dns.com for using dns over HTTPS
@@ -259,6 +644,14 @@ struct Client {
         verbatim_doc_comment
     )]
     dns_resolver_prefer_ipv4: bool,
+
+    /// Resolve statically a hostname to the given ips, without querying the dns resolver. Can be specified multiple time
+    /// Matching is case-insensitive, and a leading wildcard is supported to match all sub-domains
+    /// Example:
+    ///  --dns-static-override example.com=10.0.0.1
+    ///  --dns-static-override '*.internal=10.0.0.2,fd00::2'
+    #[arg(long, value_name = "HOST=IP[,IP...]", value_parser = parse_dns_static_override, verbatim_doc_comment)]
+    dns_static_override: Vec<(String, Vec<IpAddr>)>,
 }
 
 #[derive(clap::Args, Debug)]
@@ -267,7 +660,10 @@ struct Server {
     /// Example: With TLS wss://0.0.0.0:8080 or without ws://[::]:8080
     ///
     /// The server is capable of detecting by itself if the request is websocket or http2. So you don't need to specify it.
-    #[arg(value_name = "ws[s]://0.0.0.0[:port]", value_parser = parse_server_url, verbatim_doc_comment)]
+    ///
+    /// With tcp://0.0.0.0:8080 or tls://0.0.0.0:8080 the server only serves clients of the raw transport, tunneling
+    /// without any http upgrade. For trusted links only: it does not go through http proxies and has no health check
+    #[arg(value_name = "ws[s]|tcp|tls://0.0.0.0[:port]", value_parser = parse_server_url, verbatim_doc_comment)]
     remote_addr: Url,
 
     /// (linux only) Mark network packet with SO_MARK sockoption with the specified value.
@@ -275,6 +671,48 @@ struct Server {
     #[arg(long, value_name = "INT", verbatim_doc_comment)]
     socket_so_mark: Option<u32>,
 
+    /// Source ip to use for the outbound connections to the tunnels destinations, instead of letting the OS pick it.
+    /// Only destinations of the same ip family (v4 or v6) can be reached
+    #[arg(long, value_name = "IP", verbatim_doc_comment)]
+    socket_bind_address: Option<IpAddr>,
+
+    /// (linux only) Bind the outbound connections to the tunnels destinations to the specified network interface
+    /// with SO_BINDTODEVICE sockoption. i.e: eth1
+    /// You need to use {root, sudo, capabilities} to run wstunnel when using this option
+    #[arg(long, value_name = "INTERFACE", verbatim_doc_comment)]
+    socket_bind_device: Option<String>,
+
+    /// When a hostname resolves to several addresses (i.e: IPv6 and IPv4), delay before trying the next address
+    /// while the previous connection attempt is still pending (Happy Eyeballs, RFC 8305).
+    /// Apply to the connections to the destinations of the tunnels.
+    /// The addresses are tried in the order of --dns-resolver-prefer-ipv4, IPv6 and IPv4 alternating
+    #[arg(long, value_name = "ms", default_value = "250", value_parser = parse_duration_ms, verbatim_doc_comment)]
+    happy_eyeballs_delay_ms: Duration,
+
+    /// Number of the addresses of a hostname tried at once, the first one to connect wins and the others are closed.
+    /// The next addresses are tried after the happy eyeballs delay, or as soon as an attempt fails.
+    /// The address that connected is then tried first, alone, on the next connections to the hostname for a minute
+    #[arg(long, value_name = "INT", default_value = "1", value_parser = clap::value_parser!(u16).range(1..), verbatim_doc_comment)]
+    connect_parallelism: u16,
+
+    /// Disable Nagle's algorithm (TCP_NODELAY) on the tcp sockets carrying the tunnels traffic.
+    /// Enabled by default to not add latency to interactive traffic (i.e: ssh), set to false to favor throughput
+    #[arg(long, value_name = "BOOL", default_value = "true", action = clap::ArgAction::Set, verbatim_doc_comment)]
+    tcp_nodelay: bool,
+
+    /// Idle time before sending TCP keepalive probes on the tcp sockets carrying the tunnels traffic.
+    /// TCP keepalive allows to detect dead peers at the kernel level, it complements but does not replace the websocket ping
+    #[arg(long, value_name = "seconds", default_value = "60", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    tcp_keepalive_idle_sec: Duration,
+
+    /// Interval between TCP keepalive probes
+    #[arg(long, value_name = "seconds", default_value = "10", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    tcp_keepalive_interval_sec: Duration,
+
+    /// Number of unanswered TCP keepalive probes before considering the peer dead. Not supported on windows and openbsd
+    #[arg(long, value_name = "INT", default_value = "3", verbatim_doc_comment)]
+    tcp_keepalive_retries: u32,
+
     /// Frequency at which the server will send websocket ping to client.
     #[arg(long, value_name = "seconds", value_parser = parse_duration_sec, verbatim_doc_comment)]
     websocket_ping_frequency_sec: Option<Duration>,
@@ -286,7 +724,7 @@ struct Server {
 
     /// Dns resolver to use to lookup ips of domain name
     /// This option is not going to work if you use transparent proxy
-    /// Can be specified multiple time
+    /// Can be specified multiple time. Answers are cached respecting their TTL
     /// Example:
     ///  dns://1.1.1.1 for using udp
     ///  dns+https://1.1.1.1?sni=cloudflare-dns.com for using dns over HTTPS
@@ -307,6 +745,14 @@ struct Server {
     )]
     dns_resolver_prefer_ipv4: bool,
 
+    /// Resolve statically a hostname to the given ips, without querying the dns resolver. Can be specified multiple time
+    /// Matching is case-insensitive, and a leading wildcard is supported to match all sub-domains
+    /// Example:
+    ///  --dns-static-override example.com=10.0.0.1
+    ///  --dns-static-override '*.internal=10.0.0.2,fd00::2'
+    #[arg(long, value_name = "HOST=IP[,IP...]", value_parser = parse_dns_static_override, verbatim_doc_comment)]
+    dns_static_override: Vec<(String, Vec<IpAddr>)>,
+
     /// Server will only accept connection from the specified tunnel information.
     /// Can be specified multiple time
     /// Example: --restrict-to "google.com:443" --restrict-to "localhost:22"
@@ -331,13 +777,249 @@ struct Server {
     )]
     restrict_http_upgrade_path_prefix: Option<Vec<String>>,
 
+    /// Only answer upgrade requests made under this http path prefix, i.e: api/v2/stream
+    /// Any other path gets a 404 Not Found, before the tunnel information of the request is even looked at.
+    /// Use the same value as the client --http-upgrade-path-prefix
+    #[arg(long, value_name = "PATH_PREFIX", verbatim_doc_comment)]
+    http_upgrade_path_prefix: Option<String>,
+
+    /// Only accept upgrade requests carrying this token in an `Authorization: Bearer` header, others get a 401.
+    /// Cheap protection against scanners, checked before the tunnel information of the request.
+    /// Use the same value as the client --http-upgrade-bearer-token
+    #[arg(
+        long,
+        value_name = "TOKEN",
+        verbatim_doc_comment,
+        env = "WSTUNNEL_HTTP_UPGRADE_BEARER_TOKEN"
+    )]
+    http_upgrade_bearer_token: Option<String>,
+
+    /// Secret verifying the tunnel information sent by the clients, instead of the built-in one.
+    /// Can be given several times to rotate it without downtime: tunnels signed with any of them are accepted,
+    /// and the last one signs the information sent back to the clients. Use the same value as the client --jwt-secret
+    #[arg(long, value_name = "SECRET", verbatim_doc_comment, env = "WSTUNNEL_JWT_SECRET")]
+    jwt_secret: Vec<String>,
+
+    /// File with the secrets verifying the tunnel information, one per line, the last one signing.
+    /// Used after the ones of --jwt-secret. To rotate them without restarting, edit it and send a SIGHUP to the
+    /// server: the secrets and the key files of --jwt-public-key/--jwt-private-key are read again
+    #[arg(
+        long,
+        value_name = "FILE_PATH",
+        conflicts_with = "jwt_algorithm",
+        verbatim_doc_comment
+    )]
+    jwt_secret_file: Option<PathBuf>,
+
+    /// Verify the tunnel information with an asymmetric algorithm instead of a shared secret.
+    /// Tokens whose header announces another algorithm are rejected
+    #[arg(long, value_name = "ALGORITHM", verbatim_doc_comment)]
+    jwt_algorithm: Option<JwtAlgorithm>,
+
+    /// PEM file of the public key (SubjectPublicKeyInfo) of the clients' --jwt-private-key, verifying the tunnel
+    /// information with --jwt-algorithm. Can be given several times to rotate the key.
+    /// The server cannot forge tunnel information with it
+    #[arg(
+        long,
+        value_name = "FILE_PATH",
+        requires = "jwt_algorithm",
+        conflicts_with = "jwt_secret",
+        verbatim_doc_comment
+    )]
+    jwt_public_key: Vec<PathBuf>,
+
+    /// PEM file of the server's own private key, signing the information sent back to the clients with --jwt-algorithm.
+    /// Only needed by the reverse socks5/http proxy tunnels, the clients verify it with its public key given to their
+    /// --jwt-server-public-key. It must not be the key of the clients, it does not verify their tunnel information
+    #[arg(
+        long,
+        value_name = "FILE_PATH",
+        requires = "jwt_algorithm",
+        conflicts_with = "jwt_secret",
+        verbatim_doc_comment
+    )]
+    jwt_private_key: Option<PathBuf>,
+
+    /// The information sent back to the clients expires after this amount of seconds
+    #[arg(long, value_name = "DURATION_IN_SECONDS", default_value = "60", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    jwt_ttl_sec: Duration,
+
+    /// Reject the tunnel information valid for longer than this amount of seconds, whatever the --jwt-ttl-sec
+    /// of the client signing it
+    #[arg(long, value_name = "DURATION_IN_SECONDS", default_value = "300", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    jwt_max_ttl_sec: Duration,
+
+    /// Tolerated clock difference with the clients when checking the expiration of the tunnel information
+    #[arg(long, value_name = "DURATION_IN_SECONDS", default_value = "30", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    jwt_leeway_sec: Duration,
+
+    /// Only accept the tunnel information meant for this audience, set by the clients with --jwt-audience
+    #[arg(long, value_name = "AUDIENCE", verbatim_doc_comment)]
+    jwt_audience: Option<String>,
+
+    /// Accept the tunnel information without expiration nor unique id, as sent by older clients.
+    /// Rejected by default, as such information can be replayed forever
+    #[arg(long, default_value = "false", verbatim_doc_comment)]
+    jwt_allow_missing_expiration: bool,
+
+    /// Maximum number of tunnel information ids (jti) to remember, to reject a captured upgrade request replayed
+    /// before it expires. When it is full, new tunnels are rejected until some ids expire. 0 to disable it
+    #[arg(long, value_name = "INT", default_value = "100000", verbatim_doc_comment)]
+    jwt_replay_cache_size: usize,
+
+    /// The ids are forgotten once their tunnel information expires, at most --jwt-max-ttl-sec plus --jwt-leeway-sec
+    /// after being signed. The ids of the ones without expiration are forgotten after this amount of seconds
+    #[arg(long, value_name = "DURATION_IN_SECONDS", default_value = "90", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    jwt_replay_cache_ttl_sec: Duration,
+
+    /// Maximum number of new tunnels per second to the same destination (host:port), to not be used to scan or
+    /// brute-force it. Tunnels above the limit are rejected. Disabled by default
+    #[arg(long, value_name = "FLOAT", verbatim_doc_comment)]
+    destination_rate_limit: Option<f64>,
+
+    /// Number of tunnels to the same destination that can be opened at once before --destination-rate-limit kicks in
+    #[arg(
+        long,
+        value_name = "INT",
+        default_value = "10",
+        requires = "destination_rate_limit",
+        verbatim_doc_comment
+    )]
+    destination_rate_limit_burst: u32,
+
+    /// Answer 200 OK to GET requests on this http path, i.e: /healthz
+    /// For load balancers to check the server is up without establishing a tunnel. The request is answered before
+    /// any other check (path prefix, bearer token, jwt)
+    #[arg(long, value_name = "PATH", verbatim_doc_comment)]
+    health_check_path: Option<String>,
+
+    /// Include the version of wstunnel in the response to the health checks. Disabled by default to not leak it
+    #[arg(long, default_value = "false", requires = "health_check_path", verbatim_doc_comment)]
+    health_check_expose_version: bool,
+
+    /// On shutdown (SIGTERM or ctrl+c), stop accepting new tunnels and wait up to this amount of seconds
+    /// for the ones in flight to finish, before aborting them. Meanwhile, upgrade requests get a 503 Service Unavailable
+    /// and so do the health checks, for the load balancers to stop sending new connections
+    #[arg(long, value_name = "DURATION_IN_SECONDS", default_value = "30", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    shutdown_grace_period_sec: Duration,
+
+    /// Close the tunnels once the client sent more than this amount of bytes through them.
+    /// The client is told why with a websocket close frame carrying the policy violation code (1008)
+    /// Unlimited by default
+    #[arg(long, value_name = "BYTES", verbatim_doc_comment)]
+    max_bytes_per_tunnel_upload: Option<u64>,
+
+    /// Close the tunnels once the client received more than this amount of bytes through them.
+    /// The client is told why with a websocket close frame carrying the policy violation code (1008)
+    /// Unlimited by default
+    #[arg(long, value_name = "BYTES", verbatim_doc_comment)]
+    max_bytes_per_tunnel_download: Option<u64>,
+
+    /// Accept the clients multiplexing their tunnels over http2 (client --http2-multiplex), by announcing flow control
+    /// windows large enough for a stalled tunnel to not block the uploads of the others on the same connection.
+    /// Without it, the http2 connections keep the default windows, shared by all the streams of a connection
+    #[arg(long, default_value = "false", verbatim_doc_comment)]
+    http2_multiplex: bool,
+
+    /// With --http2-multiplex, maximum number of concurrent tunnels announced to each http2 connection
+    #[arg(long, value_name = "INT", default_value = "100", value_parser = clap::value_parser!(u32).range(1..), verbatim_doc_comment)]
+    http2_multiplex_max_streams: u32,
+
+    /// With --http2-multiplex, http2 flow control window of each tunnel. The window of a connection is this times
+    /// --http2-multiplex-max-streams, so memory used for a client is up to this amount for each of its tunnels
+    #[arg(long, value_name = "BYTES", default_value = "2097152", value_parser = clap::value_parser!(u32).range(65535..=2147483647), verbatim_doc_comment)]
+    http2_multiplex_stream_window: u32,
+
+    /// Maximum number of tunnels opened at the same time by each subject, i.e: the common name of the client
+    /// certificate with mTLS, or else the ip of the client (X-Forwarded-For is not trusted for it).
+    /// New tunnels above it are closed with the policy violation code (1008) right after the websocket upgrade,
+    /// or rejected with 429 Too Many Requests over http2. The ones of other subjects are unaffected.
+    /// Unlimited by default
+    #[arg(long, value_name = "INT", verbatim_doc_comment)]
+    max_tunnels_per_subject: Option<usize>,
+
+    /// Maximum number of bytes all the tunnels of each subject can transfer, both directions together. Once reached,
+    /// its tunnels are closed with the policy violation code (1008) and new ones are rejected. The count is forgotten
+    /// once the subject has no tunnel for an hour. Unlimited by default
+    #[arg(long, value_name = "BYTES", verbatim_doc_comment)]
+    max_bytes_per_subject: Option<u64>,
+
+    /// Limits of a given subject, instead of --max-tunnels-per-subject and --max-bytes-per-subject
+    /// Format: SUBJECT=MAX_TUNNELS[:MAX_BYTES], an empty value means unlimited
+    /// i.e: --subject-limit "acme.example.com=10:1000000000" --subject-limit "192.168.1.10=1" --subject-limit "admin=:"
+    #[arg(long, value_name = "SUBJECT=MAX_TUNNELS[:MAX_BYTES]", value_parser = parse_subject_limit, verbatim_doc_comment)]
+    subject_limit: Vec<(String, SubjectLimit)>,
+
+    /// How the connections accepted by the reverse tcp tunnels are closed, when the client cannot connect them to
+    /// their destination or goes away. With abrupt, they are reset for their peer to fail right away
+    #[arg(long, value_name = "BEHAVIOR", default_value = "graceful", verbatim_doc_comment)]
+    connect_failure_behavior: ConnectFailureBehavior,
+
+    /// Tell the clients why their tunnel could not be opened (refused, unreachable, timed out, not allowed), for them
+    /// to reply it to their socks5 peers. Disabled by default, as any client able to forge a token could then probe
+    /// which hosts and ports of the network of the server are open
+    #[arg(long, default_value = "false", verbatim_doc_comment)]
+    report_connect_errors: bool,
+
+    /// Command run for each exec tunnel of the clients (-L tcp+exec://), with the tunnel connected to its stdin/stdout.
+    /// It is not run by a shell, its arguments are separated by spaces, and {host} and {port} in them are replaced by
+    /// the destination requested by the client. Exec tunnels must also be explicitly allowed in the restrictions
+    /// Refused unless the clients are authenticated, with a --jwt-secret other than the default one or with mTLS
+    /// Example: --exec-command "ssh -W {host}:{port} bastion"
+    #[arg(long, value_name = "COMMAND", verbatim_doc_comment)]
+    exec_command: Option<String>,
+
+    /// Verify the TLS certificate of the destinations of the tcp+tls tunnels (-L tcp+tls://). Enabled by default
+    /// The certificate is verified against the host of the destination, which is also sent as SNI.
+    /// Use --destination-tls-verify-certificate=false to accept any certificate, i.e: for self-signed destinations
+    #[arg(long, value_name = "BOOL", default_value = "true", default_missing_value = "true", num_args = 0..=1, require_equals = true, action = clap::ArgAction::Set, verbatim_doc_comment)]
+    destination_tls_verify_certificate: bool,
+
+    /// Pin the public key of the certificate of the destinations of the tcp+tls tunnels. Can be specified multiple times
+    /// Same format as --tls-certificate-pin of the client. Verified even with --destination-tls-verify-certificate=false
+    #[arg(long, value_name = "BASE64_SHA256", verbatim_doc_comment)]
+    destination_tls_certificate_pin: Vec<String>,
+
+    /// Path to a PEM bundle of CA certificates used to verify the destinations of the tcp+tls tunnels,
+    /// instead of the system ones. The file can contain multiple certificates
+    #[arg(long, value_name = "FILE_PATH", verbatim_doc_comment)]
+    destination_tls_root_ca: Option<PathBuf>,
+
+    /// Trust the system CA certificates in addition to the ones of --destination-tls-root-ca
+    #[arg(long, requires = "destination_tls_root_ca", verbatim_doc_comment)]
+    destination_tls_root_ca_with_system_roots: bool,
+
+    /// Disable sending SNI during the TLS handshake with the destinations of the tcp+tls tunnels
+    #[arg(long, verbatim_doc_comment)]
+    destination_tls_sni_disable: bool,
+
+    /// Oldest TLS version to accept from the destinations of the tcp+tls tunnels, 1.2 or 1.3
+    #[arg(long, value_name = "VERSION", default_value = "1.2", verbatim_doc_comment)]
+    destination_tls_min_version: TlsVersion,
+
+    /// [Optional] Certificate (pem) to present to the destinations of the tcp+tls tunnels which require clients
+    /// to authenticate themselves with a certificate (i.e. mTLS)
+    #[arg(long, value_name = "FILE_PATH", verbatim_doc_comment)]
+    destination_tls_certificate: Option<PathBuf>,
+
+    /// [Optional] The private key for the corresponding certificate used with mTLS with the destinations
+    #[arg(long, value_name = "FILE_PATH", verbatim_doc_comment)]
+    destination_tls_private_key: Option<PathBuf>,
+
     /// Path to the location of the restriction yaml config file.
-    /// Restriction file is automatically reloaded if it changes
+    /// Restriction file is automatically reloaded if it changes, or when the server receives a SIGHUP
     #[arg(long, verbatim_doc_comment)]
     restrict_config: Option<PathBuf>,
 
+    /// Path to a MaxMind country database (i.e: GeoLite2-Country.mmdb), to tag the logs of the tunnels with the country
+    /// of the clients. The address from X-Forwarded-For is preferred over the one of the connection when present
+    #[cfg(feature = "geoip")]
+    #[arg(long, value_name = "FILE_PATH", verbatim_doc_comment)]
+    geoip_database: Option<PathBuf>,
+
     /// [Optional] Use custom certificate (pem) instead of the default embedded self-signed certificate.
     /// The certificate will be automatically reloaded if it changes
+    /// Clients only accept the embedded one with --tls-verify-certificate=false or with its --tls-certificate-pin
     #[arg(long, value_name = "FILE_PATH", verbatim_doc_comment)]
     tls_certificate: Option<PathBuf>,
 
@@ -352,6 +1034,11 @@ struct Server {
     #[arg(long, value_name = "FILE_PATH", verbatim_doc_comment)]
     tls_client_ca_certs: Option<PathBuf>,
 
+    /// Oldest TLS version accepted from the clients, 1.2 or 1.3.
+    /// Set it to 1.3 to only accept TLS 1.3 clients
+    #[arg(long, value_name = "VERSION", default_value = "1.2", verbatim_doc_comment)]
+    tls_min_version: TlsVersion,
+
     /// If set, will use this http proxy to connect to the client
     #[arg(
         short = 'p',
@@ -376,66 +1063,47 @@ struct Server {
     http_proxy_password: Option<String>,
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
-enum LocalProtocol {
-    Tcp {
-        proxy_protocol: bool,
-    },
-    Udp {
-        timeout: Option<Duration>,
-    },
-    Stdio,
-    Socks5 {
-        timeout: Option<Duration>,
-        credentials: Option<(String, String)>,
-    },
-    TProxyTcp,
-    TProxyUdp {
-        timeout: Option<Duration>,
-    },
-    HttpProxy {
-        timeout: Option<Duration>,
-        credentials: Option<(String, String)>,
-        proxy_protocol: bool,
-    },
-    ReverseTcp,
-    ReverseUdp {
-        timeout: Option<Duration>,
-    },
-    ReverseSocks5 {
-        timeout: Option<Duration>,
-        credentials: Option<(String, String)>,
-    },
-    ReverseHttpProxy {
-        timeout: Option<Duration>,
-        credentials: Option<(String, String)>,
-    },
-    ReverseUnix {
-        path: PathBuf,
-    },
-    Unix {
-        path: PathBuf,
-    },
-}
-
-impl LocalProtocol {
-    pub const fn is_reverse_tunnel(&self) -> bool {
-        matches!(
-            self,
-            Self::ReverseTcp
-                | Self::ReverseUdp { .. }
-                | Self::ReverseSocks5 { .. }
-                | Self::ReverseUnix { .. }
-                | Self::ReverseHttpProxy { .. }
-        )
-    }
-}
-
 #[derive(Clone, Debug)]
 pub struct LocalToRemote {
     local_protocol: LocalProtocol,
     local: SocketAddr,
     remote: (Host<String>, u16),
+    expect_proxy_protocol: bool,
+    use_original_dst: bool,
+    priority: TunnelPriority,
+}
+
+fn parse_dns_static_override(arg: &str) -> Result<(String, Vec<IpAddr>), io::Error> {
+    use std::io::Error;
+
+    let Some((host, ips)) = arg.split_once('=') else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse dns override {}, expected HOST=IP[,IP...]", arg),
+        ));
+    };
+
+    let host = host.trim();
+    if host.is_empty() || host == "*" {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid host for dns override {}", arg),
+        ));
+    }
+
+    let ips = ips
+        .split(',')
+        .map(|ip| {
+            IpAddr::from_str(ip.trim()).map_err(|err| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid ip {} for dns override {}: {}", ip, host, err),
+                )
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok((host.to_string(), ips))
 }
 
 fn parse_duration_sec(arg: &str) -> Result<Duration, io::Error> {
@@ -451,6 +1119,39 @@ fn parse_duration_sec(arg: &str) -> Result<Duration, io::Error> {
     Ok(Duration::from_secs(secs))
 }
 
+fn parse_duration_ms(arg: &str) -> Result<Duration, io::Error> {
+    use std::io::Error;
+
+    let Ok(millis) = arg.parse::<u64>() else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot duration of milliseconds from {}", arg),
+        ));
+    };
+
+    Ok(Duration::from_millis(millis))
+}
+
+fn parse_copy_buffer_size(arg: &str) -> Result<usize, io::Error> {
+    use std::io::Error;
+
+    let Ok(size) = arg.parse::<usize>() else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse buffer size from {}", arg),
+        ));
+    };
+
+    if size < MIN_COPY_BUFFER_SIZE {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("buffer size must be at least {} bytes", MIN_COPY_BUFFER_SIZE),
+        ));
+    }
+
+    Ok(size)
+}
+
 fn parse_local_bind(arg: &str) -> Result<(SocketAddr, &str), io::Error> {
     use std::io::Error;
 
@@ -521,18 +1222,46 @@ fn parse_tunnel_dest(remaining: &str) -> Result<(Host<String>, u16, BTreeMap<Str
     Ok((remote_host.to_owned(), remote_port, options))
 }
 
+fn parse_priority(options: &BTreeMap<String, String>) -> Result<TunnelPriority, io::Error> {
+    options
+        .get("priority")
+        .map_or(Ok(TunnelPriority::default()), |priority| {
+            TunnelPriority::from_str(priority).map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))
+        })
+}
+
 fn parse_tunnel_arg(arg: &str) -> Result<LocalToRemote, io::Error> {
     use std::io::Error;
 
     match &arg[..6] {
         "tcp://" => {
             let (local_bind, remaining) = parse_local_bind(&arg[6..])?;
-            let (dest_host, dest_port, options) = parse_tunnel_dest(remaining)?;
+            // The destination is omitted when it is the original one of the connections, i.e: tcp://1212?original_dst
+            let has_dest = remaining.contains(':');
+            let (dest_host, dest_port, options) = if has_dest {
+                parse_tunnel_dest(remaining)?
+            } else {
+                parse_tunnel_dest(&format!("0.0.0.0:0?{}", remaining))?
+            };
+            let use_original_dst = options.contains_key("original_dst");
+            if !has_dest && !use_original_dst {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("missing destination of tcp tunnel {}", arg),
+                ));
+            }
+            if use_original_dst && !cfg!(target_os = "linux") {
+                return Err(Error::new(ErrorKind::InvalidInput, "original_dst is only available on linux"));
+            }
             let proxy_protocol = options.contains_key("proxy_protocol");
+            let expect_proxy_protocol = options.contains_key("expect_proxy_protocol");
             Ok(LocalToRemote {
                 local_protocol: LocalProtocol::Tcp { proxy_protocol },
                 local: local_bind,
                 remote: (dest_host, dest_port),
+                expect_proxy_protocol,
+                use_original_dst,
+                priority: parse_priority(&options)?,
             })
         }
         "udp://" => {
@@ -542,12 +1271,15 @@ fn parse_tunnel_arg(arg: &str) -> Result<LocalToRemote, io::Error> {
                 .get("timeout_sec")
                 .and_then(|x| x.parse::<u64>().ok())
                 .map(|d| if d == 0 { None } else { Some(Duration::from_secs(d)) })
-                .unwrap_or(Some(Duration::from_secs(30)));
+                .unwrap_or(Some(protocols::udp::DEFAULT_UDP_SESSION_TIMEOUT));
 
             Ok(LocalToRemote {
                 local_protocol: LocalProtocol::Udp { timeout },
                 local: local_bind,
                 remote: (dest_host, dest_port),
+                expect_proxy_protocol: false,
+                use_original_dst: false,
+                priority: parse_priority(&options)?,
             })
         }
         "unix:/" => {
@@ -557,13 +1289,16 @@ fn parse_tunnel_arg(arg: &str) -> Result<LocalToRemote, io::Error> {
                     format!("cannot parse unix socket path from {}", arg),
                 ));
             };
-            let (dest_host, dest_port, _options) = parse_tunnel_dest(remote)?;
+            let (dest_host, dest_port, options) = parse_tunnel_dest(remote)?;
             Ok(LocalToRemote {
                 local_protocol: LocalProtocol::Unix {
                     path: PathBuf::from(path),
                 },
                 local: SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)),
                 remote: (dest_host, dest_port),
+                expect_proxy_protocol: false,
+                use_original_dst: false,
+                priority: parse_priority(&options)?,
             })
         }
         "http:/" => {
@@ -587,6 +1322,9 @@ fn parse_tunnel_arg(arg: &str) -> Result<LocalToRemote, io::Error> {
                 },
                 local: local_bind,
                 remote: (dest_host, dest_port),
+                expect_proxy_protocol: false,
+                use_original_dst: false,
+                priority: parse_priority(&options)?,
             })
         }
         _ => match &arg[..8] {
@@ -606,24 +1344,77 @@ fn parse_tunnel_arg(arg: &str) -> Result<LocalToRemote, io::Error> {
                     local_protocol: LocalProtocol::Socks5 { timeout, credentials },
                     local: local_bind,
                     remote: (dest_host, dest_port),
+                    expect_proxy_protocol: false,
+                    use_original_dst: false,
+                    priority: parse_priority(&options)?,
+                })
+            }
+            "tcp+unix" => {
+                let (local_bind, path) = parse_local_bind(&arg["tcp+unix://".len()..])?;
+                if path.is_empty() {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("cannot parse unix socket path from {}", arg),
+                    ));
+                }
+                // The destination is the socket path, host and port are meaningless for the server
+                Ok(LocalToRemote {
+                    local_protocol: LocalProtocol::UnixSocket {
+                        path: PathBuf::from(path),
+                    },
+                    local: local_bind,
+                    remote: (Host::Domain("localhost".to_string()), 0),
+                    expect_proxy_protocol: false,
+                    use_original_dst: false,
+                    priority: TunnelPriority::default(),
+                })
+            }
+            "tcp+exec" => {
+                let (local_bind, remaining) = parse_local_bind(&arg["tcp+exec://".len()..])?;
+                let (dest_host, dest_port, options) = parse_tunnel_dest(remaining)?;
+                Ok(LocalToRemote {
+                    local_protocol: LocalProtocol::Exec,
+                    local: local_bind,
+                    remote: (dest_host, dest_port),
+                    expect_proxy_protocol: false,
+                    use_original_dst: false,
+                    priority: parse_priority(&options)?,
+                })
+            }
+            "tcp+tls:" => {
+                let (local_bind, remaining) = parse_local_bind(&arg["tcp+tls://".len()..])?;
+                let (dest_host, dest_port, options) = parse_tunnel_dest(remaining)?;
+                Ok(LocalToRemote {
+                    local_protocol: LocalProtocol::TcpTls,
+                    local: local_bind,
+                    remote: (dest_host, dest_port),
+                    expect_proxy_protocol: false,
+                    use_original_dst: false,
+                    priority: parse_priority(&options)?,
                 })
             }
             "stdio://" => {
-                let (dest_host, dest_port, _options) = parse_tunnel_dest(&arg["stdio://".len()..])?;
+                let (dest_host, dest_port, options) = parse_tunnel_dest(&arg["stdio://".len()..])?;
                 Ok(LocalToRemote {
                     local_protocol: LocalProtocol::Stdio,
                     local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(0), 0)),
                     remote: (dest_host, dest_port),
+                    expect_proxy_protocol: false,
+                    use_original_dst: false,
+                    priority: parse_priority(&options)?,
                 })
             }
             "tproxy+t" => {
                 let (local_bind, remaining) = parse_local_bind(&arg["tproxy+tcp://".len()..])?;
                 let x = format!("0.0.0.0:0?{}", remaining);
-                let (dest_host, dest_port, _options) = parse_tunnel_dest(&x)?;
+                let (dest_host, dest_port, options) = parse_tunnel_dest(&x)?;
                 Ok(LocalToRemote {
                     local_protocol: LocalProtocol::TProxyTcp,
                     local: local_bind,
                     remote: (dest_host, dest_port),
+                    expect_proxy_protocol: false,
+                    use_original_dst: false,
+                    priority: parse_priority(&options)?,
                 })
             }
             "tproxy+u" => {
@@ -634,11 +1425,14 @@ fn parse_tunnel_arg(arg: &str) -> Result<LocalToRemote, io::Error> {
                     .get("timeout_sec")
                     .and_then(|x| x.parse::<u64>().ok())
                     .map(|d| if d == 0 { None } else { Some(Duration::from_secs(d)) })
-                    .unwrap_or(Some(Duration::from_secs(30)));
+                    .unwrap_or(Some(protocols::udp::DEFAULT_UDP_SESSION_TIMEOUT));
                 Ok(LocalToRemote {
                     local_protocol: LocalProtocol::TProxyUdp { timeout },
                     local: local_bind,
                     remote: (dest_host, dest_port),
+                    expect_proxy_protocol: false,
+                    use_original_dst: false,
+                    priority: parse_priority(&options)?,
                 })
             }
             _ => Err(Error::new(
@@ -649,6 +1443,25 @@ fn parse_tunnel_arg(arg: &str) -> Result<LocalToRemote, io::Error> {
     }
 }
 
+fn parse_subject_limit(arg: &str) -> Result<(String, SubjectLimit), io::Error> {
+    use std::io::Error;
+
+    // The subject can contain = itself, i.e: tenant=acme, while the limits cannot
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("cannot parse subject limit from {}", arg));
+    let (subject, limits) = arg.rsplit_once('=').ok_or_else(invalid)?;
+    let (max_tunnels, max_bytes) = limits.split_once(':').unwrap_or((limits, ""));
+    let max_tunnels = match max_tunnels {
+        "" => None,
+        max => Some(max.parse::<usize>().map_err(|_| invalid())?),
+    };
+    let max_bytes = match max_bytes {
+        "" => None,
+        max => Some(max.parse::<u64>().map_err(|_| invalid())?),
+    };
+
+    Ok((subject.to_string(), SubjectLimit { max_tunnels, max_bytes }))
+}
+
 fn parse_sni_override(arg: &str) -> Result<DnsName<'static>, io::Error> {
     match DnsName::try_from(arg.to_string()) {
         Ok(val) => Ok(val),
@@ -659,6 +1472,35 @@ fn parse_sni_override(arg: &str) -> Result<DnsName<'static>, io::Error> {
     }
 }
 
+fn parse_websocket_subprotocol(arg: &str) -> Result<String, io::Error> {
+    // RFC 6455 section 4.1: a subprotocol is a token as defined by RFC 2616
+    let is_token_char = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    if arg.is_empty() || !arg.chars().all(is_token_char) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid websocket subprotocol {:?}, it must be a non empty token", arg),
+        ));
+    }
+    if arg.starts_with(JWT_HEADER_PREFIX) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid websocket subprotocol {:?}, it is reserved for the tunnel token", arg),
+        ));
+    }
+
+    Ok(arg.to_string())
+}
+
+fn parse_tls_verify_hostname(arg: &str) -> Result<ServerName<'static>, io::Error> {
+    match ServerName::try_from(arg.to_string()) {
+        Ok(val) => Ok(val),
+        Err(err) => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid tls verification hostname: {}", err),
+        )),
+    }
+}
+
 fn parse_http_headers(arg: &str) -> Result<(HeaderName, HeaderValue), io::Error> {
     let Some((key, value)) = arg.split_once(':') else {
         return Err(io::Error::new(
@@ -667,7 +1509,11 @@ fn parse_http_headers(arg: &str) -> Result<(HeaderName, HeaderValue), io::Error>
         ));
     };
 
-    let value = match HeaderValue::from_str(value.trim()) {
+    let value = match expand_env_vars(value.trim()) {
+        Ok(value) => value,
+        Err(err) => return Err(io::Error::new(ErrorKind::InvalidInput, format!("{:?}", err))),
+    };
+    let value = match HeaderValue::from_str(&value) {
         Ok(value) => value,
         Err(err) => {
             return Err(io::Error::new(
@@ -692,6 +1538,17 @@ fn parse_http_credentials(arg: &str) -> Result<HeaderValue, io::Error> {
     Ok(header)
 }
 
+fn parse_http_bearer_token(arg: &str) -> Result<HeaderValue, io::Error> {
+    let Ok(header) = HeaderValue::from_str(&format!("Bearer {}", arg.trim())) else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse http bearer token {}", arg),
+        ));
+    };
+
+    Ok(header)
+}
+
 fn parse_server_url(arg: &str) -> Result<Url, io::Error> {
     let Ok(url) = Url::parse(arg) else {
         return Err(io::Error::new(
@@ -711,38 +1568,79 @@ fn parse_server_url(arg: &str) -> Result<Url, io::Error> {
         return Err(io::Error::new(ErrorKind::InvalidInput, format!("invalid server host {}", arg)));
     }
 
+    if url.port_or_known_default().is_none() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "missing port in server url {}, there is no default one for {}",
+                arg,
+                url.scheme()
+            ),
+        ));
+    }
+
     Ok(url)
 }
 
+// SIGTERM is what process supervisors and orchestrators send to stop a service
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Cannot install SIGTERM handler");
+        select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = sigterm.recv() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    #[cfg(feature = "config-file")]
+    let args = Wstunnel::parse_from(config_file::expand_config_file_arg(
+        std::env::args_os().collect(),
+        &Wstunnel::command(),
+        |var| std::env::var(var),
+    )?);
+    #[cfg(not(feature = "config-file"))]
     let args = Wstunnel::parse();
 
     // Setup logging
-    let mut env_filter = EnvFilter::builder().parse(&args.log_lvl).expect("Invalid log level");
-    if !(args.log_lvl.contains("h2::") || args.log_lvl.contains("h2=")) {
-        env_filter = env_filter.add_directive(Directive::from_str("h2::codec=off").expect("Invalid log directive"));
-    }
-    let logger = tracing_subscriber::fmt()
-        .with_ansi(args.no_color.is_none())
-        .with_env_filter(env_filter);
+    let logger = LogConfig::parse(&args.log_lvl)
+        .expect("Invalid log level")
+        .with_ansi(args.no_color.is_none());
 
     // stdio tunnel capture stdio, so need to log into stderr
-    if let Commands::Client(args) = &args.commands {
-        if args
-            .local_to_remote
-            .iter()
-            .filter(|x| x.local_protocol == LocalProtocol::Stdio)
-            .count()
-            > 0
+    let logger = match &args.commands {
+        Commands::Client(args)
+            if args
+                .local_to_remote
+                .iter()
+                .any(|x| x.local_protocol == LocalProtocol::Stdio) =>
         {
-            logger.with_writer(io::stderr).init();
-        } else {
-            logger.init()
+            logger.with_writer(io::stderr())
         }
+        _ => logger,
+    };
+    #[cfg(feature = "otlp")]
+    let logger = if ["OTEL_EXPORTER_OTLP_ENDPOINT", "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"]
+        .iter()
+        .any(|var| std::env::var_os(var).is_some())
+    {
+        logger.with_otlp(&std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "wstunnel".to_string()))
     } else {
-        logger.init();
+        logger
     };
+    logger.try_init().map_err(|err| err.context("Cannot setup logging"))?;
+
+    // Tunnels stop accepting new connections on shutdown, and are given a grace period to finish
+    let shutdown = CancellationToken::new();
+    let mut tunnels = JoinSet::new();
 
     match args.commands {
         Commands::Client(args) => {
@@ -766,46 +1664,72 @@ async fn main() -> anyhow::Result<()> {
                     .and_then(|leaf_cert| tls::cn_from_certificate(&leaf_cert))
                     .unwrap_or(args.http_upgrade_path_prefix)
             } else {
-                args.http_upgrade_path_prefix
+                args.http_upgrade_path_prefix.trim_matches('/').to_string()
             };
 
             let transport_scheme =
                 TransportScheme::from_str(args.remote_addr.scheme()).expect("invalid scheme in server url");
+            let tls_alpn_protocols = (!args.tls_alpn_protocol.is_empty()).then_some(args.tls_alpn_protocol);
+            let alpn_protocols = tls_alpn_protocols.as_ref().map_or_else(
+                || transport_scheme.alpn_protocols(),
+                |protocols| protocols.iter().map(|p| p.as_bytes().to_vec()).collect(),
+            );
+            let tls_verify_certificate = args.tls_verify_certificate;
+            let tls_root_store = Arc::new(
+                tls::root_cert_store(args.tls_root_ca.as_deref(), args.tls_root_ca_with_system_roots)
+                    .expect("Cannot load tls root CA certificates"),
+            );
             let tls = match transport_scheme {
-                TransportScheme::Ws | TransportScheme::Http => None,
+                TransportScheme::Ws | TransportScheme::Http | TransportScheme::Tcp => None,
                 TransportScheme::Wss => Some(TlsClientConfig {
-                    tls_connector: Arc::new(RwLock::new(
-                        tls::tls_connector(
-                            args.tls_verify_certificate,
-                            transport_scheme.alpn_protocols(),
-                            !args.tls_sni_disable,
-                            tls_certificate,
-                            tls_key,
-                        )
-                        .expect("Cannot create tls connector"),
+                    tls_client_config: Arc::new(RwLock::new(
+                        tls::tls_client_config(tls::TlsClientOptions {
+                            tls_verify_certificate,
+                            alpn_protocols: alpn_protocols.clone(),
+                            enable_sni: !args.tls_sni_disable,
+                            tls_verify_hostname: args.tls_verify_hostname.clone(),
+                            tls_certificate_pins: args.tls_certificate_pin.clone(),
+                            root_store: tls_root_store.clone(),
+                            tls_client_certificate: tls_certificate,
+                            tls_client_key: tls_key,
+                            tls_min_version: args.tls_min_version,
+                        })
+                        .expect("Cannot create tls client config"),
                     )),
                     tls_sni_override: args.tls_sni_override,
-                    tls_verify_certificate: args.tls_verify_certificate,
+                    tls_verify_hostname: args.tls_verify_hostname,
+                    tls_verify_certificate,
+                    tls_certificate_pins: args.tls_certificate_pin.clone(),
+                    tls_root_store,
                     tls_sni_disabled: args.tls_sni_disable,
                     tls_certificate_path: args.tls_certificate.clone(),
                     tls_key_path: args.tls_private_key.clone(),
+                    tls_min_version: args.tls_min_version,
                 }),
-                TransportScheme::Https => Some(TlsClientConfig {
-                    tls_connector: Arc::new(RwLock::new(
-                        tls::tls_connector(
-                            args.tls_verify_certificate,
-                            transport_scheme.alpn_protocols(),
-                            !args.tls_sni_disable,
-                            tls_certificate,
-                            tls_key,
-                        )
-                        .expect("Cannot create tls connector"),
+                TransportScheme::Https | TransportScheme::Tls => Some(TlsClientConfig {
+                    tls_client_config: Arc::new(RwLock::new(
+                        tls::tls_client_config(tls::TlsClientOptions {
+                            tls_verify_certificate,
+                            alpn_protocols: alpn_protocols.clone(),
+                            enable_sni: !args.tls_sni_disable,
+                            tls_verify_hostname: args.tls_verify_hostname.clone(),
+                            tls_certificate_pins: args.tls_certificate_pin.clone(),
+                            root_store: tls_root_store.clone(),
+                            tls_client_certificate: tls_certificate,
+                            tls_client_key: tls_key,
+                            tls_min_version: args.tls_min_version,
+                        })
+                        .expect("Cannot create tls client config"),
                     )),
                     tls_sni_override: args.tls_sni_override,
-                    tls_verify_certificate: args.tls_verify_certificate,
+                    tls_verify_hostname: args.tls_verify_hostname,
+                    tls_verify_certificate,
+                    tls_certificate_pins: args.tls_certificate_pin.clone(),
+                    tls_root_store,
                     tls_sni_disabled: args.tls_sni_disable,
                     tls_certificate_path: args.tls_certificate.clone(),
                     tls_key_path: args.tls_private_key.clone(),
+                    tls_min_version: args.tls_min_version,
                 }),
             };
 
@@ -824,7 +1748,14 @@ async fn main() -> anyhow::Result<()> {
                     panic!("http headers file does not exists: {}", path.display());
                 }
             }
-            let http_proxy = if let Some(proxy) = args.http_proxy {
+            // HTTP_PROXY is picked by clap, but like curl, honor HTTPS_PROXY when the server is reached over tls
+            let http_proxy = args.http_proxy.or_else(|| {
+                tls.as_ref()?;
+                ["HTTPS_PROXY", "https_proxy"]
+                    .iter()
+                    .find_map(|var| std::env::var(var).ok().filter(|proxy| !proxy.is_empty()))
+            });
+            let http_proxy = if let Some(proxy) = http_proxy {
                 let mut proxy = if proxy.starts_with("http://") {
                     Url::parse(&proxy).expect("Invalid http proxy url")
                 } else {
@@ -843,71 +1774,158 @@ async fn main() -> anyhow::Result<()> {
             } else {
                 None
             };
-            let client_config = WsClientConfig {
-                remote_addr: TransportAddr::new(
-                    TransportScheme::from_str(args.remote_addr.scheme()).unwrap(),
-                    args.remote_addr.host().unwrap().to_owned(),
-                    args.remote_addr.port_or_known_default().unwrap(),
-                    tls,
-                )
-                .unwrap(),
-                socket_so_mark: args.socket_so_mark,
-                http_upgrade_path_prefix,
-                http_upgrade_credentials: args.http_upgrade_credentials,
-                http_headers: args.http_headers.into_iter().filter(|(k, _)| k != HOST).collect(),
-                http_headers_file: args.http_headers_file,
-                http_header_host: host_header,
-                timeout_connect: Duration::from_secs(10),
-                websocket_ping_frequency: args.websocket_ping_frequency_sec.unwrap_or(Duration::from_secs(30)),
-                websocket_mask_frame: args.websocket_mask_frame,
-                dns_resolver: DnsResolver::new_from_urls(
-                    &args.dns_resolver,
-                    http_proxy.clone(),
-                    args.socket_so_mark,
-                    !args.dns_resolver_prefer_ipv4,
-                )
-                .expect("cannot create dns resolver"),
-                http_proxy,
-            };
+            let remote_addr_fallbacks: Vec<TransportAddr> = args
+                .fallback_server
+                .iter()
+                .map(|url| {
+                    if url.scheme() != args.remote_addr.scheme() {
+                        panic!(
+                            "fallback server {} must use the same scheme as the main server {}",
+                            url,
+                            args.remote_addr.scheme()
+                        );
+                    }
+                    TransportAddr::new(
+                        transport_scheme,
+                        url.host().unwrap().to_owned(),
+                        url.port_or_known_default().unwrap(),
+                        tls.clone(),
+                    )
+                    .unwrap()
+                })
+                .collect();
+            let remote_addr = TransportAddr::new(
+                transport_scheme,
+                args.remote_addr.host().unwrap().to_owned(),
+                args.remote_addr.port_or_known_default().unwrap(),
+                tls,
+            )
+            .unwrap();
+            let dns_resolver = DnsResolver::new_from_urls(
+                &args.dns_resolver,
+                http_proxy.clone(),
+                args.socket_so_mark,
+                !args.dns_resolver_prefer_ipv4,
+            )
+            .expect("cannot create dns resolver")
+            .with_static_overrides(args.dns_static_override);
+            if let Some(secret) = &args.jwt_secret {
+                JWT_KEYS.set_keys([JwtKey::from_secret(secret.as_bytes())]);
+            }
+            if let (Some(algorithm), Some(path)) = (args.jwt_algorithm, &args.jwt_private_key) {
+                let server_keys = args.jwt_server_public_key.iter().map(|path| (path, false));
+                let keys = server_keys
+                    .chain([(path, true)])
+                    .map(|(path, is_private)| JwtKey::from_pem_file(algorithm, path, is_private))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                JWT_KEYS.set_keys(keys);
+            }
+            JWT_KEYS.set_validity(JwtValidity {
+                ttl: args.jwt_ttl_sec,
+                audience: args.jwt_audience.clone(),
+                ..JwtValidity::default()
+            });
+            let client_config = remote_addr_fallbacks
+                .into_iter()
+                .fold(WsClientConfigBuilder::new(remote_addr), |builder, server| {
+                    builder.with_fallback_server(server)
+                })
+                .with_remote_selection(args.remote_selection)
+                .with_remote_cooldown(args.remote_cooldown_sec)
+                .with_socket_so_mark(args.socket_so_mark)
+                .with_http_upgrade_path_prefix(http_upgrade_path_prefix)
+                .with_http_upgrade_credentials(args.http_upgrade_credentials.or(args.http_upgrade_bearer_token))
+                .with_http_header(HOST, host_header);
+            let client_config = args
+                .http_headers
+                .into_iter()
+                .filter(|(k, _)| k != HOST)
+                .fold(client_config, |builder, (name, value)| builder.with_http_header(name, value))
+                .with_http_headers_file(args.http_headers_file)
+                .with_user_agent(args.user_agent)
+                .with_http_headers_order(args.http_headers_order)
+                .with_server_connect_timeout(args.server_connect_timeout_sec)
+                .with_tcp_connect_timeout(args.tcp_connect_timeout_sec)
+                .with_tls_handshake_timeout(args.tls_handshake_timeout_sec)
+                .with_happy_eyeballs_delay(args.happy_eyeballs_delay_ms)
+                .with_connect_parallelism(args.connect_parallelism as usize)
+                .with_tcp_options(TcpSocketOptions {
+                    nodelay: args.tcp_nodelay,
+                    keepalive_idle: args.tcp_keepalive_idle_sec,
+                    keepalive_interval: args.tcp_keepalive_interval_sec,
+                    keepalive_retries: args.tcp_keepalive_retries,
+                })
+                .with_listen_backlog(args.listen_backlog)
+                .with_tls_alpn_protocols(tls_alpn_protocols)
+                .with_websocket_ping_frequency(args.websocket_ping_frequency_sec.unwrap_or(Duration::from_secs(30)))
+                .with_websocket_adaptive_ping(args.websocket_adaptive_ping)
+                .with_websocket_mask_frame(args.websocket_mask_frame)
+                .with_websocket_pong_timeout(args.websocket_pong_timeout_sec)
+                .with_missed_pong_limit(args.missed_pong_limit)
+                .with_websocket_subprotocol(args.websocket_subprotocol)
+                .with_http2_multiplex(args.http2_multiplex.then_some(Http2MultiplexConfig {
+                    max_streams: args.http2_multiplex_max_streams as usize,
+                    stream_window: args.http2_multiplex_stream_window,
+                }))
+                .with_transport_fallback(args.transport_fallback)
+                .with_idle_timeout(args.idle_timeout_sec)
+                .with_max_tunnel_duration(args.max_tunnel_duration_sec)
+                .with_tunnel_metadata(args.tunnel_metadata)
+                .with_connect_failure_behavior(args.connect_failure_behavior)
+                .with_max_bytes_per_sec(args.max_bytes_per_sec)
+                .with_global_egress_limit(args.max_egress_bytes_per_sec)
+                .with_global_ingress_limit(args.max_ingress_bytes_per_sec)
+                .with_shutdown_grace_period(args.shutdown_grace_period_sec)
+                .with_copy_buffer_size(args.copy_buffer_size)
+                .with_dns_resolver(dns_resolver)
+                .with_http_proxy(http_proxy)
+                .with_no_proxy(args.no_proxy)
+                .with_reconnect_backoff(ReconnectBackoff {
+                    initial_delay: args.reverse_tunnel_reconnect_initial_delay_sec,
+                    max_delay: args.reverse_tunnel_reconnect_max_delay_sec,
+                    multiplier: args.reverse_tunnel_reconnect_multiplier,
+                    jitter: args.reverse_tunnel_reconnect_jitter,
+                })
+                .with_max_reconnect_attempts(args.reverse_tunnel_max_reconnect_attempts)
+                .with_reverse_tunnel_multiplex(args.reverse_tunnel_multiplex)
+                .with_max_concurrent_tunnels(args.max_concurrent_tunnels)
+                .with_prewarm_pool_size(args.prewarm_pool_size)
+                .with_when_saturated(args.when_saturated)
+                .build()?;
 
             let client =
                 WsClient::new(client_config, args.connection_min_idle, args.connection_retry_max_backoff_sec).await?;
 
             // Start tunnels
+            let connector_pool_size = args.reverse_tunnel_connector_pool_size;
+            let mut reverse_tcp_tunnels = Vec::new();
             for tunnel in args.remote_to_local.into_iter() {
-                let client = client.clone();
+                let client = client.clone().with_priority(tunnel.priority);
+                let shutdown = shutdown.clone();
                 match &tunnel.local_protocol {
                     LocalProtocol::Tcp { proxy_protocol: _ } => {
-                        tokio::spawn(async move {
-                            let cfg = client.config.clone();
-                            let tcp_connector = TcpTunnelConnector::new(
-                                &tunnel.remote.0,
-                                tunnel.remote.1,
-                                cfg.socket_so_mark,
-                                cfg.timeout_connect,
-                                &cfg.dns_resolver,
-                            );
-                            let (host, port) = to_host_port(tunnel.local);
-                            let remote = RemoteAddr {
-                                protocol: LocalProtocol::ReverseTcp,
-                                host,
-                                port,
-                            };
-                            if let Err(err) = client.run_reverse_tunnel(remote, tcp_connector).await {
-                                error!("{:?}", err);
-                            }
-                        });
+                        let (host, port) = to_host_port(tunnel.local);
+                        let remote = RemoteAddr {
+                            protocol: LocalProtocol::ReverseTcp,
+                            host,
+                            port,
+                            source: None,
+                            request_id: None,
+                        };
+                        reverse_tcp_tunnels.push((tunnel.priority, remote, tunnel.remote));
                     }
                     LocalProtocol::Udp { timeout } => {
                         let timeout = *timeout;
 
-                        tokio::spawn(async move {
+                        tunnels.spawn(async move {
                             let cfg = client.config.clone();
                             let (host, port) = to_host_port(tunnel.local);
                             let remote = RemoteAddr {
                                 protocol: LocalProtocol::ReverseUdp { timeout },
                                 host,
                                 port,
+                                source: None,
+                                request_id: None,
                             };
                             let udp_connector = UdpTunnelConnector::new(
                                 &remote.host,
@@ -917,7 +1935,10 @@ async fn main() -> anyhow::Result<()> {
                                 &cfg.dns_resolver,
                             );
 
-                            if let Err(err) = client.run_reverse_tunnel(remote.clone(), udp_connector).await {
+                            if let Err(err) = client
+                                .run_reverse_tunnel(remote.clone(), udp_connector, None, shutdown)
+                                .await
+                            {
                                 error!("{:?}", err);
                             }
                         });
@@ -925,18 +1946,23 @@ async fn main() -> anyhow::Result<()> {
                     LocalProtocol::Socks5 { timeout, credentials } => {
                         let credentials = credentials.clone();
                         let timeout = *timeout;
-                        tokio::spawn(async move {
+                        tunnels.spawn(async move {
                             let cfg = client.config.clone();
                             let (host, port) = to_host_port(tunnel.local);
                             let remote = RemoteAddr {
                                 protocol: LocalProtocol::ReverseSocks5 { timeout, credentials },
                                 host,
                                 port,
+                                source: None,
+                                request_id: None,
                             };
                             let socks_connector =
-                                Socks5TunnelConnector::new(cfg.socket_so_mark, cfg.timeout_connect, &cfg.dns_resolver);
+                                Socks5TunnelConnector::new(cfg.socket_so_mark, cfg.timeout_connect, &cfg.dns_resolver)
+                                    .with_happy_eyeballs_delay(cfg.happy_eyeballs_delay)
+                                    .with_connect_parallelism(cfg.connect_parallelism)
+                                    .with_tcp_options(cfg.tcp_options);
 
-                            if let Err(err) = client.run_reverse_tunnel(remote, socks_connector).await {
+                            if let Err(err) = client.run_reverse_tunnel(remote, socks_connector, None, shutdown).await {
                                 error!("{:?}", err);
                             }
                         });
@@ -946,13 +1972,15 @@ async fn main() -> anyhow::Result<()> {
                     } => {
                         let credentials = credentials.clone();
                         let timeout = *timeout;
-                        tokio::spawn(async move {
+                        tunnels.spawn(async move {
                             let cfg = client.config.clone();
                             let (host, port) = to_host_port(tunnel.local);
                             let remote = RemoteAddr {
                                 protocol: LocalProtocol::ReverseHttpProxy { timeout, credentials },
                                 host,
                                 port,
+                                source: None,
+                                request_id: None,
                             };
                             let tcp_connector = TcpTunnelConnector::new(
                                 &remote.host,
@@ -960,9 +1988,15 @@ async fn main() -> anyhow::Result<()> {
                                 cfg.socket_so_mark,
                                 cfg.timeout_connect,
                                 &cfg.dns_resolver,
-                            );
-
-                            if let Err(err) = client.run_reverse_tunnel(remote.clone(), tcp_connector).await {
+                            )
+                            .with_happy_eyeballs_delay(cfg.happy_eyeballs_delay)
+                            .with_connect_parallelism(cfg.connect_parallelism)
+                            .with_tcp_options(cfg.tcp_options);
+
+                            if let Err(err) = client
+                                .run_reverse_tunnel(remote.clone(), tcp_connector, None, shutdown)
+                                .await
+                            {
                                 error!("{:?}", err);
                             }
                         });
@@ -970,7 +2004,7 @@ async fn main() -> anyhow::Result<()> {
                     #[cfg(unix)]
                     LocalProtocol::Unix { path } => {
                         let path = path.clone();
-                        tokio::spawn(async move {
+                        tunnels.spawn(async move {
                             let cfg = client.config.clone();
                             let tcp_connector = TcpTunnelConnector::new(
                                 &tunnel.remote.0,
@@ -978,15 +2012,20 @@ async fn main() -> anyhow::Result<()> {
                                 cfg.socket_so_mark,
                                 cfg.timeout_connect,
                                 &cfg.dns_resolver,
-                            );
+                            )
+                            .with_happy_eyeballs_delay(cfg.happy_eyeballs_delay)
+                            .with_connect_parallelism(cfg.connect_parallelism)
+                            .with_tcp_options(cfg.tcp_options);
 
                             let (host, port) = to_host_port(tunnel.local);
                             let remote = RemoteAddr {
                                 protocol: LocalProtocol::ReverseUnix { path: path.clone() },
                                 host,
                                 port,
+                                source: None,
+                                request_id: None,
                             };
-                            if let Err(err) = client.run_reverse_tunnel(remote, tcp_connector).await {
+                            if let Err(err) = client.run_reverse_tunnel(remote, tcp_connector, None, shutdown).await {
                                 error!("{:?}", err);
                             }
                         });
@@ -1002,42 +2041,124 @@ async fn main() -> anyhow::Result<()> {
                     | LocalProtocol::ReverseUdp { .. }
                     | LocalProtocol::ReverseSocks5 { .. }
                     | LocalProtocol::ReverseHttpProxy { .. } => {}
-                    LocalProtocol::ReverseUnix { .. } => {
+                    LocalProtocol::ReverseUnix { .. }
+                    | LocalProtocol::UnixSocket { .. }
+                    | LocalProtocol::Exec
+                    | LocalProtocol::TcpTls => {
                         panic!("Invalid protocol for reverse tunnel");
                     }
                 }
             }
 
+            // Tcp reverse tunnels run together, one group for each priority as it is set on the client
+            for priority in [
+                TunnelPriority::Interactive,
+                TunnelPriority::Normal,
+                TunnelPriority::Bulk,
+            ] {
+                let (same_priority, others): (Vec<_>, Vec<_>) =
+                    reverse_tcp_tunnels.into_iter().partition(|(p, _, _)| *p == priority);
+                reverse_tcp_tunnels = others;
+                if same_priority.is_empty() {
+                    continue;
+                }
+
+                let client = client.clone().with_priority(priority);
+                let shutdown = shutdown.clone();
+                tunnels.spawn(async move {
+                    let cfg = client.config.clone();
+                    let connectors = same_priority.iter().map(|(_, remote, (dest_host, dest_port))| {
+                        let tcp_connector = TcpTunnelConnector::new(
+                            dest_host,
+                            *dest_port,
+                            cfg.socket_so_mark,
+                            cfg.timeout_connect,
+                            &cfg.dns_resolver,
+                        )
+                        .with_happy_eyeballs_delay(cfg.happy_eyeballs_delay)
+                        .with_connect_parallelism(cfg.connect_parallelism)
+                        .with_tcp_options(cfg.tcp_options)
+                        .with_pool_size(connector_pool_size);
+                        (remote.clone(), tcp_connector)
+                    });
+                    if let Err(err) = client.run_reverse_tunnels(connectors.collect(), None, shutdown).await {
+                        error!("{:?}", err);
+                    }
+                });
+            }
+
             for tunnel in args.local_to_remote.into_iter() {
-                let client = client.clone();
+                let client = client.clone().with_priority(tunnel.priority);
+                let shutdown = shutdown.clone();
 
                 match &tunnel.local_protocol {
                     LocalProtocol::Tcp { proxy_protocol } => {
-                        let server =
-                            TcpTunnelListener::new(tunnel.local, tunnel.remote.clone(), *proxy_protocol).await?;
-                        tokio::spawn(async move {
-                            if let Err(err) = client.run_tunnel(server).await {
+                        let server = TcpTunnelListener::new(
+                            tunnel.local,
+                            tunnel.remote.clone(),
+                            *proxy_protocol,
+                            client.config.listen_backlog,
+                        )
+                        .await?
+                        .with_tcp_options(client.config.tcp_options)
+                        .with_expect_proxy_protocol(tunnel.expect_proxy_protocol)
+                        .with_use_original_dst(tunnel.use_original_dst);
+                        // With proxy protocol, the server needs the source of each connection before connecting.
+                        // There is no single destination when it is the original one of each connection
+                        let destination = (!*proxy_protocol && !tunnel.use_original_dst).then(|| RemoteAddr {
+                            protocol: tunnel.local_protocol.clone(),
+                            host: tunnel.remote.0.clone(),
+                            port: tunnel.remote.1,
+                            source: None,
+                            request_id: None,
+                        });
+                        tunnels.spawn(async move {
+                            if let Err(err) = client.run_tunnel_with_prewarm(server, destination, shutdown).await {
+                                error!("{:?}", err);
+                            }
+                        });
+                    }
+                    LocalProtocol::UnixSocket { .. } | LocalProtocol::Exec | LocalProtocol::TcpTls => {
+                        let server = TcpTunnelListener::new(
+                            tunnel.local,
+                            tunnel.remote.clone(),
+                            false,
+                            client.config.listen_backlog,
+                        )
+                        .await?
+                        .with_protocol(tunnel.local_protocol.clone())
+                        .with_tcp_options(client.config.tcp_options);
+                        let destination = Some(RemoteAddr {
+                            protocol: tunnel.local_protocol.clone(),
+                            host: tunnel.remote.0.clone(),
+                            port: tunnel.remote.1,
+                            source: None,
+                            request_id: None,
+                        });
+                        tunnels.spawn(async move {
+                            if let Err(err) = client.run_tunnel_with_prewarm(server, destination, shutdown).await {
                                 error!("{:?}", err);
                             }
                         });
                     }
                     #[cfg(target_os = "linux")]
                     LocalProtocol::TProxyTcp => {
-                        use crate::tunnel::listeners::TproxyTcpTunnelListener;
-                        let server = TproxyTcpTunnelListener::new(tunnel.local, false).await?;
+                        use wstunnel::tunnel::listeners::TproxyTcpTunnelListener;
+                        let server =
+                            TproxyTcpTunnelListener::new(tunnel.local, false, client.config.listen_backlog).await?;
 
-                        tokio::spawn(async move {
-                            if let Err(err) = client.run_tunnel(server).await {
+                        tunnels.spawn(async move {
+                            if let Err(err) = client.run_tunnel(server, shutdown).await {
                                 error!("{:?}", err);
                             }
                         });
                     }
                     #[cfg(unix)]
                     LocalProtocol::Unix { path } => {
-                        use crate::tunnel::listeners::UnixTunnelListener;
+                        use wstunnel::tunnel::listeners::UnixTunnelListener;
                         let server = UnixTunnelListener::new(path, tunnel.remote.clone(), false).await?; // TODO: support proxy protocol
-                        tokio::spawn(async move {
-                            if let Err(err) = client.run_tunnel(server).await {
+                        tunnels.spawn(async move {
+                            if let Err(err) = client.run_tunnel(server, shutdown).await {
                                 error!("{:?}", err);
                             }
                         });
@@ -1049,10 +2170,10 @@ async fn main() -> anyhow::Result<()> {
 
                     #[cfg(target_os = "linux")]
                     LocalProtocol::TProxyUdp { timeout } => {
-                        use crate::tunnel::listeners::new_tproxy_udp;
+                        use wstunnel::tunnel::listeners::new_tproxy_udp;
                         let server = new_tproxy_udp(tunnel.local, *timeout).await?;
-                        tokio::spawn(async move {
-                            if let Err(err) = client.run_tunnel(server).await {
+                        tunnels.spawn(async move {
+                            if let Err(err) = client.run_tunnel(server, shutdown).await {
                                 error!("{:?}", err);
                             }
                         });
@@ -1064,16 +2185,16 @@ async fn main() -> anyhow::Result<()> {
                     LocalProtocol::Udp { timeout } => {
                         let server = new_udp_listener(tunnel.local, tunnel.remote.clone(), *timeout).await?;
 
-                        tokio::spawn(async move {
-                            if let Err(err) = client.run_tunnel(server).await {
+                        tunnels.spawn(async move {
+                            if let Err(err) = client.run_tunnel(server, shutdown).await {
                                 error!("{:?}", err);
                             }
                         });
                     }
                     LocalProtocol::Socks5 { timeout, credentials } => {
                         let server = Socks5TunnelListener::new(tunnel.local, *timeout, credentials.clone()).await?;
-                        tokio::spawn(async move {
-                            if let Err(err) = client.run_tunnel(server).await {
+                        tunnels.spawn(async move {
+                            if let Err(err) = client.run_tunnel(server, shutdown).await {
                                 error!("{:?}", err);
                             }
                         });
@@ -1086,8 +2207,8 @@ async fn main() -> anyhow::Result<()> {
                         let server =
                             HttpProxyTunnelListener::new(tunnel.local, *timeout, credentials.clone(), *proxy_protocol)
                                 .await?;
-                        tokio::spawn(async move {
-                            if let Err(err) = client.run_tunnel(server).await {
+                        tunnels.spawn(async move {
+                            if let Err(err) = client.run_tunnel(server, shutdown).await {
                                 error!("{:?}", err);
                             }
                         });
@@ -1095,11 +2216,7 @@ async fn main() -> anyhow::Result<()> {
 
                     LocalProtocol::Stdio => {
                         let (server, mut handle) = new_stdio_listener(tunnel.remote.clone(), false).await?; // TODO: support proxy protocol
-                        tokio::spawn(async move {
-                            if let Err(err) = client.run_tunnel(server).await {
-                                error!("{:?}", err);
-                            }
-                        });
+                        let mut tunnel = tokio::spawn(client.run_single_tunnel(server));
 
                         // We need to wait for either a ctrl+c of that the stdio tunnel is closed
                         // to force exit the program
@@ -1107,8 +2224,21 @@ async fn main() -> anyhow::Result<()> {
                            _ = handle.closed() => {},
                            _ = tokio::signal::ctrl_c() => {}
                         }
-                        tokio::time::sleep(Duration::from_secs(1)).await;
-                        std::process::exit(0);
+
+                        // Give the tunnel a moment to flush what remains to stdout, and report if it failed
+                        // with the exit status, as the process is used as a pipe (i.e: ssh ProxyCommand)
+                        let exit_code = match tokio::time::timeout(Duration::from_secs(1), &mut tunnel).await {
+                            Ok(Ok(Err(err))) => {
+                                error!("{:?}", err);
+                                1
+                            }
+                            Ok(Err(err)) => {
+                                error!("Tunnel task panicked: {:?}", err);
+                                1
+                            }
+                            Ok(Ok(Ok(_))) | Err(_) => 0,
+                        };
+                        std::process::exit(exit_code);
                     }
                     LocalProtocol::ReverseTcp => {}
                     LocalProtocol::ReverseUdp { .. } => {}
@@ -1119,7 +2249,7 @@ async fn main() -> anyhow::Result<()> {
             }
         }
         Commands::Server(args) => {
-            let tls_config = if args.remote_addr.scheme() == "wss" {
+            let tls_config = if matches!(args.remote_addr.scheme(), "wss" | "tls") {
                 let tls_certificate = if let Some(cert_path) = &args.tls_certificate {
                     tls::load_certificates_from_pem(cert_path).expect("Cannot load tls certificate")
                 } else {
@@ -1146,6 +2276,7 @@ async fn main() -> anyhow::Result<()> {
                     tls_certificate_path: args.tls_certificate,
                     tls_key_path: args.tls_private_key,
                     tls_client_ca_certs_path: args.tls_client_ca_certs,
+                    tls_min_version: args.tls_min_version,
                 })
             } else {
                 None
@@ -1162,7 +2293,7 @@ async fn main() -> anyhow::Result<()> {
                     .map(|x| {
                         let (host, port) = x.rsplit_once(':').expect("Invalid restrict-to format");
                         (
-                            host.trim_matches(&['[', ']']).to_string(),
+                            host.trim_matches(['[', ']']).to_string(),
                             port.parse::<u16>().expect("Invalid restrict-to port format"),
                         )
                     })
@@ -1196,11 +2327,128 @@ async fn main() -> anyhow::Result<()> {
                 None
             };
 
+            if let Some(ip) = args.socket_bind_address {
+                if let Err(err) = std::net::UdpSocket::bind(SocketAddr::new(ip, 0)) {
+                    return Err(anyhow::anyhow!(
+                        "Cannot use {} as source address for outbound connections: {}",
+                        ip,
+                        err
+                    ));
+                }
+            }
+
+            if args.jwt_algorithm.is_some() && args.jwt_public_key.is_empty() && args.jwt_private_key.is_none() {
+                return Err(anyhow::anyhow!(
+                    "--jwt-algorithm requires --jwt-public-key or --jwt-private-key"
+                ));
+            }
+            let load_jwt_keys = {
+                let secrets = args.jwt_secret.clone();
+                let secret_file = args.jwt_secret_file.clone();
+                let algorithm = args.jwt_algorithm;
+                let public_keys = args.jwt_public_key.clone();
+                let private_key = args.jwt_private_key.clone();
+                move || -> anyhow::Result<Vec<JwtKey>> {
+                    if let Some(algorithm) = algorithm {
+                        let public_keys = public_keys.iter().map(|path| (path, false));
+                        return public_keys
+                            .chain(private_key.iter().map(|path| (path, true)))
+                            .map(|(path, is_private)| JwtKey::from_pem_file(algorithm, path, is_private))
+                            .collect();
+                    }
+
+                    let mut keys: Vec<JwtKey> = secrets
+                        .iter()
+                        .map(|secret| JwtKey::from_secret(secret.as_bytes()))
+                        .collect();
+                    if let Some(path) = &secret_file {
+                        keys.extend(JwtKey::from_secrets_file(path)?);
+                    }
+                    Ok(keys)
+                }
+            };
+            JWT_KEYS.set_keys(load_jwt_keys()?);
+            #[cfg(unix)]
+            JWT_KEYS.reload_on_sighup(load_jwt_keys)?;
+            JWT_KEYS.set_validity(JwtValidity {
+                ttl: args.jwt_ttl_sec,
+                leeway: args.jwt_leeway_sec,
+                audience: args.jwt_audience.clone(),
+                strict: !args.jwt_allow_missing_expiration,
+                max_ttl: Some(args.jwt_max_ttl_sec),
+            });
+            let has_mtls = tls_config
+                .as_ref()
+                .is_some_and(|tls| tls.tls_client_ca_certificates.is_some());
+            if args.exec_command.is_some() && JWT_KEYS.accepts_default_secret() && !has_mtls {
+                return Err(anyhow::anyhow!(
+                    "--exec-command requires a --jwt-secret other than the default one, or mTLS with --tls-client-ca-certs"
+                ));
+            }
+            if restrictions.matches_metadata() && JWT_KEYS.accepts_default_secret() {
+                warn!("!Metadata restrictions never match with the default --jwt-secret, anyone can forge metadata with it");
+            }
+
+            #[cfg(feature = "geoip")]
+            let geoip_database = args
+                .geoip_database
+                .map(|path| GeoIpDatabase::from_file(&path).map(Arc::new))
+                .transpose()?;
+            #[cfg(not(feature = "geoip"))]
+            let geoip_database = None;
+
+            let destination_tls_verify_certificate =
+                args.destination_tls_verify_certificate || !args.destination_tls_certificate_pin.is_empty();
+            let (destination_tls_certificate, destination_tls_key) = if let (Some(cert), Some(key)) = (
+                args.destination_tls_certificate.as_ref(),
+                args.destination_tls_private_key.as_ref(),
+            ) {
+                let tls_certificate =
+                    tls::load_certificates_from_pem(cert).expect("Cannot load destination TLS certificate (mTLS)");
+                let tls_key =
+                    tls::load_private_key_from_file(key).expect("Cannot load destination TLS private key (mTLS)");
+                (Some(tls_certificate), Some(tls_key))
+            } else {
+                (None, None)
+            };
+            // The CA certificates are only needed to verify the destinations
+            let destination_tls_root_store = if destination_tls_verify_certificate {
+                tls::root_cert_store(
+                    args.destination_tls_root_ca.as_deref(),
+                    args.destination_tls_root_ca_with_system_roots,
+                )
+                .expect("Cannot load destination tls root CA certificates")
+            } else {
+                RootCertStore::empty()
+            };
+            let destination_tls = tls::tls_client_config(tls::TlsClientOptions {
+                tls_verify_certificate: destination_tls_verify_certificate,
+                alpn_protocols: vec![],
+                enable_sni: !args.destination_tls_sni_disable,
+                tls_verify_hostname: None,
+                tls_certificate_pins: args.destination_tls_certificate_pin.clone(),
+                root_store: Arc::new(destination_tls_root_store),
+                tls_client_certificate: destination_tls_certificate,
+                tls_client_key: destination_tls_key,
+                tls_min_version: args.destination_tls_min_version,
+            })
+            .expect("Cannot create destination tls config");
+
             let server_config = WsServerConfig {
                 socket_so_mark: args.socket_so_mark,
+                socket_bind_address: args.socket_bind_address,
+                socket_bind_device: args.socket_bind_device,
                 bind: args.remote_addr.socket_addrs(|| Some(8080)).unwrap()[0],
                 websocket_ping_frequency: args.websocket_ping_frequency_sec,
                 timeout_connect: Duration::from_secs(10),
+                happy_eyeballs_delay: args.happy_eyeballs_delay_ms,
+                connect_parallelism: args.connect_parallelism as usize,
+                tcp_options: TcpSocketOptions {
+                    nodelay: args.tcp_nodelay,
+                    keepalive_idle: args.tcp_keepalive_idle_sec,
+                    keepalive_interval: args.tcp_keepalive_interval_sec,
+                    keepalive_retries: args.tcp_keepalive_retries,
+                },
                 websocket_mask_frame: args.websocket_mask_frame,
                 tls: tls_config,
                 dns_resolver: DnsResolver::new_from_urls(
@@ -1209,9 +2457,41 @@ async fn main() -> anyhow::Result<()> {
                     args.socket_so_mark,
                     !args.dns_resolver_prefer_ipv4,
                 )
-                .expect("Cannot create DNS resolver"),
+                .expect("Cannot create DNS resolver")
+                .with_static_overrides(args.dns_static_override),
                 restriction_config: args.restrict_config,
                 http_proxy,
+                http_upgrade_path_prefix: args.http_upgrade_path_prefix,
+                http_upgrade_bearer_token: args.http_upgrade_bearer_token,
+                destination_rate_limit: args.destination_rate_limit,
+                destination_rate_limit_burst: args.destination_rate_limit_burst,
+                jwt_replay_cache_size: args.jwt_replay_cache_size,
+                jwt_replay_cache_ttl: args.jwt_replay_cache_ttl_sec,
+                health_check_path: args
+                    .health_check_path
+                    .map(|path| format!("/{}", path.trim_start_matches('/'))),
+                health_check_expose_version: args.health_check_expose_version,
+                shutdown_grace_period: args.shutdown_grace_period_sec,
+                geoip_database,
+                max_bytes_per_tunnel_upload: args.max_bytes_per_tunnel_upload,
+                http2_multiplex: args.http2_multiplex.then_some(Http2MultiplexConfig {
+                    max_streams: args.http2_multiplex_max_streams as usize,
+                    stream_window: args.http2_multiplex_stream_window,
+                }),
+                max_bytes_per_tunnel_download: args.max_bytes_per_tunnel_download,
+                default_subject_limit: SubjectLimit {
+                    max_tunnels: args.max_tunnels_per_subject,
+                    max_bytes: args.max_bytes_per_subject,
+                },
+                subject_limits: args.subject_limit,
+                connect_failure_behavior: args.connect_failure_behavior,
+                report_connect_errors: args.report_connect_errors,
+                exec_command: args
+                    .exec_command
+                    .map(|command| command.split_whitespace().map(str::to_string).collect()),
+                destination_tls,
+                raw_transport: TransportScheme::from_str(args.remote_addr.scheme()).is_ok_and(|scheme| scheme.is_raw()),
+                clock: Arc::new(TokioClock),
             };
             let server = WsServer::new(server_config);
 
@@ -1221,12 +2501,79 @@ async fn main() -> anyhow::Result<()> {
                 server.config
             );
             debug!("Restriction rules: {:#?}", restrictions);
-            server.serve(restrictions).await.unwrap_or_else(|err| {
+            tokio::spawn({
+                let shutdown = shutdown.clone();
+                async move {
+                    shutdown_signal().await;
+                    shutdown.cancel();
+                }
+            });
+            server.serve(restrictions, shutdown).await.unwrap_or_else(|err| {
                 panic!("Cannot start wstunnel server: {:?}", err);
             });
+            return Ok(());
         }
     }
 
-    tokio::signal::ctrl_c().await.unwrap();
+    // Tunnels only stop on their own when they give up, i.e: --reverse-tunnel-max-reconnect-attempts
+    select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = async { while tunnels.join_next().await.is_some() {} }, if !tunnels.is_empty() => {
+            return Err(anyhow::anyhow!("All the tunnels are stopped, exiting"));
+        }
+    }
+    shutdown.cancel();
+    while tunnels.join_next().await.is_some() {}
+    #[cfg(feature = "otlp")]
+    tokio::task::spawn_blocking(wstunnel::tunnel::otlp::shutdown).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tcp_tunnel_arg() {
+        let tunnel = parse_tunnel_arg("tcp://1212:google.com:443").unwrap();
+        assert_eq!(tunnel.local, SocketAddr::from_str("127.0.0.1:1212").unwrap());
+        assert_eq!(tunnel.remote, (Host::Domain("google.com".to_string()), 443));
+        assert!(!tunnel.use_original_dst);
+
+        let tunnel = parse_tunnel_arg("tcp://0.0.0.0:1212:google.com:443?proxy_protocol").unwrap();
+        assert_eq!(tunnel.local, SocketAddr::from_str("0.0.0.0:1212").unwrap());
+        assert!(matches!(tunnel.local_protocol, LocalProtocol::Tcp { proxy_protocol: true }));
+        assert!(!tunnel.use_original_dst);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_parse_tcp_tunnel_arg_with_original_dst() {
+        // The destination can be omitted, the one of each connection is used
+        let tunnel = parse_tunnel_arg("tcp://1212?original_dst").unwrap();
+        assert_eq!(tunnel.local, SocketAddr::from_str("127.0.0.1:1212").unwrap());
+        assert!(tunnel.use_original_dst);
+
+        let tunnel = parse_tunnel_arg("tcp://[::1]:1212:google.com:443?original_dst").unwrap();
+        assert_eq!(tunnel.local, SocketAddr::from_str("[::1]:1212").unwrap());
+        assert_eq!(tunnel.remote, (Host::Domain("google.com".to_string()), 443));
+        assert!(tunnel.use_original_dst);
+    }
+
+    #[test]
+    #[cfg(not(target_os = "linux"))]
+    fn test_parse_tcp_tunnel_arg_with_original_dst() {
+        let err = parse_tunnel_arg("tcp://1212?original_dst").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_parse_tcp_tunnel_arg_without_destination() {
+        let err = parse_tunnel_arg("tcp://1212").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("missing destination"), "{}", err);
+
+        let err = parse_tunnel_arg("tcp://1212?proxy_protocol").unwrap_err();
+        assert!(err.to_string().contains("missing destination"), "{}", err);
+    }
+}
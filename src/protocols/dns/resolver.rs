@@ -7,6 +7,7 @@ use hickory_resolver::proto::iocompat::AsyncIoTokioAsStd;
 use hickory_resolver::proto::TokioTime;
 use hickory_resolver::{AsyncResolver, TokioHandle};
 use log::warn;
+use std::collections::HashMap;
 use std::future::Future;
 use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::pin::Pin;
@@ -16,7 +17,7 @@ use tokio::net::{TcpStream, UdpSocket};
 use url::{Host, Url};
 
 // Interleave v4 and v6 addresses as per RFC8305.
-// The first address is v6 if we have any v6 addresses.
+// The first address is of the preferred family, if there is any of it.
 #[inline]
 fn sort_socket_addrs(socket_addrs: &[SocketAddr], prefer_ipv6: bool) -> impl Iterator<Item = &'_ SocketAddr> {
     let mut pick_v6 = !prefer_ipv6;
@@ -33,18 +34,59 @@ fn sort_socket_addrs(socket_addrs: &[SocketAddr], prefer_ipv6: bool) -> impl Ite
 }
 
 #[derive(Clone)]
+#[allow(clippy::large_enum_variant)]
 pub enum DnsResolver {
-    System,
+    System {
+        prefer_ipv6: bool,
+    },
     TrustDns {
         resolver: AsyncResolver<GenericConnector<TokioRuntimeProviderWithSoMark>>,
         prefer_ipv6: bool,
     },
+    // Hostnames (or *.domain wildcards) resolved statically, before using the inner resolver
+    Static {
+        overrides: Arc<HashMap<String, Vec<IpAddr>>>,
+        resolver: Box<DnsResolver>,
+    },
+}
+
+fn lookup_static_override<'a>(overrides: &'a HashMap<String, Vec<IpAddr>>, domain: &str) -> Option<&'a [IpAddr]> {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    if let Some(ips) = overrides.get(&domain) {
+        return Some(ips);
+    }
+
+    // Try the most specific wildcard first, i.e: *.b.internal before *.internal
+    domain
+        .match_indices('.')
+        .find_map(|(ix, _)| overrides.get(&format!("*{}", &domain[ix..])))
+        .map(|ips| ips.as_slice())
 }
 
 impl DnsResolver {
+    pub fn with_static_overrides(self, overrides: impl IntoIterator<Item = (String, Vec<IpAddr>)>) -> Self {
+        let mut static_overrides: HashMap<String, Vec<IpAddr>> = HashMap::new();
+        for (host, ips) in overrides {
+            let host = host.trim_end_matches('.').to_ascii_lowercase();
+            static_overrides.entry(host).or_default().extend(ips);
+        }
+
+        if static_overrides.is_empty() {
+            return self;
+        }
+
+        Self::Static {
+            overrides: Arc::new(static_overrides),
+            resolver: Box::new(self),
+        }
+    }
+
     pub async fn lookup_host(&self, domain: &str, port: u16) -> anyhow::Result<Vec<SocketAddr>> {
         let addrs: Vec<SocketAddr> = match self {
-            Self::System => tokio::net::lookup_host(format!("{}:{}", domain, port)).await?.collect(),
+            Self::System { prefer_ipv6 } => {
+                let addrs: Vec<_> = tokio::net::lookup_host(format!("{}:{}", domain, port)).await?.collect();
+                sort_socket_addrs(&addrs, *prefer_ipv6).copied().collect()
+            }
             Self::TrustDns { resolver, prefer_ipv6 } => {
                 let addrs: Vec<_> = resolver
                     .lookup_ip(domain)
@@ -57,6 +99,10 @@ impl DnsResolver {
                     .collect();
                 sort_socket_addrs(&addrs, *prefer_ipv6).copied().collect()
             }
+            Self::Static { overrides, resolver } => match lookup_static_override(overrides, domain) {
+                Some(ips) => ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect(),
+                None => Box::pin(resolver.lookup_host(domain, port)).await?,
+            },
         };
 
         Ok(addrs)
@@ -76,12 +122,13 @@ impl DnsResolver {
         ) -> AsyncResolver<GenericConnector<TokioRuntimeProviderWithSoMark>> {
             opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
             opts.timeout = Duration::from_secs(1);
+            // Answers are cached until their TTL expires, keep enough of them for the tunnels destinations
+            opts.cache_size = 1024;
 
             // Windows end-up with too many dns resolvers, which causes a performance issue
             // https://github.com/hickory-dns/hickory-dns/issues/1968
             #[cfg(target_os = "windows")]
             {
-                opts.cache_size = 1024;
                 opts.num_concurrent_reqs = cfg.name_servers().len();
             }
 
@@ -133,7 +180,7 @@ impl DnsResolver {
         if resolvers.is_empty() {
             let Ok((cfg, opts)) = hickory_resolver::system_conf::read_system_conf() else {
                 warn!("Fall-backing to system dns resolver. You should consider specifying a dns resolver. To avoid performance issue");
-                return Ok(Self::System);
+                return Ok(Self::System { prefer_ipv6 });
             };
 
             return Ok(Self::TrustDns {
@@ -144,7 +191,7 @@ impl DnsResolver {
 
         // if one is specified as system, use the default one from libc
         if resolvers.iter().any(|r| r.scheme() == "system") {
-            return Ok(Self::System);
+            return Ok(Self::System { prefer_ipv6 });
         }
 
         // otherwise, use the specified resolvers
@@ -210,10 +257,11 @@ impl RuntimeProvider for TokioRuntimeProviderWithSoMark {
                     &host,
                     server_addr.port(),
                     so_mark,
+                    &protocols::tcp::SocketBind::default(),
                     Duration::from_secs(10),
-                    &DnsResolver::System, // not going to be used as host is directly an ip address
+                    &DnsResolver::System { prefer_ipv6: true }, // not going to be used as host is directly an ip address
                 )
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+                .map_err(std::io::Error::other)
                 .map(|s| s.map(AsyncIoTokioAsStd))
                 .await
             } else {
@@ -222,9 +270,9 @@ impl RuntimeProvider for TokioRuntimeProviderWithSoMark {
                     server_addr.port(),
                     so_mark,
                     Duration::from_secs(10),
-                    &DnsResolver::System, // not going to be used as host is directly an ip address
+                    &DnsResolver::System { prefer_ipv6: true }, // not going to be used as host is directly an ip address
                 )
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+                .map_err(std::io::Error::other)
                 .map(|s| s.map(AsyncIoTokioAsStd))
                 .await
             }
@@ -283,4 +331,23 @@ mod tests {
         let actual: Vec<_> = sort_socket_addrs(&addrs, true).copied().collect();
         assert_eq!(expected, *actual);
     }
+
+    #[tokio::test]
+    async fn test_static_overrides() {
+        let overrides = HashMap::from([
+            ("Example.com".to_string(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]),
+            ("*.internal".to_string(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))]),
+            ("*.b.internal".to_string(), vec![IpAddr::V6(Ipv6Addr::LOCALHOST)]),
+        ]);
+        let resolver = DnsResolver::System { prefer_ipv6: true }.with_static_overrides(overrides);
+
+        let lookup = |host: &'static str| {
+            let resolver = resolver.clone();
+            async move { resolver.lookup_host(host, 443).await.unwrap() }
+        };
+        assert_eq!(lookup("EXAMPLE.com.").await, vec!["10.0.0.1:443".parse().unwrap()]);
+        assert_eq!(lookup("a.internal").await, vec!["10.0.0.2:443".parse().unwrap()]);
+        assert_eq!(lookup("a.b.internal").await, vec!["[::1]:443".parse().unwrap()]);
+        assert_eq!(lookup("127.0.0.1").await, vec!["127.0.0.1:443".parse().unwrap()]);
+    }
 }
@@ -0,0 +1,96 @@
+use anyhow::Context;
+use maxminddb::{geoip2, Reader};
+use std::net::IpAddr;
+use std::path::Path;
+
+/// Database in the MaxMind DB format (i.e: GeoLite2-Country.mmdb), to find the country of an ip
+pub struct GeoIpDatabase {
+    reader: Reader<Vec<u8>>,
+}
+
+impl GeoIpDatabase {
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let reader =
+            Reader::open_readfile(path).with_context(|| format!("Cannot load GeoIP database {}", path.display()))?;
+        Ok(Self { reader })
+    }
+
+    /// ISO code of the country of the ip (i.e: FR), if the database knows it
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        // Ipv4 databases cannot be searched for an ipv6 address, even when it maps an ipv4 one
+        let ip = match ip {
+            IpAddr::V6(ip) if self.reader.metadata.ip_version == 4 => IpAddr::V4(ip.to_ipv4_mapped()?),
+            ip => ip,
+        };
+        let record: geoip2::Country = self.reader.lookup(ip).ok()??;
+
+        [record.country, record.registered_country]
+            .into_iter()
+            .find_map(|country| country?.iso_code)
+            .map(str::to_string)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METADATA_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+    const DATA_SECTION_SEPARATOR_LEN: usize = 16;
+
+    fn string(value: &str) -> Vec<u8> {
+        let mut buf = vec![(2 << 5) | value.len() as u8];
+        buf.extend_from_slice(value.as_bytes());
+        buf
+    }
+
+    fn map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut buf = vec![(7 << 5) | entries.len() as u8];
+        for (key, value) in entries {
+            buf.extend(string(key));
+            buf.extend_from_slice(value);
+        }
+        buf
+    }
+
+    fn uint16(value: u16) -> Vec<u8> {
+        vec![(5 << 5) | 2, (value >> 8) as u8, value as u8]
+    }
+
+    fn empty_array() -> Vec<u8> {
+        // Extended type, 11 - 7
+        vec![0, 4]
+    }
+
+    #[test]
+    fn test_country_lookup() {
+        // Ipv4 database with a single node: 0.0.0.0/1 is in FR, 128.0.0.0/1 is unknown
+        let node_count = 1u32;
+        let data_record = node_count + DATA_SECTION_SEPARATOR_LEN as u32;
+        let mut db = vec![];
+        db.extend_from_slice(&data_record.to_be_bytes()[1..]);
+        db.extend_from_slice(&node_count.to_be_bytes()[1..]);
+        db.extend_from_slice(&[0; DATA_SECTION_SEPARATOR_LEN]);
+        db.extend(map(&[("country", map(&[("iso_code", string("FR"))]))]));
+        db.extend_from_slice(METADATA_MARKER);
+        db.extend(map(&[
+            ("binary_format_major_version", uint16(2)),
+            ("binary_format_minor_version", uint16(0)),
+            ("build_epoch", uint16(0)),
+            ("database_type", string("Test-Country")),
+            ("description", map(&[])),
+            ("ip_version", uint16(4)),
+            ("languages", empty_array()),
+            ("node_count", uint16(node_count as u16)),
+            ("record_size", uint16(24)),
+        ]));
+
+        let db = GeoIpDatabase {
+            reader: Reader::from_source(db).unwrap(),
+        };
+        assert_eq!(db.country("1.2.3.4".parse().unwrap()).as_deref(), Some("FR"));
+        assert_eq!(db.country("::ffff:1.2.3.4".parse().unwrap()).as_deref(), Some("FR"));
+        assert_eq!(db.country("200.2.3.4".parse().unwrap()), None);
+        assert_eq!(db.country("2001:db8::1".parse().unwrap()), None);
+    }
+}
@@ -0,0 +1,16 @@
+#[cfg(feature = "geoip")]
+mod mmdb;
+
+#[cfg(feature = "geoip")]
+pub use mmdb::GeoIpDatabase;
+
+/// Built without the geoip feature, no database can be loaded
+#[cfg(not(feature = "geoip"))]
+pub enum GeoIpDatabase {}
+
+#[cfg(not(feature = "geoip"))]
+impl GeoIpDatabase {
+    pub fn country(&self, _ip: std::net::IpAddr) -> Option<String> {
+        match *self {}
+    }
+}
@@ -7,13 +7,14 @@ use std::net::{Ipv4Addr, SocketAddr};
 use std::pin::Pin;
 use std::sync::Arc;
 
+use crate::tunnel::parse_host;
 use base64::Engine;
 use futures_util::{future, stream, Stream};
 use http_body_util::Empty;
 use hyper::body::Incoming;
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
-use hyper::{Request, Response};
+use hyper::{Request, Response, StatusCode};
 use hyper_util::rt::TokioTimer;
 use parking_lot::Mutex;
 use std::time::Duration;
@@ -38,26 +39,39 @@ impl Stream for HttpProxyListener {
 
 fn handle_request(
     credentials: &Option<String>,
-    dest: &Mutex<(Host, u16)>,
+    dest: &Mutex<Option<(Host, u16)>>,
     req: Request<Incoming>,
 ) -> impl Future<Output = Result<Response<Empty<Bytes>>, &'static str>> {
     const PROXY_AUTHORIZATION_PREFIX: &str = "Basic ";
     let ok_response = |forward_to: (Host, u16)| -> Result<Response<Empty<Bytes>>, _> {
-        *dest.lock() = forward_to;
-        Ok(Response::builder().status(200).body(Empty::new()).unwrap())
+        *dest.lock() = Some(forward_to);
+        Ok(Response::builder().status(StatusCode::OK).body(Empty::new()).unwrap())
     };
-    fn err_response() -> Result<Response<Empty<Bytes>>, &'static str> {
+    fn unauthorized_response() -> Result<Response<Empty<Bytes>>, &'static str> {
         info!("Un-authorized connection to http proxy");
-        Err("Un-authorized")
+        Ok(Response::builder()
+            .status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+            .header(hyper::header::PROXY_AUTHENTICATE, "Basic realm=\"wstunnel\"")
+            .body(Empty::new())
+            .unwrap())
     }
 
+    // Only tunneling is supported, we are not a forward proxy for plain http requests
     if req.method() != hyper::Method::CONNECT {
-        return future::ready(err_response());
+        info!(
+            "Rejecting http proxy request with method {}, only CONNECT is supported",
+            req.method()
+        );
+        return future::ready(Ok(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(hyper::header::ALLOW, "CONNECT")
+            .body(Empty::new())
+            .unwrap()));
     }
 
     debug!("HTTP Proxy CONNECT request to {}", req.uri());
     let forward_to = (
-        Host::parse(req.uri().host().unwrap_or_default()).unwrap_or(Host::Ipv4(Ipv4Addr::new(0, 0, 0, 0))),
+        parse_host(req.uri().host().unwrap_or_default()).unwrap_or(Host::Ipv4(Ipv4Addr::new(0, 0, 0, 0))),
         req.uri().port_u16().unwrap_or(443),
     );
 
@@ -66,7 +80,7 @@ fn handle_request(
     };
 
     let Some(auth) = req.headers().get(hyper::header::PROXY_AUTHORIZATION) else {
-        return future::ready(err_response());
+        return future::ready(unauthorized_response());
     };
 
     let auth = auth.to_str().unwrap_or_default().trim();
@@ -74,7 +88,7 @@ fn handle_request(
         return future::ready(ok_response(forward_to));
     }
 
-    future::ready(err_response())
+    future::ready(unauthorized_response())
 }
 
 pub async fn run_server(
@@ -84,7 +98,8 @@ pub async fn run_server(
 ) -> Result<HttpProxyListener, anyhow::Error> {
     info!(
         "Starting http proxy server listening cnx on {} with credentials {:?}",
-        bind, credentials
+        bind,
+        credentials.as_ref().map(|(username, _)| (username, "***"))
     );
 
     let listener = TcpListener::bind(bind)
@@ -140,14 +155,15 @@ pub async fn run_server(
                 async move {
                     let http1 = &proxy_cfg.1;
                     let auth_header = &proxy_cfg.0;
-                    let forward_to = Mutex::new((Host::Ipv4(Ipv4Addr::new(0, 0, 0, 0)), 0));
+                    let forward_to = Mutex::new(None);
                     let conn_fut = http1.serve_connection(
                         hyper_util::rt::TokioIo::new(&mut stream),
                         service_fn(|req| handle_request(auth_header, &forward_to, req)),
                     );
 
+                    // The request may have been rejected, in which case there is nothing to forward
                     match conn_fut.await {
-                        Ok(_) => Some((stream, forward_to.into_inner())),
+                        Ok(_) => forward_to.into_inner().map(|forward_to| (stream, forward_to)),
                         Err(err) => {
                             info!("Error while serving connection: {}", err);
                             None
@@ -164,17 +180,82 @@ pub async fn run_server(
     })
 }
 
-//#[cfg(test)]
-//mod tests {
-//    use super::*;
-//    use tracing::level_filters::LevelFilter;
-//
-//    #[tokio::test]
-//    async fn test_run_server() {
-//        tracing_subscriber::fmt()
-//            .with_ansi(true)
-//            .with_max_level(LevelFilter::TRACE)
-//            .init();
-//        let x = run_server("127.0.0.1:1212".parse().unwrap(), None, None).await;
-//    }
-//}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+    use std::str::FromStr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn send_request(server_addr: SocketAddr, request: &str) -> String {
+        let mut client = TcpStream::connect(server_addr).await.unwrap();
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = vec![0u8; 1024];
+        let len = client.read(&mut response).await.unwrap();
+        String::from_utf8_lossy(&response[..len]).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_http_proxy_rejects_non_connect_methods() {
+        let server_addr = SocketAddr::from_str("127.0.0.1:1291").unwrap();
+        let mut server = run_server(server_addr, None, None).await.unwrap();
+        tokio::spawn(async move { while server.next().await.is_some() {} });
+
+        let response = send_request(server_addr, "GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405"), "{}", response);
+        assert!(response.to_lowercase().contains("allow: connect"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_http_proxy_authentication() {
+        let server_addr = SocketAddr::from_str("127.0.0.1:1292").unwrap();
+        let mut server = run_server(server_addr, None, Some(("user".to_string(), "pass".to_string())))
+            .await
+            .unwrap();
+        let server = tokio::spawn(async move { server.next().await.unwrap().unwrap() });
+
+        let response =
+            send_request(server_addr, "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 407"), "{}", response);
+
+        // base64("user:pass")
+        let response = send_request(
+            server_addr,
+            "CONNECT example.com:8443 HTTP/1.1\r\nHost: example.com:8443\r\nProxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+        let (_, (host, port)) = server.await.unwrap();
+        assert_eq!(host, Host::<String>::Domain("example.com".to_string()));
+        assert_eq!(port, 8443);
+    }
+
+    #[tokio::test]
+    async fn test_http_proxy_connect_to_ipv6() {
+        let server_addr = SocketAddr::from_str("127.0.0.1:1303").unwrap();
+        let mut server = run_server(server_addr, None, None).await.unwrap();
+        let server = tokio::spawn(async move {
+            let (_, first) = server.next().await.unwrap().unwrap();
+            let (_, second) = server.next().await.unwrap().unwrap();
+            (first, second)
+        });
+
+        let response = send_request(
+            server_addr,
+            "CONNECT [2001:db8::1]:443 HTTP/1.1\r\nHost: [2001:db8::1]:443\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        let response = send_request(
+            server_addr,
+            "CONNECT [fe80::1%25eth0]:22 HTTP/1.1\r\nHost: [fe80::1%25eth0]:22\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+        let (first, second) = server.await.unwrap();
+        assert_eq!(first, (Host::Ipv6("2001:db8::1".parse().unwrap()), 443));
+        assert_eq!(second, (Host::Ipv6("fe80::1".parse().unwrap()), 22));
+    }
+}
@@ -1,4 +1,5 @@
 pub mod dns;
+pub mod geoip;
 pub mod http_proxy;
 pub mod socks5;
 pub mod stdio;
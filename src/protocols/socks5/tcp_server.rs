@@ -5,10 +5,11 @@ use fast_socks5::server::{Config, DenyAuthentication, SimpleUserPassword, Socks5
 use fast_socks5::util::target_addr::TargetAddr;
 use fast_socks5::{consts, ReplyError};
 use futures_util::{stream, Stream, StreamExt};
-use std::io::{Error, IoSlice};
+use std::future::poll_fn;
+use std::io::{Error, ErrorKind, IoSlice};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::pin::Pin;
-use std::task::Poll;
+use std::task::{ready, Poll};
 use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 use tokio::net::TcpStream;
@@ -22,7 +23,7 @@ pub struct Socks5Listener {
 }
 
 pub enum Socks5Stream {
-    Tcp(TcpStream),
+    Tcp(Socks5TcpStream),
     Udp(Socks5UdpStream),
 }
 
@@ -35,6 +36,120 @@ impl Socks5Stream {
             },
         }
     }
+
+    /// Answer the CONNECT command of the client with an error, if the tunnel could not be opened
+    pub async fn reply_error(self, error: ReplyError) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(s) => s.reply_error(error).await,
+            Self::Udp(_) => Ok(()),
+        }
+    }
+}
+
+/// Connection of a socks5 client which asked to CONNECT.
+/// The reply is only sent once we start to forward data, or with the error if the tunnel could not be opened,
+/// for the client to know whether the destination is reachable
+pub struct Socks5TcpStream {
+    inner: TcpStream,
+    bind_addr: TargetAddr,
+    reply: Vec<u8>,
+    nb_reply_bytes_sent: usize,
+}
+
+impl Socks5TcpStream {
+    fn new(inner: TcpStream) -> Self {
+        // We don't know the address the server uses to connect to the destination,
+        // give back the one the client reached us on, for the reply to be of the same family
+        let ip = inner
+            .local_addr()
+            .map_or(IpAddr::V4(Ipv4Addr::LOCALHOST), |addr| addr.ip().to_canonical());
+        let bind_addr = TargetAddr::Ip(SocketAddr::new(ip, 0));
+        Self {
+            inner,
+            reply: new_reply(&ReplyError::Succeeded, &bind_addr),
+            bind_addr,
+            nb_reply_bytes_sent: 0,
+        }
+    }
+
+    fn poll_send_reply(&mut self, cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+        while self.nb_reply_bytes_sent < self.reply.len() {
+            let nb_bytes = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.reply[self.nb_reply_bytes_sent..]))?;
+            if nb_bytes == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.nb_reply_bytes_sent += nb_bytes;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    async fn reply_error(mut self, error: ReplyError) -> std::io::Result<()> {
+        // Too late if the client has already been told that it is connected
+        if self.nb_reply_bytes_sent > 0 {
+            return Ok(());
+        }
+        self.reply = new_reply(&error, &self.bind_addr);
+        poll_fn(|cx| self.poll_send_reply(cx)).await?;
+        self.inner.shutdown().await
+    }
+}
+
+impl Drop for Socks5TcpStream {
+    // Do not let the client think that it is connected if the tunnel is dropped before being opened
+    fn drop(&mut self) {
+        if self.nb_reply_bytes_sent == 0 {
+            let _ = self
+                .inner
+                .try_write(&new_reply(&ReplyError::GeneralFailure, &self.bind_addr));
+        }
+    }
+}
+
+impl AsyncRead for Socks5TcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        // The client does not send anything before it gets the reply
+        let this = self.get_mut();
+        ready!(this.poll_send_reply(cx))?;
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Socks5TcpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_reply(cx))?;
+        Pin::new(&mut this.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_reply(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_reply(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize, Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_reply(cx))?;
+        Pin::new(&mut this.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
 }
 
 impl Stream for Socks5Listener {
@@ -52,7 +167,8 @@ pub async fn run_server(
 ) -> Result<Socks5Listener, anyhow::Error> {
     info!(
         "Starting SOCKS5 server listening cnx on {} with credentials {:?}",
-        bind, credentials
+        bind,
+        credentials.as_ref().map(|(username, _)| (username, "***"))
     );
 
     let server = Socks5Server::<DenyAuthentication>::bind(bind)
@@ -129,7 +245,14 @@ pub async fn run_server(
             // Special case for UDP Associate where we return the bind addr of the udp server
             if matches!(cnx.cmd(), Some(fast_socks5::Socks5Command::UDPAssociate)) {
                 let mut cnx = cnx.into_inner();
-                let ret = cnx.write_all(&new_reply(&ReplyError::Succeeded, bind)).await;
+                // When listening on every interface, give back the address the client used to reach us
+                let relay_addr = match cnx.local_addr() {
+                    Ok(local_addr) if bind.ip().is_unspecified() => SocketAddr::new(local_addr.ip(), bind.port()),
+                    _ => bind,
+                };
+                let ret = cnx
+                    .write_all(&new_reply(&ReplyError::Succeeded, &TargetAddr::Ip(relay_addr)))
+                    .await;
 
                 if let Err(err) = ret {
                     warn!("Cannot reply to socks5 udp client: {}", err);
@@ -148,19 +271,7 @@ pub async fn run_server(
                 continue;
             };
 
-            let mut cnx = cnx.into_inner();
-            let ret = cnx
-                .write_all(&new_reply(
-                    &ReplyError::Succeeded,
-                    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0),
-                ))
-                .await;
-
-            if let Err(err) = ret {
-                warn!("Cannot reply to socks5 client: {}", err);
-                continue;
-            }
-
+            let cnx = Socks5TcpStream::new(cnx.into_inner());
             drop(acceptor);
             return Some((Ok((Socks5Stream::Tcp(cnx), (host, port))), (server, udp_server)));
         }
@@ -173,28 +284,39 @@ pub async fn run_server(
     Ok(listener)
 }
 
-fn new_reply(error: &ReplyError, sock_addr: SocketAddr) -> Vec<u8> {
-    let (addr_type, mut ip_oct, mut port) = match sock_addr {
-        SocketAddr::V4(sock) => (
-            consts::SOCKS5_ADDR_TYPE_IPV4,
-            sock.ip().octets().to_vec(),
-            sock.port().to_be_bytes().to_vec(),
-        ),
-        SocketAddr::V6(sock) => (
-            consts::SOCKS5_ADDR_TYPE_IPV6,
-            sock.ip().octets().to_vec(),
-            sock.port().to_be_bytes().to_vec(),
-        ),
-    };
-
+/// The address type is the one of the address: IPv4 (0x01), domain name (0x03) or IPv6 (0x04).
+/// IPv4-mapped IPv6 addresses, i.e: from a dual-stack socket, are given back as IPv4 ones
+fn new_reply(error: &ReplyError, bind_addr: &TargetAddr) -> Vec<u8> {
     let mut reply = vec![
         consts::SOCKS5_VERSION,
         error.as_u8(), // transform the error into byte code
         0x00,          // reserved
-        addr_type,     // address type (ipv4, v6, domain)
     ];
-    reply.append(&mut ip_oct);
-    reply.append(&mut port);
+
+    let port = match bind_addr {
+        TargetAddr::Ip(addr) => {
+            match addr.ip().to_canonical() {
+                IpAddr::V4(ip) => {
+                    reply.push(consts::SOCKS5_ADDR_TYPE_IPV4);
+                    reply.extend_from_slice(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    reply.push(consts::SOCKS5_ADDR_TYPE_IPV6);
+                    reply.extend_from_slice(&ip.octets());
+                }
+            }
+            addr.port()
+        }
+        TargetAddr::Domain(domain, port) => {
+            // The length is on a single byte, longer names are not valid ones anyway
+            let domain = &domain.as_bytes()[..domain.len().min(u8::MAX as usize)];
+            reply.push(consts::SOCKS5_ADDR_TYPE_DOMAIN_NAME);
+            reply.push(domain.len() as u8);
+            reply.extend_from_slice(domain);
+            *port
+        }
+    };
+    reply.extend_from_slice(&port.to_be_bytes());
 
     reply
 }
@@ -254,21 +376,147 @@ impl AsyncWrite for Socks5Stream {
     }
 }
 
-//#[cfg(test)]
-//mod test {
-//    use super::*;
-//    use futures_util::StreamExt;
-//    use std::str::FromStr;
-//
-//    #[tokio::test]
-//    async fn socks5_server() {
-//        let mut x = run_server(SocketAddr::from_str("[::]:4343").unwrap())
-//            .await
-//            .unwrap();
-//
-//        loop {
-//            let cnx = x.next().await.unwrap().unwrap();
-//            eprintln!("{:?}", cnx);
-//        }
-//    }
-//}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+    use std::str::FromStr;
+    use tokio::net::UdpSocket;
+
+    async fn authenticate(server_addr: SocketAddr, username: &str, password: &str) -> (TcpStream, [u8; 2]) {
+        let mut client = TcpStream::connect(server_addr).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x02]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [0x05, 0x02], "username/password method must be selected");
+
+        let mut auth = vec![0x01, username.len() as u8];
+        auth.extend_from_slice(username.as_bytes());
+        auth.push(password.len() as u8);
+        auth.extend_from_slice(password.as_bytes());
+        client.write_all(&auth).await.unwrap();
+        let mut status = [0u8; 2];
+        client.read_exact(&mut status).await.unwrap();
+
+        (client, status)
+    }
+
+    #[test]
+    fn test_socks5_reply_address_types() {
+        let addr = TargetAddr::Ip(SocketAddr::from_str("10.0.0.1:1080").unwrap());
+        assert_eq!(
+            new_reply(&ReplyError::Succeeded, &addr),
+            [0x05, 0x00, 0x00, 0x01, 10, 0, 0, 1, 0x04, 0x38]
+        );
+
+        let addr = TargetAddr::Ip(SocketAddr::from_str("[2001:db8::1]:1080").unwrap());
+        let mut expected = vec![0x05, 0x00, 0x00, 0x04];
+        expected.extend_from_slice(&Ipv6Addr::from_str("2001:db8::1").unwrap().octets());
+        expected.extend_from_slice(&[0x04, 0x38]);
+        assert_eq!(new_reply(&ReplyError::Succeeded, &addr), expected);
+
+        // Dual-stack sockets see IPv4 clients with mapped addresses
+        let addr = TargetAddr::Ip(SocketAddr::from_str("[::ffff:10.0.0.1]:1080").unwrap());
+        assert_eq!(
+            new_reply(&ReplyError::Succeeded, &addr),
+            [0x05, 0x00, 0x00, 0x01, 10, 0, 0, 1, 0x04, 0x38]
+        );
+
+        let addr = TargetAddr::Domain("proxy.local".to_string(), 1080);
+        let mut expected = vec![0x05, 0x04, 0x00, 0x03, 11];
+        expected.extend_from_slice(b"proxy.local");
+        expected.extend_from_slice(&[0x04, 0x38]);
+        assert_eq!(new_reply(&ReplyError::HostUnreachable, &addr), expected);
+    }
+
+    #[tokio::test]
+    async fn test_socks5_auth_success() {
+        let server_addr = SocketAddr::from_str("127.0.0.1:1281").unwrap();
+        let mut server = run_server(server_addr, None, Some(("user".to_string(), "pass".to_string())))
+            .await
+            .unwrap();
+
+        let client = tokio::spawn(async move {
+            let (mut client, status) = authenticate(server_addr, "user", "pass").await;
+            assert_eq!(status, [0x01, 0x00]);
+
+            client
+                .write_all(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0x1f, 0x90])
+                .await
+                .unwrap();
+            let mut reply = [0u8; 10];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply[..2], [0x05, 0x00]);
+        });
+
+        let (mut stream, (host, port)) = server.next().await.unwrap().unwrap();
+        assert_eq!(host, Host::<String>::Ipv4(Ipv4Addr::new(127, 0, 0, 1)));
+        assert_eq!(port, 8080);
+        // The reply is only sent once the tunnel is opened and starts forwarding
+        stream.flush().await.unwrap();
+        client.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socks5_auth_wrong_password_is_rejected() {
+        let server_addr = SocketAddr::from_str("127.0.0.1:1282").unwrap();
+        let mut server = run_server(server_addr, None, Some(("user".to_string(), "pass".to_string())))
+            .await
+            .unwrap();
+        tokio::spawn(async move { while server.next().await.is_some() {} });
+
+        let (mut client, status) = authenticate(server_addr, "user", "wrong").await;
+        assert_eq!(status[0], 0x01);
+        assert_ne!(status[1], 0x00, "authentication must fail");
+
+        // The server must close the connection without processing any command
+        let mut buf = [0u8; 1];
+        let _ = client
+            .write_all(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0x1f, 0x90])
+            .await;
+        assert_eq!(client.read(&mut buf).await.unwrap_or(0), 0);
+    }
+
+    #[tokio::test]
+    async fn test_socks5_udp_associate() {
+        let server_addr = SocketAddr::from_str("0.0.0.0:1283").unwrap();
+        let mut server = run_server(server_addr, None, None).await.unwrap();
+        let server = tokio::spawn(async move { server.next().await.unwrap().unwrap() });
+
+        let mut client = TcpStream::connect("127.0.0.1:1283").await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [0x05, 0x00]);
+
+        client
+            .write_all(&[0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [0x05, 0x00]);
+        // The relay address must be reachable, not the unspecified bind address
+        assert_eq!(reply[4..], [127, 0, 0, 1, 0x05, 0x03]);
+
+        let udp_client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut datagram = vec![0x00, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0x00, 0x35];
+        datagram.extend_from_slice(b"dns query");
+        udp_client.send_to(&datagram, "127.0.0.1:1283").await.unwrap();
+
+        let (mut stream, (host, port)) = server.await.unwrap();
+        assert!(matches!(stream, Socks5Stream::Udp(_)));
+        assert_eq!(host, Host::<String>::Ipv4(Ipv4Addr::new(127, 0, 0, 1)));
+        assert_eq!(port, 53);
+
+        // Answers are sent back with the socks5 udp header of the destination
+        stream.write_all(b"dns answer").await.unwrap();
+        let mut buf = [0u8; 64];
+        let len = udp_client.recv(&mut buf).await.unwrap();
+        assert_eq!(buf[..10], datagram[..10]);
+        assert_eq!(&buf[10..len], b"dns answer");
+        let mut buf = [0u8; 64];
+        let len = stream.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"dns query");
+    }
+}
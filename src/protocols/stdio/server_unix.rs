@@ -5,6 +5,8 @@ use tokio::sync::oneshot;
 use tokio_fd::AsyncFd;
 use tracing::info;
 
+pub type WsStdout = AsyncFd;
+
 pub struct WsStdin {
     stdin: AsyncFd,
     _receiver: oneshot::Receiver<()>,
@@ -16,7 +18,7 @@ impl AsyncRead for WsStdin {
     }
 }
 
-pub async fn run_server() -> Result<((WsStdin, AsyncFd), oneshot::Sender<()>), anyhow::Error> {
+pub async fn run_server() -> Result<((WsStdin, WsStdout), oneshot::Sender<()>), anyhow::Error> {
     info!("Starting STDIO server");
 
     let stdin = AsyncFd::try_from(nix::libc::STDIN_FILENO)?;
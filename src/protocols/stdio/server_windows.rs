@@ -5,14 +5,17 @@ use scopeguard::guard;
 use std::io::{Read, Write};
 use std::sync::Arc;
 use std::{io, thread};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::io::{AsyncReadExt, DuplexStream};
 use tokio::sync::oneshot;
 use tokio::task::LocalSet;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use tokio_util::io::StreamReader;
 use tracing::info;
 
-pub async fn run_server() -> Result<((impl AsyncRead, impl AsyncWrite), oneshot::Sender<()>), anyhow::Error> {
+pub type WsStdin = StreamReader<UnboundedReceiverStream<io::Result<BytesMut>>, BytesMut>;
+pub type WsStdout = DuplexStream;
+
+pub async fn run_server() -> Result<((WsStdin, WsStdout), oneshot::Sender<()>), anyhow::Error> {
     info!("Starting STDIO server. Press ctrl+c twice to exit");
 
     crossterm::terminal::enable_raw_mode()?;
@@ -1,6 +1,22 @@
+mod reset;
 mod server;
 
+pub use reset::reset_connection;
+pub use reset::ConnectFailureBehavior;
+pub use reset::ResetOnDropWriter;
+
 pub use server::configure_socket;
 pub use server::connect;
+pub use server::connect_to_addrs;
 pub use server::connect_with_http_proxy;
+pub use server::original_destination;
+pub use server::read_proxy_protocol_header;
+pub use server::resolve;
 pub use server::run_server;
+pub use server::ConnectOptions;
+pub use server::DomainResolutionError;
+pub use server::SocketBind;
+pub use server::TcpSocketOptions;
+pub use server::DEFAULT_CONNECT_PARALLELISM;
+pub use server::DEFAULT_HAPPY_EYEBALLS_DELAY;
+pub use server::DEFAULT_LISTEN_BACKLOG;
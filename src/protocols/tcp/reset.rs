@@ -0,0 +1,125 @@
+use socket2::SockRef;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::AsyncWrite;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+
+/// How the local tcp connection of a tunnel is closed, when the tunnel fails to connect to its destination
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConnectFailureBehavior {
+    /// Close it as if the destination ended the connection (FIN)
+    #[default]
+    Graceful,
+    /// Reset it (RST), for the peer to see that its connection failed instead of an empty response
+    Abrupt,
+}
+
+/// Make the connection send a RST once closed, instead of a FIN.
+/// Without lingering, closing the socket drops the data not sent yet and resets the connection
+pub fn reset_connection(stream: &TcpStream) {
+    let _ = SockRef::from(stream).set_linger(Some(Duration::ZERO));
+}
+
+/// Write half of a tcp connection which is reset if it is dropped without having been shut down first,
+/// i.e: when the tunnel ended with an error instead of being closed by the remote end
+pub struct ResetOnDropWriter {
+    inner: Option<OwnedWriteHalf>,
+    is_shutdown: bool,
+}
+
+impl ResetOnDropWriter {
+    pub const fn new(inner: OwnedWriteHalf) -> Self {
+        Self {
+            inner: Some(inner),
+            is_shutdown: false,
+        }
+    }
+
+    fn inner(&mut self) -> Pin<&mut OwnedWriteHalf> {
+        Pin::new(self.inner.as_mut().expect("write half is only taken on drop"))
+    }
+}
+
+impl AsyncWrite for ResetOnDropWriter {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.inner().poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let ret = self.inner().poll_shutdown(cx);
+        if ret.is_ready() {
+            self.is_shutdown = true;
+        }
+        ret
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        self.inner().poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.as_ref().is_some_and(|inner| inner.is_write_vectored())
+    }
+}
+
+impl Drop for ResetOnDropWriter {
+    fn drop(&mut self) {
+        let Some(inner) = self.inner.take() else {
+            return;
+        };
+        if self.is_shutdown {
+            return;
+        }
+
+        // Dropping the write half would shut it down, send a FIN, before the RST of the socket being closed
+        reset_connection(inner.as_ref());
+        inner.forget();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    async fn connection() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn test_reset_on_drop_unless_shutdown() {
+        let (mut client, server) = connection().await;
+        let (rx, tx) = server.into_split();
+        let mut tx = ResetOnDropWriter::new(tx);
+        tx.shutdown().await.unwrap();
+        drop((rx, tx));
+        assert_eq!(client.read(&mut [0; 8]).await.unwrap(), 0);
+
+        let (mut client, server) = connection().await;
+        let (rx, tx) = server.into_split();
+        drop((rx, ResetOnDropWriter::new(tx)));
+        let err = client.read(&mut [0; 8]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+
+        let (mut client, server) = connection().await;
+        reset_connection(&server);
+        drop(server);
+        let err = client.read(&mut [0; 8]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    }
+}
@@ -1,4 +1,5 @@
 use anyhow::{anyhow, Context};
+use std::collections::HashMap;
 use std::{io, vec};
 use tokio::task::JoinSet;
 
@@ -6,36 +7,111 @@ use base64::Engine;
 use bytes::BytesMut;
 use log::warn;
 use socket2::{SockRef, TcpKeepalive};
-use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
 use crate::protocols::dns::DnsResolver;
-use std::time::Duration;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::net::{TcpSocket, TcpStream};
 use tokio::time::{sleep, timeout};
 use tokio_stream::wrappers::TcpListenerStream;
 use tracing::log::info;
 use tracing::{debug, instrument};
 use url::{Host, Url};
 
-pub fn configure_socket(socket: SockRef, so_mark: &Option<u32>) -> Result<(), anyhow::Error> {
+/// Head start given to a connection attempt before starting the next one, as per RFC8305
+pub const DEFAULT_HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
+/// Number of addresses tried at once by default, the next ones waiting for the happy eyeballs delay
+pub const DEFAULT_CONNECT_PARALLELISM: usize = 1;
+
+/// Local address and/or network interface outbound sockets are bound to, instead of letting the OS pick them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SocketBind {
+    pub address: Option<IpAddr>,
+    pub device: Option<String>,
+}
+
+impl SocketBind {
+    /// Local address to bind a socket connecting to `dest`, if any
+    /// Error if the bind address is not of the same family as the destination
+    pub fn local_addr_for(&self, dest: &SocketAddr) -> io::Result<Option<SocketAddr>> {
+        match self.address {
+            None => Ok(None),
+            Some(ip) if ip.is_ipv4() == dest.is_ipv4() => Ok(Some(SocketAddr::new(ip, 0))),
+            Some(ip) => Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("bind address {} cannot be used to reach {}", ip, dest),
+            )),
+        }
+    }
+
+    /// Bind the socket to the configured network interface
+    pub fn bind_device(&self, socket: &SockRef) -> anyhow::Result<()> {
+        let Some(device) = &self.device else {
+            return Ok(());
+        };
+
+        #[cfg(target_os = "linux")]
+        {
+            socket
+                .bind_device(Some(device.as_bytes()))
+                .with_context(|| format!("cannot bind socket to device {}", device))
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = socket;
+            Err(anyhow!("binding socket to device {} is only supported on linux", device))
+        }
+    }
+}
+
+/// TCP options set on the sockets carrying tunnels traffic.
+/// TCP keepalive detects dead peers at the kernel level, it complements but does not replace the websocket ping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpSocketOptions {
+    pub nodelay: bool,
+    pub keepalive_idle: Duration,
+    pub keepalive_interval: Duration,
+    pub keepalive_retries: u32,
+}
+
+impl Default for TcpSocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive_idle: Duration::from_secs(60),
+            keepalive_interval: Duration::from_secs(10),
+            keepalive_retries: 3,
+        }
+    }
+}
+
+pub fn configure_socket(
+    socket: SockRef,
+    so_mark: &Option<u32>,
+    tcp_options: &TcpSocketOptions,
+) -> Result<(), anyhow::Error> {
     socket
-        .set_nodelay(true)
+        .set_nodelay(tcp_options.nodelay)
         .with_context(|| format!("cannot set no_delay on socket: {:?}", io::Error::last_os_error()))?;
 
     #[cfg(not(any(target_os = "windows", target_os = "openbsd")))]
     let tcp_keepalive = TcpKeepalive::new()
-        .with_time(Duration::from_secs(60))
-        .with_interval(Duration::from_secs(10))
-        .with_retries(3);
+        .with_time(tcp_options.keepalive_idle)
+        .with_interval(tcp_options.keepalive_interval)
+        .with_retries(tcp_options.keepalive_retries);
 
     #[cfg(target_os = "windows")]
     let tcp_keepalive = TcpKeepalive::new()
-        .with_time(Duration::from_secs(60))
-        .with_interval(Duration::from_secs(10));
+        .with_time(tcp_options.keepalive_idle)
+        .with_interval(tcp_options.keepalive_interval);
 
     #[cfg(target_os = "openbsd")]
-    let tcp_keepalive = TcpKeepalive::new().with_time(Duration::from_secs(60));
+    let tcp_keepalive = TcpKeepalive::new().with_time(tcp_options.keepalive_idle);
 
     socket
         .set_tcp_keepalive(&tcp_keepalive)
@@ -60,81 +136,230 @@ pub async fn connect(
 ) -> Result<TcpStream, anyhow::Error> {
     info!("Opening TCP connection to {}:{}", host, port);
 
+    let socket_addrs = resolve(host, port, dns_resolver).await?;
+    connect_to_addrs(
+        host,
+        port,
+        socket_addrs,
+        &ConnectOptions {
+            so_mark,
+            socket_bind: &SocketBind::default(),
+            tcp_options: &TcpSocketOptions::default(),
+            connect_timeout,
+            happy_eyeballs_delay: DEFAULT_HAPPY_EYEBALLS_DELAY,
+            connect_parallelism: DEFAULT_CONNECT_PARALLELISM,
+        },
+    )
+    .await
+}
+
+/// Context of the errors of name resolution, to tell them apart from the failures to connect
+#[derive(Debug)]
+pub struct DomainResolutionError(pub String);
+
+impl std::fmt::Display for DomainResolutionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "cannot resolve domain: {}", self.0)
+    }
+}
+
+pub async fn resolve(host: &Host<String>, port: u16, dns_resolver: &DnsResolver) -> anyhow::Result<Vec<SocketAddr>> {
     let socket_addrs: Vec<SocketAddr> = match host {
         Host::Domain(domain) => dns_resolver
             .lookup_host(domain.as_str(), port)
             .await
-            .with_context(|| format!("cannot resolve domain: {}", domain))?,
+            .with_context(|| DomainResolutionError(domain.clone()))?,
         Host::Ipv4(ip) => vec![SocketAddr::V4(SocketAddrV4::new(*ip, port))],
         Host::Ipv6(ip) => vec![SocketAddr::V6(SocketAddrV6::new(*ip, port, 0, 0))],
     };
 
+    Ok(socket_addrs)
+}
+
+/// How long the address of a hostname that won the race to connect is tried first, alone
+const CONNECTED_ADDR_TTL: Duration = Duration::from_secs(60);
+const CONNECTED_ADDRS_MAX_SIZE: usize = 1024;
+
+#[allow(clippy::type_complexity)]
+static CONNECTED_ADDRS: Lazy<Mutex<HashMap<(Host<String>, u16), (SocketAddr, Instant)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn connected_addr(host: &Host<String>, port: u16) -> Option<SocketAddr> {
+    let mut connected_addrs = CONNECTED_ADDRS.lock();
+    let key = (host.clone(), port);
+    match connected_addrs.get(&key) {
+        Some((addr, at)) if at.elapsed() < CONNECTED_ADDR_TTL => Some(*addr),
+        Some(_) => {
+            connected_addrs.remove(&key);
+            None
+        }
+        None => None,
+    }
+}
+
+fn set_connected_addr(host: &Host<String>, port: u16, addr: Option<SocketAddr>) {
+    let mut connected_addrs = CONNECTED_ADDRS.lock();
+    let Some(addr) = addr else {
+        connected_addrs.remove(&(host.clone(), port));
+        return;
+    };
+    if connected_addrs.len() >= CONNECTED_ADDRS_MAX_SIZE {
+        connected_addrs.retain(|_, (_, at)| at.elapsed() < CONNECTED_ADDR_TTL);
+    }
+    if connected_addrs.len() < CONNECTED_ADDRS_MAX_SIZE {
+        connected_addrs.insert((host.clone(), port), (addr, Instant::now()));
+    }
+}
+
+// Socket ready to connect to addr, or the io error for which addr must be skipped
+fn prepare_socket(
+    addr: &SocketAddr,
+    so_mark: Option<u32>,
+    socket_bind: &SocketBind,
+    tcp_options: &TcpSocketOptions,
+) -> anyhow::Result<Result<TcpSocket, io::Error>> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4(),
+        SocketAddr::V6(_) => TcpSocket::new_v6(),
+    };
+    let socket = match socket {
+        Ok(s) => s,
+        Err(err) => return Ok(Err(err)),
+    };
+    // Skip the addresses we can't reach from the bind address, i.e: IPv6 ones when binding on an IPv4
+    let local_addr = match socket_bind.local_addr_for(addr) {
+        Ok(local_addr) => local_addr,
+        Err(err) => {
+            debug!("Skipping tcp endpoint {addr}: {err}");
+            return Ok(Err(err));
+        }
+    };
+    configure_socket(socket2::SockRef::from(&socket), &so_mark, tcp_options)?;
+    socket_bind.bind_device(&socket2::SockRef::from(&socket))?;
+    if let Some(local_addr) = local_addr {
+        socket
+            .bind(local_addr)
+            .with_context(|| format!("cannot bind socket to {}", local_addr))?;
+    }
+
+    Ok(Ok(socket))
+}
+
+/// How the sockets of connect_to_addrs are set up, and how long they are given to connect
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectOptions<'a> {
+    pub so_mark: Option<u32>,
+    pub socket_bind: &'a SocketBind,
+    pub tcp_options: &'a TcpSocketOptions,
+    pub connect_timeout: Duration,
+    pub happy_eyeballs_delay: Duration,
+    pub connect_parallelism: usize,
+}
+
+/// Connect to the first reachable address, host and port are only used for logging and to remember the address
+/// that connected. Addresses are tried connect_parallelism at a time, the next ones starting after
+/// happy_eyeballs_delay or as soon as an attempt fails, as per RFC8305.
+/// See https://datatracker.ietf.org/doc/html/rfc8305#section-5
+pub async fn connect_to_addrs(
+    host: &Host<String>,
+    port: u16,
+    mut socket_addrs: Vec<SocketAddr>,
+    options: &ConnectOptions<'_>,
+) -> Result<TcpStream, anyhow::Error> {
+    let &ConnectOptions {
+        so_mark,
+        socket_bind,
+        tcp_options,
+        connect_timeout,
+        happy_eyeballs_delay,
+        connect_parallelism,
+    } = options;
+    let connect_parallelism = connect_parallelism.max(1);
+    let is_raced = matches!(host, Host::Domain(_)) && socket_addrs.len() > 1;
+    // The address that connected last time goes first, without racing the others unless it does not answer in time
+    let connected_addr = if is_raced { connected_addr(host, port) } else { None };
+    let mut nb_to_start = match connected_addr.and_then(|addr| socket_addrs.iter().position(|a| *a == addr)) {
+        Some(ix) => {
+            socket_addrs[..=ix].rotate_right(1);
+            1
+        }
+        None => connect_parallelism,
+    };
+
     let mut cnx = None;
     let mut last_err = None;
     let mut join_set = JoinSet::new();
+    let mut socket_addrs = socket_addrs.into_iter();
+    loop {
+        while nb_to_start > 0 {
+            let Some(addr) = socket_addrs.next() else {
+                break;
+            };
+            let socket = match prepare_socket(&addr, so_mark, socket_bind, tcp_options)? {
+                Ok(socket) => socket,
+                Err(err) => {
+                    last_err = Some(err);
+                    continue;
+                }
+            };
+            nb_to_start -= 1;
+            join_set.spawn(async move {
+                debug!("Connecting to {}", addr);
+                match timeout(connect_timeout, socket.connect(addr)).await {
+                    Ok(Ok(s)) => Ok(Ok(s)),
+                    Ok(Err(e)) => Ok(Err((addr, e))),
+                    Err(e) => Err((addr, e)),
+                }
+            });
+        }
+        if join_set.is_empty() {
+            break;
+        }
 
-    for (ix, addr) in socket_addrs.into_iter().enumerate() {
-        let socket = match &addr {
-            SocketAddr::V4(_) => TcpSocket::new_v4(),
-            SocketAddr::V6(_) => TcpSocket::new_v6(),
-        };
-        let socket = match socket {
-            Ok(s) => s,
-            Err(err) => {
-                last_err = Some(err);
+        // Wait for the next attempt to finish, or for the delay to start the next ones
+        let res = tokio::select! {
+            res = join_set.join_next() => res.expect("join set is not empty"),
+            _ = sleep(happy_eyeballs_delay), if !socket_addrs.as_slice().is_empty() => {
+                nb_to_start = connect_parallelism;
                 continue;
             }
         };
-        configure_socket(socket2::SockRef::from(&socket), &so_mark)?;
-
-        // Spawn the connection attempt in the join set.
-        // We include a delay of ix * 250 milliseconds, as per RFC8305.
-        // See https://datatracker.ietf.org/doc/html/rfc8305#section-5
-        let fut = async move {
-            if ix > 0 {
-                sleep(Duration::from_millis(250 * ix as u64)).await;
-            }
-            debug!("Connecting to {}", addr);
-            match timeout(connect_timeout, socket.connect(addr)).await {
-                Ok(Ok(s)) => Ok(Ok(s)),
-                Ok(Err(e)) => Ok(Err((addr, e))),
-                Err(e) => Err((addr, e)),
-            }
-        };
-        join_set.spawn(fut);
-    }
-
-    // Wait for the next future that finishes in the join set, until we got one
-    // that resulted in a successful connection.
-    // If cnx is no longer None, we exit the loop, since this means that we got
-    // a successful connection.
-    while let (None, Some(res)) = (&cnx, join_set.join_next().await) {
         match res? {
             Ok(Ok(stream)) => {
-                // We've got a successful connection, so we can abort all other
-                // ongoing attempts.
-                join_set.abort_all();
-
                 debug!(
-                    "Connected to tcp endpoint {}, aborted all other connection attempts",
+                    "Connected to tcp endpoint {}, aborting all other connection attempts",
                     stream.peer_addr()?
                 );
                 cnx = Some(stream);
+                break;
             }
             Ok(Err((addr, err))) => {
                 debug!("Cannot connect to tcp endpoint {addr} reason {err}");
                 last_err = Some(err);
+                nb_to_start = 1;
             }
             Err((addr, _)) => {
                 warn!(
                     "Cannot connect to tcp endpoint {addr} due to timeout of {}s elapsed",
                     connect_timeout.as_secs()
                 );
+                last_err = Some(io::Error::new(io::ErrorKind::TimedOut, "connection timed out"));
+                nb_to_start = 1;
             }
         }
     }
+    // Close the losing connections right away, even the ones that connected at the same time as the winner
+    join_set.shutdown().await;
+
+    if is_raced {
+        set_connected_addr(host, port, cnx.as_ref().and_then(|stream| stream.peer_addr().ok()));
+    }
 
-    cnx.ok_or_else(|| anyhow!("Cannot connect to tcp endpoint {}:{} reason {:?}", host, port, last_err))
+    // Keep the io error in the chain, for the callers to tell why it failed
+    cnx.ok_or_else(|| {
+        let err = last_err.map_or_else(|| anyhow!("no address to connect to"), anyhow::Error::new);
+        err.context(format!("Cannot connect to tcp endpoint {}:{}", host, port))
+    })
 }
 
 #[instrument(level = "info", name = "http_proxy", skip_all)]
@@ -143,6 +368,7 @@ pub async fn connect_with_http_proxy(
     host: &Host<String>,
     port: u16,
     so_mark: Option<u32>,
+    socket_bind: &SocketBind,
     connect_timeout: Duration,
     dns_resolver: &DnsResolver,
 ) -> Result<TcpStream, anyhow::Error> {
@@ -150,7 +376,21 @@ pub async fn connect_with_http_proxy(
     let proxy_port = proxy.port_or_known_default().unwrap_or(80);
 
     info!("Connecting to http proxy {}:{}", proxy_host, proxy_port);
-    let mut socket = connect(&proxy_host, proxy_port, so_mark, connect_timeout, dns_resolver).await?;
+    let proxy_addrs = resolve(&proxy_host, proxy_port, dns_resolver).await?;
+    let mut socket = connect_to_addrs(
+        &proxy_host,
+        proxy_port,
+        proxy_addrs,
+        &ConnectOptions {
+            so_mark,
+            socket_bind,
+            tcp_options: &TcpSocketOptions::default(),
+            connect_timeout,
+            happy_eyeballs_delay: DEFAULT_HAPPY_EYEBALLS_DELAY,
+            connect_parallelism: DEFAULT_CONNECT_PARALLELISM,
+        },
+    )
+    .await?;
     debug!("Connected to http proxy {}", socket.peer_addr().unwrap());
 
     let authorization = if let Some((user, password)) = proxy.password().map(|p| (proxy.username(), p)) {
@@ -212,18 +452,111 @@ pub async fn connect_with_http_proxy(
     Ok(socket)
 }
 
-pub async fn run_server(bind: SocketAddr, ip_transparent: bool) -> Result<TcpListenerStream, anyhow::Error> {
-    info!("Starting TCP server listening cnx on {}", bind);
+/// Read and strip the PROXY protocol (v1 or v2) header at the start of the stream.
+/// Return the source address announced by the header, or None if the header does not carry one (i.e: LOCAL command)
+pub async fn read_proxy_protocol_header(stream: &mut TcpStream) -> anyhow::Result<Option<SocketAddr>> {
+    const V1_MAX_LEN: usize = 107;
+    const V2_HEADER_LEN: usize = 16;
+
+    // Both versions have a signature of at least 6 bytes, so we can tell them apart without over-reading the stream
+    let mut buf = vec![0u8; 6];
+    stream.read_exact(&mut buf).await?;
+
+    if buf.as_slice() == b"PROXY " {
+        // v1 is a single line terminated by \r\n, read it byte by byte to not consume the payload that follows
+        while !buf.ends_with(b"\r\n") {
+            if buf.len() >= V1_MAX_LEN {
+                return Err(anyhow!("proxy protocol v1 header is too long"));
+            }
+            buf.push(stream.read_u8().await?);
+        }
 
-    let listener = TcpListener::bind(bind)
-        .await
-        .with_context(|| format!("Cannot create TCP server {:?}", bind))?;
+        let header = ppp::v1::Header::try_from(buf.as_slice())
+            .map_err(|err| anyhow!("invalid proxy protocol v1 header: {:?}", err))?;
+        return Ok(match header.addresses {
+            ppp::v1::Addresses::Tcp4(ip) => Some(SocketAddr::new(IpAddr::V4(ip.source_address), ip.source_port)),
+            ppp::v1::Addresses::Tcp6(ip) => Some(SocketAddr::new(IpAddr::V6(ip.source_address), ip.source_port)),
+            ppp::v1::Addresses::Unknown => None,
+        });
+    }
+
+    buf.resize(V2_HEADER_LEN, 0);
+    stream.read_exact(&mut buf[6..]).await?;
+    if !buf.starts_with(ppp::v2::PROTOCOL_PREFIX) {
+        return Err(anyhow!("missing proxy protocol header"));
+    }
+    let addresses_len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    buf.resize(V2_HEADER_LEN + addresses_len, 0);
+    stream.read_exact(&mut buf[V2_HEADER_LEN..]).await?;
+
+    let header = ppp::v2::Header::try_from(buf.as_slice())
+        .map_err(|err| anyhow!("invalid proxy protocol v2 header: {:?}", err))?;
+    if header.command == ppp::v2::Command::Local {
+        return Ok(None);
+    }
+
+    Ok(match header.addresses {
+        ppp::v2::Addresses::IPv4(ip) => Some(SocketAddr::new(IpAddr::V4(ip.source_address), ip.source_port)),
+        ppp::v2::Addresses::IPv6(ip) => Some(SocketAddr::new(IpAddr::V6(ip.source_address), ip.source_port)),
+        ppp::v2::Addresses::Unspecified | ppp::v2::Addresses::Unix(_) => None,
+    })
+}
+
+/// Destination of a connection before it was redirected to us, i.e: by iptables/ip6tables REDIRECT
+#[cfg(target_os = "linux")]
+pub fn original_destination(stream: &TcpStream) -> io::Result<SocketAddr> {
+    use nix::sys::socket::{getsockopt, sockopt};
+
+    // IPv4 clients of a dual-stack socket are tracked, and so redirected, as IPv4 ones
+    if stream.local_addr()?.ip().to_canonical().is_ipv4() {
+        let addr = getsockopt(stream, sockopt::OriginalDst)?;
+        let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+        Ok(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(addr.sin_port))))
+    } else {
+        let addr = getsockopt(stream, sockopt::Ip6tOriginalDst)?;
+        let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+        Ok(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(addr.sin6_port), 0, 0)))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn original_destination(_stream: &TcpStream) -> io::Result<SocketAddr> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "original destination of redirected connections is only available on linux",
+    ))
+}
+
+/// Same as tokio TcpListener::bind
+pub const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
 
+pub async fn run_server(
+    bind: SocketAddr,
+    ip_transparent: bool,
+    listen_backlog: u32,
+) -> Result<TcpListenerStream, anyhow::Error> {
+    info!("Starting TCP server listening cnx on {}", bind);
+
+    let socket = match bind {
+        SocketAddr::V4(_) => TcpSocket::new_v4(),
+        SocketAddr::V6(_) => TcpSocket::new_v6(),
+    }
+    .with_context(|| format!("Cannot create TCP server {:?}", bind))?;
+    // As TcpListener::bind does, to be able to restart right away while connections of the previous run linger
+    #[cfg(not(windows))]
+    socket.set_reuseaddr(true)?;
+    // Before binding, for the bind address to be allowed to not be a local one (i.e: the one of a gateway)
     #[cfg(target_os = "linux")]
     if ip_transparent {
         info!("TCP server listening in TProxy mode");
-        socket2::SockRef::from(&listener).set_ip_transparent(ip_transparent)?;
+        socket2::SockRef::from(&socket)
+            .set_ip_transparent(ip_transparent)
+            .context("Cannot set IP_TRANSPARENT on TCP server, CAP_NET_ADMIN is required")?;
     }
+    let listener = socket
+        .bind(bind)
+        .and_then(|_| socket.listen(listen_backlog))
+        .with_context(|| format!("Cannot create TCP server {:?}", bind))?;
 
     Ok(TcpListenerStream::new(listener))
 }
@@ -236,6 +569,7 @@ mod tests {
     use testcontainers::core::WaitFor;
     use testcontainers::runners::AsyncRunner;
     use testcontainers::{ContainerAsync, Image, ImageArgs, RunnableImage};
+    use tokio::net::TcpListener;
 
     #[derive(Debug, Clone, Default)]
     pub struct MitmProxy {}
@@ -280,8 +614,9 @@ mod tests {
             &Host::Domain("[::1]".to_string()),
             1236,
             None,
+            &SocketBind::default(),
             Duration::from_secs(1),
-            &DnsResolver::System,
+            &DnsResolver::System { prefer_ipv6: true },
         )
         .await
         .unwrap();
@@ -299,4 +634,140 @@ mod tests {
         let _ = client.read(&mut buf).await.unwrap();
         assert!(buf.starts_with(b"HTTP/1.1 200 OK\r\n\r\n"));
     }
+
+    #[tokio::test]
+    async fn test_socket_bind_address() {
+        let server_addr: SocketAddr = "127.0.0.1:1299".parse().unwrap();
+        let _server = TcpListener::bind(server_addr).await.unwrap();
+        let socket_bind = SocketBind {
+            address: Some("127.0.0.1".parse().unwrap()),
+            device: None,
+        };
+        let host = Host::Domain("localhost".to_string());
+
+        // IPv6 addresses can't be reached from an IPv4 bind address, they must be skipped
+        let v6_addr: SocketAddr = "[::1]:1299".parse().unwrap();
+        let client = connect_to_addrs(
+            &host,
+            1299,
+            vec![v6_addr, server_addr],
+            &ConnectOptions {
+                so_mark: None,
+                socket_bind: &socket_bind,
+                tcp_options: &TcpSocketOptions::default(),
+                connect_timeout: Duration::from_secs(1),
+                happy_eyeballs_delay: DEFAULT_HAPPY_EYEBALLS_DELAY,
+                connect_parallelism: DEFAULT_CONNECT_PARALLELISM,
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(client.local_addr().unwrap().ip(), socket_bind.address.unwrap());
+
+        let ret = connect_to_addrs(
+            &host,
+            1299,
+            vec![v6_addr],
+            &ConnectOptions {
+                so_mark: None,
+                socket_bind: &socket_bind,
+                tcp_options: &TcpSocketOptions::default(),
+                connect_timeout: Duration::from_secs(1),
+                happy_eyeballs_delay: DEFAULT_HAPPY_EYEBALLS_DELAY,
+                connect_parallelism: DEFAULT_CONNECT_PARALLELISM,
+            },
+        )
+        .await;
+        assert!(ret.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_to_addrs_remembers_connected_addr() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        // Nothing listens there once the listeners are dropped, the attempts fail right away
+        let refused_addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let other_refused_addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let host = Host::Domain("parallel.localhost".to_string());
+        let (socket_bind, tcp_options) = (SocketBind::default(), TcpSocketOptions::default());
+        let options = ConnectOptions {
+            so_mark: None,
+            socket_bind: &socket_bind,
+            tcp_options: &tcp_options,
+            connect_timeout: Duration::from_secs(1),
+            happy_eyeballs_delay: Duration::from_secs(10),
+            connect_parallelism: 2,
+        };
+        let connect = |socket_addrs| connect_to_addrs(&host, server_addr.port(), socket_addrs, &options);
+
+        // The failure of an attempt starts the next one without waiting for the happy eyeballs delay
+        let stream = timeout(Duration::from_secs(2), connect(vec![refused_addr, server_addr]))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), server_addr);
+        assert_eq!(connected_addr(&host, server_addr.port()), Some(server_addr));
+
+        let ret = connect(vec![refused_addr, other_refused_addr]).await;
+        assert!(ret.is_err());
+        assert_eq!(connected_addr(&host, server_addr.port()), None);
+    }
+
+    #[tokio::test]
+    async fn test_configure_socket_tcp_options() {
+        let server_addr: SocketAddr = "127.0.0.1:1300".parse().unwrap();
+        let _server = TcpListener::bind(server_addr).await.unwrap();
+        let stream = TcpStream::connect(server_addr).await.unwrap();
+        let tcp_options = TcpSocketOptions {
+            nodelay: false,
+            keepalive_idle: Duration::from_secs(42),
+            ..TcpSocketOptions::default()
+        };
+
+        configure_socket(SockRef::from(&stream), &None, &tcp_options).unwrap();
+        let socket = SockRef::from(&stream);
+        assert!(!socket.nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(42));
+    }
+
+    #[tokio::test]
+    async fn test_read_proxy_protocol_header() {
+        let server_addr: SocketAddr = "127.0.0.1:1301".parse().unwrap();
+        let server = TcpListener::bind(server_addr).await.unwrap();
+        let source: SocketAddr = "192.168.1.2:4242".parse().unwrap();
+        let v2_header = ppp::v2::Builder::with_addresses(
+            ppp::v2::Version::Two | ppp::v2::Command::Proxy,
+            ppp::v2::Protocol::Stream,
+            (source, server_addr),
+        )
+        .build()
+        .unwrap();
+
+        let headers: [(&[u8], Option<Option<SocketAddr>>); 4] = [
+            (b"PROXY TCP4 192.168.1.2 127.0.0.1 4242 1301\r\n", Some(Some(source))),
+            (b"PROXY UNKNOWN\r\n", Some(None)),
+            (&v2_header, Some(Some(source))),
+            (b"GET / HTTP/1.1\r\n\r\n", None),
+        ];
+        for (header, expected) in headers {
+            let mut client = TcpStream::connect(server_addr).await.unwrap();
+            client.write_all(header).await.unwrap();
+            client.write_all(b"payload").await.unwrap();
+
+            let mut stream = server.accept().await.unwrap().0;
+            let ret = read_proxy_protocol_header(&mut stream).await;
+            let Some(expected) = expected else {
+                assert!(ret.is_err());
+                continue;
+            };
+            assert_eq!(ret.unwrap(), expected);
+
+            // The payload following the header must be left untouched
+            let mut buf = [0u8; 7];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"payload");
+        }
+    }
 }
@@ -4,7 +4,10 @@ mod utils;
 pub use server::connect;
 pub use server::load_certificates_from_pem;
 pub use server::load_private_key_from_file;
+pub use server::root_cert_store;
 pub use server::tls_acceptor;
-pub use server::tls_connector;
+pub use server::tls_client_config;
+pub use server::TlsClientOptions;
+pub use server::TlsVersion;
 pub use utils::cn_from_certificate;
 pub use utils::find_leaf_certificate;
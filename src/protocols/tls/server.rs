@@ -1,4 +1,5 @@
 use anyhow::{anyhow, Context};
+use base64::Engine;
 use std::fs::File;
 
 use log::warn;
@@ -8,15 +9,50 @@ use std::sync::Arc;
 use tokio::net::TcpStream;
 use tokio_rustls::client::TlsStream;
 
-use crate::tunnel::client::WsClientConfig;
 use crate::tunnel::server::TlsServerConfig;
 use crate::tunnel::TransportAddr;
 use tokio_rustls::rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::client::WebPkiServerVerifier;
+use tokio_rustls::rustls::crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms};
 use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
 use tokio_rustls::rustls::server::WebPkiClientVerifier;
-use tokio_rustls::rustls::{ClientConfig, DigitallySignedStruct, Error, KeyLogFile, SignatureScheme};
-use tokio_rustls::{rustls, TlsAcceptor, TlsConnector};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{
+    AlertDescription, CertificateError, ClientConfig, DigitallySignedStruct, Error, InconsistentKeys, KeyLogFile,
+    RootCertStore, SignatureScheme, SupportedProtocolVersion,
+};
+use tokio_rustls::{rustls, TlsAcceptor};
 use tracing::info;
+use x509_parser::parse_x509_certificate;
+
+/// Oldest TLS version accepted during the handshake
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum TlsVersion {
+    #[default]
+    #[value(name = "1.2")]
+    Tls12,
+    #[value(name = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    fn protocol_versions(self) -> &'static [&'static SupportedProtocolVersion] {
+        static TLS13_ONLY: &[&SupportedProtocolVersion] = &[&rustls::version::TLS13];
+        match self {
+            Self::Tls12 => rustls::ALL_VERSIONS,
+            Self::Tls13 => TLS13_ONLY,
+        }
+    }
+}
+
+impl std::fmt::Display for TlsVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tls12 => write!(f, "1.2"),
+            Self::Tls13 => write!(f, "1.3"),
+        }
+    }
+}
 
 #[derive(Debug)]
 struct NullVerifier;
@@ -70,10 +106,121 @@ impl ServerCertVerifier for NullVerifier {
     }
 }
 
+/// Verify the server certificate against a fixed hostname, instead of the one used as SNI
+#[derive(Debug)]
+struct HostnameVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    hostname: ServerName<'static>,
+}
+
+impl ServerCertVerifier for HostnameVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        self.inner
+            .verify_server_cert(end_entity, intermediates, &self.hostname, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Accept the server certificate only if the sha256 hash of its public key (SPKI) is one of the pins.
+/// The chain of trust is not checked, the pin is what is trusted
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    algorithms: WebPkiSupportedAlgorithms,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        let spki_hash = spki_sha256(end_entity).ok_or(Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        if self.pins.iter().any(|pin| pin == &spki_hash) {
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        Err(Error::General(format!(
+            "server certificate public key sha256/{} does not match any of the pinned keys",
+            base64::engine::general_purpose::STANDARD.encode(spki_hash)
+        )))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+fn spki_sha256(certificate: &CertificateDer<'_>) -> Option<[u8; 32]> {
+    let (_, certificate) = parse_x509_certificate(certificate).ok()?;
+    let digest = ring::digest::digest(&ring::digest::SHA256, certificate.tbs_certificate.subject_pki.raw);
+    digest.as_ref().try_into().ok()
+}
+
+/// Decode a pin, in the base64 form of the sha256 of the certificate public key. The sha256/ prefix is optional
+pub fn parse_certificate_pin(pin: &str) -> anyhow::Result<[u8; 32]> {
+    let pin = pin.strip_prefix("sha256/").unwrap_or(pin);
+    let hash = base64::engine::general_purpose::STANDARD
+        .decode(pin)
+        .with_context(|| format!("Invalid certificate pin {pin}, it must be base64 encoded"))?;
+
+    hash.try_into()
+        .map_err(|_| anyhow!("Invalid certificate pin {pin}, it must be a sha256 hash"))
+}
+
 pub fn load_certificates_from_pem(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
     info!("Loading tls certificate from {:?}", path);
 
-    let file = File::open(path)?;
+    let file = File::open(path).with_context(|| format!("Cannot read tls certificate {path:?}"))?;
     let mut reader = BufReader::new(file);
     let certs = rustls_pemfile::certs(&mut reader);
 
@@ -92,24 +239,37 @@ pub fn load_certificates_from_pem(path: &Path) -> anyhow::Result<Vec<Certificate
 pub fn load_private_key_from_file(path: &Path) -> anyhow::Result<PrivateKeyDer<'static>> {
     info!("Loading tls private key from {:?}", path);
 
-    let file = File::open(path)?;
-    let mut reader = BufReader::new(file);
+    let pem = std::fs::read(path).with_context(|| format!("Cannot read tls private key {path:?}"))?;
 
-    let Some(private_key) = rustls_pemfile::private_key(&mut reader)? else {
+    // PKCS#1, PKCS#8 and SEC1 keys are supported, but not encrypted ones
+    let Some(private_key) = rustls_pemfile::private_key(&mut pem.as_slice())? else {
+        if pem.windows(b"ENCRYPTED".len()).any(|w| w == b"ENCRYPTED") {
+            return Err(anyhow!(
+                "Encrypted private key {path:?} is not supported, decrypt it first with: openssl pkey -in encrypted_key.pem -out key.pem"
+            ));
+        }
         return Err(anyhow!("No private key found in {path:?}"));
     };
 
     Ok(private_key)
 }
 
-pub fn tls_connector(
-    tls_verify_certificate: bool,
-    alpn_protocols: Vec<Vec<u8>>,
-    enable_sni: bool,
-    tls_client_certificate: Option<Vec<CertificateDer<'static>>>,
-    tls_client_key: Option<PrivateKeyDer<'static>>,
-) -> anyhow::Result<TlsConnector> {
-    let mut root_store = rustls::RootCertStore::empty();
+/// Build the store of the CA trusted to verify the server certificate.
+/// Use the system certificates, unless a CA bundle is provided. In this case system ones are only added on demand
+pub fn root_cert_store(tls_root_ca: Option<&Path>, with_system_roots: bool) -> anyhow::Result<RootCertStore> {
+    let mut root_store = RootCertStore::empty();
+
+    if let Some(path) = tls_root_ca {
+        let certs = load_certificates_from_pem(path)?;
+        let (added, _) = root_store.add_parsable_certificates(certs);
+        if added == 0 {
+            return Err(anyhow!("No valid CA certificate found in {path:?}"));
+        }
+        info!("Loaded {added} CA certificates from {path:?}");
+        if !with_system_roots {
+            return Ok(root_store);
+        }
+    }
 
     // Load system certificates and add them to the root store
     let certs = rustls_native_certs::load_native_certs().with_context(|| "Cannot load system certificates")?;
@@ -120,12 +280,77 @@ pub fn tls_connector(
         }
     }
 
-    let config_builder = ClientConfig::builder().with_root_certificates(root_store);
+    Ok(root_store)
+}
+
+/// What a TLS client configuration is built from, see [tls_client_config]
+pub struct TlsClientOptions {
+    pub tls_verify_certificate: bool,
+    pub alpn_protocols: Vec<Vec<u8>>,
+    pub enable_sni: bool,
+    /// Hostname the certificate must be valid for, instead of the one we connect to
+    pub tls_verify_hostname: Option<ServerName<'static>>,
+    pub tls_certificate_pins: Vec<String>,
+    pub root_store: Arc<RootCertStore>,
+    /// Certificate and key to authenticate with, for mTLS
+    pub tls_client_certificate: Option<Vec<CertificateDer<'static>>>,
+    pub tls_client_key: Option<PrivateKeyDer<'static>>,
+    pub tls_min_version: TlsVersion,
+}
+
+impl Default for TlsClientOptions {
+    fn default() -> Self {
+        Self {
+            tls_verify_certificate: true,
+            alpn_protocols: vec![],
+            enable_sni: true,
+            tls_verify_hostname: None,
+            tls_certificate_pins: vec![],
+            root_store: Arc::new(RootCertStore::empty()),
+            tls_client_certificate: None,
+            tls_client_key: None,
+            tls_min_version: TlsVersion::default(),
+        }
+    }
+}
+
+pub fn tls_client_config(options: TlsClientOptions) -> anyhow::Result<Arc<ClientConfig>> {
+    let TlsClientOptions {
+        tls_verify_certificate,
+        alpn_protocols,
+        enable_sni,
+        tls_verify_hostname,
+        tls_certificate_pins,
+        root_store,
+        tls_client_certificate,
+        tls_client_key,
+        tls_min_version,
+    } = options;
+    let config_builder = ClientConfig::builder_with_protocol_versions(tls_min_version.protocol_versions())
+        .with_root_certificates(root_store.clone());
 
     let mut config = match (tls_client_certificate, tls_client_key) {
-        (Some(tls_client_certificate), Some(tls_client_key)) => config_builder
-            .with_client_auth_cert(tls_client_certificate, tls_client_key)
-            .with_context(|| "Error setting up mTLS")?,
+        (Some(tls_client_certificate), Some(tls_client_key)) => {
+            if tls_client_certificate.is_empty() {
+                return Err(anyhow!("No certificate found for mTLS"));
+            }
+            let config = config_builder
+                .with_client_auth_cert(tls_client_certificate.clone(), tls_client_key.clone_key())
+                .with_context(|| "Error setting up mTLS")?;
+
+            // rustls does not check that the key belongs to the certificate on the client side, and we would only
+            // know about it when the server rejects us
+            let signing_key = config
+                .crypto_provider()
+                .key_provider
+                .load_private_key(tls_client_key)
+                .with_context(|| "Unsupported mTLS private key")?;
+            match CertifiedKey::new(tls_client_certificate, signing_key).keys_match() {
+                Ok(()) | Err(Error::InconsistentKeys(InconsistentKeys::Unknown)) => {}
+                Err(err) => return Err(anyhow!("mTLS certificate does not match the private key: {err}")),
+            }
+            config
+        }
         _ => config_builder.with_no_client_auth(),
     };
 
@@ -134,12 +359,32 @@ pub fn tls_connector(
 
     // To bypass certificate verification
     if !tls_verify_certificate {
+        if !tls_certificate_pins.is_empty() {
+            return Err(anyhow!(
+                "TLS certificate pinning cannot be used with certificate verification disabled"
+            ));
+        }
         config.dangerous().set_certificate_verifier(Arc::new(NullVerifier));
+    } else if !tls_certificate_pins.is_empty() {
+        let pins = tls_certificate_pins
+            .iter()
+            .map(|pin| parse_certificate_pin(pin))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let algorithms = config.crypto_provider().signature_verification_algorithms;
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(PinnedCertVerifier { algorithms, pins }));
+    } else if let Some(hostname) = tls_verify_hostname {
+        let inner = WebPkiServerVerifier::builder(root_store)
+            .build()
+            .with_context(|| "Cannot create tls certificate verifier")?;
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(HostnameVerifier { inner, hostname }));
     }
 
     config.alpn_protocols = alpn_protocols;
-    let tls_connector = TlsConnector::from(Arc::new(config));
-    Ok(tls_connector)
+    Ok(Arc::new(config))
 }
 
 pub fn tls_acceptor(tls_cfg: &TlsServerConfig, alpn_protocols: Option<Vec<Vec<u8>>>) -> anyhow::Result<TlsAcceptor> {
@@ -158,7 +403,7 @@ pub fn tls_acceptor(tls_cfg: &TlsServerConfig, alpn_protocols: Option<Vec<Vec<u8
         WebPkiClientVerifier::no_client_auth()
     };
 
-    let mut config = rustls::ServerConfig::builder()
+    let mut config = rustls::ServerConfig::builder_with_protocol_versions(tls_cfg.tls_min_version.protocol_versions())
         .with_client_cert_verifier(client_cert_verifier)
         .with_single_cert(tls_cfg.tls_certificate.lock().clone(), tls_cfg.tls_key.lock().clone_key())
         .with_context(|| "invalid tls certificate or private key")?;
@@ -170,37 +415,203 @@ pub fn tls_acceptor(tls_cfg: &TlsServerConfig, alpn_protocols: Option<Vec<Vec<u8
     Ok(TlsAcceptor::from(Arc::new(config)))
 }
 
-pub async fn connect(client_cfg: &WsClientConfig, tcp_stream: TcpStream) -> anyhow::Result<TlsStream<TcpStream>> {
-    let sni = client_cfg.tls_server_name();
-    let (tls_connector, sni_disabled) = match &client_cfg.remote_addr {
-        TransportAddr::Wss { tls, .. } => (tls.tls_connector(), tls.tls_sni_disabled),
-        TransportAddr::Https { tls, .. } => (tls.tls_connector(), tls.tls_sni_disabled),
-        TransportAddr::Http { .. } | TransportAddr::Ws { .. } => {
-            return Err(anyhow!("Transport does not support TLS: {}", client_cfg.remote_addr.scheme()))
+/// alpn_protocols replaces the protocols offered by the transport, if set
+pub async fn connect(
+    server: &TransportAddr,
+    tcp_stream: TcpStream,
+    alpn_protocols: Option<Vec<Vec<u8>>>,
+) -> anyhow::Result<TlsStream<TcpStream>> {
+    let sni = server.tls_server_name();
+    let tls = match &server {
+        TransportAddr::Wss { tls, .. } | TransportAddr::Https { tls, .. } | TransportAddr::Tls { tls, .. } => tls,
+        TransportAddr::Http { .. } | TransportAddr::Ws { .. } | TransportAddr::Tcp { .. } => {
+            return Err(anyhow!("Transport does not support TLS: {}", server.scheme()))
         }
     };
+    let tls_connector = match alpn_protocols {
+        Some(alpn_protocols) => tls.tls_connector_with_alpn(alpn_protocols),
+        None => tls.tls_connector(),
+    };
+    let sni_disabled = tls.tls_sni_disabled;
+    if tls.tls_verify_certificate && server.tls_verification_name().is_none() {
+        return Err(anyhow!(
+            "Cannot verify the TLS certificate of the server {}:{}, its host is not a valid domain name. Use --tls-verify-hostname",
+            server.host(),
+            server.port()
+        ));
+    }
+
+    if !tls.tls_verify_certificate {
+        warn!(
+            "TLS certificate of the server {}:{} is not verified, the connection is open to man-in-the-middle attacks. Use --tls-verify-certificate",
+            server.host(),
+            server.port()
+        );
+    }
 
     if sni_disabled {
         info!(
             "Doing TLS handshake without SNI with the server {}:{}",
-            client_cfg.remote_addr.host(),
-            client_cfg.remote_addr.port()
+            server.host(),
+            server.port()
         );
     } else {
         info!(
             "Doing TLS handshake using SNI {sni:?} with the server {}:{}",
-            client_cfg.remote_addr.host(),
-            client_cfg.remote_addr.port()
+            server.host(),
+            server.port()
         );
     }
 
-    let tls_stream = tls_connector.connect(sni, tcp_stream).await.with_context(|| {
-        format!(
-            "failed to do TLS handshake with the server {}:{}",
-            client_cfg.remote_addr.host(),
-            client_cfg.remote_addr.port()
-        )
-    })?;
+    let tls_min_version = tls.tls_min_version;
+    let tls_stream = tls_connector
+        .connect(sni, tcp_stream)
+        .await
+        .map_err(|err| {
+            let rustls_err = err.get_ref().and_then(|err| err.downcast_ref::<Error>());
+            let is_version_mismatch = matches!(
+                rustls_err,
+                Some(Error::PeerIncompatible(_) | Error::AlertReceived(AlertDescription::ProtocolVersion))
+            );
+            let err = anyhow::Error::new(err);
+            if is_version_mismatch {
+                err.context(format!(
+                    "server does not support TLS {} or later (--tls-min-version)",
+                    tls_min_version
+                ))
+            } else {
+                err
+            }
+        })
+        .with_context(|| format!("failed to do TLS handshake with the server {}:{}", server.host(), server.port()))?;
 
     Ok(tls_stream)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embedded_certificate::{TLS_CERTIFICATE, TLS_PRIVATE_KEY};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsConnector;
+
+    // sha256 of the public key of the embedded certificate
+    const EMBEDDED_CERTIFICATE_PIN: &str = "/LxgDP5yw8s0TB5IG+lEFeXlxIqUVd0itJlKl1MWi5E=";
+
+    async fn handshake_with_pins(pins: &[String]) -> anyhow::Result<()> {
+        handshake(pins, TlsVersion::Tls12, TlsVersion::Tls12).await
+    }
+
+    async fn handshake(
+        pins: &[String],
+        server_min_version: TlsVersion,
+        client_min_version: TlsVersion,
+    ) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let port = listener.local_addr()?.port();
+        // A server with only TLS 1.2 when asked for 1.2
+        let server_versions: &[&SupportedProtocolVersion] = match server_min_version {
+            TlsVersion::Tls12 => &[&rustls::version::TLS12],
+            TlsVersion::Tls13 => server_min_version.protocol_versions(),
+        };
+        let server_config = rustls::ServerConfig::builder_with_protocol_versions(server_versions)
+            .with_no_client_auth()
+            .with_single_cert(TLS_CERTIFICATE.clone(), TLS_PRIVATE_KEY.clone_key())?;
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            acceptor.accept(stream).await.map(|_| ())
+        });
+
+        let connector = TlsConnector::from(tls_client_config(TlsClientOptions {
+            tls_certificate_pins: pins.to_vec(),
+            tls_min_version: client_min_version,
+            ..Default::default()
+        })?);
+        let tcp_stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        let ret = connector
+            .connect(ServerName::try_from("localhost").unwrap(), tcp_stream)
+            .await;
+        server.abort();
+
+        ret.map(|_| ()).map_err(anyhow::Error::from)
+    }
+
+    #[tokio::test]
+    async fn test_certificate_pin_match() {
+        // The embedded certificate is self-signed, it is only accepted thanks to the pin
+        assert!(handshake_with_pins(&[]).await.is_err());
+
+        let pins = vec![
+            "sha256/AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=".to_string(),
+            EMBEDDED_CERTIFICATE_PIN.to_string(),
+        ];
+        handshake_with_pins(&pins).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_certificate_pin_mismatch() {
+        let pins = vec!["AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=".to_string()];
+        let err = handshake_with_pins(&pins).await.unwrap_err();
+        assert!(err.to_string().contains("does not match any of the pinned keys"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_tls_min_version() {
+        let pins = vec![EMBEDDED_CERTIFICATE_PIN.to_string()];
+        handshake(&pins, TlsVersion::Tls12, TlsVersion::Tls12).await.unwrap();
+        handshake(&pins, TlsVersion::Tls13, TlsVersion::Tls13).await.unwrap();
+        // A TLS 1.2 only server is refused by a client requiring 1.3
+        assert!(handshake(&pins, TlsVersion::Tls12, TlsVersion::Tls13).await.is_err());
+    }
+
+    #[test]
+    fn test_certificate_pin_requires_verification() {
+        let pins = vec![EMBEDDED_CERTIFICATE_PIN.to_string()];
+        let ret = tls_client_config(TlsClientOptions {
+            tls_verify_certificate: false,
+            tls_certificate_pins: pins,
+            ..Default::default()
+        });
+        assert!(ret.is_err());
+    }
+
+    #[test]
+    fn test_mtls_key_must_match_certificate() {
+        tls_client_config(TlsClientOptions {
+            tls_client_certificate: Some(TLS_CERTIFICATE.clone()),
+            tls_client_key: Some(TLS_PRIVATE_KEY.clone_key()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let other_key = ring::signature::Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new()).unwrap();
+        let other_key = PrivateKeyDer::Pkcs8(other_key.as_ref().to_vec().into());
+        let ret = tls_client_config(TlsClientOptions {
+            tls_client_certificate: Some(TLS_CERTIFICATE.clone()),
+            tls_client_key: Some(other_key),
+            ..Default::default()
+        });
+        assert!(ret.is_err());
+    }
+
+    #[test]
+    fn test_root_ca_bundle() {
+        let bundle = std::env::temp_dir().join("wstunnel_test_root_ca_bundle.pem");
+        let cert = include_str!("../../../certs/cert.pem");
+        std::fs::write(&bundle, format!("{cert}{cert}")).unwrap();
+        assert_eq!(load_certificates_from_pem(&bundle).unwrap().len(), 2);
+        assert!(!root_cert_store(Some(&bundle), false).unwrap().is_empty());
+
+        std::fs::write(&bundle, "not a certificate").unwrap();
+        assert!(root_cert_store(Some(&bundle), false).is_err());
+        std::fs::remove_file(&bundle).unwrap();
+    }
+
+    #[test]
+    fn test_parse_certificate_pin() {
+        assert!(parse_certificate_pin(EMBEDDED_CERTIFICATE_PIN).is_ok());
+        assert!(parse_certificate_pin("not base64!").is_err());
+        assert!(parse_certificate_pin("AAAA").is_err());
+    }
+}
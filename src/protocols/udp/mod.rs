@@ -3,9 +3,12 @@ mod server;
 #[cfg(target_os = "linux")]
 pub use server::configure_tproxy;
 pub use server::connect;
+pub use server::connect_to_addrs;
+pub use server::live_sessions;
 #[cfg(target_os = "linux")]
 pub use server::mk_send_socket_tproxy;
 pub use server::run_server;
 pub use server::UdpStream;
 pub use server::UdpStreamWriter;
 pub use server::WsUdpSocket;
+pub use server::DEFAULT_UDP_SESSION_TIMEOUT;
@@ -12,7 +12,8 @@ use tokio::task::JoinSet;
 
 use log::warn;
 use socket2::SockRef;
-use std::pin::{pin, Pin};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Weak};
 use std::task::{ready, Poll};
 use std::time::Duration;
@@ -21,11 +22,25 @@ use tokio::net::UdpSocket;
 use tokio::sync::futures::Notified;
 
 use crate::protocols::dns::DnsResolver;
+use crate::protocols::tcp::{DomainResolutionError, SocketBind};
 use tokio::sync::Notify;
 use tokio::time::{sleep, timeout, Interval};
 use tracing::{debug, error, info};
 use url::Host;
 
+/// How long a udp session is kept without receiving any packet from its peer, before its tunnel is closed.
+/// Too short breaks slow request/response protocols, too long keeps sessions of scanners around
+pub const DEFAULT_UDP_SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Sessions alive across all the udp servers of the process
+static LIVE_SESSIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of udp sessions currently alive, across all the udp listeners of the process. A session is alive until
+/// its stream is dropped, i.e: once its tunnel is closed on timeout
+pub fn live_sessions() -> usize {
+    LIVE_SESSIONS.load(Ordering::Relaxed)
+}
+
 struct IoInner {
     has_data_to_read: Notify,
     has_read_data: Notify,
@@ -35,6 +50,8 @@ struct UdpServer {
     peers: HashMap<SocketAddr, Pin<Arc<IoInner>>, ahash::RandomState>,
     keys_to_delete: Arc<RwLock<Vec<SocketAddr>>>,
     cnx_timeout: Option<Duration>,
+    // Sessions whose stream is still alive, the peers map can lag behind until the next cleaning
+    live_sessions: Arc<AtomicUsize>,
 }
 
 impl UdpServer {
@@ -75,6 +92,7 @@ impl UdpServer {
             peers: HashMap::with_hasher(ahash::RandomState::new()),
             keys_to_delete: Default::default(),
             cnx_timeout: timeout,
+            live_sessions: Default::default(),
         }
     }
 
@@ -85,7 +103,11 @@ impl UdpServer {
             return;
         }
 
-        debug!("Cleaning {} dead udp peers", nb_key_to_delete);
+        debug!(
+            "Cleaning {} dead udp peers, {} sessions still alive",
+            nb_key_to_delete,
+            self.live_sessions.load(Ordering::Relaxed)
+        );
         let mut keys_to_delete = self.keys_to_delete.write();
         for key in keys_to_delete.iter() {
             self.peers.remove(key);
@@ -110,6 +132,7 @@ pub struct UdpStream {
     pending_notification: Option<Notified<'static>>,
     io: Pin<Arc<IoInner>>,
     keys_to_delete: Weak<RwLock<Vec<SocketAddr>>>,
+    live_sessions: Arc<AtomicUsize>,
 }
 
 #[pinned_drop]
@@ -118,6 +141,9 @@ impl PinnedDrop for UdpStream {
         if let Some(keys_to_delete) = self.keys_to_delete.upgrade() {
             keys_to_delete.write().push(self.peer);
         }
+        LIVE_SESSIONS.fetch_sub(1, Ordering::Relaxed);
+        let live_sessions = self.live_sessions.fetch_sub(1, Ordering::Relaxed) - 1;
+        debug!("UDP session with {} closed, {} sessions still alive", self.peer, live_sessions);
 
         // safety: we are dropping the notification as we extend its lifetime to 'static unsafely
         // So it must be gone before we drop its parent. It should never happen but in case
@@ -134,7 +160,10 @@ impl UdpStream {
         peer: SocketAddr,
         watchdog_deadline: Option<Duration>,
         keys_to_delete: Weak<RwLock<Vec<SocketAddr>>>,
+        live_sessions: Arc<AtomicUsize>,
     ) -> (Self, Pin<Arc<IoInner>>) {
+        live_sessions.fetch_add(1, Ordering::Relaxed);
+        LIVE_SESSIONS.fetch_add(1, Ordering::Relaxed);
         let has_data_to_read = Notify::new();
         let has_read_data = Notify::new();
         let io = Arc::pin(IoInner {
@@ -152,6 +181,7 @@ impl UdpStream {
             pending_notification: None,
             io: io.clone(),
             keys_to_delete,
+            live_sessions,
         };
 
         let pending_notification =
@@ -274,13 +304,18 @@ pub async fn run_server(
                         io.has_read_data.notified().await;
                     }
                     None => {
-                        info!("New UDP connection from {}", peer_addr);
                         let (udp_client, io) = UdpStream::new(
                             server.clone_socket(),
                             mk_send_socket(&server.listener).ok()?,
                             peer_addr,
                             server.cnx_timeout,
                             Arc::downgrade(&server.keys_to_delete),
+                            server.live_sessions.clone(),
+                        );
+                        info!(
+                            "New UDP connection from {} ({} sessions alive)",
+                            peer_addr,
+                            server.live_sessions.load(Ordering::Relaxed)
                         );
                         io.has_data_to_read.notify_waiters();
                         server.peers.insert(peer_addr, io);
@@ -332,36 +367,62 @@ pub async fn connect(
     port: u16,
     connect_timeout: Duration,
     so_mark: Option<u32>,
+    socket_bind: &SocketBind,
     dns_resolver: &DnsResolver,
 ) -> anyhow::Result<WsUdpSocket> {
-    info!("Opening UDP connection to {}:{}", host, port);
-
     let socket_addrs: Vec<SocketAddr> = match host {
         Host::Ipv4(ip) => vec![SocketAddr::V4(SocketAddrV4::new(*ip, port))],
         Host::Ipv6(ip) => vec![SocketAddr::V6(SocketAddrV6::new(*ip, port, 0, 0))],
         Host::Domain(domain) => dns_resolver
             .lookup_host(domain.as_str(), port)
             .await
-            .with_context(|| format!("cannot resolve domain: {}", domain))?,
+            .with_context(|| DomainResolutionError(domain.clone()))?,
     };
 
+    connect_to_addrs(host, port, socket_addrs, connect_timeout, so_mark, socket_bind).await
+}
+
+/// Connect to the first reachable of the already resolved addresses of host
+pub async fn connect_to_addrs(
+    host: &Host<String>,
+    port: u16,
+    socket_addrs: Vec<SocketAddr>,
+    connect_timeout: Duration,
+    so_mark: Option<u32>,
+    socket_bind: &SocketBind,
+) -> anyhow::Result<WsUdpSocket> {
+    info!("Opening UDP connection to {}:{}", host, port);
+
     let mut cnx = None;
     let mut last_err = None;
     let mut join_set = JoinSet::new();
 
     for (ix, addr) in socket_addrs.into_iter().enumerate() {
-        let socket = match &addr {
-            SocketAddr::V4(_) => UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await,
-            SocketAddr::V6(_) => UdpSocket::bind(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)).await,
-        };
+        let socket = match socket_bind.local_addr_for(&addr) {
+            Ok(Some(local_addr)) => UdpSocket::bind(local_addr)
+                .await
+                .with_context(|| format!("cannot bind udp socket to {}", local_addr))?,
+            Ok(None) => {
+                let socket = match &addr {
+                    SocketAddr::V4(_) => UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await,
+                    SocketAddr::V6(_) => UdpSocket::bind(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)).await,
+                };
 
-        let socket = match socket {
-            Ok(socket) => socket,
+                match socket {
+                    Ok(socket) => socket,
+                    Err(err) => {
+                        warn!("cannot bind udp socket {:?}", err);
+                        continue;
+                    }
+                }
+            }
             Err(err) => {
-                warn!("cannot bind udp socket {:?}", err);
+                debug!("Skipping udp endpoint {addr}: {err}");
+                last_err = Some(err);
                 continue;
             }
         };
+        socket_bind.bind_device(&SockRef::from(&socket))?;
 
         #[cfg(target_os = "linux")]
         if let Some(so_mark) = so_mark {
@@ -638,6 +699,7 @@ mod tests {
 
         // Take the stream of data
         let stream = fut.unwrap().unwrap().unwrap();
+        assert!(live_sessions() >= 1);
         pin_mut!(stream);
 
         let mut buf = [0u8; 25];
@@ -1,11 +1,12 @@
-use anyhow::Context;
+use anyhow::{anyhow, Context};
 use futures_util::Stream;
 use std::io;
+use std::os::unix::fs::FileTypeExt;
 use std::path::Path;
 use std::pin::Pin;
 use std::task::Poll;
 use tokio::net::{UnixListener, UnixStream};
-use tracing::log::info;
+use tracing::log::{info, warn};
 
 pub struct UnixListenerStream {
     inner: UnixListener,
@@ -47,12 +48,56 @@ impl Stream for UnixListenerStream {
     }
 }
 
+// A socket file left behind by a previous run that did not exit cleanly prevents to bind again.
+// Only remove it if nobody is listening on it anymore, and never touch anything that is not a socket
+async fn remove_stale_socket(socket_path: &Path) -> Result<(), anyhow::Error> {
+    let Ok(metadata) = std::fs::symlink_metadata(socket_path) else {
+        return Ok(());
+    };
+
+    if !metadata.file_type().is_socket() {
+        return Err(anyhow!("{:?} already exists and is not a Unix socket", socket_path));
+    }
+
+    match UnixStream::connect(socket_path).await {
+        Ok(_) => Err(anyhow!("{:?} is already in use by another process", socket_path)),
+        Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+            warn!("Removing stale Unix socket {:?}", socket_path);
+            std::fs::remove_file(socket_path)
+                .with_context(|| format!("Cannot remove stale Unix socket {:?}", socket_path))
+        }
+        Err(err) => Err(anyhow::Error::new(err).context(format!("Cannot check Unix socket {:?}", socket_path))),
+    }
+}
+
 pub async fn run_server(socket_path: &Path) -> Result<UnixListenerStream, anyhow::Error> {
     info!("Starting Unix socket server listening cnx on {:?}", socket_path);
 
+    remove_stale_socket(socket_path).await?;
     let path_to_delete = !socket_path.exists();
     let listener = UnixListener::bind(socket_path)
         .with_context(|| format!("Cannot create Unix socket server {:?}", socket_path))?;
 
     Ok(UnixListenerStream::new(listener, path_to_delete))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_stale_socket_is_removed() {
+        let socket_path = std::env::temp_dir().join(format!("wstunnel-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket_path);
+
+        // Socket file left behind without anybody listening on it
+        drop(std::os::unix::net::UnixListener::bind(&socket_path).unwrap());
+        assert!(socket_path.exists());
+
+        let server = run_server(&socket_path).await.unwrap();
+        assert!(run_server(&socket_path).await.is_err(), "a socket in use must not be removed");
+
+        drop(server);
+        assert!(!socket_path.exists());
+    }
+}
@@ -66,6 +66,22 @@ impl RestrictionsRulesReloader {
         watcher.watch(&this.config_path, notify::RecursiveMode::NonRecursive)?;
         *this.fs_watcher.lock() = watcher;
 
+        #[cfg(unix)]
+        {
+            let mut sighup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+                .with_context(|| "Cannot listen for SIGHUP to reload restriction config")?;
+            let this = Arc::downgrade(&this);
+            tokio::spawn(async move {
+                while sighup.recv().await.is_some() {
+                    let Some(this) = this.upgrade() else {
+                        break;
+                    };
+                    info!("Received SIGHUP, reloading restrictions config file");
+                    this.should_reload_config.notify_one();
+                }
+            });
+        }
+
         Ok(Self {
             state: Config(this),
             restrictions: Arc::new(restrictions_rules),
@@ -77,7 +93,10 @@ impl RestrictionsRulesReloader {
             Static(_) => return,
             Config(st) => match RestrictionsRules::from_config_file(&st.config_path) {
                 Ok(restrictions) => {
-                    info!("Restrictions config file has been reloaded");
+                    info!(
+                        "Restrictions config file has been reloaded with {} restrictions",
+                        restrictions.restrictions.len()
+                    );
                     restrictions
                 }
                 Err(err) => {
@@ -94,7 +113,7 @@ impl RestrictionsRulesReloader {
         &self.restrictions
     }
 
-    pub fn reload_notifier(&self) -> Notified {
+    pub fn reload_notifier(&self) -> Notified<'_> {
         match &self.state {
             Static(st) => st.notified(),
             Config(st) => st.should_reload_config.notified(),
@@ -172,3 +191,40 @@ impl RestrictionsRulesReloader {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+restrictions:
+  - name: "first"
+    match:
+      - !Any
+    allow:
+      - !Tunnel
+  - name: "second"
+    match:
+      - !Any
+    allow:
+      - !ReverseTunnel
+"#;
+
+    #[tokio::test]
+    async fn test_malformed_reload_keeps_previous_rules() {
+        let path = std::env::temp_dir().join("wstunnel_test_restrictions_reload.yaml");
+        std::fs::write(&path, CONFIG).unwrap();
+        let rules = RestrictionsRules::from_config_file(&path).unwrap();
+        let mut reloader = RestrictionsRulesReloader::new(rules, Some(path.clone())).unwrap();
+
+        std::fs::write(&path, "restrictions: [not valid").unwrap();
+        reloader.reload_restrictions_config();
+        assert_eq!(reloader.restrictions_rules().restrictions.len(), 2);
+
+        std::fs::write(&path, CONFIG.split("  - name: \"second\"").next().unwrap()).unwrap();
+        reloader.reload_restrictions_config();
+        assert_eq!(reloader.restrictions_rules().restrictions.len(), 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -22,6 +22,14 @@ impl RestrictionsRules {
         Ok(restrictions)
     }
 
+    /// Whether some restrictions match the metadata sent by the clients
+    pub fn matches_metadata(&self) -> bool {
+        self.restrictions
+            .iter()
+            .flat_map(|restriction| &restriction.r#match)
+            .any(|m| matches!(m, types::MatchConfig::Metadata(_)))
+    }
+
     pub fn from_path_prefix(path_prefixes: &[String], restrict_to: &[(String, u16)]) -> anyhow::Result<Self> {
         let tunnels_restrictions = if restrict_to.is_empty() {
             let r = types::AllowConfig::Tunnel(types::AllowTunnelConfig {
@@ -89,6 +97,7 @@ impl RestrictionsRules {
                 name: "Allow All".to_string(),
                 r#match: vec![types::MatchConfig::Any],
                 allow: tunnels_restrictions,
+                deny: vec![],
             };
             vec![r]
         } else {
@@ -100,6 +109,7 @@ impl RestrictionsRules {
                         name: format!("Allow path prefix {}", path_prefix),
                         r#match: vec![types::MatchConfig::PathPrefix(reg)],
                         allow: tunnels_restrictions.clone(),
+                        deny: vec![],
                     })
                 })
                 .collect::<Result<Vec<_>, anyhow::Error>>()?
@@ -16,6 +16,8 @@ pub struct RestrictionConfig {
     #[serde(deserialize_with = "deserialize_non_empty_vec")]
     pub r#match: Vec<MatchConfig>,
     pub allow: Vec<AllowConfig>,
+    #[serde(default)]
+    pub deny: Vec<DenyTunnelConfig>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -23,6 +25,10 @@ pub enum MatchConfig {
     Any,
     #[serde(with = "serde_regex")]
     PathPrefix(Regex),
+    // Metadata sent by the client with --tunnel-metadata, never matches tunnels without any.
+    // Forgeable by anyone knowing the jwt secret, the server does not match it with the default one
+    #[serde(with = "serde_regex")]
+    Metadata(Regex),
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -65,10 +71,32 @@ pub struct AllowReverseTunnelConfig {
     pub cidr: Vec<IpNet>,
 }
 
+/// Forward tunnels matching it are rejected, even if allowed. Criteria not specified match everything
+#[derive(Debug, Clone, Deserialize)]
+pub struct DenyTunnelConfig {
+    #[serde(default)]
+    pub protocol: Vec<TunnelConfigProtocol>,
+
+    #[serde(deserialize_with = "deserialize_port_range")]
+    #[serde(default)]
+    pub port: Vec<RangeInclusive<u16>>,
+
+    #[serde(with = "serde_regex")]
+    #[serde(default)]
+    pub host: Option<Regex>,
+
+    #[serde(default)]
+    pub cidr: Vec<IpNet>,
+}
+
 #[derive(Debug, Clone, Deserialize, Eq, PartialEq)]
 pub enum TunnelConfigProtocol {
     Tcp,
     Udp,
+    // Forwarding to a unix socket of the server must always be explicitly allowed
+    Unix,
+    // Same for running the command of the server (--exec-command)
+    Exec,
     Unknown,
 }
 
@@ -159,10 +187,13 @@ impl From<&LocalProtocol> for ReverseTunnelConfigProtocol {
             | LocalProtocol::Udp { .. }
             | LocalProtocol::Stdio
             | LocalProtocol::Socks5 { .. }
-            | LocalProtocol::TProxyTcp { .. }
+            | LocalProtocol::TProxyTcp
             | LocalProtocol::TProxyUdp { .. }
             | LocalProtocol::HttpProxy { .. }
-            | LocalProtocol::Unix { .. } => Self::Unknown,
+            | LocalProtocol::Unix { .. }
+            | LocalProtocol::UnixSocket { .. }
+            | LocalProtocol::Exec
+            | LocalProtocol::TcpTls => Self::Unknown,
             LocalProtocol::ReverseTcp => Self::Tcp,
             LocalProtocol::ReverseUdp { .. } => Self::Udp,
             LocalProtocol::ReverseSocks5 { .. } => Self::Socks5,
@@ -180,13 +211,15 @@ impl From<&LocalProtocol> for TunnelConfigProtocol {
             | LocalProtocol::ReverseUnix { .. }
             | LocalProtocol::Stdio
             | LocalProtocol::Socks5 { .. }
-            | LocalProtocol::TProxyTcp { .. }
+            | LocalProtocol::TProxyTcp
             | LocalProtocol::TProxyUdp { .. }
             | LocalProtocol::HttpProxy { .. }
             | LocalProtocol::ReverseHttpProxy { .. }
             | LocalProtocol::Unix { .. } => Self::Unknown,
-            LocalProtocol::Tcp { .. } => Self::Tcp,
+            LocalProtocol::Tcp { .. } | LocalProtocol::TcpTls => Self::Tcp,
             LocalProtocol::Udp { .. } => Self::Udp,
+            LocalProtocol::UnixSocket { .. } => Self::Unix,
+            LocalProtocol::Exec => Self::Exec,
         }
     }
 }
@@ -0,0 +1,131 @@
+use crate::tunnel::client::{WsClient, WsClientConfig};
+use crate::tunnel::connectors::TunnelConnector;
+use crate::tunnel::listeners::TunnelListener;
+use crate::tunnel::RemoteAddr;
+use anyhow::Context;
+use std::future::Future;
+use std::num::NonZeroUsize;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio_util::sync::CancellationToken;
+
+/// Threading model of the blocking run functions.
+/// The runtime is created by the call, and dropped with all its remaining tasks when it returns
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BlockingRuntime {
+    /// The tunnels run on the calling thread only, which is blocked until shutdown.
+    /// Enough for a handful of tunnels, as all of them share this single thread
+    #[default]
+    CurrentThread,
+    /// The tunnels run on a pool of worker threads, the calling thread only waits for the shutdown
+    MultiThread { worker_threads: NonZeroUsize },
+}
+
+impl BlockingRuntime {
+    fn build(self) -> anyhow::Result<tokio::runtime::Runtime> {
+        let mut builder = match self {
+            Self::CurrentThread => tokio::runtime::Builder::new_current_thread(),
+            Self::MultiThread { worker_threads } => {
+                let mut builder = tokio::runtime::Builder::new_multi_thread();
+                builder.worker_threads(worker_threads.get());
+                builder
+            }
+        };
+
+        builder.enable_all().build().context("Cannot create tokio runtime")
+    }
+}
+
+// Sending on the channel or dropping its sender requests the shutdown
+fn block_on<F, Fut>(runtime: BlockingRuntime, shutdown: oneshot::Receiver<()>, run: F) -> anyhow::Result<()>
+where
+    F: FnOnce(CancellationToken) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    runtime.build()?.block_on(async move {
+        let cancel = CancellationToken::new();
+        let _guard = cancel.clone().drop_guard();
+        tokio::spawn({
+            let cancel = cancel.clone();
+            async move {
+                let _ = shutdown.await;
+                cancel.cancel();
+            }
+        });
+
+        run(cancel).await
+    })
+}
+
+/// Blocking version of WsClient::run_tunnel, for programs not running a tokio runtime.
+/// The listener is a future creating it (i.e: TcpTunnelListener::new(...)), as it must be created within the runtime.
+/// Blocks the calling thread until the listener is closed or the shutdown is requested, by sending on the channel or
+/// dropping its sender. The tunnels in flight are then given the shutdown grace period of the config to finish
+pub fn run_tunnel_blocking<L: TunnelListener>(
+    config: WsClientConfig,
+    connection_min_idle: u32,
+    connection_retry_max_backoff: Duration,
+    listener: impl Future<Output = anyhow::Result<L>>,
+    shutdown: oneshot::Receiver<()>,
+    runtime: BlockingRuntime,
+) -> anyhow::Result<()> {
+    block_on(runtime, shutdown, |cancel| async move {
+        let client = WsClient::new(config, connection_min_idle, connection_retry_max_backoff).await?;
+        client.run_tunnel(listener.await?, cancel).await
+    })
+}
+
+/// Blocking version of WsClient::run_reverse_tunnel, for programs not running a tokio runtime.
+/// Blocks the calling thread until the tunnel gives up or the shutdown is requested, by sending on the channel or
+/// dropping its sender
+pub fn run_reverse_tunnel_blocking(
+    config: WsClientConfig,
+    connection_min_idle: u32,
+    connection_retry_max_backoff: Duration,
+    remote_addr: RemoteAddr,
+    connector: impl TunnelConnector,
+    shutdown: oneshot::Receiver<()>,
+    runtime: BlockingRuntime,
+) -> anyhow::Result<()> {
+    block_on(runtime, shutdown, |cancel| async move {
+        let client = WsClient::new(config, connection_min_idle, connection_retry_max_backoff).await?;
+        client.run_reverse_tunnel(remote_addr, connector, None, cancel).await
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_on_until_shutdown() {
+        let runtimes = [
+            BlockingRuntime::CurrentThread,
+            BlockingRuntime::MultiThread {
+                worker_threads: NonZeroUsize::new(2).unwrap(),
+            },
+        ];
+        for runtime in runtimes {
+            let (shutdown_tx, shutdown_rx) = oneshot::channel();
+            let sender = std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(50));
+                shutdown_tx.send(()).unwrap();
+            });
+            block_on(runtime, shutdown_rx, |cancel| async move {
+                cancel.cancelled().await;
+                Ok(())
+            })
+            .unwrap();
+            sender.join().unwrap();
+
+            // Dropping the sender requests the shutdown too
+            let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+            drop(shutdown_tx);
+            block_on(runtime, shutdown_rx, |cancel| async move {
+                cancel.cancelled().await;
+                Ok(())
+            })
+            .unwrap();
+        }
+    }
+}
@@ -0,0 +1,621 @@
+use crate::protocols::dns::DnsResolver;
+use crate::protocols::tcp::{
+    ConnectFailureBehavior, TcpSocketOptions, DEFAULT_CONNECT_PARALLELISM, DEFAULT_HAPPY_EYEBALLS_DELAY,
+    DEFAULT_LISTEN_BACKLOG,
+};
+use crate::tunnel::client::config::default_http_header_host;
+use crate::tunnel::client::{ReconnectBackoff, RemoteSelection, SaturationPolicy, WsClientConfig};
+use crate::tunnel::clock::{Clock, TokioClock};
+use crate::tunnel::metrics::{NoopTunnelMetrics, TunnelMetrics};
+use crate::tunnel::transport::http2::{Http2MultiplexConfig, MAX_WINDOW_SIZE, MIN_WINDOW_SIZE};
+use crate::tunnel::transport::websocket::MAX_PINGS_IN_FLIGHT;
+use crate::tunnel::{RateLimit, TransportAddr, TransportScheme, MIN_COPY_BUFFER_SIZE};
+use hyper::header::{HeaderName, HeaderValue, HOST};
+use std::fmt::{Display, Formatter};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+
+/// Invalid combination of options detected when building a WsClientConfig
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The option does not apply to the transport of the server, i.e: websocket only options with http2
+    UnsupportedByTransport {
+        option: &'static str,
+        scheme: TransportScheme,
+    },
+    /// The two options cannot be used together
+    Conflict {
+        option: &'static str,
+        conflicts_with: &'static str,
+    },
+    InvalidValue {
+        option: &'static str,
+        reason: String,
+    },
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnsupportedByTransport { option, scheme } => {
+                write!(f, "{option} is not supported by the {scheme} transport")
+            }
+            Self::Conflict { option, conflicts_with } => write!(f, "{option} cannot be used with {conflicts_with}"),
+            Self::InvalidValue { option, reason } => write!(f, "invalid {option}: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Build a WsClientConfig, with the same defaults as the command line client.
+/// Cross-option constraints are checked by build(), instead of failing later when connecting to the server
+pub struct WsClientConfigBuilder {
+    config: WsClientConfig,
+    // The default resolver depends on the http proxy and the socket mark, so it is only created by build()
+    dns_resolver: Option<DnsResolver>,
+    // The limits shared by all the tunnels pace them with the clock, so they are only created by build() as well
+    global_egress_limit: Option<u64>,
+    global_ingress_limit: Option<u64>,
+}
+
+impl WsClientConfigBuilder {
+    pub fn new(remote_addr: TransportAddr) -> Self {
+        let http_header_host = default_http_header_host(&remote_addr);
+        Self {
+            config: WsClientConfig {
+                remote_addr,
+                remote_addr_fallbacks: vec![],
+                remote_selection: RemoteSelection::Failover,
+                remote_cooldown: Duration::from_secs(30),
+                socket_so_mark: None,
+                http_upgrade_path_prefix: "v1".to_string(),
+                http_upgrade_credentials: None,
+                http_headers: vec![],
+                http_headers_file: None,
+                user_agent: None,
+                http_headers_order: vec![],
+                http_header_host,
+                timeout_connect: Duration::from_secs(10),
+                server_connect_timeout: Duration::from_secs(30),
+                tcp_connect_timeout: Duration::from_secs(10),
+                tls_handshake_timeout: Duration::from_secs(10),
+                happy_eyeballs_delay: DEFAULT_HAPPY_EYEBALLS_DELAY,
+                connect_parallelism: DEFAULT_CONNECT_PARALLELISM,
+                tcp_options: TcpSocketOptions::default(),
+                tls_alpn_protocols: None,
+                listen_backlog: DEFAULT_LISTEN_BACKLOG,
+                websocket_ping_frequency: Duration::from_secs(30),
+                websocket_adaptive_ping: false,
+                websocket_mask_frame: true,
+                websocket_pong_timeout: None,
+                missed_pong_limit: None,
+                tunnel_metadata: None,
+                websocket_subprotocol: None,
+                http2_multiplex: None,
+                transport_fallback: false,
+                idle_timeout: None,
+                connect_failure_behavior: ConnectFailureBehavior::Graceful,
+                max_tunnel_duration: None,
+                max_bytes_per_sec: None,
+                global_egress_limit: None,
+                global_ingress_limit: None,
+                copy_buffer_size: None,
+                shutdown_grace_period: Duration::from_secs(10),
+                http_proxy: None,
+                no_proxy: vec![],
+                dns_resolver: DnsResolver::System { prefer_ipv6: true },
+                reconnect_backoff: ReconnectBackoff {
+                    initial_delay: Duration::from_secs(1),
+                    max_delay: Duration::from_secs(60),
+                    multiplier: 2.0,
+                    jitter: 0.1,
+                },
+                max_reconnect_attempts: None,
+                reverse_tunnel_multiplex: false,
+                max_concurrent_tunnels: None,
+                prewarm_pool_size: 0,
+                when_saturated: SaturationPolicy::Queue,
+                metrics: Arc::new(NoopTunnelMetrics),
+                clock: Arc::new(TokioClock),
+            },
+            dns_resolver: None,
+            global_egress_limit: None,
+            global_ingress_limit: None,
+        }
+    }
+
+    /// Server to use when the main one is not reachable. It must use the same scheme as the main one
+    pub fn with_fallback_server(mut self, server: TransportAddr) -> Self {
+        self.config.remote_addr_fallbacks.push(server);
+        self
+    }
+
+    pub fn with_remote_selection(mut self, remote_selection: RemoteSelection) -> Self {
+        self.config.remote_selection = remote_selection;
+        self
+    }
+
+    pub fn with_remote_cooldown(mut self, remote_cooldown: Duration) -> Self {
+        self.config.remote_cooldown = remote_cooldown;
+        self
+    }
+
+    pub fn with_socket_so_mark(mut self, so_mark: Option<u32>) -> Self {
+        self.config.socket_so_mark = so_mark;
+        self
+    }
+
+    pub fn with_http_upgrade_path_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.config.http_upgrade_path_prefix = prefix.into();
+        self
+    }
+
+    pub fn with_http_upgrade_credentials(mut self, credentials: Option<HeaderValue>) -> Self {
+        self.config.http_upgrade_credentials = credentials;
+        self
+    }
+
+    /// Header to add to the upgrade request. The Host header replaces the default one instead
+    pub fn with_http_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        if name == HOST {
+            self.config.http_header_host = value;
+        } else {
+            self.config.http_headers.push((name, value));
+        }
+        self
+    }
+
+    pub fn with_http_headers_file(mut self, path: Option<PathBuf>) -> Self {
+        self.config.http_headers_file = path;
+        self
+    }
+
+    pub fn with_user_agent(mut self, user_agent: Option<HeaderValue>) -> Self {
+        self.config.user_agent = user_agent;
+        self
+    }
+
+    pub fn with_http_headers_order(mut self, order: Vec<HeaderName>) -> Self {
+        self.config.http_headers_order = order;
+        self
+    }
+
+    pub fn with_timeout_connect(mut self, timeout: Duration) -> Self {
+        self.config.timeout_connect = timeout;
+        self
+    }
+
+    pub fn with_server_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.server_connect_timeout = timeout;
+        self
+    }
+
+    pub fn with_tcp_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.tcp_connect_timeout = timeout;
+        self
+    }
+
+    pub fn with_tls_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.config.tls_handshake_timeout = timeout;
+        self
+    }
+
+    pub fn with_happy_eyeballs_delay(mut self, delay: Duration) -> Self {
+        self.config.happy_eyeballs_delay = delay;
+        self
+    }
+
+    pub fn with_connect_parallelism(mut self, connect_parallelism: usize) -> Self {
+        self.config.connect_parallelism = connect_parallelism;
+        self
+    }
+
+    pub fn with_tcp_options(mut self, tcp_options: TcpSocketOptions) -> Self {
+        self.config.tcp_options = tcp_options;
+        self
+    }
+
+    pub fn with_tls_alpn_protocols(mut self, protocols: Option<Vec<String>>) -> Self {
+        self.config.tls_alpn_protocols = protocols;
+        self
+    }
+
+    pub fn with_listen_backlog(mut self, backlog: u32) -> Self {
+        self.config.listen_backlog = backlog;
+        self
+    }
+
+    pub fn with_websocket_ping_frequency(mut self, frequency: Duration) -> Self {
+        self.config.websocket_ping_frequency = frequency;
+        self
+    }
+
+    pub fn with_websocket_adaptive_ping(mut self, adaptive_ping: bool) -> Self {
+        self.config.websocket_adaptive_ping = adaptive_ping;
+        self
+    }
+
+    pub fn with_websocket_mask_frame(mut self, mask_frame: bool) -> Self {
+        self.config.websocket_mask_frame = mask_frame;
+        self
+    }
+
+    pub fn with_websocket_pong_timeout(mut self, pong_timeout: Option<Duration>) -> Self {
+        self.config.websocket_pong_timeout = pong_timeout;
+        self
+    }
+
+    pub fn with_missed_pong_limit(mut self, missed_pong_limit: Option<usize>) -> Self {
+        self.config.missed_pong_limit = missed_pong_limit;
+        self
+    }
+
+    pub fn with_websocket_subprotocol(mut self, subprotocol: Option<String>) -> Self {
+        self.config.websocket_subprotocol = subprotocol;
+        self
+    }
+
+    pub fn with_http2_multiplex(mut self, multiplex: Option<Http2MultiplexConfig>) -> Self {
+        self.config.http2_multiplex = multiplex;
+        self
+    }
+
+    pub fn with_transport_fallback(mut self, fallback: bool) -> Self {
+        self.config.transport_fallback = fallback;
+        self
+    }
+
+    pub fn with_tunnel_metadata(mut self, metadata: Option<String>) -> Self {
+        self.config.tunnel_metadata = metadata;
+        self
+    }
+
+    pub fn with_connect_failure_behavior(mut self, behavior: ConnectFailureBehavior) -> Self {
+        self.config.connect_failure_behavior = behavior;
+        self
+    }
+
+    pub fn with_idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.config.idle_timeout = idle_timeout;
+        self
+    }
+
+    pub fn with_max_tunnel_duration(mut self, max_tunnel_duration: Option<Duration>) -> Self {
+        self.config.max_tunnel_duration = max_tunnel_duration;
+        self
+    }
+
+    pub fn with_max_bytes_per_sec(mut self, max_bytes_per_sec: Option<u64>) -> Self {
+        self.config.max_bytes_per_sec = max_bytes_per_sec;
+        self
+    }
+
+    pub fn with_global_egress_limit(mut self, bytes_per_sec: Option<u64>) -> Self {
+        self.global_egress_limit = bytes_per_sec;
+        self
+    }
+
+    pub fn with_global_ingress_limit(mut self, bytes_per_sec: Option<u64>) -> Self {
+        self.global_ingress_limit = bytes_per_sec;
+        self
+    }
+
+    pub fn with_copy_buffer_size(mut self, size: Option<usize>) -> Self {
+        self.config.copy_buffer_size = size;
+        self
+    }
+
+    pub fn with_shutdown_grace_period(mut self, grace_period: Duration) -> Self {
+        self.config.shutdown_grace_period = grace_period;
+        self
+    }
+
+    pub fn with_http_proxy(mut self, http_proxy: Option<Url>) -> Self {
+        self.config.http_proxy = http_proxy;
+        self
+    }
+
+    /// Hosts to reach without the http proxy, empty entries are ignored
+    pub fn with_no_proxy(mut self, no_proxy: Vec<String>) -> Self {
+        self.config.no_proxy = no_proxy.into_iter().filter(|host| !host.trim().is_empty()).collect();
+        self
+    }
+
+    /// Defaults to the resolvers of the system configuration
+    pub fn with_dns_resolver(mut self, dns_resolver: DnsResolver) -> Self {
+        self.dns_resolver = Some(dns_resolver);
+        self
+    }
+
+    pub fn with_reconnect_backoff(mut self, reconnect_backoff: ReconnectBackoff) -> Self {
+        self.config.reconnect_backoff = reconnect_backoff;
+        self
+    }
+
+    pub fn with_max_reconnect_attempts(mut self, max_attempts: Option<u32>) -> Self {
+        self.config.max_reconnect_attempts = max_attempts;
+        self
+    }
+
+    pub fn with_reverse_tunnel_multiplex(mut self, multiplex: bool) -> Self {
+        self.config.reverse_tunnel_multiplex = multiplex;
+        self
+    }
+
+    pub fn with_max_concurrent_tunnels(mut self, max_tunnels: Option<NonZeroUsize>) -> Self {
+        self.config.max_concurrent_tunnels = max_tunnels;
+        self
+    }
+
+    pub fn with_prewarm_pool_size(mut self, pool_size: usize) -> Self {
+        self.config.prewarm_pool_size = pool_size;
+        self
+    }
+
+    pub fn with_when_saturated(mut self, policy: SaturationPolicy) -> Self {
+        self.config.when_saturated = policy;
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<dyn TunnelMetrics>) -> Self {
+        self.config.metrics = metrics;
+        self
+    }
+
+    /// Clock of the timeouts, pings and reconnection backoff of the tunnels, to control the time in tests
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.config.clock = clock;
+        self
+    }
+
+    pub fn build(self) -> Result<WsClientConfig, ConfigError> {
+        let mut config = self.config;
+        validate(&config)?;
+
+        config.dns_resolver = match self.dns_resolver {
+            Some(dns_resolver) => dns_resolver,
+            None => DnsResolver::new_from_urls(&[], config.http_proxy.clone(), config.socket_so_mark, true).map_err(
+                |err| ConfigError::InvalidValue {
+                    option: "dns_resolver",
+                    reason: err.to_string(),
+                },
+            )?,
+        };
+
+        let rate_limit = |bytes_per_sec| Arc::new(RateLimit::new(bytes_per_sec, config.clock.clone()));
+        config.global_egress_limit = self.global_egress_limit.map(rate_limit);
+        config.global_ingress_limit = self.global_ingress_limit.map(rate_limit);
+
+        Ok(config)
+    }
+}
+
+fn validate(config: &WsClientConfig) -> Result<(), ConfigError> {
+    let scheme = *config.remote_addr.scheme();
+    let unsupported = |option| ConfigError::UnsupportedByTransport { option, scheme };
+    if !config.remote_addr.is_websocket() && config.websocket_subprotocol.is_some() {
+        return Err(unsupported("websocket_subprotocol"));
+    }
+    // The raw transport has its own ping frames
+    if !config.remote_addr.is_websocket() && !scheme.is_raw() {
+        if config.websocket_pong_timeout.is_some() {
+            return Err(unsupported("websocket_pong_timeout"));
+        }
+        if config.missed_pong_limit.is_some() {
+            return Err(unsupported("missed_pong_limit"));
+        }
+    }
+    if !config.remote_addr.is_http2() {
+        if config.http2_multiplex.is_some() {
+            return Err(unsupported("http2_multiplex"));
+        }
+        if config.transport_fallback {
+            return Err(unsupported("transport_fallback"));
+        }
+    }
+
+    if let Some(protocols) = &config.tls_alpn_protocols {
+        scheme
+            .check_alpn_protocols(protocols)
+            .map_err(|err| ConfigError::InvalidValue {
+                option: "tls_alpn_protocols",
+                reason: err.to_string(),
+            })?;
+    }
+
+    if let Some(server) = config.remote_addr_fallbacks.iter().find(|s| *s.scheme() != scheme) {
+        return Err(ConfigError::InvalidValue {
+            option: "remote_addr_fallbacks",
+            reason: format!(
+                "fallback server {}:{} uses {} instead of {} like the main server",
+                server.host(),
+                server.port(),
+                server.scheme(),
+                scheme
+            ),
+        });
+    }
+
+    for server in std::iter::once(&config.remote_addr).chain(&config.remote_addr_fallbacks) {
+        let Some(tls) = server.tls() else {
+            continue;
+        };
+        if !tls.tls_verify_certificate && !tls.tls_certificate_pins.is_empty() {
+            return Err(ConfigError::Conflict {
+                option: "tls_certificate_pins",
+                conflicts_with: "the TLS certificate verification disabled",
+            });
+        }
+        if tls.tls_verify_certificate && server.tls_verification_name().is_none() {
+            return Err(ConfigError::InvalidValue {
+                option: "tls_verify_hostname",
+                reason: format!(
+                    "cannot verify the TLS certificate of {}, its host is not a valid domain name. Set the hostname to verify",
+                    server.host()
+                ),
+            });
+        }
+    }
+
+    let backoff = &config.reconnect_backoff;
+    if !(backoff.multiplier >= 1.0 && backoff.multiplier.is_finite()) {
+        return Err(ConfigError::InvalidValue {
+            option: "reconnect_backoff",
+            reason: format!("multiplier must be 1.0 or more, got {}", backoff.multiplier),
+        });
+    }
+    if !(0.0..=1.0).contains(&backoff.jitter) {
+        return Err(ConfigError::InvalidValue {
+            option: "reconnect_backoff",
+            reason: format!("jitter must be between 0.0 and 1.0, got {}", backoff.jitter),
+        });
+    }
+
+    if config
+        .missed_pong_limit
+        .is_some_and(|limit| !(1..MAX_PINGS_IN_FLIGHT).contains(&limit))
+    {
+        return Err(ConfigError::InvalidValue {
+            option: "missed_pong_limit",
+            reason: format!("it must be between 1 and {}", MAX_PINGS_IN_FLIGHT - 1),
+        });
+    }
+    if let Some(multiplex) = &config.http2_multiplex {
+        if multiplex.max_streams == 0 {
+            return Err(ConfigError::InvalidValue {
+                option: "http2_multiplex",
+                reason: "max_streams must be at least 1".to_string(),
+            });
+        }
+        if !(MIN_WINDOW_SIZE..=MAX_WINDOW_SIZE).contains(&multiplex.stream_window) {
+            return Err(ConfigError::InvalidValue {
+                option: "http2_multiplex",
+                reason: format!(
+                    "stream_window must be between {} and {} bytes",
+                    MIN_WINDOW_SIZE, MAX_WINDOW_SIZE
+                ),
+            });
+        }
+    }
+    if config.copy_buffer_size.is_some_and(|size| size < MIN_COPY_BUFFER_SIZE) {
+        return Err(ConfigError::InvalidValue {
+            option: "copy_buffer_size",
+            reason: format!("it must be at least {} bytes", MIN_COPY_BUFFER_SIZE),
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use url::Host;
+
+    fn server(scheme: TransportScheme) -> TransportAddr {
+        TransportAddr::new(scheme, Host::Domain("example.com".to_string()), 8080, None).unwrap()
+    }
+
+    fn build_err(builder: WsClientConfigBuilder) -> ConfigError {
+        match builder.build() {
+            Ok(_) => panic!("invalid config accepted"),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn test_builder_validation() {
+        let config = WsClientConfigBuilder::new(server(TransportScheme::Ws)).build().unwrap();
+        assert_eq!(config.http_header_host, "example.com:8080");
+        assert_eq!(config.http_upgrade_path_prefix, "v1");
+        assert_eq!(config.copy_buffer_size, None);
+
+        let config = WsClientConfigBuilder::new(server(TransportScheme::Ws))
+            .with_http_header(HOST, HeaderValue::from_static("other.com"))
+            .build()
+            .unwrap();
+        assert_eq!(config.http_header_host, "other.com");
+        assert!(config.http_headers.is_empty());
+
+        let err = build_err(
+            WsClientConfigBuilder::new(server(TransportScheme::Http))
+                .with_websocket_subprotocol(Some("chat".to_string())),
+        );
+        assert!(matches!(
+            err,
+            ConfigError::UnsupportedByTransport {
+                option: "websocket_subprotocol",
+                ..
+            }
+        ));
+
+        let err = build_err(
+            WsClientConfigBuilder::new(server(TransportScheme::Ws)).with_http2_multiplex(Some(Default::default())),
+        );
+        assert!(matches!(err, ConfigError::UnsupportedByTransport { .. }));
+
+        let err = build_err(
+            WsClientConfigBuilder::new(server(TransportScheme::Ws)).with_fallback_server(server(TransportScheme::Http)),
+        );
+        assert!(matches!(
+            err,
+            ConfigError::InvalidValue {
+                option: "remote_addr_fallbacks",
+                ..
+            }
+        ));
+
+        let err = build_err(
+            WsClientConfigBuilder::new(server(TransportScheme::Ws))
+                .with_tls_alpn_protocols(Some(vec!["h2".to_string()])),
+        );
+        assert!(matches!(
+            err,
+            ConfigError::InvalidValue {
+                option: "tls_alpn_protocols",
+                ..
+            }
+        ));
+
+        let err = build_err(
+            WsClientConfigBuilder::new(server(TransportScheme::Ws))
+                .with_copy_buffer_size(Some(MIN_COPY_BUFFER_SIZE - 1)),
+        );
+        assert!(matches!(err, ConfigError::InvalidValue { .. }));
+
+        let err = build_err(WsClientConfigBuilder::new(server(TransportScheme::Ws)).with_missed_pong_limit(Some(0)));
+        assert!(matches!(
+            err,
+            ConfigError::InvalidValue {
+                option: "missed_pong_limit",
+                ..
+            }
+        ));
+
+        for multiplex in [
+            Http2MultiplexConfig {
+                max_streams: 0,
+                ..Default::default()
+            },
+            Http2MultiplexConfig {
+                stream_window: MIN_WINDOW_SIZE - 1,
+                ..Default::default()
+            },
+        ] {
+            let err = build_err(
+                WsClientConfigBuilder::new(server(TransportScheme::Http)).with_http2_multiplex(Some(multiplex)),
+            );
+            assert!(matches!(
+                err,
+                ConfigError::InvalidValue {
+                    option: "http2_multiplex",
+                    ..
+                }
+            ));
+        }
+    }
+}
@@ -1,30 +1,58 @@
 use crate::tunnel;
 use crate::tunnel::client::cnx_pool::WsConnection;
-use crate::tunnel::client::WsClientConfig;
+use crate::tunnel::client::events::TunnelEventSender;
+use crate::tunnel::client::handle::TunnelCounters;
+use crate::tunnel::client::prewarm::TunnelPrewarmer;
+use crate::tunnel::client::servers::RemoteServers;
+use crate::tunnel::client::{SaturationPolicy, TunnelEvent, TunnelHandle, WsClientConfig};
+use crate::tunnel::clock::Clock;
 use crate::tunnel::connectors::TunnelConnector;
-use crate::tunnel::listeners::TunnelListener;
+use crate::tunnel::listeners::{classify_accept_error, AcceptErrorKind, LocalConnection, TunnelListener};
+use crate::tunnel::logging::LogThrottle;
+use crate::tunnel::metrics::TunnelMetrics;
 use crate::tunnel::tls_reloader::TlsReloader;
-use crate::tunnel::transport::{TunnelReader, TunnelWriter};
-use crate::tunnel::{JwtTunnelConfig, RemoteAddr, TransportScheme, JWT_DECODE};
-use anyhow::Context;
-use futures_util::pin_mut;
+use crate::tunnel::transport::connection_info::ConnectionInfo;
+use crate::tunnel::transport::datagram::{has_datagram_framing, DatagramTunnelRead, DatagramTunnelWrite};
+use crate::tunnel::transport::http2::Http2Multiplexer;
+use crate::tunnel::transport::io::{log_tunnel_closed, IdleTimeout, PropagateOptions};
+use crate::tunnel::transport::mux;
+use crate::tunnel::transport::priority::{
+    PrioritizedTunnelRead, PrioritizedTunnelWrite, PriorityScheduler, TunnelPriority,
+};
+use crate::tunnel::transport::{CloseReason, TunnelReader, TunnelWrite, TunnelWriter};
+use crate::tunnel::{JwtTunnelConfig, RemoteAddr, TransportAddr, TransportScheme, TunnelConnectError, JWT_KEYS};
+use crate::LocalProtocol;
+use anyhow::{anyhow, Context};
+use bb8::{PooledConnection, RunError};
+use futures_util::stream::{self, FuturesUnordered};
+use futures_util::{pin_mut, FutureExt};
 use hyper::header::COOKIE;
+use hyper::http::response::Parts;
 use jsonwebtoken::TokenData;
 use log::debug;
-use std::ops::Deref;
+use parking_lot::Mutex;
+use std::pin::Pin;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::sync::oneshot;
-use tokio_stream::StreamExt;
-use tracing::{error, event, span, Instrument, Level, Span};
-use url::Host;
+use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore};
+use tokio::task::{JoinError, JoinSet};
+use tokio_stream::{Stream, StreamExt};
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tracing::{error, event, field, info, span, warn, Instrument, Level, Span};
 use uuid::Uuid;
 
 #[derive(Clone)]
 pub struct WsClient {
     pub config: Arc<WsClientConfig>,
     pub cnx_pool: bb8::Pool<WsConnection>,
+    // To open connections outside of the pool
+    cnx: WsConnection,
+    pub(crate) servers: Arc<RemoteServers>,
+    cnx_last_error: Arc<Mutex<Option<TunnelConnectError>>>,
+    pub(crate) http2_connections: Arc<Http2Multiplexer>,
+    priority: TunnelPriority,
+    scheduler: Arc<PriorityScheduler>,
     _tls_reloader: Arc<TlsReloader>,
 }
 
@@ -35,7 +63,14 @@ impl WsClient {
         connection_retry_max_backoff_sec: Duration,
     ) -> anyhow::Result<Self> {
         let config = Arc::new(config);
-        let cnx = WsConnection::new(config.clone());
+        let servers = Arc::new(RemoteServers::new(
+            config.remote_addr.clone(),
+            config.remote_addr_fallbacks.clone(),
+            config.remote_selection,
+            config.remote_cooldown,
+        ));
+        let cnx = WsConnection::new(config.clone(), servers.clone());
+        let cnx_last_error = cnx.last_error();
         let tls_reloader = TlsReloader::new_for_client(config.clone()).with_context(|| "Cannot create tls reloader")?;
         let cnx_pool = bb8::Pool::builder()
             .max_size(1000)
@@ -43,179 +78,1082 @@ impl WsClient {
             .max_lifetime(Some(Duration::from_secs(30)))
             .connection_timeout(connection_retry_max_backoff_sec)
             .retry_connection(true)
-            .build(cnx)
+            .build(cnx.clone())
             .await?;
 
         Ok(Self {
             config,
             cnx_pool,
+            cnx,
+            servers,
+            cnx_last_error,
+            http2_connections: Arc::new(Http2Multiplexer::default()),
+            priority: TunnelPriority::default(),
+            scheduler: Arc::new(PriorityScheduler::default()),
             _tls_reloader: Arc::new(tls_reloader),
         })
     }
 }
 
 impl WsClient {
+    /// Priority of the tunnels run by this client, against the ones of its other clones
+    pub fn with_priority(mut self, priority: TunnelPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Server the client is currently connected to, can change with fallback servers
+    pub fn active_server(&self) -> &TransportAddr {
+        self.servers.active()
+    }
+
+    pub(crate) async fn get_server_connection(&self) -> Result<PooledConnection<'_, WsConnection>, TunnelConnectError> {
+        match self.cnx_pool.get().await {
+            Ok(cnx) => Ok(cnx),
+            Err(RunError::User(err)) => Err(err),
+            // The pool retries on its own until the timeout, report the reason of the last failure if any
+            Err(RunError::TimedOut) => Err(match self.cnx_last_error.lock().as_ref() {
+                Some(err) => err.to_owned_lossy(),
+                None => TunnelConnectError::Timeout(anyhow!("failed to get a connection to the server from the pool")),
+            }),
+        }
+    }
+
+    // Each tunnel gets its own span, with connect and transfer child spans to tell where the time is spent
+    fn tunnel_span(&self, request_id: Uuid, remote_addr: &RemoteAddr) -> Span {
+        let span = span!(
+            Level::INFO,
+            "tunnel",
+            id = request_id.to_string(),
+            remote = format!("{}:{}", remote_addr.host, remote_addr.port),
+            client = field::Empty,
+            transport = self.config.remote_addr.scheme().to_str(),
+            server = field::Empty,
+        );
+        if !self.config.remote_addr_fallbacks.is_empty() {
+            let server = self.active_server();
+            span.record("server", format!("{}:{}", server.host(), server.port()));
+        }
+        if let Some(source) = remote_addr.source {
+            span.record("client", source.to_string());
+        }
+        span
+    }
+
+    async fn connect_websocket_fallback(
+        &self,
+        request_id: Uuid,
+        remote_cfg: &RemoteAddr,
+    ) -> Result<(TunnelReader, TunnelWriter, Parts), TunnelConnectError> {
+        let (transport, server_ix, timing) = self
+            .cnx
+            .connect_with_alpn(TransportScheme::Wss.alpn_protocols())
+            .await?;
+        tunnel::transport::websocket::upgrade(request_id, self, remote_cfg, transport, server_ix, timing)
+            .await
+            .map(|(r, w, response)| (TunnelReader::Websocket(r), TunnelWriter::Websocket(w), response))
+    }
+
+    pub(super) async fn connect_transport(
+        &self,
+        request_id: Uuid,
+        remote_cfg: &RemoteAddr,
+    ) -> Result<(DatagramTunnelRead<TunnelReader>, DatagramTunnelWrite<TunnelWriter>, Parts), TunnelConnectError> {
+        let connect = async {
+            match self.config.remote_addr.scheme() {
+                TransportScheme::Ws | TransportScheme::Wss => {
+                    tunnel::transport::websocket::connect(request_id, self, remote_cfg)
+                        .await
+                        .map(|(r, w, response)| (TunnelReader::Websocket(r), TunnelWriter::Websocket(w), response))
+                }
+                // Once http2 did not go through, the server is reached with websocket for a while without trying it again
+                TransportScheme::Http | TransportScheme::Https
+                    if self.config.transport_fallback && self.servers.is_active_http2_unavailable() =>
+                {
+                    self.connect_websocket_fallback(request_id, remote_cfg).await
+                }
+                TransportScheme::Http | TransportScheme::Https => {
+                    match tunnel::transport::http2::connect(request_id, self, remote_cfg).await {
+                        Err(TunnelConnectError::Http2Unavailable(err)) if self.config.transport_fallback => {
+                            warn!("Falling back to websocket transport, http2 does not go through: {:?}", err);
+                            self.connect_websocket_fallback(request_id, remote_cfg).await
+                        }
+                        ret => ret.map(|(r, w, response)| (TunnelReader::Http2(r), TunnelWriter::Http2(w), response)),
+                    }
+                }
+                TransportScheme::Tcp | TransportScheme::Tls => {
+                    tunnel::transport::raw::connect(request_id, self, remote_cfg)
+                        .await
+                        .map(|(r, w, response)| (TunnelReader::Raw(r), TunnelWriter::Raw(w), response))
+                }
+            }
+        };
+
+        let (ws_rx, ws_tx, response) = tokio::time::timeout(self.config.server_connect_timeout, connect)
+            .await
+            .unwrap_or_else(|_| {
+                // The pool may still be retrying a failing connection, tell why
+                let err = anyhow!(
+                    "no answer from the server after {:?} (dns + tcp + tls + upgrade)",
+                    self.config.server_connect_timeout
+                );
+                Err(TunnelConnectError::Timeout(match self.cnx_last_error.lock().as_ref() {
+                    Some(last_error) => err.context(format!("last error: {:?}", last_error)),
+                    None => err,
+                }))
+            })?;
+        if let Some(connection_info) = response.extensions.get::<ConnectionInfo>() {
+            debug!("Connected to the server with {}", connection_info);
+        }
+
+        // The server only enables it for datagram tunnels, and if it is recent enough to support it
+        let length_prefixed = has_datagram_framing(&response.headers);
+        Ok((
+            DatagramTunnelRead::new(ws_rx, length_prefixed),
+            DatagramTunnelWrite::new(ws_tx, length_prefixed),
+            response,
+        ))
+    }
+
     async fn connect_to_server<R, W>(
         &self,
         request_id: Uuid,
         remote_cfg: &RemoteAddr,
         duplex_stream: (R, W),
-    ) -> anyhow::Result<()>
+        metrics: Arc<dyn TunnelMetrics>,
+    ) -> Result<(), TunnelConnectError>
     where
-        R: AsyncRead + Send + 'static,
+        R: AsyncRead + LocalConnection<W> + Send + 'static,
         W: AsyncWrite + Send + 'static,
     {
         // Connect to server with the correct protocol
-        let (ws_rx, ws_tx, response) = match self.config.remote_addr.scheme() {
-            TransportScheme::Ws | TransportScheme::Wss => {
-                tunnel::transport::websocket::connect(request_id, self, remote_cfg)
-                    .await
-                    .map(|(r, w, response)| (TunnelReader::Websocket(r), TunnelWriter::Websocket(w), response))?
-            }
-            TransportScheme::Http | TransportScheme::Https => {
-                tunnel::transport::http2::connect(request_id, self, remote_cfg)
-                    .await
-                    .map(|(r, w, response)| (TunnelReader::Http2(r), TunnelWriter::Http2(w), response))?
+        let (ws_rx, ws_tx, response) = match self
+            .connect_transport(request_id, remote_cfg)
+            .instrument(span!(Level::DEBUG, "connect"))
+            .await
+        {
+            Ok(cnx) => cnx,
+            Err(err) => {
+                let (rx, tx) = duplex_stream;
+                rx.close_on_connect_error(tx, &err, self.config.connect_failure_behavior)
+                    .await;
+                return Err(err);
             }
         };
 
+        self.forward(ws_rx, ws_tx, response, duplex_stream, metrics).await;
+        Ok(())
+    }
+
+    async fn forward<R, W>(
+        &self,
+        ws_rx: DatagramTunnelRead<TunnelReader>,
+        ws_tx: DatagramTunnelWrite<TunnelWriter>,
+        response: Parts,
+        duplex_stream: (R, W),
+        metrics: Arc<dyn TunnelMetrics>,
+    ) where
+        R: AsyncRead + Send + 'static,
+        W: AsyncWrite + Send + 'static,
+    {
         debug!("Server response: {:?}", response);
         let (local_rx, local_tx) = duplex_stream;
         let (close_tx, close_rx) = oneshot::channel::<()>();
+        let idle_timeout = self
+            .config
+            .idle_timeout
+            .map(|timeout| IdleTimeout::new(timeout, self.config.clock.clone()));
+        let options = PropagateOptions::new(self.config.clock.clone())
+            .with_idle_timeout(idle_timeout)
+            .with_max_duration(self.config.max_tunnel_duration)
+            .with_metrics(metrics.clone());
+        let started_at = Instant::now();
+        metrics.on_tunnel_open();
+        // Also when the task is aborted, i.e: by TunnelHandle::cancel
+        let _close_guard =
+            scopeguard::guard(metrics.clone(), move |metrics| metrics.on_tunnel_close(started_at.elapsed()));
 
         // Forward local tx to websocket tx
+        let transfer_span = span!(Level::DEBUG, "transfer");
         let ping_frequency = self.config.websocket_ping_frequency;
-        tokio::spawn(
-            super::super::transport::io::propagate_local_to_remote(local_rx, ws_tx, close_tx, Some(ping_frequency))
-                .instrument(Span::current()),
+        let local_to_remote = tokio::spawn(
+            super::super::transport::io::propagate_local_to_remote(
+                local_rx,
+                PrioritizedTunnelWrite::new(ws_tx, self.priority, self.scheduler.clone()),
+                close_tx,
+                options
+                    .clone()
+                    .with_ping(Some(ping_frequency), self.config.websocket_adaptive_ping)
+                    .with_bandwidth_limit(self.config.egress_limit()),
+            )
+            .instrument(transfer_span.clone()),
         );
 
         // Forward websocket rx to local rx
-        let _ = super::super::transport::io::propagate_remote_to_local(local_tx, ws_rx, close_rx).await;
+        let remote_to_local = super::super::transport::io::propagate_remote_to_local(
+            local_tx,
+            PrioritizedTunnelRead::new(ws_rx, self.priority, self.scheduler.clone()),
+            close_rx,
+            options.with_bandwidth_limit(self.config.ingress_limit()),
+        )
+        .instrument(transfer_span)
+        .await;
+        // The local => remote direction stops as soon as this one is done
+        let local_to_remote = local_to_remote.await.unwrap_or_default();
+        log_tunnel_closed(&local_to_remote, &remote_to_local, started_at.elapsed());
+    }
+
+    /// Forward only the first connection of the listener, and return once it is closed (i.e: stdio).
+    /// Failing to establish the tunnel is returned instead of logged, so the caller can report it
+    pub async fn run_single_tunnel(self, tunnel_listener: impl TunnelListener) -> anyhow::Result<()> {
+        pin_mut!(tunnel_listener);
+        let Some(cnx) = tunnel_listener.next().await else {
+            return Ok(());
+        };
+        let (cnx_stream, remote_addr) = cnx?;
+
+        self.open_tunnel(&remote_addr, cnx_stream).await
+    }
+
+    /// Tunnel a single stream to the remote, and return once it is closed.
+    /// For streams coming from a source that is not a TunnelListener, to drive them one by one from the caller loop.
+    /// Failing to establish the tunnel is returned, and the stream is then closed as it would be by the listeners
+    pub async fn open_tunnel<R, W>(&self, remote_addr: &RemoteAddr, stream: (R, W)) -> anyhow::Result<()>
+    where
+        R: AsyncRead + LocalConnection<W> + Send + 'static,
+        W: AsyncWrite + Send + 'static,
+    {
+        let request_id = remote_addr.request_id.unwrap_or_else(Uuid::now_v7);
+        let span = self.tunnel_span(request_id, remote_addr);
+        self.connect_to_server(request_id, remote_addr, stream, self.config.metrics.clone())
+            .instrument(span)
+            .await?;
 
         Ok(())
     }
 
-    pub async fn run_tunnel(self, tunnel_listener: impl TunnelListener) -> anyhow::Result<()> {
-        pin_mut!(tunnel_listener);
-        while let Some(cnx) = tunnel_listener.next().await {
-            let (cnx_stream, remote_addr) = match cnx {
-                Ok((cnx_stream, remote_addr)) => (cnx_stream, remote_addr),
-                Err(err) => {
-                    error!("Error accepting connection: {:?}", err);
-                    continue;
-                }
-            };
+    pub async fn run_tunnel(
+        self,
+        tunnel_listener: impl TunnelListener,
+        shutdown: CancellationToken,
+    ) -> anyhow::Result<()> {
+        self.run_tunnel_with_prewarm(tunnel_listener, None, shutdown).await
+    }
 
-            let request_id = Uuid::now_v7();
-            let span = span!(
-                Level::INFO,
-                "tunnel",
-                id = request_id.to_string(),
-                remote = format!("{}:{}", remote_addr.host, remote_addr.port)
-            );
-            let client = self.clone();
-            let tunnel = async move {
-                let _ = client
-                    .connect_to_server(request_id, &remote_addr, cnx_stream)
-                    .await
-                    .map_err(|err| error!("{:?}", err));
+    /// Same as run_tunnel, but for a listener whose connections all go to the given destination (i.e: -L tcp://).
+    /// Up to prewarm_pool_size tunnels to it are established in advance, and handed over to the new connections
+    pub async fn run_tunnel_with_prewarm(
+        self,
+        tunnel_listener: impl TunnelListener,
+        destination: Option<RemoteAddr>,
+        shutdown: CancellationToken,
+    ) -> anyhow::Result<()> {
+        let grace_period = self.config.shutdown_grace_period;
+        let new_tunnels = self.tunnels_with_prewarm(tunnel_listener, destination, shutdown.clone());
+        pin_mut!(new_tunnels);
+
+        let mut tunnels = FuturesUnordered::new();
+        let ret = loop {
+            tokio::select! {
+                biased;
+                // Reap finished tunnels as they come, so the set only holds live ones
+                Some(ret) = tunnels.next() => log_tunnel_exit(ret),
+                tunnel = new_tunnels.next() => match tunnel {
+                    Some(Ok(tunnel)) => tunnels.push(tunnel),
+                    Some(Err(err)) => break Err(err),
+                    None => break Ok(()),
+                },
             }
-            .instrument(span);
+        };
 
-            tokio::spawn(tunnel);
-        }
+        drain_tunnel_handles(tunnels, &shutdown, grace_period).await;
+        ret
+    }
 
-        Ok(())
+    /// Accept the connections of the listener and tunnel each of them in its own task, as run_tunnel does.
+    /// Every tunnel is yielded once spawned, for the caller to supervise it instead of it being fire and forget.
+    /// The stream ends when the listener is closed or the shutdown is requested, leaving the tunnels in flight running.
+    /// An error is its last item, when the listener cannot accept connections anymore
+    pub fn tunnels(
+        self,
+        tunnel_listener: impl TunnelListener,
+        shutdown: CancellationToken,
+    ) -> impl Stream<Item = anyhow::Result<TunnelHandle>> {
+        self.tunnels_with_prewarm(tunnel_listener, None, shutdown)
+    }
+
+    fn tunnels_with_prewarm<L: TunnelListener>(
+        self,
+        tunnel_listener: L,
+        destination: Option<RemoteAddr>,
+        shutdown: CancellationToken,
+    ) -> impl Stream<Item = anyhow::Result<TunnelHandle>> {
+        let prewarm_shutdown = shutdown.child_token();
+        let prewarm_guard = prewarm_shutdown.clone().drop_guard();
+        let prewarmer = match destination {
+            Some(destination) if self.config.prewarm_pool_size > 0 => {
+                Some(TunnelPrewarmer::spawn(self.clone(), destination, prewarm_shutdown))
+            }
+            _ => None,
+        };
+        let tunnels_limit = self
+            .config
+            .max_concurrent_tunnels
+            .map(|max| Arc::new(Semaphore::new(max.get())));
+
+        let acceptor = TunnelAcceptor {
+            client: self,
+            listener: Box::pin(tunnel_listener),
+            prewarmer,
+            _prewarm_guard: prewarm_guard,
+            tunnels_limit,
+            exhausted_warn: LogThrottle::new(ACCEPT_EXHAUSTED_WARN_INTERVAL),
+            shutdown,
+        };
+
+        // Nothing is accepted anymore after an error
+        stream::unfold(Some(acceptor), |acceptor| async move {
+            let mut acceptor = acceptor?;
+            match acceptor.next_tunnel().await? {
+                Ok(tunnel) => Some((Ok(tunnel), Some(acceptor))),
+                Err(err) => Some((Err(err), None)),
+            }
+        })
     }
 
     pub async fn run_reverse_tunnel(
         self,
-        remote_addr: RemoteAddr,
+        mut remote_addr: RemoteAddr,
         connector: impl TunnelConnector,
+        events: Option<mpsc::Sender<TunnelEvent>>,
+        shutdown: CancellationToken,
     ) -> anyhow::Result<()> {
-        loop {
+        // After this many tokens in a row that we cannot decode, the issue is most likely not transient
+        const JWT_FAILURES_BEFORE_HINT: u32 = 5;
+
+        let events = TunnelEventSender::new(events);
+        let cookie_required = matches!(
+            remote_addr.protocol,
+            LocalProtocol::ReverseSocks5 { .. } | LocalProtocol::ReverseHttpProxy { .. }
+        );
+        let mut retry_attempt: u32 = 0;
+        let mut progress = ForwardProgress::new(self.config.metrics.clone());
+        let mut jwt_failures: u32 = 0;
+        let mut tunnels = JoinSet::new();
+        let ret = loop {
+            while let Some(ret) = tunnels.try_join_next() {
+                log_tunnel_exit(ret);
+            }
             let client = self.clone();
-            let request_id = Uuid::now_v7();
-            let span = span!(
-                Level::INFO,
-                "tunnel",
-                id = request_id.to_string(),
-                remote = format!("{}:{}", remote_addr.host, remote_addr.port)
-            );
+            // The id given by the caller is for the first connection, the next ones must be told apart from it
+            let request_id = remote_addr.request_id.take().unwrap_or_else(Uuid::now_v7);
+            let span = client.tunnel_span(request_id, &remote_addr);
             // Correctly configure tunnel cfg
-            let (ws_rx, ws_tx, response) = match client.config.remote_addr.scheme() {
-                TransportScheme::Ws | TransportScheme::Wss => {
-                    match tunnel::transport::websocket::connect(request_id, &client, &remote_addr)
-                        .instrument(span.clone())
-                        .await
-                    {
-                        Ok((r, w, response)) => (TunnelReader::Websocket(r), TunnelWriter::Websocket(w), response),
-                        Err(err) => {
-                            event!(parent: &span, Level::ERROR, "Retrying in 1sec, cannot connect to remote server: {:?}", err);
-                            tokio::time::sleep(Duration::from_secs(1)).await;
-                            continue;
-                        }
+            // The server only answers when it has a connection to forward, so stop waiting on shutdown.
+            // Meanwhile, let the connector open the connections to the local endpoint in advance if it pools them
+            let cnx = {
+                let connect = client
+                    .connect_transport(request_id, &remote_addr)
+                    .instrument(span!(parent: &span, Level::DEBUG, "connect"));
+                let prewarm = connector.prewarm().instrument(span.clone()).fuse();
+                pin_mut!(connect);
+                pin_mut!(prewarm);
+                loop {
+                    tokio::select! {
+                        biased;
+                        _ = shutdown.cancelled() => break None,
+                        cnx = &mut connect => break Some(cnx),
+                        _ = &mut prewarm => {}
                     }
                 }
-                TransportScheme::Http | TransportScheme::Https => {
-                    match tunnel::transport::http2::connect(request_id, &client, &remote_addr)
-                        .instrument(span.clone())
-                        .await
-                    {
-                        Ok((r, w, response)) => (TunnelReader::Http2(r), TunnelWriter::Http2(w), response),
-                        Err(err) => {
-                            event!(parent: &span, Level::ERROR, "Retrying in 1sec, cannot connect to remote server: {:?}", err);
-                            tokio::time::sleep(Duration::from_secs(1)).await;
-                            continue;
-                        }
+            };
+            let Some(cnx) = cnx else {
+                break Ok(());
+            };
+            let cnx = cnx.and_then(|(ws_rx, ws_tx, response)| {
+                event!(parent: &span, Level::DEBUG, "Server response: {:?}", response);
+                let remote = remote_from_cookie(&response, cookie_required || remote_addr.is_ephemeral_bind())?;
+                Ok((ws_rx, ws_tx, remote, mux::has_reverse_multiplex(&response.headers)))
+            });
+            let (ws_rx, ws_tx, remote, multiplexed) = match cnx {
+                Ok(cnx) => cnx,
+                Err(err) if !err.is_retryable() => {
+                    event!(parent: &span, Level::ERROR, "Giving up, cannot connect to remote server: {:?}", err);
+                    break Err(err.into());
+                }
+                Err(TunnelConnectError::JwtRejected(err)) => {
+                    // Do not try to connect to anything, we don't know where the server wants us to go
+                    let Some(delay) = client.next_retry_delay(&mut retry_attempt, &mut progress, None) else {
+                        event!(parent: &span, Level::ERROR, "Giving up after {} failures in a row, invalid tunnel token received from server: {:?}", retry_attempt, err);
+                        break Err(err.context(format!("giving up after {} failures in a row", retry_attempt)));
+                    };
+                    jwt_failures = jwt_failures.saturating_add(1);
+                    event!(parent: &span, Level::ERROR, "Retrying in {:?}, invalid tunnel token received from server: {:#}", delay, err);
+                    if jwt_failures >= JWT_FAILURES_BEFORE_HINT {
+                        event!(parent: &span, Level::ERROR, "{} tunnel tokens in a row from the server could not be decoded. Check that client and server use the same secret and compatible versions of wstunnel", jwt_failures);
+                    }
+                    events.send(TunnelEvent::RetryScheduled { delay });
+                    if sleep_unless_cancelled(self.config.clock.as_ref(), delay, &shutdown).await {
+                        break Ok(());
+                    }
+                    continue;
+                }
+                Err(err) => {
+                    let Some(delay) = client.next_retry_delay(&mut retry_attempt, &mut progress, err.retry_after())
+                    else {
+                        event!(parent: &span, Level::ERROR, "Giving up after {} failures in a row, cannot connect to remote server: {:?}", retry_attempt, err);
+                        break Err(anyhow::Error::from(err)
+                            .context(format!("giving up after {} failures in a row", retry_attempt)));
+                    };
+                    event!(parent: &span, Level::ERROR, "Retrying in {:?}, cannot connect to remote server: {:?}", delay, err);
+                    events.send(TunnelEvent::RetryScheduled { delay });
+                    if sleep_unless_cancelled(self.config.clock.as_ref(), delay, &shutdown).await {
+                        break Ok(());
                     }
+                    continue;
                 }
             };
+            jwt_failures = 0;
 
-            // Connect to endpoint
-            event!(parent: &span, Level::DEBUG, "Server response: {:?}", response);
-            let remote = response
-                .headers
-                .get(COOKIE)
-                .and_then(|h| h.to_str().ok())
-                .and_then(|h| {
-                    let (validation, decode_key) = JWT_DECODE.deref();
-                    let jwt: Option<TokenData<JwtTunnelConfig>> = jsonwebtoken::decode(h, decode_key, validation).ok();
-                    jwt
-                })
-                .map(|jwt| RemoteAddr {
-                    protocol: jwt.claims.p,
-                    host: Host::parse(&jwt.claims.r).unwrap_or_else(|_| Host::Domain(String::new())),
-                    port: jwt.claims.rp,
-                });
+            // The server only bound an ephemeral port, ask for it from now on to get the actual connections
+            if remote_addr.is_ephemeral_bind() {
+                let Some(bound) = remote else {
+                    continue;
+                };
+                event!(parent: &span, Level::INFO, "Server is listening for the reverse tunnel on {}:{}", bound.host, bound.port);
+                events.send(TunnelEvent::Bound { addr: bound.clone() });
+                remote_addr.port = bound.port;
+                drop((ws_rx, ws_tx));
+                continue;
+            }
+
+            if let Some(remote) = &remote {
+                events.send(TunnelEvent::RemoteResolved { addr: remote.clone() });
+            }
 
+            // The tunnel stays open and the server pushes all the connections of the listener through it.
+            // Wait for it to close before asking for a new one
+            if multiplexed {
+                events.send(TunnelEvent::Connected);
+                event!(parent: &span, Level::INFO, "Reverse tunnel is multiplexed, connections are received through it");
+                let (tunnel_pipe, mux_pipe) = tokio::io::duplex(mux::MUX_PIPE_SIZE);
+                let (local_rx, local_tx) = tokio::io::split(tunnel_pipe);
+                let tunnel = client
+                    .forward_reverse_tunnel(ws_rx, ws_tx, local_rx, local_tx, progress.metrics(), events.clone())
+                    .instrument(span.clone());
+                tunnels.spawn(tunnel);
+                let started_at = self.config.clock.now();
+                tokio::select! {
+                    _ = shutdown.cancelled() => break Ok(()),
+                    _ = mux::run_client(mux_pipe, &connector).instrument(span.clone()) => {}
+                }
+
+                // Only a tunnel that stayed up for a while resets the backoff, a server closing it right away
+                // must not make us reconnect in a loop
+                if self.config.clock.now().duration_since(started_at) >= MULTIPLEXED_TUNNEL_MIN_UPTIME {
+                    retry_attempt = 0;
+                    continue;
+                }
+                let Some(delay) = self.next_retry_delay(&mut retry_attempt, &mut progress, None) else {
+                    event!(parent: &span, Level::ERROR, "Giving up after {} multiplexed tunnels in a row closed right away", retry_attempt);
+                    break Err(anyhow!(
+                        "giving up after {} multiplexed tunnels in a row closed right away",
+                        retry_attempt
+                    ));
+                };
+                event!(parent: &span, Level::WARN, "Multiplexed tunnel closed right away, reconnecting in {:?}", delay);
+                events.send(TunnelEvent::RetryScheduled { delay });
+                if sleep_unless_cancelled(self.config.clock.as_ref(), delay, &shutdown).await {
+                    break Ok(());
+                }
+                continue;
+            }
+
+            // Connect to endpoint
             let (local_rx, local_tx) = match connector.connect(&remote).instrument(span.clone()).await {
                 Ok(s) => s,
                 Err(err) => {
-                    event!(parent: &span, Level::ERROR, "Cannot connect to {remote:?}: {err:?}");
+                    // For the server to close the connection it accepted right away, as configured on its side
+                    let mut ws_tx = ws_tx;
+                    let _ = ws_tx.close(&CloseReason::connect_failed()).await;
+                    drop((ws_rx, ws_tx));
+                    let Some(delay) = client.next_retry_delay(&mut retry_attempt, &mut progress, None) else {
+                        event!(parent: &span, Level::ERROR, "Giving up after {} failures in a row, cannot connect to {:?}: {:?}", retry_attempt, remote, err);
+                        break Err(err.context(format!("giving up after {} failures in a row", retry_attempt)));
+                    };
+                    event!(parent: &span, Level::ERROR, "Retrying in {delay:?}, cannot connect to {remote:?}: {err:?}");
+                    events.send(TunnelEvent::RetryScheduled { delay });
+                    if sleep_unless_cancelled(self.config.clock.as_ref(), delay, &shutdown).await {
+                        break Ok(());
+                    }
                     continue;
                 }
             };
 
-            let (close_tx, close_rx) = oneshot::channel::<()>();
-            let tunnel = async move {
-                let ping_frequency = client.config.websocket_ping_frequency;
-                tokio::spawn(
-                    super::super::transport::io::propagate_local_to_remote(
-                        local_rx,
-                        ws_tx,
-                        close_tx,
-                        Some(ping_frequency),
-                    )
-                    .in_current_span(),
-                );
+            events.send(TunnelEvent::Connected);
+
+            let tunnel = client
+                .forward_reverse_tunnel(ws_rx, ws_tx, local_rx, local_tx, progress.metrics(), events.clone())
+                .instrument(span.clone());
+            tunnels.spawn(tunnel);
+        };
+
+        if let Err(err) = &ret {
+            events.send(TunnelEvent::Disconnected {
+                reason: err.to_string(),
+            });
+        }
+        drain_tunnels(tunnels, &shutdown, self.config.shutdown_grace_period).await;
+        ret
+    }
+
+    // Delay before reconnecting after a failure, None once max_reconnect_attempts failures in a row are reached
+    fn next_retry_delay(
+        &self,
+        retry_attempt: &mut u32,
+        progress: &mut ForwardProgress,
+        retry_after: Option<Duration>,
+    ) -> Option<Duration> {
+        // Data went through a tunnel since the last failure, so the server works again.
+        // A local connection succeeding is not enough, the server may close every tunnel right away
+        if progress.advanced() {
+            *retry_attempt = 0;
+        }
+        let delay = self
+            .config
+            .reconnect_backoff
+            .delay_for_retry(*retry_attempt, retry_after);
+        *retry_attempt = retry_attempt.saturating_add(1);
+        match self.config.max_reconnect_attempts {
+            Some(max_attempts) if *retry_attempt >= max_attempts => None,
+            _ => Some(delay),
+        }
+    }
+
+    // Forward a reverse tunnel in both directions until it is closed, by the server or the local side
+    async fn forward_reverse_tunnel(
+        self,
+        ws_rx: DatagramTunnelRead<TunnelReader>,
+        ws_tx: DatagramTunnelWrite<TunnelWriter>,
+        local_rx: impl AsyncRead + Send + 'static,
+        local_tx: impl AsyncWrite + Send + 'static,
+        metrics: Arc<dyn TunnelMetrics>,
+        events: TunnelEventSender,
+    ) {
+        let (close_tx, close_rx) = oneshot::channel::<()>();
+        let idle_timeout = self
+            .config
+            .idle_timeout
+            .map(|timeout| IdleTimeout::new(timeout, self.config.clock.clone()));
+        let options = PropagateOptions::new(self.config.clock.clone())
+            .with_idle_timeout(idle_timeout)
+            .with_max_duration(self.config.max_tunnel_duration)
+            .with_metrics(metrics.clone());
+        let started_at = Instant::now();
+        metrics.on_tunnel_open();
+        let close_guard =
+            scopeguard::guard(metrics.clone(), move |metrics| metrics.on_tunnel_close(started_at.elapsed()));
+        let transfer_span = span!(Level::DEBUG, "transfer");
+        let ping_frequency = self.config.websocket_ping_frequency;
+        let local_to_remote = tokio::spawn(
+            super::super::transport::io::propagate_local_to_remote(
+                local_rx,
+                PrioritizedTunnelWrite::new(ws_tx, self.priority, self.scheduler.clone()),
+                close_tx,
+                options
+                    .clone()
+                    .with_ping(Some(ping_frequency), self.config.websocket_adaptive_ping)
+                    .with_bandwidth_limit(self.config.egress_limit()),
+            )
+            .instrument(transfer_span.clone()),
+        );
+
+        // Forward websocket rx to local rx
+        let remote_to_local = super::super::transport::io::propagate_remote_to_local(
+            local_tx,
+            PrioritizedTunnelRead::new(ws_rx, self.priority, self.scheduler.clone()),
+            close_rx,
+            options.with_bandwidth_limit(self.config.ingress_limit()),
+        )
+        .instrument(transfer_span)
+        .await;
+        let local_to_remote = local_to_remote.await.unwrap_or_default();
+        log_tunnel_closed(&local_to_remote, &remote_to_local, started_at.elapsed());
+        // Before the events, for the metrics to be up to date for whoever receives them
+        drop(close_guard);
+        if local_to_remote.close_reason == Some(CloseReason::expired()) {
+            events.send(TunnelEvent::Expired {
+                after: started_at.elapsed(),
+            });
+        }
+        events.send(TunnelEvent::Disconnected {
+            reason: match remote_to_local.close_reason {
+                Some(close_reason) => format!("tunnel closed by server with {close_reason}"),
+                None => "tunnel closed".to_string(),
+            },
+        });
+    }
+
+    /// Run several reverse tunnels with the same config and reconnection policy, until shutdown is cancelled.
+    /// Each of them backs off and gives up on its own, without impacting the others.
+    /// Events are tagged with the remote address of the tunnel that emitted them
+    pub async fn run_reverse_tunnels<C: TunnelConnector>(
+        self,
+        tunnels: Vec<(RemoteAddr, C)>,
+        events: Option<mpsc::Sender<(RemoteAddr, TunnelEvent)>>,
+        shutdown: CancellationToken,
+    ) -> anyhow::Result<()> {
+        let nb_tunnels = tunnels.len();
+        let tunnels = tunnels.into_iter().map(|(remote_addr, connector)| {
+            let client = self.clone();
+            let shutdown = shutdown.clone();
+            let events = events.clone();
+            async move {
+                let Some(events) = events else {
+                    return client.run_reverse_tunnel(remote_addr, connector, None, shutdown).await;
+                };
+
+                // Tag the events of this tunnel before handing them over to the shared channel
+                let (tunnel_events_tx, mut tunnel_events) = mpsc::channel(16);
+                let forward_events = async {
+                    while let Some(event) = tunnel_events.recv().await {
+                        if events.send((remote_addr.clone(), event)).await.is_err() {
+                            break;
+                        }
+                    }
+                };
+                let tunnel =
+                    client.run_reverse_tunnel(remote_addr.clone(), connector, Some(tunnel_events_tx), shutdown);
+                let (ret, _) = futures_util::future::join(tunnel, forward_events).await;
+                ret
+            }
+        });
+
+        // Polled as they finish, for a tunnel giving up to be reported right away rather than once all of them stopped.
+        // Not spawned on their own task, as the futures of the connectors are not required to be Send
+        let mut tunnels: FuturesUnordered<_> = tunnels.collect();
+        let mut errors = vec![];
+        while let Some(ret) = tunnels.next().await {
+            if let Err(err) = ret {
+                error!("Reverse tunnel gave up, {} others still running: {:?}", tunnels.len(), err);
+                errors.push(err);
+            }
+        }
+        if errors.is_empty() {
+            return Ok(());
+        }
+
+        Err(anyhow!(
+            "{} reverse tunnels out of {} gave up: {:?}",
+            errors.len(),
+            nb_tunnels,
+            errors
+        ))
+    }
+}
+
+// State of the stream of WsClient::tunnels, between two accepted connections
+struct TunnelAcceptor<L> {
+    client: WsClient,
+    listener: Pin<Box<L>>,
+    prewarmer: Option<Arc<TunnelPrewarmer>>,
+    // Prewarming stops along with the stream
+    _prewarm_guard: DropGuard,
+    tunnels_limit: Option<Arc<Semaphore>>,
+    exhausted_warn: LogThrottle,
+    shutdown: CancellationToken,
+}
+
+impl<L: TunnelListener> TunnelAcceptor<L> {
+    // None once the listener is closed or shutdown is requested
+    async fn next_tunnel(&mut self) -> Option<anyhow::Result<TunnelHandle>> {
+        loop {
+            let cnx = tokio::select! {
+                biased;
+                _ = self.shutdown.cancelled() => return None,
+                cnx = self.listener.next() => cnx?,
+            };
+
+            let (cnx_stream, remote_addr) = match cnx {
+                Ok((cnx_stream, remote_addr)) => (cnx_stream, remote_addr),
+                Err(err) => match classify_accept_error(&err) {
+                    AcceptErrorKind::Connection => {
+                        error!("Error accepting connection: {:?}", err);
+                        continue;
+                    }
+                    AcceptErrorKind::ResourceExhausted => {
+                        if let Some(suppressed) = self.exhausted_warn.allow() {
+                            warn!(
+                                "Cannot accept connections, out of resources ({} similar errors suppressed), retrying in {:?}: {:?}",
+                                suppressed, ACCEPT_EXHAUSTED_BACKOFF, err
+                            );
+                        }
+                        if sleep_unless_cancelled(
+                            self.client.config.clock.as_ref(),
+                            ACCEPT_EXHAUSTED_BACKOFF,
+                            &self.shutdown,
+                        )
+                        .await
+                        {
+                            return None;
+                        }
+                        continue;
+                    }
+                    AcceptErrorKind::Fatal => return Some(Err(err.context("Cannot accept connections anymore"))),
+                },
+            };
+
+            // The permit is held by the tunnel task for its whole lifetime
+            let permit = match &self.tunnels_limit {
+                None => None,
+                Some(limit) => match self.client.config.when_saturated {
+                    SaturationPolicy::Queue => tokio::select! {
+                        _ = self.shutdown.cancelled() => return None,
+                        permit = limit.clone().acquire_owned() => match permit {
+                            Ok(permit) => Some(permit),
+                            Err(err) => return Some(Err(err.into())),
+                        },
+                    },
+                    SaturationPolicy::Reject => match limit.clone().try_acquire_owned() {
+                        Ok(permit) => Some(permit),
+                        Err(_) => {
+                            warn!(
+                                "Rejecting connection for {}:{}, max concurrent tunnels reached",
+                                remote_addr.host, remote_addr.port
+                            );
+                            drop(cnx_stream);
+                            continue;
+                        }
+                    },
+                },
+            };
+
+            return Some(Ok(self.spawn_tunnel(cnx_stream, remote_addr, permit)));
+        }
+    }
+
+    fn spawn_tunnel(
+        &self,
+        cnx_stream: (L::Reader, L::Writer),
+        remote_addr: RemoteAddr,
+        permit: Option<OwnedSemaphorePermit>,
+    ) -> TunnelHandle {
+        // A prewarmed tunnel already has its own id, it cannot take the one given by the listener
+        let prewarmed = match remote_addr.request_id {
+            Some(_) => None,
+            None => self.prewarmer.as_ref().and_then(|prewarmer| prewarmer.take()),
+        };
+        let request_id = remote_addr
+            .request_id
+            .or_else(|| prewarmed.as_ref().map(|tunnel| tunnel.request_id))
+            .unwrap_or_else(Uuid::now_v7);
+        let span = self.client.tunnel_span(request_id, &remote_addr);
+        let counters = TunnelCounters::new(self.client.config.metrics.clone());
+        let client = self.client.clone();
+        let tunnel = {
+            let remote_addr = remote_addr.clone();
+            let counters = counters.clone();
+            async move {
+                let _permit = permit;
+                match prewarmed {
+                    Some(tunnel) => {
+                        debug!("Using prewarmed tunnel");
+                        let (ws_rx, ws_tx, response) = tunnel.into_parts();
+                        client.forward(ws_rx, ws_tx, response, cnx_stream, counters).await
+                    }
+                    None => {
+                        let _ = client
+                            .connect_to_server(request_id, &remote_addr, cnx_stream, counters)
+                            .await
+                            .map_err(|err| error!("{:?}", err));
+                    }
+                }
+            }
+            .instrument(span)
+        };
+
+        TunnelHandle::new(request_id, remote_addr, counters, tokio::spawn(tunnel))
+    }
+}
+
+// A panic in a tunnel task only kills this tunnel, but must not go unnoticed
+fn log_tunnel_exit(ret: Result<(), JoinError>) {
+    if let Err(err) = ret {
+        if err.is_panic() {
+            error!("Tunnel task panicked: {:?}", err);
+        }
+    }
+}
+
+// Pause of the accept loop when out of file descriptors, to let tunnels close instead of spinning on the error
+const ACCEPT_EXHAUSTED_BACKOFF: Duration = Duration::from_millis(100);
+// Such errors repeat at every pause until the situation clears, log them at most this often
+const ACCEPT_EXHAUSTED_WARN_INTERVAL: Duration = Duration::from_secs(5);
+// A multiplexed reverse tunnel closed before this is retried with backoff, instead of right away
+const MULTIPLEXED_TUNNEL_MIN_UPTIME: Duration = Duration::from_secs(10);
+
+// Bytes forwarded by all the tunnels of a reverse tunnel, to tell if any went through since the last check
+struct ForwardProgress {
+    counters: Arc<TunnelCounters>,
+    seen: u64,
+}
+
+impl ForwardProgress {
+    fn new(metrics: Arc<dyn TunnelMetrics>) -> Self {
+        Self {
+            counters: TunnelCounters::new(metrics),
+            seen: 0,
+        }
+    }
+
+    fn metrics(&self) -> Arc<dyn TunnelMetrics> {
+        self.counters.clone()
+    }
+
+    fn advanced(&mut self) -> bool {
+        let total = self.counters.total();
+        std::mem::replace(&mut self.seen, total) != total
+    }
+}
+
+// Return true if the shutdown has been requested before the delay elapsed
+async fn sleep_unless_cancelled(clock: &dyn Clock, delay: Duration, shutdown: &CancellationToken) -> bool {
+    tokio::select! {
+        _ = shutdown.cancelled() => true,
+        _ = clock.sleep(delay) => false,
+    }
+}
+
+// Once shutdown is requested, the tunnels in flight are given the grace period to finish before being aborted
+async fn shutdown_deadline(shutdown: &CancellationToken, grace_period: Duration) {
+    shutdown.cancelled().await;
+    info!(
+        "Shutting down, waiting up to {:?} for tunnels in flight to finish",
+        grace_period
+    );
+    tokio::time::sleep(grace_period).await;
+}
+
+// Wait for the tunnels in flight to finish on their own, until the shutdown deadline
+async fn drain_tunnels(mut tunnels: JoinSet<()>, shutdown: &CancellationToken, grace_period: Duration) {
+    if tunnels.is_empty() {
+        return;
+    }
+
+    tokio::select! {
+        _ = async {
+            while let Some(ret) = tunnels.join_next().await {
+                log_tunnel_exit(ret);
+            }
+        } => return,
+        _ = shutdown_deadline(shutdown, grace_period) => {}
+    }
+
+    warn!("Aborting {} tunnels still open after the shutdown grace period", tunnels.len());
+    tunnels.shutdown().await;
+}
 
-                // Forward websocket rx to local rx
-                let _ = super::super::transport::io::propagate_remote_to_local(local_tx, ws_rx, close_rx).await;
+// Same as drain_tunnels, for the tunnels accepted from a listener
+async fn drain_tunnel_handles(
+    mut tunnels: FuturesUnordered<TunnelHandle>,
+    shutdown: &CancellationToken,
+    grace_period: Duration,
+) {
+    if tunnels.is_empty() {
+        return;
+    }
+
+    tokio::select! {
+        _ = async {
+            while let Some(ret) = tunnels.next().await {
+                log_tunnel_exit(ret);
+            }
+        } => return,
+        _ = shutdown_deadline(shutdown, grace_period) => {}
+    }
+
+    warn!("Aborting {} tunnels still open after the shutdown grace period", tunnels.len());
+    for tunnel in tunnels.iter() {
+        tunnel.cancel();
+    }
+    while tunnels.next().await.is_some() {}
+}
+
+// The server sends back the resolved destination in a cookie for dynamic reverse tunnels (i.e: socks5, http proxy)
+fn remote_from_cookie(response: &Parts, is_required: bool) -> Result<Option<RemoteAddr>, TunnelConnectError> {
+    let Some(cookie) = response.headers.get(COOKIE) else {
+        return if is_required {
+            Err(TunnelConnectError::JwtRejected(anyhow!("missing cookie with the tunnel token")))
+        } else {
+            Ok(None)
+        };
+    };
+
+    let cookie = cookie
+        .to_str()
+        .map_err(|err| TunnelConnectError::JwtRejected(anyhow!("invalid cookie header: {err}")))?;
+    let jwt: TokenData<JwtTunnelConfig> = JWT_KEYS.decode(cookie).map_err(|err| {
+        let reason = match err.kind() {
+            jsonwebtoken::errors::ErrorKind::ExpiredSignature => "token expired".to_string(),
+            jsonwebtoken::errors::ErrorKind::InvalidSignature => {
+                "bad signature, client and server may not use the same secret".to_string()
+            }
+            _ => err.to_string(),
+        };
+        TunnelConnectError::JwtRejected(anyhow!("cannot decode tunnel token: {reason}"))
+    })?;
+
+    let host = tunnel::parse_host(&jwt.claims.r)
+        .map_err(|err| TunnelConnectError::JwtRejected(anyhow!("invalid host in tunnel token: {err:#}")))?;
+    Ok(Some(RemoteAddr {
+        protocol: jwt.claims.p,
+        host,
+        port: jwt.claims.rp,
+        source: None,
+        request_id: None,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::dns::DnsResolver;
+    use crate::tunnel::client::{ReconnectBackoff, WsClientConfigBuilder};
+    use crate::tunnel::connectors::TcpTunnelConnector;
+    use crate::tunnel::JWT_HEADER_PREFIX;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use url::Host;
+
+    #[tokio::test]
+    async fn test_reverse_tunnel_connections_have_distinct_ids() {
+        // Server refusing every upgrade, after noting the id of the tunnel in its token
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let mut ids = vec![];
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = vec![];
+                let mut buf = [0u8; 4096];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    assert_ne!(n, 0, "upgrade request is incomplete");
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8(request).unwrap();
+                let token = request
+                    .split(JWT_HEADER_PREFIX)
+                    .nth(1)
+                    .and_then(|token| token.split([',', '\r']).next())
+                    .unwrap();
+                let jwt: TokenData<JwtTunnelConfig> = JWT_KEYS.decode(token).unwrap();
+                ids.push(jwt.claims.id);
+                stream
+                    .write_all(b"HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n")
+                    .await
+                    .unwrap();
+                if ids.len() == 2 {
+                    return ids;
+                }
             }
-            .instrument(span.clone());
-            tokio::spawn(tunnel);
+            ids
+        });
+
+        let localhost = Host::Ipv4("127.0.0.1".parse().unwrap());
+        let config =
+            WsClientConfigBuilder::new(TransportAddr::new(TransportScheme::Ws, localhost.clone(), port, None).unwrap())
+                .with_reconnect_backoff(ReconnectBackoff {
+                    initial_delay: Duration::from_millis(10),
+                    max_delay: Duration::from_millis(10),
+                    multiplier: 1.0,
+                    jitter: 0.0,
+                })
+                .with_max_reconnect_attempts(Some(2))
+                .build()
+                .unwrap();
+        let client = WsClient::new(config, 0, Duration::from_secs(1)).await.unwrap();
+        let request_id = Uuid::now_v7();
+        let remote_addr = RemoteAddr {
+            protocol: LocalProtocol::ReverseTcp,
+            host: localhost.clone(),
+            port: 8080,
+            source: None,
+            request_id: Some(request_id),
+        };
+        let connector = TcpTunnelConnector::new(
+            &localhost,
+            1,
+            None,
+            Duration::from_secs(1),
+            &DnsResolver::System { prefer_ipv6: false },
+        );
+        let ret = tokio::time::timeout(
+            Duration::from_secs(5),
+            client.run_reverse_tunnel(remote_addr, connector, None, CancellationToken::new()),
+        )
+        .await
+        .unwrap();
+        assert!(ret.is_err());
+
+        let ids = server.await.unwrap();
+        assert_eq!(ids.len(), 2);
+        assert_eq!(ids[0], request_id.to_string());
+        assert_ne!(ids[1], ids[0]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reverse_tunnel_gives_up_after_max_reconnect_attempts() {
+        // Nothing listens there anymore, every connection to the server is refused
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let localhost = Host::Ipv4("127.0.0.1".parse().unwrap());
+        let config =
+            WsClientConfigBuilder::new(TransportAddr::new(TransportScheme::Ws, localhost.clone(), port, None).unwrap())
+                .with_reconnect_backoff(ReconnectBackoff {
+                    initial_delay: Duration::from_secs(1),
+                    max_delay: Duration::from_secs(10),
+                    multiplier: 2.0,
+                    jitter: 0.0,
+                })
+                .with_max_reconnect_attempts(Some(3))
+                .build()
+                .unwrap();
+        let client = WsClient::new(config, 0, Duration::from_secs(1)).await.unwrap();
+        let remote_addr = RemoteAddr {
+            protocol: LocalProtocol::ReverseTcp,
+            host: localhost.clone(),
+            port: 8080,
+            source: None,
+            request_id: None,
+        };
+        let connector = TcpTunnelConnector::new(
+            &localhost,
+            1,
+            None,
+            Duration::from_secs(1),
+            &DnsResolver::System { prefer_ipv6: false },
+        );
+        let (events_tx, mut events_rx) = mpsc::channel(16);
+
+        let ret = client
+            .run_reverse_tunnel(remote_addr, connector, Some(events_tx), CancellationToken::new())
+            .await;
+        assert!(format!("{:#}", ret.unwrap_err()).contains("giving up after 3 failures in a row"));
+
+        let mut events = vec![];
+        while let Some(event) = events_rx.recv().await {
+            events.push(event);
         }
+        let delays: Vec<Duration> = events
+            .iter()
+            .filter_map(|event| match event {
+                TunnelEvent::RetryScheduled { delay } => Some(*delay),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(delays, [Duration::from_secs(1), Duration::from_secs(2)]);
+        assert!(matches!(events.last(), Some(TunnelEvent::Disconnected { .. })), "{:?}", events);
     }
 }
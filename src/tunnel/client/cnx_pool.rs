@@ -1,68 +1,180 @@
 use crate::protocols;
+use crate::protocols::tcp::SocketBind;
 use crate::protocols::tls;
+use crate::tunnel::client::servers::RemoteServers;
 use crate::tunnel::client::WsClientConfig;
-use crate::tunnel::TransportStream;
+use crate::tunnel::metrics::ConnectTiming;
+use crate::tunnel::{TransportAddr, TransportStream, TunnelConnectError};
+use anyhow::anyhow;
 use async_trait::async_trait;
 use bb8::ManageConnection;
+use parking_lot::Mutex;
 use std::ops::Deref;
 use std::sync::Arc;
+use std::time::Instant;
 use tracing::instrument;
 
 #[derive(Clone)]
-pub struct WsConnection(Arc<WsClientConfig>);
+pub struct WsConnection {
+    config: Arc<WsClientConfig>,
+    servers: Arc<RemoteServers>,
+    // bb8 retries failed connections on its own and only reports a timeout to the caller,
+    // so keep the last failure around to be able to tell why we could not connect
+    last_error: Arc<Mutex<Option<TunnelConnectError>>>,
+}
 
 impl WsConnection {
-    pub fn new(config: Arc<WsClientConfig>) -> Self {
-        Self(config)
+    pub fn new(config: Arc<WsClientConfig>, servers: Arc<RemoteServers>) -> Self {
+        Self {
+            config,
+            servers,
+            last_error: Arc::new(Mutex::new(None)),
+        }
     }
-}
 
-impl Deref for WsConnection {
-    type Target = WsClientConfig;
+    pub fn last_error(&self) -> Arc<Mutex<Option<TunnelConnectError>>> {
+        self.last_error.clone()
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+    /// Open a connection outside of the pool, offering these protocols with ALPN instead of the transport ones.
+    /// i.e: to fall back to websocket when http2 does not go through
+    pub async fn connect_with_alpn(
+        &self,
+        alpn_protocols: Vec<Vec<u8>>,
+    ) -> Result<(TransportStream, usize, ConnectTiming), TunnelConnectError> {
+        self.connect_any(Some(alpn_protocols)).await
     }
-}
 
-#[async_trait]
-impl ManageConnection for WsConnection {
-    type Connection = Option<TransportStream>;
-    type Error = anyhow::Error;
+    async fn connect_any(
+        &self,
+        alpn_protocols: Option<Vec<Vec<u8>>>,
+    ) -> Result<(TransportStream, usize, ConnectTiming), TunnelConnectError> {
+        let mut last_error = None;
+        for ix in self.servers.connection_order() {
+            match self
+                .connect_to_server(self.servers.get(ix), alpn_protocols.clone())
+                .await
+            {
+                Ok((stream, timing)) => {
+                    self.servers.mark_success(ix);
+                    *self.last_error.lock() = None;
+                    return Ok((stream, ix, timing));
+                }
+                Err(err) => {
+                    self.servers.mark_failure(ix);
+                    last_error = Some(err);
+                }
+            }
+        }
 
-    #[instrument(level = "trace", name = "cnx_server", skip_all)]
-    async fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        // There is always at least one server
+        let err = last_error.unwrap();
+        *self.last_error.lock() = Some(err.to_owned_lossy());
+        Err(err)
+    }
+
+    async fn connect_to_server(
+        &self,
+        server: &TransportAddr,
+        alpn_protocols: Option<Vec<Vec<u8>>>,
+    ) -> Result<(TransportStream, ConnectTiming), TunnelConnectError> {
         let so_mark = self.socket_so_mark;
-        let timeout = self.timeout_connect;
+        let timeout = self.tcp_connect_timeout;
+        let mut timing = ConnectTiming::default();
 
-        let tcp_stream = if let Some(http_proxy) = &self.http_proxy {
+        let started_at = Instant::now();
+        let tcp_stream = if let Some(http_proxy) = self.http_proxy_for(server) {
             protocols::tcp::connect_with_http_proxy(
                 http_proxy,
-                self.remote_addr.host(),
-                self.remote_addr.port(),
+                server.host(),
+                server.port(),
                 so_mark,
+                &SocketBind::default(),
                 timeout,
                 &self.dns_resolver,
             )
-            .await?
+            .await
+            .map_err(TunnelConnectError::Tcp)?
         } else {
-            protocols::tcp::connect(
-                self.remote_addr.host(),
-                self.remote_addr.port(),
-                so_mark,
-                timeout,
-                &self.dns_resolver,
+            let socket_addrs = protocols::tcp::resolve(server.host(), server.port(), &self.dns_resolver)
+                .await
+                .map_err(TunnelConnectError::Dns)?;
+            timing.dns = Some(started_at.elapsed());
+            protocols::tcp::connect_to_addrs(
+                server.host(),
+                server.port(),
+                socket_addrs,
+                &protocols::tcp::ConnectOptions {
+                    so_mark,
+                    socket_bind: &SocketBind::default(),
+                    tcp_options: &self.tcp_options,
+                    connect_timeout: timeout,
+                    happy_eyeballs_delay: self.happy_eyeballs_delay,
+                    connect_parallelism: self.connect_parallelism,
+                },
             )
-            .await?
+            .await
+            .map_err(TunnelConnectError::Tcp)?
         };
+        timing.tcp = Some(started_at.elapsed() - timing.dns.unwrap_or_default());
+
+        if server.tls().is_some() {
+            let started_at = Instant::now();
+            // Connections for another transport than the configured one offer their own protocols
+            let check_alpn = alpn_protocols.is_none() && self.tls_alpn_protocols.is_some();
+            let tls_stream =
+                tokio::time::timeout(self.tls_handshake_timeout, tls::connect(server, tcp_stream, alpn_protocols))
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(anyhow!(
+                            "timeout after {:?} during TLS handshake with {}:{}",
+                            self.tls_handshake_timeout,
+                            server.host(),
+                            server.port()
+                        ))
+                    })
+                    .map_err(TunnelConnectError::Tls)?;
 
-        if self.remote_addr.tls().is_some() {
-            let tls_stream = tls::connect(self, tcp_stream).await?;
-            Ok(Some(TransportStream::Tls(tls_stream)))
+            // rustls only rejects a protocol we did not offer, not the absence of one
+            if check_alpn {
+                let negotiated = tls_stream.get_ref().1.alpn_protocol();
+                let expected = server.scheme().alpn_protocol().unwrap_or_default();
+                if negotiated != Some(expected.as_bytes()) {
+                    return Err(TunnelConnectError::Tls(anyhow!(
+                        "server {:?} negotiated ALPN protocol {:?}, but the {} transport requires {}",
+                        server,
+                        negotiated.map(String::from_utf8_lossy),
+                        server.scheme(),
+                        expected
+                    )));
+                }
+            }
+            timing.tls = Some(started_at.elapsed());
+            Ok((TransportStream::Tls(tls_stream), timing))
         } else {
-            Ok(Some(TransportStream::Plain(tcp_stream)))
+            Ok((TransportStream::Plain(tcp_stream), timing))
         }
     }
+}
+
+impl Deref for WsConnection {
+    type Target = WsClientConfig;
+
+    fn deref(&self) -> &Self::Target {
+        &self.config
+    }
+}
+
+#[async_trait]
+impl ManageConnection for WsConnection {
+    // The stream along with the index of the server it is connected to, and how long it took
+    type Connection = Option<(TransportStream, usize, ConnectTiming)>;
+    type Error = TunnelConnectError;
+
+    #[instrument(level = "trace", name = "cnx_server", skip_all)]
+    async fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        self.connect_any(None).await.map(Some)
+    }
 
     async fn is_valid(&self, _conn: &mut Self::Connection) -> Result<(), Self::Error> {
         Ok(())
@@ -72,3 +184,73 @@ impl ManageConnection for WsConnection {
         conn.is_none()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::client::{TlsClientConfig, WsClientConfigBuilder};
+    use crate::tunnel::TransportScheme;
+    use parking_lot::RwLock;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::RootCertStore;
+    use url::Host;
+
+    #[tokio::test]
+    async fn test_tls_handshake_timeout() {
+        // Accepts the tcp connection, but never answers the client hello
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            drop(stream);
+        });
+
+        let root_store = Arc::new(RootCertStore::empty());
+        let tls = TlsClientConfig {
+            tls_sni_disabled: false,
+            tls_sni_override: None,
+            tls_verify_hostname: None,
+            tls_verify_certificate: false,
+            tls_certificate_pins: vec![],
+            tls_root_store: root_store.clone(),
+            tls_client_config: Arc::new(RwLock::new(
+                tls::tls_client_config(tls::TlsClientOptions {
+                    tls_verify_certificate: false,
+                    root_store,
+                    ..Default::default()
+                })
+                .unwrap(),
+            )),
+            tls_certificate_path: None,
+            tls_key_path: None,
+            tls_min_version: Default::default(),
+        };
+        let remote =
+            TransportAddr::new(TransportScheme::Wss, Host::Domain("localhost".to_string()), port, Some(tls)).unwrap();
+        let config = WsClientConfigBuilder::new(remote.clone())
+            .with_tls_handshake_timeout(Duration::from_millis(200))
+            .build()
+            .unwrap();
+        let servers = Arc::new(RemoteServers::new(
+            remote,
+            vec![],
+            config.remote_selection,
+            config.remote_cooldown,
+        ));
+        let cnx = WsConnection::new(Arc::new(config), servers);
+
+        let started_at = Instant::now();
+        let err = match cnx.connect_any(None).await {
+            Ok(_) => panic!("TLS handshake completed without a server"),
+            Err(err) => err,
+        };
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+        match err {
+            TunnelConnectError::Tls(err) => assert!(err.to_string().contains("timeout"), "{:?}", err),
+            err => panic!("expected a TLS error, got {:?}", err),
+        }
+        server.abort();
+    }
+}
@@ -1,76 +1,339 @@
 use crate::protocols::dns::DnsResolver;
-use crate::tunnel::TransportAddr;
+use crate::protocols::tcp::{ConnectFailureBehavior, TcpSocketOptions};
+use crate::protocols::tls::TlsVersion;
+use crate::tunnel::clock::Clock;
+use crate::tunnel::metrics::TunnelMetrics;
+use crate::tunnel::transport::http2::Http2MultiplexConfig;
+use crate::tunnel::transport::io::{BandwidthLimit, RateLimit};
+use crate::tunnel::{RemoteAddr, TransportAddr};
+use crate::LocalProtocol;
 use hyper::header::{HeaderName, HeaderValue};
-use once_cell::sync::Lazy;
 use parking_lot::RwLock;
-use std::collections::HashMap;
-use std::net::IpAddr;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio_rustls::rustls::pki_types::{DnsName, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
 use tokio_rustls::TlsConnector;
 use url::{Host, Url};
 
 #[derive(Clone)]
 pub struct WsClientConfig {
     pub remote_addr: TransportAddr,
+    pub remote_addr_fallbacks: Vec<TransportAddr>,
+    pub remote_selection: RemoteSelection,
+    pub remote_cooldown: Duration,
     pub socket_so_mark: Option<u32>,
     pub http_upgrade_path_prefix: String,
     pub http_upgrade_credentials: Option<HeaderValue>,
-    pub http_headers: HashMap<HeaderName, HeaderValue>,
+    pub http_headers: Vec<(HeaderName, HeaderValue)>,
     pub http_headers_file: Option<PathBuf>,
+    pub user_agent: Option<HeaderValue>,
+    // Names of the headers of the upgrade request to send first, in this order. Empty to keep the default order
+    pub http_headers_order: Vec<HeaderName>,
     pub http_header_host: HeaderValue,
     pub timeout_connect: Duration,
+    // Bound on getting a tunnel up with the server: dns + tcp + tls + http upgrade combined.
+    // timeout_connect only covers the tcp connection of the tunnels' destinations
+    pub server_connect_timeout: Duration,
+    // Bounds of the individual steps of the connection to the server, within server_connect_timeout
+    pub tcp_connect_timeout: Duration,
+    pub tls_handshake_timeout: Duration,
+    pub happy_eyeballs_delay: Duration,
+    pub connect_parallelism: usize,
+    pub tcp_options: TcpSocketOptions,
+    // Replace the ALPN protocols offered by the transport (http/1.1 for wss, h2 for https) during the TLS handshake
+    pub tls_alpn_protocols: Option<Vec<String>>,
+    // Backlog of the local tcp listeners
+    pub listen_backlog: u32,
     pub websocket_ping_frequency: Duration,
+    pub websocket_adaptive_ping: bool,
     pub websocket_mask_frame: bool,
+    // Close the tunnel when a ping did not get its pong within this duration
+    pub websocket_pong_timeout: Option<Duration>,
+    // Close the tunnel after this many pings in a row did not get their pong
+    pub missed_pong_limit: Option<usize>,
+    // Sent in the tunnel tokens for the server to attribute the tunnels, i.e: to a tenant. Opaque to wstunnel
+    pub tunnel_metadata: Option<String>,
+    // Sent instead of the default v1 in Sec-WebSocket-Protocol, the server must accept it
+    pub websocket_subprotocol: Option<String>,
+    // Carry the tunnels as streams of shared connections with the http2 transport, instead of one connection each
+    pub http2_multiplex: Option<Http2MultiplexConfig>,
+    // Use the websocket transport instead of http2 when http2 does not go through to the server
+    pub transport_fallback: bool,
+    pub idle_timeout: Option<Duration>,
+    // How the local connections are closed when their tunnel cannot be established
+    pub connect_failure_behavior: ConnectFailureBehavior,
+    // Tunnels are closed once they are older than this, whatever their activity
+    pub max_tunnel_duration: Option<Duration>,
+    // Cap of the throughput of each direction of every tunnel, unlimited if None
+    pub max_bytes_per_sec: Option<u64>,
+    // Caps of the aggregated throughput of all the tunnels, towards the server (egress) and from it (ingress)
+    pub global_egress_limit: Option<Arc<RateLimit>>,
+    pub global_ingress_limit: Option<Arc<RateLimit>>,
+    // Size of the buffer to read from the local connections. None for the default of the transport
+    pub copy_buffer_size: Option<usize>,
+    pub shutdown_grace_period: Duration,
     pub http_proxy: Option<Url>,
+    pub no_proxy: Vec<String>,
     pub dns_resolver: DnsResolver,
+    pub reconnect_backoff: ReconnectBackoff,
+    // Reverse tunnels give up after this many failures in a row, instead of retrying forever if None
+    pub max_reconnect_attempts: Option<u32>,
+    // Receive all the connections of a reverse tcp listener through a single tunnel, instead of one tunnel each
+    pub reverse_tunnel_multiplex: bool,
+    pub max_concurrent_tunnels: Option<NonZeroUsize>,
+    // Tunnels kept established in advance for listeners with a fixed destination, 0 to disable
+    pub prewarm_pool_size: usize,
+    pub when_saturated: SaturationPolicy,
+    pub metrics: Arc<dyn TunnelMetrics>,
+    pub clock: Arc<dyn Clock>,
 }
 
 impl WsClientConfig {
-    pub const fn websocket_scheme(&self) -> &'static str {
-        match self.remote_addr.tls().is_some() {
-            false => "ws",
-            true => "wss",
+    /// Limits of the local => remote direction of a tunnel
+    pub fn egress_limit(&self) -> BandwidthLimit {
+        BandwidthLimit {
+            per_tunnel: self.max_bytes_per_sec,
+            global: self.global_egress_limit.clone(),
         }
     }
 
-    pub fn websocket_host_url(&self) -> String {
-        format!("{}:{}", self.remote_addr.host(), self.remote_addr.port())
+    /// Limits of the remote => local direction of a tunnel
+    pub fn ingress_limit(&self) -> BandwidthLimit {
+        BandwidthLimit {
+            per_tunnel: self.max_bytes_per_sec,
+            global: self.global_ingress_limit.clone(),
+        }
+    }
+
+    /// ALPN protocols to offer during the TLS handshake with the server
+    pub fn alpn_protocols(&self) -> Vec<Vec<u8>> {
+        match &self.tls_alpn_protocols {
+            Some(protocols) => protocols.iter().map(|p| p.as_bytes().to_vec()).collect(),
+            None => self.remote_addr.scheme().alpn_protocols(),
+        }
+    }
+
+    /// Whether to ask the server to carry all the connections of this reverse tunnel through a single tunnel
+    pub fn multiplexes_reverse_tunnel(&self, remote: &RemoteAddr) -> bool {
+        self.reverse_tunnel_multiplex
+            && matches!(remote.protocol, LocalProtocol::ReverseTcp)
+            && !remote.is_ephemeral_bind()
     }
 
-    pub fn tls_server_name(&self) -> ServerName<'static> {
-        static INVALID_DNS_NAME: Lazy<DnsName> = Lazy::new(|| DnsName::try_from("dns-name-invalid.com").unwrap());
-
-        self.remote_addr
-            .tls()
-            .and_then(|tls| tls.tls_sni_override.as_ref())
-            .map_or_else(
-                || match &self.remote_addr.host() {
-                    Host::Domain(domain) => ServerName::DnsName(
-                        DnsName::try_from(domain.clone()).unwrap_or_else(|_| INVALID_DNS_NAME.clone()),
-                    ),
-                    Host::Ipv4(ip) => ServerName::IpAddress(IpAddr::V4(*ip).into()),
-                    Host::Ipv6(ip) => ServerName::IpAddress(IpAddr::V6(*ip).into()),
-                },
-                |sni_override| ServerName::DnsName(sni_override.clone()),
-            )
+    /// Host header to send to the given server.
+    /// Fallback servers get their own one, unless the header has been explicitly overridden for the primary server
+    pub fn http_header_host_for(&self, server: &TransportAddr) -> HeaderValue {
+        let primary_default = default_http_header_host(&self.remote_addr);
+        if self.http_header_host != primary_default {
+            return self.http_header_host.clone();
+        }
+
+        default_http_header_host(server)
+    }
+
+    /// Http proxy to use to reach the given server, if it is not excluded by no_proxy
+    pub fn http_proxy_for(&self, server: &TransportAddr) -> Option<&Url> {
+        let http_proxy = self.http_proxy.as_ref()?;
+        if is_proxy_bypassed(&self.no_proxy, server.host()) {
+            return None;
+        }
+
+        Some(http_proxy)
     }
 }
 
+// Same rules as curl for NO_PROXY: '*' matches every host, an entry matches the host itself and all its subdomains.
+// Ip addresses must match exactly
+fn is_proxy_bypassed(no_proxy: &[String], host: &Host) -> bool {
+    let host = match host {
+        Host::Domain(domain) => domain.to_ascii_lowercase(),
+        Host::Ipv4(ip) => ip.to_string(),
+        Host::Ipv6(ip) => ip.to_string(),
+    };
+
+    no_proxy.iter().any(|entry| {
+        let entry = entry
+            .trim()
+            .trim_start_matches('.')
+            .trim_matches(['[', ']'])
+            .to_ascii_lowercase();
+        entry == "*"
+            || (!entry.is_empty()
+                && (host == entry || host.strip_suffix(&entry).is_some_and(|prefix| prefix.ends_with('.'))))
+    })
+}
+
+pub fn default_http_header_host(server: &TransportAddr) -> HeaderValue {
+    let host = match server.port() {
+        80 | 443 => server.host().to_string(),
+        port => format!("{}:{}", server.host(), port),
+    };
+    HeaderValue::from_str(&host).unwrap()
+}
+
 #[derive(Clone)]
 pub struct TlsClientConfig {
     pub tls_sni_disabled: bool,
     pub tls_sni_override: Option<DnsName<'static>>,
+    pub tls_verify_hostname: Option<ServerName<'static>>,
     pub tls_verify_certificate: bool,
-    pub tls_connector: Arc<RwLock<TlsConnector>>,
+    pub tls_certificate_pins: Vec<String>,
+    pub tls_root_store: Arc<RootCertStore>,
+    pub tls_client_config: Arc<RwLock<Arc<ClientConfig>>>,
     pub tls_certificate_path: Option<PathBuf>,
     pub tls_key_path: Option<PathBuf>,
+    pub tls_min_version: TlsVersion,
 }
 
 impl TlsClientConfig {
     pub fn tls_connector(&self) -> TlsConnector {
-        self.tls_connector.read().clone()
+        TlsConnector::from(self.tls_client_config.read().clone())
+    }
+
+    /// Same as tls_connector, but offering other protocols with ALPN
+    pub fn tls_connector_with_alpn(&self, alpn_protocols: Vec<Vec<u8>>) -> TlsConnector {
+        let mut config = ClientConfig::clone(&self.tls_client_config.read());
+        config.alpn_protocols = alpn_protocols;
+        TlsConnector::from(Arc::new(config))
+    }
+}
+
+/// How to pick the server to connect to, when fallback servers are configured
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum RemoteSelection {
+    /// Always use the same server, and only move to the next one when it fails
+    Failover,
+    /// Spread new connections across all servers in rotation
+    RoundRobin,
+}
+
+/// What to do with a new local connection when max_concurrent_tunnels is reached
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum SaturationPolicy {
+    /// Wait for a running tunnel to finish before accepting the connection
+    Queue,
+    /// Drop the connection right away
+    Reject,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReconnectBackoff {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f64,
+    pub jitter: f64,
+}
+
+impl ReconnectBackoff {
+    /// Delay to wait before the given retry attempt (0 based), capped to max_delay and randomized by +/- jitter
+    pub fn delay_for_attempt(&self, attempt: u32) -> Duration {
+        let exp = self.multiplier.max(1.0).powi(attempt.min(i32::MAX as u32) as i32);
+        let max_delay = self.max_delay.as_secs_f64().max(self.initial_delay.as_secs_f64());
+        let delay = (self.initial_delay.as_secs_f64() * exp).min(max_delay);
+
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let delay = delay * (1.0 + jitter * (fastrand::f64() * 2.0 - 1.0));
+        Duration::try_from_secs_f64(delay.min(max_delay)).unwrap_or(self.max_delay)
+    }
+
+    /// Delay asked by the server with Retry-After if any, capped to max_delay, otherwise the one of the attempt
+    pub fn delay_for_retry(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        match retry_after {
+            Some(retry_after) => retry_after.min(self.max_delay),
+            None => self.delay_for_attempt(attempt),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::TransportScheme;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn test_no_proxy_matching() {
+        let no_proxy = vec![
+            ".corp.example.com".to_string(),
+            "10.0.0.1".to_string(),
+            "[::1]".to_string(),
+        ];
+        let domain = |d: &str| Host::Domain(d.to_string());
+
+        assert!(is_proxy_bypassed(&no_proxy, &domain("corp.example.com")));
+        assert!(is_proxy_bypassed(&no_proxy, &domain("git.CORP.example.com")));
+        assert!(!is_proxy_bypassed(&no_proxy, &domain("notcorp.example.com")));
+        assert!(!is_proxy_bypassed(&no_proxy, &domain("example.com")));
+        assert!(is_proxy_bypassed(&no_proxy, &Host::Ipv4(Ipv4Addr::new(10, 0, 0, 1))));
+        assert!(!is_proxy_bypassed(&no_proxy, &Host::Ipv4(Ipv4Addr::new(10, 0, 0, 10))));
+        assert!(is_proxy_bypassed(&no_proxy, &Host::Ipv6(Ipv6Addr::LOCALHOST)));
+        assert!(is_proxy_bypassed(&["*".to_string()], &domain("anything.com")));
+        assert!(!is_proxy_bypassed(&[], &domain("anything.com")));
+    }
+
+    #[test]
+    fn test_default_http_header_host_brackets_ipv6() {
+        let header = |host: Host, port: u16| {
+            let server = TransportAddr::new(TransportScheme::Ws, host, port, None).unwrap();
+            default_http_header_host(&server)
+        };
+        let ipv6 = Host::Ipv6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+
+        assert_eq!(header(ipv6.clone(), 8080), "[2001:db8::1]:8080");
+        assert_eq!(header(ipv6, 80), "[2001:db8::1]");
+        assert_eq!(header(Host::Ipv4(Ipv4Addr::new(10, 0, 0, 1)), 8080), "10.0.0.1:8080");
+        assert_eq!(header(Host::Domain("example.com".to_string()), 443), "example.com");
+    }
+    #[test]
+    fn test_reconnect_backoff_grows_up_to_max_delay() {
+        let backoff = ReconnectBackoff {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: 0.0,
+        };
+
+        let delays: Vec<_> = (0..6)
+            .map(|attempt| backoff.delay_for_attempt(attempt).as_secs())
+            .collect();
+        assert_eq!(delays, [1, 2, 4, 8, 10, 10]);
+        assert_eq!(backoff.delay_for_attempt(u32::MAX), Duration::from_secs(10));
+
+        // The server knows best when to retry, but never beyond max_delay
+        assert_eq!(backoff.delay_for_retry(0, Some(Duration::from_secs(5))), Duration::from_secs(5));
+        assert_eq!(
+            backoff.delay_for_retry(0, Some(Duration::from_secs(60))),
+            Duration::from_secs(10)
+        );
+        assert_eq!(backoff.delay_for_retry(3, None), Duration::from_secs(8));
+    }
+
+    #[test]
+    fn test_reconnect_backoff_jitter_bounds() {
+        let backoff = ReconnectBackoff {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: 0.5,
+        };
+
+        for _ in 0..1000 {
+            let delay = backoff.delay_for_attempt(2);
+            assert!(
+                delay >= Duration::from_secs(2) && delay <= Duration::from_secs(6),
+                "{:?}",
+                delay
+            );
+            // Even randomized, the delay never goes above max_delay
+            let delay = backoff.delay_for_attempt(10);
+            assert!(
+                delay >= Duration::from_secs(5) && delay <= Duration::from_secs(10),
+                "{:?}",
+                delay
+            );
+        }
     }
 }
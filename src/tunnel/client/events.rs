@@ -0,0 +1,66 @@
+use crate::tunnel::RemoteAddr;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tracing::warn;
+
+/// Lifecycle events of a reverse tunnel, to be able to observe it without scraping the logs
+#[derive(Debug, Clone)]
+pub enum TunnelEvent {
+    /// A connection from the server has been forwarded to the local destination
+    Connected,
+    /// A forwarded connection has been closed
+    Disconnected { reason: String },
+    /// A forwarded connection has been closed because it lived longer than the max tunnel duration.
+    /// Followed by its Disconnected event
+    Expired { after: Duration },
+    /// Connecting failed, a new attempt will be made after the delay
+    RetryScheduled { delay: Duration },
+    /// The server asked to forward the connection to this destination (i.e: reverse socks5/http proxy)
+    RemoteResolved { addr: RemoteAddr },
+    /// The server is listening on this address, when the tunnel asked for an ephemeral port
+    Bound { addr: RemoteAddr },
+}
+
+impl Display for TunnelEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Connected => write!(f, "connected"),
+            Self::Disconnected { reason } => write!(f, "disconnected: {reason}"),
+            Self::Expired { after } => write!(f, "expired after {after:?}"),
+            Self::RetryScheduled { delay } => write!(f, "retry scheduled in {delay:?}"),
+            Self::RemoteResolved { addr } => write!(f, "remote resolved to {}:{}", addr.host, addr.port),
+            Self::Bound { addr } => write!(f, "bound on {}:{}", addr.host, addr.port),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(super) struct TunnelEventSender {
+    tx: Option<mpsc::Sender<TunnelEvent>>,
+    nb_dropped: Arc<AtomicU64>,
+}
+
+impl TunnelEventSender {
+    pub fn new(tx: Option<mpsc::Sender<TunnelEvent>>) -> Self {
+        Self {
+            tx,
+            nb_dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    // Never wait on the consumer, a slow one must not stall the tunnel
+    pub fn send(&self, event: TunnelEvent) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+
+        if let Err(TrySendError::Full(event)) = tx.try_send(event) {
+            let nb_dropped = self.nb_dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!("Tunnel event channel is full, dropping event '{event}'. {nb_dropped} events dropped so far");
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use crate::tunnel::metrics::{ConnectTiming, Direction, TunnelMetrics};
+use crate::tunnel::RemoteAddr;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::task::{JoinError, JoinHandle};
+use uuid::Uuid;
+
+/// Bytes transferred by a single tunnel, on top of the metrics of the client
+pub(super) struct TunnelCounters {
+    inner: Arc<dyn TunnelMetrics>,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+impl TunnelCounters {
+    pub fn new(inner: Arc<dyn TunnelMetrics>) -> Arc<Self> {
+        Arc::new(Self {
+            inner,
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+        })
+    }
+
+    // Bytes forwarded in both directions so far
+    pub fn total(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed) + self.bytes_received.load(Ordering::Relaxed)
+    }
+}
+
+impl TunnelMetrics for TunnelCounters {
+    fn on_tunnel_open(&self) {
+        self.inner.on_tunnel_open();
+    }
+
+    fn on_bytes(&self, direction: Direction, nb_bytes: usize) {
+        let counter = match direction {
+            Direction::LocalToRemote => &self.bytes_sent,
+            Direction::RemoteToLocal => &self.bytes_received,
+        };
+        counter.fetch_add(nb_bytes as u64, Ordering::Relaxed);
+        self.inner.on_bytes(direction, nb_bytes);
+    }
+
+    fn on_tunnel_close(&self, duration: Duration) {
+        self.inner.on_tunnel_close(duration);
+    }
+
+    fn on_rtt(&self, rtt: Duration) {
+        self.inner.on_rtt(rtt);
+    }
+
+    fn on_connect(&self, timing: &ConnectTiming) {
+        self.inner.on_connect(timing);
+    }
+}
+
+/// A tunnel accepted from a listener by WsClient::tunnels, running in its own task.
+/// Awaiting it waits for the tunnel to be closed. Dropping it leaves the tunnel running, use cancel to stop it
+pub struct TunnelHandle {
+    request_id: Uuid,
+    remote_addr: RemoteAddr,
+    counters: Arc<TunnelCounters>,
+    task: JoinHandle<()>,
+}
+
+impl TunnelHandle {
+    pub(super) fn new(
+        request_id: Uuid,
+        remote_addr: RemoteAddr,
+        counters: Arc<TunnelCounters>,
+        task: JoinHandle<()>,
+    ) -> Self {
+        Self {
+            request_id,
+            remote_addr,
+            counters,
+            task,
+        }
+    }
+
+    /// Id of the tunnel, as found in the logs of the client and of the server
+    pub const fn request_id(&self) -> Uuid {
+        self.request_id
+    }
+
+    pub const fn remote_addr(&self) -> &RemoteAddr {
+        &self.remote_addr
+    }
+
+    /// Bytes sent from the local connection to the remote, so far
+    pub fn bytes_sent(&self) -> u64 {
+        self.counters.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Bytes received from the remote and written to the local connection, so far
+    pub fn bytes_received(&self) -> u64 {
+        self.counters.bytes_received.load(Ordering::Relaxed)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Stop the tunnel right away, its local connection is closed without waiting for the data in flight.
+    /// Awaiting the handle then returns a cancelled JoinError, unless the tunnel was already done
+    pub fn cancel(&self) {
+        self.task.abort();
+    }
+}
+
+impl Future for TunnelHandle {
+    type Output = Result<(), JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.task).poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::metrics::AtomicTunnelMetrics;
+    use crate::LocalProtocol;
+    use url::Host;
+
+    #[tokio::test]
+    async fn test_tunnel_handle_counters_and_cancel() {
+        let metrics = Arc::new(AtomicTunnelMetrics::default());
+        let counters = TunnelCounters::new(metrics.clone());
+        counters.on_bytes(Direction::LocalToRemote, 10);
+        counters.on_bytes(Direction::RemoteToLocal, 3);
+
+        let remote_addr = RemoteAddr {
+            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            host: Host::Domain("localhost".to_string()),
+            port: 80,
+            source: None,
+            request_id: None,
+        };
+        let handle = TunnelHandle::new(Uuid::now_v7(), remote_addr, counters, tokio::spawn(std::future::pending()));
+        assert_eq!((handle.bytes_sent(), handle.bytes_received()), (10, 3));
+        // The metrics of the client still see all the tunnels
+        assert_eq!(metrics.bytes_local_to_remote.load(Ordering::Relaxed), 10);
+
+        assert!(!handle.is_finished());
+        handle.cancel();
+        assert!(handle.await.unwrap_err().is_cancelled());
+    }
+}
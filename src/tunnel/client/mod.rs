@@ -1,8 +1,20 @@
 #![allow(clippy::module_inception)]
+pub mod blocking;
+pub mod builder;
 mod client;
 mod cnx_pool;
 mod config;
+mod events;
+mod handle;
+mod prewarm;
+mod servers;
 
+pub use builder::WsClientConfigBuilder;
 pub use client::WsClient;
+pub use config::ReconnectBackoff;
+pub use config::RemoteSelection;
+pub use config::SaturationPolicy;
 pub use config::TlsClientConfig;
 pub use config::WsClientConfig;
+pub use events::TunnelEvent;
+pub use handle::TunnelHandle;
@@ -0,0 +1,120 @@
+use crate::tunnel::client::WsClient;
+use crate::tunnel::transport::datagram::{DatagramTunnelRead, DatagramTunnelWrite};
+use crate::tunnel::transport::prefetch::PrefetchTunnelRead;
+use crate::tunnel::transport::{TunnelReader, TunnelWrite, TunnelWriter};
+use crate::tunnel::RemoteAddr;
+use hyper::http::response::Parts;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// A tunnel to the server already upgraded, and so already connected to its destination by the server.
+/// It is read while idle, for its pongs to be received and its close to be noticed. Data that the destination
+/// sends first, i.e: a ssh banner, is kept for the local connection it is handed over to
+pub(super) struct PrewarmedTunnel {
+    pub request_id: Uuid,
+    ws_rx: PrefetchTunnelRead,
+    pub ws_tx: DatagramTunnelWrite<TunnelWriter>,
+    pub response: Parts,
+}
+
+impl PrewarmedTunnel {
+    pub fn into_parts(self) -> (DatagramTunnelRead<TunnelReader>, DatagramTunnelWrite<TunnelWriter>, Parts) {
+        // The datagrams are already split by the prefetching, one per frame
+        let ws_rx = DatagramTunnelRead::new(TunnelReader::Prefetched(self.ws_rx), false);
+        (ws_rx, self.ws_tx, self.response)
+    }
+}
+
+/// Keep up to prewarm_pool_size tunnels established in advance to a fixed destination,
+/// for local connections to not wait for the tcp + tls + upgrade handshakes with the server
+pub(super) struct TunnelPrewarmer {
+    idle: Mutex<VecDeque<PrewarmedTunnel>>,
+    need_refill: Notify,
+}
+
+impl TunnelPrewarmer {
+    /// Start filling the pool in the background, until shutdown is cancelled
+    pub fn spawn(client: WsClient, destination: RemoteAddr, shutdown: CancellationToken) -> Arc<Self> {
+        let prewarmer = Arc::new(Self {
+            idle: Mutex::new(VecDeque::with_capacity(client.config.prewarm_pool_size)),
+            need_refill: Notify::new(),
+        });
+        tokio::spawn(prewarmer.clone().fill(client, destination, shutdown));
+
+        prewarmer
+    }
+
+    pub fn take(&self) -> Option<PrewarmedTunnel> {
+        let tunnel = loop {
+            let tunnel = self.idle.lock().pop_front()?;
+            if !tunnel.ws_rx.is_closed() {
+                break tunnel;
+            }
+        };
+        self.need_refill.notify_one();
+
+        Some(tunnel)
+    }
+
+    async fn fill(self: Arc<Self>, client: WsClient, destination: RemoteAddr, shutdown: CancellationToken) {
+        let pool_size = client.config.prewarm_pool_size;
+        let mut retry_attempt = 0;
+        loop {
+            // Tunnels are taken out while being pinged, local connections meanwhile do a cold handshake.
+            // A tunnel missing its pongs is closed by its reader, and so replaced below
+            let tunnels = std::mem::take(&mut *self.idle.lock());
+            let mut alive = VecDeque::with_capacity(pool_size);
+            for mut tunnel in tunnels {
+                if !tunnel.ws_rx.is_closed() && tunnel.ws_tx.ping().await.is_ok() {
+                    alive.push_back(tunnel);
+                }
+            }
+            self.idle.lock().extend(alive);
+
+            let mut delay = client.config.websocket_ping_frequency;
+            while self.idle.lock().len() < pool_size {
+                let request_id = Uuid::now_v7();
+                let cnx = tokio::select! {
+                    _ = shutdown.cancelled() => return,
+                    cnx = client.connect_transport(request_id, &destination) => cnx,
+                };
+
+                match cnx {
+                    Ok((ws_rx, ws_tx, response)) => {
+                        debug!("Prewarmed tunnel {} to {}:{}", request_id, destination.host, destination.port);
+                        retry_attempt = 0;
+                        self.idle.lock().push_back(PrewarmedTunnel {
+                            request_id,
+                            ws_rx: PrefetchTunnelRead::spawn(ws_rx),
+                            ws_tx,
+                            response,
+                        });
+                    }
+                    Err(err) => {
+                        delay = client
+                            .config
+                            .reconnect_backoff
+                            .delay_for_retry(retry_attempt, err.retry_after());
+                        retry_attempt = retry_attempt.saturating_add(1);
+                        warn!(
+                            "Cannot prewarm tunnel to {}:{}, retrying in {:?}: {:?}",
+                            destination.host, destination.port, delay, err
+                        );
+                        break;
+                    }
+                }
+            }
+
+            tokio::select! {
+                _ = shutdown.cancelled() => return,
+                _ = self.need_refill.notified(), if retry_attempt == 0 => {},
+                _ = tokio::time::sleep(delay) => {},
+            }
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use crate::tunnel::client::RemoteSelection;
+use crate::tunnel::TransportAddr;
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+// With the transport fallback, a server http2 did not go through to is reached with websocket for this long,
+// before trying http2 again
+const HTTP2_UNAVAILABLE_COOLDOWN: Duration = Duration::from_secs(60 * 5);
+
+struct ServerHealth {
+    failures: AtomicU32,
+    unavailable_until: Mutex<Option<Instant>>,
+    http2_unavailable_until: Mutex<Option<Instant>>,
+}
+
+/// Ordered list of the wstunnel servers the client can use, the first one being the primary.
+/// With RemoteSelection::Failover, connections are made to the active server, and on failure to the next ones in order.
+/// The last server we managed to connect to becomes the active one (sticky).
+/// With RemoteSelection::RoundRobin, each new connection starts from the next server in rotation.
+/// Servers that failed are tried last until their cooldown expires.
+/// Changes of health/active server are logged, to know which one is currently in use
+pub struct RemoteServers {
+    servers: Vec<TransportAddr>,
+    health: Vec<ServerHealth>,
+    selection: RemoteSelection,
+    cooldown: Duration,
+    active: AtomicUsize,
+    next: AtomicUsize,
+}
+
+impl RemoteServers {
+    pub fn new(
+        primary: TransportAddr,
+        fallbacks: Vec<TransportAddr>,
+        selection: RemoteSelection,
+        cooldown: Duration,
+    ) -> Self {
+        let servers: Vec<TransportAddr> = std::iter::once(primary).chain(fallbacks).collect();
+        let health = servers
+            .iter()
+            .map(|_| ServerHealth {
+                failures: AtomicU32::new(0),
+                unavailable_until: Mutex::new(None),
+                http2_unavailable_until: Mutex::new(None),
+            })
+            .collect();
+        Self {
+            servers,
+            health,
+            selection,
+            cooldown,
+            active: AtomicUsize::new(0),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn get(&self, ix: usize) -> &TransportAddr {
+        &self.servers[ix]
+    }
+
+    /// Last server we managed to connect to, or the primary one if none yet
+    pub fn active(&self) -> &TransportAddr {
+        &self.servers[self.active.load(Ordering::Relaxed)]
+    }
+
+    fn is_cooling_down(&self, ix: usize, now: Instant) -> bool {
+        self.health[ix]
+            .unavailable_until
+            .lock()
+            .is_some_and(|until| until > now)
+    }
+
+    /// Index of the servers to try, starting from the active one or the next in rotation.
+    /// Servers in cooldown are kept at the end, in case every other one is also down
+    pub fn connection_order(&self) -> impl Iterator<Item = usize> {
+        let len = self.servers.len();
+        let start = match self.selection {
+            RemoteSelection::Failover => self.active.load(Ordering::Relaxed),
+            RemoteSelection::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % len,
+        };
+
+        let now = Instant::now();
+        let (available, cooling_down): (Vec<usize>, Vec<usize>) = (0..len)
+            .map(|ix| (start + ix) % len)
+            .partition(|ix| !self.is_cooling_down(*ix, now));
+
+        available.into_iter().chain(cooling_down)
+    }
+
+    pub fn mark_success(&self, ix: usize) {
+        *self.health[ix].unavailable_until.lock() = None;
+        let nb_failures = self.health[ix].failures.swap(0, Ordering::Relaxed);
+        if nb_failures > 0 && self.servers.len() > 1 {
+            info!("Server {:?} is reachable again", self.servers[ix]);
+        }
+
+        let previous = self.active.swap(ix, Ordering::Relaxed);
+        if previous != ix && self.selection == RemoteSelection::Failover {
+            info!(
+                "Switching active server from {:?} to {:?}",
+                self.servers[previous], self.servers[ix]
+            );
+        }
+    }
+
+    /// Http2 does not go through to the active server, it is still in cooldown since it was marked so
+    pub fn is_active_http2_unavailable(&self) -> bool {
+        self.health[self.active.load(Ordering::Relaxed)]
+            .http2_unavailable_until
+            .lock()
+            .is_some_and(|until| until > Instant::now())
+    }
+
+    pub fn mark_http2_unavailable(&self, ix: usize) {
+        let mut until = self.health[ix].http2_unavailable_until.lock();
+        let now = Instant::now();
+        if until.is_none_or(|until| until <= now) {
+            warn!(
+                "Http2 does not go through to server {:?}, using websocket for the next {:?}",
+                self.servers[ix], HTTP2_UNAVAILABLE_COOLDOWN
+            );
+        }
+        *until = now.checked_add(HTTP2_UNAVAILABLE_COOLDOWN);
+    }
+
+    pub fn mark_failure(&self, ix: usize) {
+        *self.health[ix].unavailable_until.lock() = Instant::now().checked_add(self.cooldown);
+        let nb_failures = self.health[ix].failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.servers.len() > 1 {
+            warn!(
+                "Server {:?} is unreachable ({} failures in a row), skipping it for {:?}",
+                self.servers[ix], nb_failures, self.cooldown
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::TransportScheme;
+    use url::Host;
+
+    fn servers(selection: RemoteSelection) -> RemoteServers {
+        let addr = |port| TransportAddr::new(TransportScheme::Ws, Host::Domain("localhost".to_string()), port, None);
+        RemoteServers::new(
+            addr(1).unwrap(),
+            vec![addr(2).unwrap(), addr(3).unwrap()],
+            selection,
+            Duration::from_secs(30),
+        )
+    }
+
+    #[test]
+    fn test_failover_is_sticky() {
+        let servers = servers(RemoteSelection::Failover);
+        assert_eq!(servers.connection_order().collect::<Vec<_>>(), vec![0, 1, 2]);
+
+        assert_eq!(servers.active().port(), 1);
+
+        servers.mark_failure(0);
+        servers.mark_success(1);
+        assert_eq!(servers.active().port(), 2);
+        assert_eq!(servers.connection_order().collect::<Vec<_>>(), vec![1, 2, 0]);
+        assert_eq!(servers.connection_order().collect::<Vec<_>>(), vec![1, 2, 0]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_http2_unavailable_expires() {
+        let servers = servers(RemoteSelection::Failover);
+        servers.mark_http2_unavailable(1);
+        assert!(!servers.is_active_http2_unavailable());
+
+        // Only the server it failed with is affected
+        servers.mark_success(1);
+        assert!(servers.is_active_http2_unavailable());
+        servers.mark_success(0);
+        assert!(!servers.is_active_http2_unavailable());
+
+        servers.mark_success(1);
+        tokio::time::advance(HTTP2_UNAVAILABLE_COOLDOWN).await;
+        assert!(!servers.is_active_http2_unavailable());
+    }
+
+    #[test]
+    fn test_round_robin_skips_servers_in_cooldown() {
+        let servers = servers(RemoteSelection::RoundRobin);
+        assert_eq!(servers.connection_order().next(), Some(0));
+        assert_eq!(servers.connection_order().next(), Some(1));
+        assert_eq!(servers.connection_order().next(), Some(2));
+
+        servers.mark_failure(1);
+        assert_eq!(servers.connection_order().collect::<Vec<_>>(), vec![0, 2, 1]);
+        assert_eq!(servers.connection_order().collect::<Vec<_>>(), vec![2, 0, 1]);
+
+        servers.mark_success(1);
+        assert_eq!(servers.connection_order().collect::<Vec<_>>(), vec![2, 0, 1]);
+        assert_eq!(servers.connection_order().collect::<Vec<_>>(), vec![0, 1, 2]);
+    }
+}
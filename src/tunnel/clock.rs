@@ -0,0 +1,33 @@
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Source of time of the tunnels: idle timeout, pings, max duration and reconnection backoff.
+/// To control it in tests, either with tokio::time::pause on the default one or with a mock
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()>;
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        // Far enough in the future to never fire, if the duration overflows
+        let deadline = self
+            .now()
+            .checked_add(duration)
+            .unwrap_or_else(|| self.now() + Duration::from_secs(86400 * 365 * 30));
+        self.sleep_until(deadline)
+    }
+}
+
+/// Default clock, the one of the tokio runtime
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        tokio::time::sleep_until(deadline).boxed()
+    }
+}
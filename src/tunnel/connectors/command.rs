@@ -0,0 +1,161 @@
+use std::io;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::task::{Context, Poll};
+
+use anyhow::{anyhow, Context as _};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader, ReadBuf};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tracing::{warn, Instrument, Span};
+use url::Url;
+
+use crate::tunnel::connectors::TunnelConnector;
+use crate::tunnel::RemoteAddr;
+
+/// Run a fixed command for each tunnel, and connect the tunnel to its stdin/stdout (like inetd).
+/// The command is not run by a shell: its arguments are separated by spaces, and {host} and {port} in them
+/// are replaced by the destination of the tunnel
+pub struct CommandTunnelConnector<'a> {
+    command: &'a [String],
+}
+
+impl<'a> CommandTunnelConnector<'a> {
+    pub fn new(command: &'a [String]) -> CommandTunnelConnector<'a> {
+        CommandTunnelConnector { command }
+    }
+
+    fn args(&self, remote: &Option<RemoteAddr>) -> anyhow::Result<Vec<String>> {
+        let (host, port) = match remote {
+            Some(remote) => (remote.host.to_string(), remote.port.to_string()),
+            None => (String::new(), String::new()),
+        };
+        // Each value ends up in a single argument, but must not be taken for an option of the command, nor hold
+        // anything else than a hostname or an ip. The port is a number, so it is always safe
+        if host.starts_with('-') || !host.chars().all(is_host_char) {
+            return Err(anyhow!("Invalid host {} for command tunnel", host));
+        }
+
+        Ok(self
+            .command
+            .iter()
+            .map(|arg| arg.replace("{host}", &host).replace("{port}", &port))
+            .collect())
+    }
+}
+
+fn is_host_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | ':' | '[' | ']')
+}
+
+/// Stdout of the command of a tunnel. The command is killed once it is dropped, i.e: when the tunnel is closed
+pub struct CommandStdout {
+    stdout: ChildStdout,
+    _child: Child,
+}
+
+impl AsyncRead for CommandStdout {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
+
+impl TunnelConnector for CommandTunnelConnector<'_> {
+    type Reader = CommandStdout;
+    type Writer = ChildStdin;
+
+    async fn connect(&self, remote: &Option<RemoteAddr>) -> anyhow::Result<(Self::Reader, Self::Writer)> {
+        let args = self.args(remote)?;
+        let Some((program, args)) = args.split_first() else {
+            return Err(anyhow!("Empty command for command tunnel"));
+        };
+
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Cannot run command {:?}", program))?;
+
+        let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+            return Err(anyhow!("Cannot get stdio of command {:?}", program));
+        };
+        if let Some(stderr) = child.stderr.take() {
+            let program = program.clone();
+            tokio::spawn(
+                async move {
+                    let mut lines = BufReader::new(stderr).lines();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        warn!("{}: {}", program, line);
+                    }
+                }
+                .instrument(Span::current()),
+            );
+        }
+
+        Ok((CommandStdout { stdout, _child: child }, stdin))
+    }
+
+    async fn connect_with_http_proxy(
+        &self,
+        _proxy: &Url,
+        _remote: &Option<RemoteAddr>,
+    ) -> anyhow::Result<(Self::Reader, Self::Writer)> {
+        Err(anyhow!("Command tunneling is not supported with HTTP proxy"))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::LocalProtocol;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use url::Host;
+
+    #[tokio::test]
+    async fn test_command_tunnel_stdio() {
+        let command = ["sh", "-c", "read line; echo \"$line {host}:{port}\""].map(str::to_string);
+        let remote = RemoteAddr {
+            protocol: LocalProtocol::Exec,
+            host: Host::Domain("n.lan".to_string()),
+            port: 22,
+            source: None,
+            request_id: None,
+        };
+
+        let (mut rx, mut tx) = CommandTunnelConnector::new(&command)
+            .connect(&Some(remote.clone()))
+            .await
+            .unwrap();
+        tx.write_all(b"hello\n").await.unwrap();
+        let mut out = String::new();
+        rx.read_to_string(&mut out).await.unwrap();
+        assert_eq!(out, "hello n.lan:22\n");
+
+        let remote = RemoteAddr {
+            host: Host::Domain("-oProxyCommand=x".to_string()),
+            ..remote
+        };
+        assert!(CommandTunnelConnector::new(&command)
+            .connect(&Some(remote.clone()))
+            .await
+            .is_err());
+
+        for host in ["n.lan;reboot", "n.lan host", "$(id)", "n.lan\n"] {
+            let remote = RemoteAddr {
+                host: Host::Domain(host.to_string()),
+                ..remote.clone()
+            };
+            assert!(CommandTunnelConnector::new(&command).args(&Some(remote)).is_err());
+        }
+        let remote = RemoteAddr {
+            host: Host::Ipv6("::1".parse().unwrap()),
+            ..remote
+        };
+        assert_eq!(
+            CommandTunnelConnector::new(&command).args(&Some(remote)).unwrap()[2],
+            "read line; echo \"$line [::1]:22\""
+        );
+    }
+}
@@ -1,16 +1,33 @@
+use std::net::SocketAddr;
+
+use anyhow::anyhow;
+use ipnet::IpNet;
+use log::warn;
 use tokio::io::{AsyncRead, AsyncWrite};
-use url::Url;
+use url::{Host, Url};
 
+pub use command::CommandTunnelConnector;
 pub use sock5::Socks5TunnelConnector;
 pub use tcp::TcpTunnelConnector;
+pub use tls::TlsTunnelConnector;
 pub use udp::UdpTunnelConnector;
+#[cfg(unix)]
+pub use unix_sock::UnixSocketTunnelConnector;
 
+use crate::protocols;
+use crate::protocols::dns::DnsResolver;
 use crate::tunnel::RemoteAddr;
 
+mod command;
 mod sock5;
 mod tcp;
+mod tls;
 mod udp;
+#[cfg(unix)]
+mod unix_sock;
 
+// The futures are spawned on the tokio runtime by the tunnels, implementations must keep them Send
+#[allow(async_fn_in_trait)]
 pub trait TunnelConnector {
     type Reader: AsyncRead + Send + 'static;
     type Writer: AsyncWrite + Send + 'static;
@@ -21,4 +38,52 @@ pub trait TunnelConnector {
         proxy: &Url,
         remote: &Option<RemoteAddr>,
     ) -> anyhow::Result<(Self::Reader, Self::Writer)>;
+
+    /// Open connections in advance, for the next call to connect to not have to wait for them.
+    /// Called by reverse tunnels while they wait for the server to hand over a client
+    async fn prewarm(&self) {}
+}
+
+// Resolve the destination, leaving out its addresses in the denied networks
+async fn resolve_allowed(
+    host: &Host,
+    port: u16,
+    dns_resolver: &DnsResolver,
+    denied_cidrs: &[IpNet],
+) -> anyhow::Result<Vec<SocketAddr>> {
+    let socket_addrs = protocols::tcp::resolve(host, port, dns_resolver).await?;
+    if denied_cidrs.is_empty() {
+        return Ok(socket_addrs);
+    }
+
+    let (denied, allowed): (Vec<_>, Vec<_>) = socket_addrs
+        .into_iter()
+        .partition(|addr| denied_cidrs.iter().any(|cidr| cidr.contains(&addr.ip())));
+    if allowed.is_empty() {
+        return Err(anyhow!("{}:{} only resolves to denied addresses {:?}", host, port, denied));
+    }
+    if !denied.is_empty() {
+        warn!("Skipping denied addresses {:?} of {}:{}", denied, host, port);
+    }
+
+    Ok(allowed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_resolve_allowed_skips_denied_addresses() {
+        let dns_resolver = DnsResolver::System { prefer_ipv6: false };
+        let host = Host::Domain("localhost".to_string());
+        let loopback: Vec<IpNet> = vec!["127.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()];
+
+        assert!(!resolve_allowed(&host, 80, &dns_resolver, &[]).await.unwrap().is_empty());
+        assert!(resolve_allowed(&host, 80, &dns_resolver, &loopback).await.is_err());
+
+        let other: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+        let addrs = resolve_allowed(&host, 80, &dns_resolver, &other).await.unwrap();
+        assert!(addrs.iter().all(|addr| addr.ip().is_loopback()));
+    }
 }
@@ -9,6 +9,7 @@ use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use url::Url;
 
 use crate::protocols::dns::DnsResolver;
+use crate::protocols::tcp::{SocketBind, TcpSocketOptions};
 use crate::protocols::udp;
 use crate::protocols::udp::WsUdpSocket;
 use crate::tunnel::connectors::TunnelConnector;
@@ -18,17 +19,45 @@ use crate::{protocols, LocalProtocol};
 pub struct Socks5TunnelConnector<'a> {
     so_mark: Option<u32>,
     connect_timeout: Duration,
+    happy_eyeballs_delay: Duration,
+    connect_parallelism: usize,
+    tcp_options: TcpSocketOptions,
     dns_resolver: &'a DnsResolver,
 }
 
 impl Socks5TunnelConnector<'_> {
-    pub fn new(so_mark: Option<u32>, connect_timeout: Duration, dns_resolver: &DnsResolver) -> Socks5TunnelConnector {
+    pub fn new(
+        so_mark: Option<u32>,
+        connect_timeout: Duration,
+        dns_resolver: &DnsResolver,
+    ) -> Socks5TunnelConnector<'_> {
         Socks5TunnelConnector {
             so_mark,
             connect_timeout,
+            happy_eyeballs_delay: protocols::tcp::DEFAULT_HAPPY_EYEBALLS_DELAY,
+            connect_parallelism: protocols::tcp::DEFAULT_CONNECT_PARALLELISM,
+            tcp_options: TcpSocketOptions::default(),
             dns_resolver,
         }
     }
+
+    /// Head start given to each resolved address before trying the next one
+    pub fn with_happy_eyeballs_delay(mut self, happy_eyeballs_delay: Duration) -> Self {
+        self.happy_eyeballs_delay = happy_eyeballs_delay;
+        self
+    }
+
+    /// Number of resolved addresses tried at once, the first one to connect wins
+    pub fn with_connect_parallelism(mut self, connect_parallelism: usize) -> Self {
+        self.connect_parallelism = connect_parallelism;
+        self
+    }
+
+    /// TCP options of the outbound sockets (nodelay, keepalive)
+    pub fn with_tcp_options(mut self, tcp_options: TcpSocketOptions) -> Self {
+        self.tcp_options = tcp_options;
+        self
+    }
 }
 
 impl TunnelConnector for Socks5TunnelConnector<'_> {
@@ -42,21 +71,34 @@ impl TunnelConnector for Socks5TunnelConnector<'_> {
 
         match remote.protocol {
             LocalProtocol::Tcp { proxy_protocol: _ } => {
-                let stream = protocols::tcp::connect(
+                let socket_addrs = protocols::tcp::resolve(&remote.host, remote.port, self.dns_resolver).await?;
+                let stream = protocols::tcp::connect_to_addrs(
                     &remote.host,
                     remote.port,
-                    self.so_mark,
-                    self.connect_timeout,
-                    self.dns_resolver,
+                    socket_addrs,
+                    &protocols::tcp::ConnectOptions {
+                        so_mark: self.so_mark,
+                        socket_bind: &SocketBind::default(),
+                        tcp_options: &self.tcp_options,
+                        connect_timeout: self.connect_timeout,
+                        happy_eyeballs_delay: self.happy_eyeballs_delay,
+                        connect_parallelism: self.connect_parallelism,
+                    },
                 )
                 .await?;
                 let (reader, writer) = stream.into_split();
                 Ok((Socks5Reader::Tcp(reader), Socks5Writer::Tcp(writer)))
             }
             LocalProtocol::Udp { .. } => {
-                let stream =
-                    udp::connect(&remote.host, remote.port, self.connect_timeout, self.so_mark, self.dns_resolver)
-                        .await?;
+                let stream = udp::connect(
+                    &remote.host,
+                    remote.port,
+                    self.connect_timeout,
+                    self.so_mark,
+                    &SocketBind::default(),
+                    self.dns_resolver,
+                )
+                .await?;
                 Ok((Socks5Reader::Udp(stream.clone()), Socks5Writer::Udp(stream)))
             }
             _ => Err(anyhow!("Invalid protocol for reverse socks5 {:?}", remote.protocol)),
@@ -65,8 +107,8 @@ impl TunnelConnector for Socks5TunnelConnector<'_> {
 
     async fn connect_with_http_proxy(
         &self,
-        proxy: &Url,
-        remote: &Option<RemoteAddr>,
+        _proxy: &Url,
+        _remote: &Option<RemoteAddr>,
     ) -> anyhow::Result<(Self::Reader, Self::Writer)> {
         Err(anyhow!("SOCKS5 tunneling is not supported with HTTP proxy"))
     }
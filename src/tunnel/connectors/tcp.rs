@@ -1,11 +1,21 @@
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::mem::MaybeUninit;
+use std::net::SocketAddr;
 use std::time::Duration;
 
+use ipnet::IpNet;
+use log::{debug, warn};
+use parking_lot::Mutex;
+use socket2::SockRef;
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
 use url::{Host, Url};
 
 use crate::protocols;
 use crate::protocols::dns::DnsResolver;
-use crate::tunnel::connectors::TunnelConnector;
+use crate::protocols::tcp::{SocketBind, TcpSocketOptions};
+use crate::tunnel::connectors::{resolve_allowed, TunnelConnector};
 use crate::tunnel::RemoteAddr;
 
 pub struct TcpTunnelConnector<'a> {
@@ -13,7 +23,17 @@ pub struct TcpTunnelConnector<'a> {
     port: u16,
     so_mark: Option<u32>,
     connect_timeout: Duration,
+    happy_eyeballs_delay: Duration,
+    connect_parallelism: usize,
+    socket_bind: SocketBind,
+    tcp_options: TcpSocketOptions,
     dns_resolver: &'a DnsResolver,
+    // Resolved addresses in those networks are never connected to
+    denied_cidrs: Vec<IpNet>,
+    pool_size: usize,
+    // Warm connections and resolved addresses of the fixed destination, only used when pool_size > 0
+    pool: Mutex<VecDeque<TcpStream>>,
+    resolved_addrs: Mutex<Option<Vec<SocketAddr>>>,
 }
 
 impl<'a> TcpTunnelConnector<'a> {
@@ -29,22 +49,145 @@ impl<'a> TcpTunnelConnector<'a> {
             port,
             so_mark,
             connect_timeout,
+            happy_eyeballs_delay: protocols::tcp::DEFAULT_HAPPY_EYEBALLS_DELAY,
+            connect_parallelism: protocols::tcp::DEFAULT_CONNECT_PARALLELISM,
+            socket_bind: SocketBind::default(),
+            tcp_options: TcpSocketOptions::default(),
             dns_resolver,
+            denied_cidrs: vec![],
+            pool_size: 0,
+            pool: Mutex::new(VecDeque::new()),
+            resolved_addrs: Mutex::new(None),
+        }
+    }
+
+    /// Head start given to each resolved address before trying the next one
+    pub fn with_happy_eyeballs_delay(mut self, happy_eyeballs_delay: Duration) -> Self {
+        self.happy_eyeballs_delay = happy_eyeballs_delay;
+        self
+    }
+
+    /// Number of resolved addresses tried at once, the first one to connect wins
+    pub fn with_connect_parallelism(mut self, connect_parallelism: usize) -> Self {
+        self.connect_parallelism = connect_parallelism;
+        self
+    }
+
+    /// Bind the outbound sockets to a local address and/or network interface
+    pub fn with_socket_bind(mut self, socket_bind: SocketBind) -> Self {
+        self.socket_bind = socket_bind;
+        self
+    }
+
+    /// TCP options of the outbound sockets (nodelay, keepalive)
+    pub fn with_tcp_options(mut self, tcp_options: TcpSocketOptions) -> Self {
+        self.tcp_options = tcp_options;
+        self
+    }
+
+    /// Never connect to the addresses the destination resolves to in those networks
+    pub fn with_denied_cidrs(mut self, denied_cidrs: Vec<IpNet>) -> Self {
+        self.denied_cidrs = denied_cidrs;
+        self
+    }
+
+    /// Keep up to pool_size connections open in advance to the fixed destination, and cache its resolution.
+    /// Connections to a destination given by the server (i.e: reverse socks5) are never pooled
+    pub fn with_pool_size(mut self, pool_size: usize) -> Self {
+        self.pool_size = pool_size;
+        self
+    }
+
+    async fn connect_to_fixed_destination(&self) -> anyhow::Result<TcpStream> {
+        let cached_addrs = self.resolved_addrs.lock().clone();
+        let socket_addrs = match cached_addrs {
+            Some(socket_addrs) => socket_addrs,
+            None => {
+                let socket_addrs = resolve_allowed(self.host, self.port, self.dns_resolver, &self.denied_cidrs).await?;
+                *self.resolved_addrs.lock() = Some(socket_addrs.clone());
+                socket_addrs
+            }
+        };
+
+        let stream = protocols::tcp::connect_to_addrs(
+            self.host,
+            self.port,
+            socket_addrs,
+            &protocols::tcp::ConnectOptions {
+                so_mark: self.so_mark,
+                socket_bind: &self.socket_bind,
+                tcp_options: &self.tcp_options,
+                connect_timeout: self.connect_timeout,
+                happy_eyeballs_delay: self.happy_eyeballs_delay,
+                connect_parallelism: self.connect_parallelism,
+            },
+        )
+        .await;
+        if stream.is_err() {
+            // The destination may have moved, resolve it again next time
+            *self.resolved_addrs.lock() = None;
+        }
+        stream
+    }
+
+    fn take_pooled_connection(&self) -> Option<TcpStream> {
+        loop {
+            let stream = self.pool.lock().pop_front()?;
+            if is_alive(&stream) {
+                return Some(stream);
+            }
+            debug!(
+                "Discarding pooled connection to {}:{} closed by the endpoint",
+                self.host, self.port
+            );
         }
     }
 }
 
+// A pooled connection must not have been closed by the endpoint while waiting.
+// Data already sent by the endpoint (i.e: ssh banner) is only peeked, to be forwarded once the tunnel is up
+fn is_alive(stream: &TcpStream) -> bool {
+    let mut buf = [MaybeUninit::<u8>::uninit(); 1];
+    match SockRef::from(stream).peek(&mut buf) {
+        Ok(0) => false,
+        Ok(_) => true,
+        Err(err) => err.kind() == ErrorKind::WouldBlock,
+    }
+}
+
 impl TunnelConnector for TcpTunnelConnector<'_> {
     type Reader = OwnedReadHalf;
     type Writer = OwnedWriteHalf;
 
     async fn connect(&self, remote: &Option<RemoteAddr>) -> anyhow::Result<(Self::Reader, Self::Writer)> {
+        if remote.is_none() && self.pool_size > 0 {
+            let stream = match self.take_pooled_connection() {
+                Some(stream) => stream,
+                None => self.connect_to_fixed_destination().await?,
+            };
+            return Ok(stream.into_split());
+        }
+
         let (host, port) = match remote {
             Some(remote) => (&remote.host, remote.port),
             None => (self.host, self.port),
         };
 
-        let stream = protocols::tcp::connect(host, port, self.so_mark, self.connect_timeout, self.dns_resolver).await?;
+        let socket_addrs = resolve_allowed(host, port, self.dns_resolver, &self.denied_cidrs).await?;
+        let stream = protocols::tcp::connect_to_addrs(
+            host,
+            port,
+            socket_addrs,
+            &protocols::tcp::ConnectOptions {
+                so_mark: self.so_mark,
+                socket_bind: &self.socket_bind,
+                tcp_options: &self.tcp_options,
+                connect_timeout: self.connect_timeout,
+                happy_eyeballs_delay: self.happy_eyeballs_delay,
+                connect_parallelism: self.connect_parallelism,
+            },
+        )
+        .await?;
         Ok(stream.into_split())
     }
 
@@ -63,10 +206,61 @@ impl TunnelConnector for TcpTunnelConnector<'_> {
             host,
             port,
             self.so_mark,
+            &self.socket_bind,
             self.connect_timeout,
             self.dns_resolver,
         )
         .await?;
         Ok(stream.into_split())
     }
+
+    async fn prewarm(&self) {
+        if self.pool_size == 0 {
+            return;
+        }
+
+        self.pool.lock().retain(is_alive);
+        loop {
+            if self.pool.lock().len() >= self.pool_size {
+                return;
+            }
+            match self.connect_to_fixed_destination().await {
+                Ok(stream) => self.pool.lock().push_back(stream),
+                Err(err) => {
+                    warn!("Cannot open connection in advance to {}:{}: {:?}", self.host, self.port, err);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::dns::DnsResolver;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_pooled_connections() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:1302").await.unwrap();
+        let host = Host::Ipv4("127.0.0.1".parse().unwrap());
+        let dns_resolver = DnsResolver::System { prefer_ipv6: false };
+        let connector =
+            TcpTunnelConnector::new(&host, 1302, None, Duration::from_secs(1), &dns_resolver).with_pool_size(2);
+
+        connector.prewarm().await;
+        assert_eq!(connector.pool.lock().len(), 2);
+        let (mut closed, _) = listener.accept().await.unwrap();
+        let (mut alive, _) = listener.accept().await.unwrap();
+
+        // The connection closed by the endpoint is discarded, the one with pending data is kept
+        closed.shutdown().await.unwrap();
+        alive.write_all(b"banner").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let (rx, _tx) = connector.connect(&None).await.unwrap();
+        assert_eq!(rx.peer_addr().unwrap(), alive.local_addr().unwrap());
+        assert_eq!(rx.local_addr().unwrap(), alive.peer_addr().unwrap());
+        assert!(connector.pool.lock().is_empty());
+    }
 }
@@ -0,0 +1,150 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use anyhow::Context;
+use tokio::io::{Join, ReadHalf, WriteHalf};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::ClientConfig;
+use tokio_rustls::TlsConnector;
+use url::{Host, Url};
+
+use crate::tunnel::connectors::TunnelConnector;
+use crate::tunnel::RemoteAddr;
+
+/// Do a TLS handshake with the destination over the connection of the inner connector, for the tunnel to carry the
+/// plaintext protocol. The host of the destination is used as SNI and as the name its certificate is verified against
+pub struct TlsTunnelConnector<'a, C> {
+    inner: C,
+    host: &'a Host,
+    port: u16,
+    tls_client_config: Arc<ClientConfig>,
+}
+
+impl<'a, C> TlsTunnelConnector<'a, C> {
+    /// host and port are the destination when connect is called without one, as for the inner connector
+    pub fn new(inner: C, host: &'a Host, port: u16, tls_client_config: Arc<ClientConfig>) -> TlsTunnelConnector<'a, C> {
+        TlsTunnelConnector {
+            inner,
+            host,
+            port,
+            tls_client_config,
+        }
+    }
+}
+
+fn server_name(host: &Host) -> anyhow::Result<ServerName<'static>> {
+    match host {
+        Host::Domain(domain) => {
+            ServerName::try_from(domain.clone()).with_context(|| format!("Invalid TLS server name {}", domain))
+        }
+        Host::Ipv4(ip) => Ok(ServerName::IpAddress(IpAddr::V4(*ip).into())),
+        Host::Ipv6(ip) => Ok(ServerName::IpAddress(IpAddr::V6(*ip).into())),
+    }
+}
+
+impl<C> TlsTunnelConnector<'_, C>
+where
+    C: TunnelConnector,
+    C::Reader: Unpin,
+    C::Writer: Unpin,
+{
+    async fn handshake(
+        &self,
+        remote: &Option<RemoteAddr>,
+        (reader, writer): (C::Reader, C::Writer),
+    ) -> anyhow::Result<(<Self as TunnelConnector>::Reader, <Self as TunnelConnector>::Writer)> {
+        let (host, port) = match remote {
+            Some(remote) => (&remote.host, remote.port),
+            None => (self.host, self.port),
+        };
+
+        let tls_stream = TlsConnector::from(self.tls_client_config.clone())
+            .connect(server_name(host)?, tokio::io::join(reader, writer))
+            .await
+            .with_context(|| format!("failed to do TLS handshake with {}:{}", host, port))?;
+        Ok(tokio::io::split(tls_stream))
+    }
+}
+
+impl<C> TunnelConnector for TlsTunnelConnector<'_, C>
+where
+    C: TunnelConnector,
+    C::Reader: Unpin,
+    C::Writer: Unpin,
+{
+    type Reader = ReadHalf<TlsStream<Join<C::Reader, C::Writer>>>;
+    type Writer = WriteHalf<TlsStream<Join<C::Reader, C::Writer>>>;
+
+    async fn connect(&self, remote: &Option<RemoteAddr>) -> anyhow::Result<(Self::Reader, Self::Writer)> {
+        let stream = self.inner.connect(remote).await?;
+        self.handshake(remote, stream).await
+    }
+
+    async fn connect_with_http_proxy(
+        &self,
+        proxy: &Url,
+        remote: &Option<RemoteAddr>,
+    ) -> anyhow::Result<(Self::Reader, Self::Writer)> {
+        let stream = self.inner.connect_with_http_proxy(proxy, remote).await?;
+        self.handshake(remote, stream).await
+    }
+
+    async fn prewarm(&self) {
+        self.inner.prewarm().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embedded_certificate::{TLS_CERTIFICATE, TLS_PRIVATE_KEY};
+    use crate::protocols::dns::DnsResolver;
+    use crate::protocols::tls::{tls_client_config, TlsClientOptions};
+    use crate::tunnel::connectors::TcpTunnelConnector;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsAcceptor;
+
+    #[tokio::test]
+    async fn test_tls_connector_terminates_tls() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server_config = tokio_rustls::rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(TLS_CERTIFICATE.clone(), TLS_PRIVATE_KEY.clone_key())
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream.flush().await.unwrap();
+        });
+
+        let host = Host::Ipv4("127.0.0.1".parse().unwrap());
+        let dns_resolver = DnsResolver::System { prefer_ipv6: false };
+        // The embedded certificate is self-signed, only accepted without verification
+        let tls_client_config = tls_client_config(TlsClientOptions {
+            tls_verify_certificate: false,
+            ..Default::default()
+        })
+        .unwrap();
+        let connector = TlsTunnelConnector::new(
+            TcpTunnelConnector::new(&host, port, None, Duration::from_secs(1), &dns_resolver),
+            &host,
+            port,
+            tls_client_config,
+        );
+
+        let (mut rx, mut tx) = connector.connect(&None).await.unwrap();
+        tx.write_all(b"hello").await.unwrap();
+        tx.flush().await.unwrap();
+        let mut buf = [0u8; 5];
+        rx.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        server.await.unwrap();
+    }
+}
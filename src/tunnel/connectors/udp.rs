@@ -1,12 +1,14 @@
 use std::time::Duration;
 
 use anyhow::anyhow;
+use ipnet::IpNet;
 use url::{Host, Url};
 
 use crate::protocols;
 use crate::protocols::dns::DnsResolver;
+use crate::protocols::tcp::SocketBind;
 use crate::protocols::udp::WsUdpSocket;
-use crate::tunnel::connectors::TunnelConnector;
+use crate::tunnel::connectors::{resolve_allowed, TunnelConnector};
 use crate::tunnel::RemoteAddr;
 
 pub struct UdpTunnelConnector<'a> {
@@ -14,7 +16,10 @@ pub struct UdpTunnelConnector<'a> {
     port: u16,
     so_mark: Option<u32>,
     connect_timeout: Duration,
+    socket_bind: SocketBind,
     dns_resolver: &'a DnsResolver,
+    // Resolved addresses in those networks are never connected to
+    denied_cidrs: Vec<IpNet>,
 }
 
 impl<'a> UdpTunnelConnector<'a> {
@@ -30,9 +35,23 @@ impl<'a> UdpTunnelConnector<'a> {
             port,
             so_mark,
             connect_timeout,
+            socket_bind: SocketBind::default(),
             dns_resolver,
+            denied_cidrs: vec![],
         }
     }
+
+    /// Bind the outbound sockets to a local address and/or network interface
+    pub fn with_socket_bind(mut self, socket_bind: SocketBind) -> Self {
+        self.socket_bind = socket_bind;
+        self
+    }
+
+    /// Never connect to the addresses the destination resolves to in those networks
+    pub fn with_denied_cidrs(mut self, denied_cidrs: Vec<IpNet>) -> Self {
+        self.denied_cidrs = denied_cidrs;
+        self
+    }
 }
 
 impl TunnelConnector for UdpTunnelConnector<'_> {
@@ -40,17 +59,24 @@ impl TunnelConnector for UdpTunnelConnector<'_> {
     type Writer = WsUdpSocket;
 
     async fn connect(&self, _: &Option<RemoteAddr>) -> anyhow::Result<(Self::Reader, Self::Writer)> {
-        let stream =
-            protocols::udp::connect(self.host, self.port, self.connect_timeout, self.so_mark, self.dns_resolver)
-                .await?;
+        let socket_addrs = resolve_allowed(self.host, self.port, self.dns_resolver, &self.denied_cidrs).await?;
+        let stream = protocols::udp::connect_to_addrs(
+            self.host,
+            self.port,
+            socket_addrs,
+            self.connect_timeout,
+            self.so_mark,
+            &self.socket_bind,
+        )
+        .await?;
 
         Ok((stream.clone(), stream))
     }
 
     async fn connect_with_http_proxy(
         &self,
-        proxy: &Url,
-        remote: &Option<RemoteAddr>,
+        _proxy: &Url,
+        _remote: &Option<RemoteAddr>,
     ) -> anyhow::Result<(Self::Reader, Self::Writer)> {
         Err(anyhow!("UDP tunneling is not supported with HTTP proxy"))
     }
@@ -0,0 +1,72 @@
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::UnixStream;
+use url::Url;
+
+use crate::tunnel::connectors::TunnelConnector;
+use crate::tunnel::RemoteAddr;
+
+pub struct UnixSocketTunnelConnector<'a> {
+    path: &'a Path,
+}
+
+impl<'a> UnixSocketTunnelConnector<'a> {
+    pub fn new(path: &'a Path) -> UnixSocketTunnelConnector<'a> {
+        UnixSocketTunnelConnector { path }
+    }
+}
+
+impl TunnelConnector for UnixSocketTunnelConnector<'_> {
+    type Reader = OwnedReadHalf;
+    type Writer = OwnedWriteHalf;
+
+    async fn connect(&self, _remote: &Option<RemoteAddr>) -> anyhow::Result<(Self::Reader, Self::Writer)> {
+        // Check the path first, the error of connect() alone is not explicit about what is wrong
+        let metadata = tokio::fs::metadata(self.path)
+            .await
+            .with_context(|| format!("Cannot access unix socket {:?}", self.path))?;
+        if !metadata.file_type().is_socket() {
+            return Err(anyhow!("{:?} is not a unix socket", self.path));
+        }
+
+        let stream = UnixStream::connect(self.path)
+            .await
+            .with_context(|| format!("Cannot connect to unix socket {:?}", self.path))?;
+        Ok(stream.into_split())
+    }
+
+    async fn connect_with_http_proxy(
+        &self,
+        _proxy: &Url,
+        _remote: &Option<RemoteAddr>,
+    ) -> anyhow::Result<(Self::Reader, Self::Writer)> {
+        Err(anyhow!("Unix socket tunneling is not supported with HTTP proxy"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_connect_to_unix_socket() {
+        let socket_path = std::env::temp_dir().join(format!("wstunnel-connector-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket_path);
+        let listener = tokio::net::UnixListener::bind(&socket_path).unwrap();
+
+        let connector = UnixSocketTunnelConnector::new(&socket_path);
+        assert!(connector.connect(&None).await.is_ok());
+        assert!(listener.accept().await.is_ok());
+        let _ = std::fs::remove_file(&socket_path);
+
+        let not_a_socket = std::env::temp_dir();
+        let err = UnixSocketTunnelConnector::new(&not_a_socket)
+            .connect(&None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("is not a unix socket"));
+    }
+}
@@ -0,0 +1,191 @@
+use crate::protocols::tcp::DomainResolutionError;
+use hyper::http::{HeaderMap, HeaderName, HeaderValue};
+use hyper::StatusCode;
+use std::fmt::{Debug, Display, Formatter};
+use std::io;
+use std::time::Duration;
+
+static CONNECT_ERROR_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-connect-error");
+
+/// Why the server could not open the tunnel to the destination, for the client to tell its local peer
+/// (i.e: with the reply code of socks5)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectErrorKind {
+    Refused,
+    HostUnreachable,
+    NetworkUnreachable,
+    TimedOut,
+    NotAllowed,
+    General,
+}
+
+impl ConnectErrorKind {
+    /// Classify the error with the first io error of its chain.
+    /// Failing to resolve the destination is reported as the host being unreachable
+    pub fn from_error(err: &anyhow::Error) -> Self {
+        if err.downcast_ref::<DomainResolutionError>().is_some() {
+            return Self::HostUnreachable;
+        }
+
+        err.chain()
+            .find_map(|err| err.downcast_ref::<io::Error>())
+            .map_or(Self::General, |err| match err.kind() {
+                io::ErrorKind::ConnectionRefused => Self::Refused,
+                io::ErrorKind::HostUnreachable => Self::HostUnreachable,
+                io::ErrorKind::NetworkUnreachable => Self::NetworkUnreachable,
+                io::ErrorKind::TimedOut => Self::TimedOut,
+                _ => Self::General,
+            })
+    }
+
+    pub const fn to_str(self) -> &'static str {
+        match self {
+            Self::Refused => "refused",
+            Self::HostUnreachable => "host-unreachable",
+            Self::NetworkUnreachable => "network-unreachable",
+            Self::TimedOut => "timed-out",
+            Self::NotAllowed => "not-allowed",
+            Self::General => "general",
+        }
+    }
+
+    fn from_str(kind: &str) -> Self {
+        match kind {
+            "refused" => Self::Refused,
+            "host-unreachable" => Self::HostUnreachable,
+            "network-unreachable" => Self::NetworkUnreachable,
+            "timed-out" => Self::TimedOut,
+            "not-allowed" => Self::NotAllowed,
+            _ => Self::General,
+        }
+    }
+}
+
+/// Sent by the server along the rejection of the upgrade request
+pub fn set_connect_error(headers: &mut HeaderMap, kind: ConnectErrorKind) {
+    headers.insert(CONNECT_ERROR_HEADER.clone(), HeaderValue::from_static(kind.to_str()));
+}
+
+/// None if the server did not fail because of the destination, or is too old to tell
+pub fn connect_error(headers: &HeaderMap) -> Option<ConnectErrorKind> {
+    let kind = headers.get(&CONNECT_ERROR_HEADER)?.to_str().ok()?;
+    Some(ConnectErrorKind::from_str(kind))
+}
+
+/// Reason why a tunnel could not be established with the wstunnel server
+pub enum TunnelConnectError {
+    /// Cannot resolve the address of the server (or of the http proxy)
+    Dns(anyhow::Error),
+    /// Cannot open a tcp connection to the server (or through the http proxy)
+    Tcp(anyhow::Error),
+    /// TLS handshake with the server failed
+    Tls(anyhow::Error),
+    /// The server did not accept the websocket/http2 upgrade request.
+    /// status is None if the server did not send back any response.
+    /// retry_after is the delay the server asked to wait before trying again, with the Retry-After header
+    HttpUpgrade {
+        status: Option<StatusCode>,
+        retry_after: Option<Duration>,
+        cause: anyhow::Error,
+    },
+    /// Http2 does not go through to the server: ALPN did not negotiate h2,
+    /// or the connection was reset during the http2 handshake (i.e: by a middlebox only allowing http/1.1)
+    Http2Unavailable(anyhow::Error),
+    /// The server accepted the request but its response does not carry a valid tunnel token
+    JwtRejected(anyhow::Error),
+    /// Could not get a connection to the server in the allotted time
+    Timeout(anyhow::Error),
+    /// The server is reachable, but it could not connect to the destination of the tunnel
+    Destination {
+        kind: ConnectErrorKind,
+        cause: anyhow::Error,
+    },
+}
+
+impl TunnelConnectError {
+    pub const fn cause(&self) -> &anyhow::Error {
+        match self {
+            Self::Dns(cause)
+            | Self::Tcp(cause)
+            | Self::Tls(cause)
+            | Self::HttpUpgrade { cause, .. }
+            | Self::Http2Unavailable(cause)
+            | Self::JwtRejected(cause)
+            | Self::Timeout(cause)
+            | Self::Destination { cause, .. } => cause,
+        }
+    }
+
+    /// Whether trying again the same request has a chance to succeed.
+    /// Authentication/authorization failures from the server are considered definitive, as are the failures to reach
+    /// the destination, the server itself is fine
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::HttpUpgrade {
+                status: Some(status), ..
+            } => !matches!(*status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN),
+            Self::Destination { .. } => false,
+            _ => true,
+        }
+    }
+
+    /// Delay asked by the server before trying again, i.e: when it is overloaded
+    pub const fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::HttpUpgrade { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// anyhow::Error is not Clone, so copy the error kind and only keep the message of the cause
+    pub fn to_owned_lossy(&self) -> Self {
+        let cause = anyhow::anyhow!("{:?}", self.cause());
+        match self {
+            Self::Dns(_) => Self::Dns(cause),
+            Self::Tcp(_) => Self::Tcp(cause),
+            Self::Tls(_) => Self::Tls(cause),
+            Self::HttpUpgrade {
+                status, retry_after, ..
+            } => Self::HttpUpgrade {
+                status: *status,
+                retry_after: *retry_after,
+                cause,
+            },
+            Self::Http2Unavailable(_) => Self::Http2Unavailable(cause),
+            Self::JwtRejected(_) => Self::JwtRejected(cause),
+            Self::Timeout(_) => Self::Timeout(cause),
+            Self::Destination { kind, .. } => Self::Destination { kind: *kind, cause },
+        }
+    }
+}
+
+impl Display for TunnelConnectError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Dns(_) => write!(f, "cannot resolve server address"),
+            Self::Tcp(_) => write!(f, "cannot connect to server"),
+            Self::Tls(_) => write!(f, "TLS handshake with server failed"),
+            Self::HttpUpgrade {
+                status: Some(status), ..
+            } => write!(f, "server rejected upgrade request with {status}"),
+            Self::HttpUpgrade { status: None, .. } => write!(f, "upgrade request to server failed"),
+            Self::Http2Unavailable(_) => write!(f, "http2 is not available with server"),
+            Self::JwtRejected(_) => write!(f, "invalid tunnel token received from server"),
+            Self::Timeout(_) => write!(f, "timeout while connecting to server"),
+            Self::Destination { kind, .. } => write!(f, "server cannot connect to destination ({})", kind.to_str()),
+        }
+    }
+}
+
+// Print the whole chain of causes, as anyhow does, because this is what ends up in the logs
+impl Debug for TunnelConnectError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {:?}", self, self.cause())
+    }
+}
+
+impl std::error::Error for TunnelConnectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.cause().as_ref())
+    }
+}
@@ -0,0 +1,571 @@
+use anyhow::{anyhow, Context};
+use base64::Engine;
+use jsonwebtoken::errors::{Error, ErrorKind};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation};
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, RsaKeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::Duration;
+use tracing::{error, info};
+use uuid::Uuid;
+use x509_parser::der_parser::parse_der;
+use x509_parser::prelude::FromDer;
+use x509_parser::public_key::PublicKey;
+use x509_parser::x509::SubjectPublicKeyInfo;
+
+// Used when none is configured, client and server must share the same secret
+static DEFAULT_SECRET: &[u8; 15] = b"champignonfrais";
+
+/// Keys signing and verifying the tunnel tokens of this process
+pub static JWT_KEYS: Lazy<JwtKeys> = Lazy::new(|| JwtKeys::new(DEFAULT_SECRET));
+
+/// Asymmetric algorithms, for a side to not be able to forge the tokens of the other one. Each side signs with its
+/// own private key, and verifies the tokens of the other side with its public key
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum JwtAlgorithm {
+    /// RSA PKCS#1 v1.5 with SHA-256
+    #[value(name = "RS256")]
+    Rs256,
+    /// ECDSA P-256 with SHA-256
+    #[value(name = "ES256")]
+    Es256,
+}
+
+impl JwtAlgorithm {
+    const fn algorithm(self) -> Algorithm {
+        match self {
+            Self::Rs256 => Algorithm::RS256,
+            Self::Es256 => Algorithm::ES256,
+        }
+    }
+}
+
+/// Key signing and/or verifying the tunnel tokens
+pub struct JwtKey {
+    // Derived from the secret or the public key, so the client and the server agree on it without configuring it
+    kid: String,
+    // Tokens with another alg in their header are rejected, to not verify a HS256 token with a public key as secret
+    validation: Validation,
+    encoding: Option<EncodingKey>,
+    decoding: Option<DecodingKey>,
+}
+
+impl JwtKey {
+    pub fn from_secret(secret: &[u8]) -> Self {
+        Self {
+            kid: key_id(secret),
+            validation: validation(Algorithm::HS256),
+            encoding: Some(EncodingKey::from_secret(secret)),
+            decoding: Some(DecodingKey::from_secret(secret)),
+        }
+    }
+
+    /// Keys of the secrets of the file, one per line. Empty lines and the ones starting with # are ignored
+    pub fn from_secrets_file(path: &Path) -> anyhow::Result<Vec<Self>> {
+        let secrets =
+            std::fs::read_to_string(path).with_context(|| format!("cannot read jwt secrets {}", path.display()))?;
+        Ok(secrets
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|secret| Self::from_secret(secret.as_bytes()))
+            .collect())
+    }
+
+    /// Key only signing the tokens, from a PEM private key (PKCS#8, or PKCS#1 for RSA). It does not verify them,
+    /// for the tokens signed by one side to not be accepted back by it as if they came from the other side
+    pub fn from_private_key_pem(algorithm: JwtAlgorithm, pem: &[u8]) -> anyhow::Result<Self> {
+        let (label, der) = pem_decode(pem)?;
+        let (encoding, public_key) = match (algorithm, label.as_str()) {
+            (JwtAlgorithm::Rs256, "RSA PRIVATE KEY") => rsa_private_key(&der)?,
+            (JwtAlgorithm::Rs256, "PRIVATE KEY") => {
+                // PrivateKeyInfo ::= SEQUENCE { version, algorithm, privateKey OCTET STRING (the PKCS#1 key) }
+                let pkcs1 = parse_der(&der)
+                    .ok()
+                    .and_then(|(_, info)| info.as_sequence().ok()?.get(2)?.as_slice().ok().map(<[u8]>::to_vec))
+                    .ok_or_else(|| anyhow!("invalid PKCS#8 RSA private key"))?;
+                rsa_private_key(&pkcs1)?
+            }
+            (JwtAlgorithm::Es256, "PRIVATE KEY") => {
+                let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &der, &SystemRandom::new())
+                    .map_err(|err| anyhow!("invalid P-256 private key: {}", err))?;
+                (EncodingKey::from_ec_der(&der), key_pair.public_key().as_ref().to_vec())
+            }
+            (JwtAlgorithm::Es256, "EC PRIVATE KEY") => {
+                return Err(anyhow!(
+                    "SEC1 EC private keys are not supported, convert it to PKCS#8 with `openssl pkcs8 -topk8 -nocrypt`"
+                ))
+            }
+            (_, label) => return Err(anyhow!("unexpected {} in PEM, expected a private key", label)),
+        };
+
+        let mut key = Self::from_public_key(algorithm, &public_key);
+        key.encoding = Some(encoding);
+        key.decoding = None;
+        Ok(key)
+    }
+
+    /// Key only verifying the tokens, from a PEM public key (SubjectPublicKeyInfo)
+    pub fn from_public_key_pem(algorithm: JwtAlgorithm, pem: &[u8]) -> anyhow::Result<Self> {
+        let (label, der) = pem_decode(pem)?;
+        if label != "PUBLIC KEY" {
+            return Err(anyhow!("unexpected {} in PEM, expected a public key", label));
+        }
+        let (_, spki) = SubjectPublicKeyInfo::from_der(&der).map_err(|err| anyhow!("invalid public key: {}", err))?;
+        match (algorithm, spki.parsed()) {
+            (JwtAlgorithm::Rs256, Ok(PublicKey::RSA(_))) | (JwtAlgorithm::Es256, Ok(PublicKey::EC(_))) => {}
+            _ => return Err(anyhow!("public key does not match algorithm {:?}", algorithm)),
+        }
+
+        Ok(Self::from_public_key(algorithm, &spki.subject_public_key.data))
+    }
+
+    /// Read the key from a PEM file, with a private key if it can sign, or a public key if it can only verify
+    pub fn from_pem_file(algorithm: JwtAlgorithm, path: &Path, is_private: bool) -> anyhow::Result<Self> {
+        let pem = std::fs::read(path).with_context(|| format!("cannot read jwt key {}", path.display()))?;
+        let key = if is_private {
+            Self::from_private_key_pem(algorithm, &pem)
+        } else {
+            Self::from_public_key_pem(algorithm, &pem)
+        };
+
+        key.with_context(|| format!("invalid jwt key {}", path.display()))
+    }
+
+    // public_key is a PKCS#1 RSAPublicKey for RSA, the uncompressed point for EC, the same bytes ring verifies with
+    fn from_public_key(algorithm: JwtAlgorithm, public_key: &[u8]) -> Self {
+        Self {
+            kid: key_id(public_key),
+            validation: validation(algorithm.algorithm()),
+            encoding: None,
+            decoding: Some(match algorithm {
+                JwtAlgorithm::Rs256 => DecodingKey::from_rsa_der(public_key),
+                JwtAlgorithm::Es256 => DecodingKey::from_ec_der(public_key),
+            }),
+        }
+    }
+}
+
+fn key_id(key: &[u8]) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, key);
+    digest.as_ref()[..4].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn validation(algorithm: Algorithm) -> Validation {
+    let mut validation = Validation::new(algorithm);
+    validation.required_spec_claims = HashSet::with_capacity(0);
+    validation
+}
+
+fn rsa_private_key(pkcs1: &[u8]) -> anyhow::Result<(EncodingKey, Vec<u8>)> {
+    let key_pair = RsaKeyPair::from_der(pkcs1).map_err(|err| anyhow!("invalid RSA private key: {}", err))?;
+    Ok((EncodingKey::from_rsa_der(pkcs1), key_pair.public().as_ref().to_vec()))
+}
+
+// Label and content of the first PEM block
+fn pem_decode(pem: &[u8]) -> anyhow::Result<(String, Vec<u8>)> {
+    let pem = std::str::from_utf8(pem).context("PEM is not valid utf-8")?;
+    let (_, block) = pem
+        .split_once("-----BEGIN ")
+        .ok_or_else(|| anyhow!("no PEM block found"))?;
+    let (label, block) = block.split_once("-----").ok_or_else(|| anyhow!("invalid PEM header"))?;
+    let (content, _) = block
+        .split_once(&format!("-----END {}-----", label))
+        .ok_or_else(|| anyhow!("PEM block {} is not terminated", label))?;
+    let content: String = content.split_whitespace().collect();
+    let der = base64::engine::general_purpose::STANDARD
+        .decode(content)
+        .context("invalid base64 in PEM")?;
+
+    Ok((label.to_string(), der))
+}
+
+/// How long the tokens are valid and who they are for, set when signing them and checked when receiving them
+#[derive(Clone, Debug)]
+pub struct JwtValidity {
+    /// Tokens expire this long after being signed
+    pub ttl: Duration,
+    /// Tolerated clock difference between the clients and the server
+    pub leeway: Duration,
+    /// Set in the tokens, and required in the received ones when set. Older servers reject tokens with an audience
+    pub audience: Option<String>,
+    /// Reject the tokens without exp and nbf, i.e: signed by older versions
+    pub strict: bool,
+    /// Reject the received tokens valid longer than this from nbf to exp, whatever the ttl of their signer
+    pub max_ttl: Option<Duration>,
+}
+
+impl Default for JwtValidity {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(60),
+            leeway: Duration::from_secs(30),
+            audience: None,
+            strict: true,
+            max_ttl: None,
+        }
+    }
+}
+
+impl JwtValidity {
+    /// Whether a received token is not valid longer than max_ttl. Tokens without nbf count from now,
+    /// the ones without exp are only accepted when not strict
+    pub fn accepts_lifetime(&self, nbf: Option<u64>, exp: Option<u64>, now: u64) -> bool {
+        match (self.max_ttl, exp) {
+            (Some(max_ttl), Some(exp)) => exp.saturating_sub(nbf.unwrap_or(now)) <= max_ttl.as_secs(),
+            (Some(_), None) => !self.strict,
+            (None, _) => true,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ClaimsWithValidity<'a, T> {
+    #[serde(flatten)]
+    claims: &'a T,
+    nbf: u64,
+    exp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aud: Option<&'a str>,
+    // Unique per token, for the server to not accept the same one twice
+    jti: String,
+}
+
+/// Claims of a received token, with the registered ones needed to reject it if it is replayed
+#[derive(Debug, Deserialize)]
+pub struct ClaimsWithId<T> {
+    #[serde(flatten)]
+    pub claims: T,
+    // Not sent by older versions
+    pub jti: Option<String>,
+    pub nbf: Option<u64>,
+    pub exp: Option<u64>,
+}
+
+/// Set of keys the tunnel tokens can be signed with. Tokens signed with any of them are accepted, while the
+/// newest one able to sign signs the new tokens. To rotate the secret without downtime, add the new one to the
+/// servers, then to the clients, and remove the old one once no client uses it anymore
+pub struct JwtKeys {
+    // Oldest first
+    keys: RwLock<Vec<JwtKey>>,
+    validity: RwLock<JwtValidity>,
+}
+
+impl JwtKeys {
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            keys: RwLock::new(vec![JwtKey::from_secret(secret)]),
+            validity: RwLock::new(JwtValidity::default()),
+        }
+    }
+
+    pub fn set_validity(&self, validity: JwtValidity) {
+        *self.validity.write() = validity;
+    }
+
+    pub fn validity(&self) -> JwtValidity {
+        self.validity.read().clone()
+    }
+
+    /// Sign the new tokens with this key if it can, the tokens signed with the previous ones are still accepted
+    pub fn add_key(&self, key: JwtKey) {
+        let mut keys = self.keys.write();
+        keys.retain(|k| k.kid != key.kid);
+        keys.push(key);
+    }
+
+    /// Sign the new tokens with this secret, the tokens signed with the previous ones are still accepted
+    pub fn add_secret(&self, secret: &[u8]) {
+        self.add_key(JwtKey::from_secret(secret));
+    }
+
+    /// Replace all the keys by these ones, the last one able to sign signs the new tokens
+    pub fn set_keys(&self, keys: impl IntoIterator<Item = JwtKey>) {
+        let keys: Vec<JwtKey> = keys.into_iter().collect();
+        if !keys.is_empty() {
+            *self.keys.write() = keys;
+        }
+    }
+
+    /// Stop accepting the tokens signed with this secret. The last key cannot be removed, false is returned then
+    pub fn remove_secret(&self, secret: &[u8]) -> bool {
+        let kid = key_id(secret);
+        let mut keys = self.keys.write();
+        let Some(ix) = keys.iter().position(|k| k.kid == kid) else {
+            return false;
+        };
+        if keys.len() == 1 {
+            return false;
+        }
+
+        keys.remove(ix);
+        true
+    }
+
+    /// Replace the keys by the ones returned by load each time the process receives a SIGHUP, i.e: once a secret
+    /// has been added to or removed from the files they are read from. The current keys are kept if it fails
+    #[cfg(unix)]
+    pub fn reload_on_sighup(
+        &'static self,
+        load: impl Fn() -> anyhow::Result<Vec<JwtKey>> + Send + 'static,
+    ) -> anyhow::Result<()> {
+        let mut sighup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .with_context(|| "Cannot listen for SIGHUP to reload jwt keys")?;
+        tokio::spawn(async move {
+            while sighup.recv().await.is_some() {
+                match load() {
+                    Ok(keys) if keys.is_empty() => {
+                        error!("Cannot reload jwt keys, none found. Keeping the current ones");
+                    }
+                    Ok(keys) => {
+                        info!("Received SIGHUP, jwt keys have been reloaded with {} keys", keys.len());
+                        self.set_keys(keys);
+                    }
+                    Err(err) => error!("Cannot reload jwt keys, keeping the current ones. Error: {:?}", err),
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Whether the tokens signed with the default secret are accepted. Anyone can forge them then, so their claims
+    /// are only as trustworthy as the peer sending them
+    pub fn accepts_default_secret(&self) -> bool {
+        let kid = key_id(DEFAULT_SECRET);
+        self.keys.read().iter().any(|k| k.kid == kid)
+    }
+
+    pub(crate) fn encode(&self, claims: &impl Serialize) -> anyhow::Result<String> {
+        let keys = self.keys.read();
+        let Some((key, encoding)) = keys.iter().rev().find_map(|k| Some((k, k.encoding.as_ref()?))) else {
+            return Err(anyhow!(
+                "no jwt key can sign, a private key is needed with an asymmetric algorithm"
+            ));
+        };
+        let mut header = Header::new(key.validation.algorithms[0]);
+        header.kid = Some(key.kid.clone());
+
+        let validity = self.validity.read();
+        let now = jsonwebtoken::get_current_timestamp();
+        let claims = ClaimsWithValidity {
+            claims,
+            nbf: now,
+            exp: now + validity.ttl.as_secs(),
+            aud: validity.audience.as_deref(),
+            jti: Uuid::now_v7().to_string(),
+        };
+        jsonwebtoken::encode(&header, &claims, encoding).context("cannot sign jwt")
+    }
+
+    fn validation(&self, key: &JwtKey) -> Validation {
+        let validity = self.validity.read();
+        let mut validation = key.validation.clone();
+        validation.leeway = validity.leeway.as_secs();
+        validation.validate_nbf = true;
+        let mut required = vec!["exp", "nbf"];
+        match &validity.audience {
+            Some(audience) => {
+                validation.set_audience(&[audience]);
+                required.push("aud");
+            }
+            None => validation.validate_aud = false,
+        }
+        if validity.strict {
+            validation.set_required_spec_claims(&required);
+        }
+
+        validation
+    }
+
+    pub(crate) fn decode<T: DeserializeOwned>(&self, token: &str) -> Result<TokenData<T>, Error> {
+        let kid = jsonwebtoken::decode_header(token)?.kid;
+        let keys = self.keys.read();
+
+        // Tokens of older versions carry no key id, try all the keys for them
+        let mut err = Error::from(ErrorKind::InvalidSignature);
+        for key in keys
+            .iter()
+            .rev()
+            .filter(|k| kid.is_none() || kid.as_ref() == Some(&k.kid))
+        {
+            let Some(decoding) = &key.decoding else {
+                continue;
+            };
+            match jsonwebtoken::decode(token, decoding, &self.validation(key)) {
+                Ok(token) => return Ok(token),
+                Err(e) => err = e,
+            }
+        }
+
+        Err(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Claims {
+        id: String,
+    }
+
+    fn pem(label: &str, der: &[u8]) -> Vec<u8> {
+        let content = base64::engine::general_purpose::STANDARD.encode(der);
+        format!("-----BEGIN {label}-----\n{content}\n-----END {label}-----\n").into_bytes()
+    }
+
+    #[test]
+    fn test_jwt_secrets_file() {
+        let path = std::env::temp_dir().join(format!("wstunnel_jwt_secrets_{}", std::process::id()));
+        std::fs::write(&path, "# rotated on 2024-01-01\nold\n\n  new  \n").unwrap();
+        let keys = JwtKey::from_secrets_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let kids: Vec<&str> = keys.iter().map(|k| k.kid.as_str()).collect();
+        assert_eq!(kids, vec![key_id(b"old"), key_id(b"new")]);
+        assert!(JwtKey::from_secrets_file(&path).is_err());
+    }
+
+    #[test]
+    fn test_jwt_key_rotation() {
+        let claims = Claims { id: "1".to_string() };
+        let keys = JwtKeys::new(b"old");
+        let old_token = keys.encode(&claims).unwrap();
+
+        keys.add_secret(b"new");
+        let new_token = keys.encode(&claims).unwrap();
+        assert_ne!(old_token, new_token);
+        assert_eq!(keys.decode::<Claims>(&old_token).unwrap().claims, claims);
+        assert_eq!(keys.decode::<Claims>(&new_token).unwrap().claims, claims);
+
+        // A server knowing only the new secret rejects the tokens of the old one
+        let new_only = JwtKeys::new(b"new");
+        assert_eq!(new_only.decode::<Claims>(&new_token).unwrap().claims, claims);
+        assert!(new_only.decode::<Claims>(&old_token).is_err());
+
+        assert!(!keys.accepts_default_secret());
+        keys.add_secret(DEFAULT_SECRET);
+        assert!(keys.accepts_default_secret());
+        assert!(keys.remove_secret(DEFAULT_SECRET));
+
+        assert!(keys.remove_secret(b"old"));
+        assert!(keys.decode::<Claims>(&old_token).is_err());
+        assert!(!keys.remove_secret(b"new"));
+        assert!(keys.decode::<Claims>(&new_token).is_ok());
+
+        // Tokens without key id are checked against all the keys, older clients also don't send their expiration
+        keys.set_validity(JwtValidity {
+            strict: false,
+            ..JwtValidity::default()
+        });
+        let legacy =
+            jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(b"new")).unwrap();
+        assert_eq!(keys.decode::<Claims>(&legacy).unwrap().claims, claims);
+    }
+
+    #[test]
+    fn test_jwt_asymmetric_keys() {
+        let claims = Claims { id: "1".to_string() };
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new()).unwrap();
+        let key_pair =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &SystemRandom::new()).unwrap();
+        // SubjectPublicKeyInfo of a P-256 key is a fixed prefix followed by the point
+        let mut spki = hex_decode("3059301306072a8648ce3d020106082a8648ce3d030107034200");
+        spki.extend_from_slice(key_pair.public_key().as_ref());
+
+        let signer = JwtKeys::new(b"secret");
+        signer.set_keys([
+            JwtKey::from_private_key_pem(JwtAlgorithm::Es256, &pem("PRIVATE KEY", pkcs8.as_ref())).unwrap(),
+        ]);
+        let verifier = JwtKeys::new(b"secret");
+        verifier.set_keys([JwtKey::from_public_key_pem(JwtAlgorithm::Es256, &pem("PUBLIC KEY", &spki)).unwrap()]);
+
+        let token = signer.encode(&claims).unwrap();
+        assert_eq!(verifier.decode::<Claims>(&token).unwrap().claims, claims);
+        // The verifier cannot sign, and does not fall back to a secret. The signer does not accept its own tokens
+        assert!(verifier.encode(&claims).is_err());
+        assert!(signer.decode::<Claims>(&token).is_err());
+        assert!(JwtKey::from_public_key_pem(JwtAlgorithm::Rs256, &pem("PUBLIC KEY", &spki)).is_err());
+
+        // alg confusion: a HS256 token using the public key as secret, with the kid of the public key
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(key_id(key_pair.public_key().as_ref()));
+        let forged =
+            jsonwebtoken::encode(&header, &claims, &EncodingKey::from_secret(key_pair.public_key().as_ref())).unwrap();
+        assert!(verifier.decode::<Claims>(&forged).is_err());
+    }
+
+    #[derive(Serialize)]
+    struct RawClaims<'a> {
+        id: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        nbf: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        exp: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        aud: Option<&'a str>,
+    }
+
+    #[test]
+    fn test_jwt_validity() {
+        let claims = Claims { id: "1".to_string() };
+        let keys = JwtKeys::new(b"secret");
+        let now = jsonwebtoken::get_current_timestamp();
+        let sign = |nbf: Option<u64>, exp: Option<u64>, aud: Option<&str>| {
+            let mut header = Header::new(Algorithm::HS256);
+            header.kid = Some(key_id(b"secret"));
+            let claims = RawClaims { id: "1", nbf, exp, aud };
+            jsonwebtoken::encode(&header, &claims, &EncodingKey::from_secret(b"secret")).unwrap()
+        };
+        let is_valid = |token: &str| keys.decode::<Claims>(token).is_ok();
+
+        assert!(is_valid(&keys.encode(&claims).unwrap()));
+        // Within the leeway
+        assert!(is_valid(&sign(Some(now + 10), Some(now - 10), None)));
+        assert!(!is_valid(&sign(Some(now), Some(now - 60), None)));
+        assert!(!is_valid(&sign(Some(now + 60), Some(now + 120), None)));
+
+        // Tokens of older versions have no validity
+        let legacy = sign(None, None, None);
+        assert!(!is_valid(&legacy));
+        keys.set_validity(JwtValidity {
+            strict: false,
+            ..JwtValidity::default()
+        });
+        assert!(is_valid(&legacy));
+
+        keys.set_validity(JwtValidity {
+            audience: Some("wstunnel.example.com".to_string()),
+            ..JwtValidity::default()
+        });
+        assert!(is_valid(&keys.encode(&claims).unwrap()));
+        assert!(!is_valid(&sign(Some(now), Some(now + 60), None)));
+        assert!(!is_valid(&sign(Some(now), Some(now + 60), Some("other.example.com"))));
+
+        // A client signing with a long ttl does not get a token valid longer than the server allows
+        let validity = JwtValidity {
+            max_ttl: Some(Duration::from_secs(300)),
+            ..JwtValidity::default()
+        };
+        assert!(validity.accepts_lifetime(Some(now), Some(now + 300), now));
+        assert!(!validity.accepts_lifetime(Some(now), Some(now + 86400), now));
+        assert!(!validity.accepts_lifetime(None, Some(now + 301), now));
+        assert!(!validity.accepts_lifetime(None, None, now));
+        assert!(JwtValidity::default().accepts_lifetime(Some(now), Some(now + 86400), now));
+    }
+
+    fn hex_decode(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+}
@@ -44,7 +44,16 @@ impl Stream for HttpProxyTunnelListener {
                 let protocol = LocalProtocol::Tcp {
                     proxy_protocol: this.proxy_protocol,
                 };
-                Some(anyhow::Ok((stream.into_split(), RemoteAddr { protocol, host, port })))
+                Some(anyhow::Ok((
+                    stream.into_split(),
+                    RemoteAddr {
+                        protocol,
+                        host,
+                        port,
+                        source: None,
+                        request_id: None,
+                    },
+                )))
             }
             Some(Err(err)) => Some(Err(err)),
             None => None,
@@ -23,12 +23,16 @@ pub use udp::new_udp_listener;
 #[cfg(unix)]
 pub use unix_sock::UnixTunnelListener;
 
-use crate::tunnel::RemoteAddr;
-use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_stream::Stream;
+use crate::protocols::tcp::ConnectFailureBehavior;
+use crate::tunnel::{RemoteAddr, TunnelConnectError};
+use std::future::Future;
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadHalf, WriteHalf};
+use tokio_stream::{Stream, StreamExt};
+use uuid::Uuid;
 
 pub trait TunnelListener: Stream<Item = anyhow::Result<((Self::Reader, Self::Writer), RemoteAddr)>> {
-    type Reader: AsyncRead + Send + 'static;
+    type Reader: AsyncRead + LocalConnection<Self::Writer> + Send + 'static;
     type Writer: AsyncWrite + Send + 'static;
     type OkReturn; // = ((Self::Reader, Self::Writer), RemoteAddr);
 }
@@ -36,10 +40,170 @@ pub trait TunnelListener: Stream<Item = anyhow::Result<((Self::Reader, Self::Wri
 impl<T, R, W> TunnelListener for T
 where
     T: Stream<Item = anyhow::Result<((R, W), RemoteAddr)>>,
-    R: AsyncRead + Send + 'static,
+    R: AsyncRead + LocalConnection<W> + Send + 'static,
     W: AsyncWrite + Send + 'static,
 {
     type Reader = R;
     type Writer = W;
     type OkReturn = ((R, W), RemoteAddr);
 }
+
+/// Give the tunnels of the connections of the listener the id returned by request_id, instead of a generated uuid v7.
+/// i.e: to correlate the logs of the tunnels, on both client and server, with the system that opened the connections
+pub fn with_request_ids<L: TunnelListener>(
+    listener: L,
+    mut request_id: impl FnMut(&RemoteAddr) -> Uuid,
+) -> impl TunnelListener<Reader = L::Reader, Writer = L::Writer> {
+    listener.map(move |cnx| {
+        let (stream, mut remote_addr) = cnx?;
+        remote_addr.request_id = Some(request_id(&remote_addr));
+        Ok((stream, remote_addr))
+    })
+}
+
+/// Read half of a local connection, with W its write half, that knows how to tell its peer that the tunnel
+/// of the connection could not be opened
+pub trait LocalConnection<W>: Sized {
+    /// Close the connection after its tunnel failed to connect. By default it is only dropped, as it would be
+    /// once the tunnel is done
+    fn close_on_connect_error(
+        self,
+        _tx: W,
+        _err: &TunnelConnectError,
+        _behavior: ConnectFailureBehavior,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+}
+
+// In memory streams, i.e: to tunnel a connection handed over by the caller of WsClient::open_tunnel
+impl LocalConnection<WriteHalf<DuplexStream>> for ReadHalf<DuplexStream> {}
+impl LocalConnection<DuplexStream> for DuplexStream {}
+
+/// How a listener should react to an error while accepting a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptErrorKind {
+    /// Only this connection is affected (i.e: reset by the peer before being accepted), keep accepting
+    Connection,
+    /// The process or the system is out of file descriptors or memory. Accepting again right away
+    /// would fail the same way, and spin, so back off until some connections are closed
+    ResourceExhausted,
+    /// The listener itself is broken, it will never accept a connection again
+    Fatal,
+}
+
+pub fn classify_accept_error(err: &anyhow::Error) -> AcceptErrorKind {
+    let Some(err) = err.chain().find_map(|err| err.downcast_ref::<io::Error>()) else {
+        return AcceptErrorKind::Connection;
+    };
+
+    #[cfg(unix)]
+    {
+        use nix::errno::Errno;
+        match err.raw_os_error().map(Errno::from_raw) {
+            Some(Errno::EMFILE | Errno::ENFILE | Errno::ENOBUFS | Errno::ENOMEM) => {
+                return AcceptErrorKind::ResourceExhausted
+            }
+            Some(Errno::EBADF | Errno::ENOTSOCK | Errno::EINVAL | Errno::EOPNOTSUPP) => return AcceptErrorKind::Fatal,
+            _ => {}
+        }
+    }
+
+    match err.kind() {
+        io::ErrorKind::OutOfMemory => AcceptErrorKind::ResourceExhausted,
+        _ => AcceptErrorKind::Connection,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::client::{WsClient, WsClientConfigBuilder};
+    use crate::tunnel::{TransportAddr, TransportScheme, JWT_HEADER_PREFIX, JWT_KEYS};
+    use crate::LocalProtocol;
+    use serde::Deserialize;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_util::sync::CancellationToken;
+    use url::Host;
+
+    // Server rejecting every upgrade request, after handing over the id of the tunnel found in its token
+    async fn spawn_token_id_server() -> (u16, tokio::sync::mpsc::Receiver<String>) {
+        #[derive(Deserialize)]
+        struct Claims {
+            id: String,
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (ids_tx, ids_rx) = tokio::sync::mpsc::channel(8);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let Ok(n @ 1..) = stream.read(&mut buf).await else {
+                        break;
+                    };
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8_lossy(&request);
+                let Some((_, token)) = request.split_once(JWT_HEADER_PREFIX) else {
+                    continue;
+                };
+                let token = token.split(['\r', ',']).next().unwrap_or_default();
+                let claims = JWT_KEYS.decode::<Claims>(token).unwrap().claims;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\n\r\n")
+                    .await;
+                let _ = ids_tx.send(claims.id).await;
+            }
+        });
+        (port, ids_rx)
+    }
+
+    #[tokio::test]
+    async fn test_tunnels_use_the_request_ids_given_to_the_listener() {
+        let (port, mut ids) = spawn_token_id_server().await;
+        let localhost = Host::Ipv4("127.0.0.1".parse().unwrap());
+        let config =
+            WsClientConfigBuilder::new(TransportAddr::new(TransportScheme::Ws, localhost.clone(), port, None).unwrap())
+                .build()
+                .unwrap();
+        let client = WsClient::new(config, 0, Duration::from_secs(1)).await.unwrap();
+
+        let remote_addr = RemoteAddr {
+            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            host: localhost,
+            port: 443,
+            source: None,
+            request_id: None,
+        };
+        let (local, _peer) = tokio::io::duplex(1024);
+        let listener = tokio_stream::iter([anyhow::Ok((tokio::io::split(local), remote_addr))]);
+        let request_id = Uuid::from_u128(0x1234);
+        let listener = with_request_ids(listener, move |_| request_id);
+        client.run_tunnel(listener, CancellationToken::new()).await.unwrap();
+
+        let id = tokio::time::timeout(Duration::from_secs(5), ids.recv()).await.unwrap();
+        assert_eq!(id, Some(request_id.to_string()));
+    }
+
+    #[test]
+    fn test_classify_accept_error() {
+        let err = anyhow::Error::new(io::Error::from(io::ErrorKind::ConnectionAborted));
+        assert_eq!(classify_accept_error(&err), AcceptErrorKind::Connection);
+        let err = anyhow::anyhow!("not an io error");
+        assert_eq!(classify_accept_error(&err), AcceptErrorKind::Connection);
+
+        #[cfg(unix)]
+        {
+            use nix::errno::Errno;
+            let err = anyhow::Error::new(io::Error::from_raw_os_error(Errno::EMFILE as i32)).context("accept");
+            assert_eq!(classify_accept_error(&err), AcceptErrorKind::ResourceExhausted);
+            let err = anyhow::Error::new(io::Error::from_raw_os_error(Errno::EBADF as i32));
+            assert_eq!(classify_accept_error(&err), AcceptErrorKind::Fatal);
+        }
+    }
+}
@@ -1,7 +1,10 @@
 use crate::protocols::socks5;
 use crate::protocols::socks5::{Socks5Listener, Socks5Stream};
-use crate::tunnel::RemoteAddr;
+use crate::protocols::tcp::ConnectFailureBehavior;
+use crate::tunnel::listeners::LocalConnection;
+use crate::tunnel::{ConnectErrorKind, RemoteAddr, TunnelConnectError};
 use anyhow::{anyhow, Context};
+use fast_socks5::ReplyError;
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::task::{ready, Poll};
@@ -37,7 +40,16 @@ impl Stream for Socks5TunnelListener {
         let ret = match ret {
             Some(Ok((stream, (host, port)))) => {
                 let protocol = stream.local_protocol();
-                Some(anyhow::Ok((tokio::io::split(stream), RemoteAddr { protocol, host, port })))
+                Some(anyhow::Ok((
+                    tokio::io::split(stream),
+                    RemoteAddr {
+                        protocol,
+                        host,
+                        port,
+                        source: None,
+                        request_id: None,
+                    },
+                )))
             }
             Some(Err(err)) => Some(Err(err)),
             None => None,
@@ -45,3 +57,98 @@ impl Stream for Socks5TunnelListener {
         Poll::Ready(ret)
     }
 }
+
+// Socks5 clients are told why with the reply to their command, so the connection is never reset
+impl LocalConnection<WriteHalf<Socks5Stream>> for ReadHalf<Socks5Stream> {
+    async fn close_on_connect_error(
+        self,
+        tx: WriteHalf<Socks5Stream>,
+        err: &TunnelConnectError,
+        _behavior: ConnectFailureBehavior,
+    ) {
+        let reply = match err {
+            TunnelConnectError::Destination { kind, .. } => socks5_reply(*kind),
+            // We cannot reach the server, so we know nothing about the destination
+            _ => ReplyError::GeneralFailure,
+        };
+        let _ = self.unsplit(tx).reply_error(reply).await;
+    }
+}
+
+const fn socks5_reply(kind: ConnectErrorKind) -> ReplyError {
+    match kind {
+        ConnectErrorKind::Refused => ReplyError::ConnectionRefused,
+        ConnectErrorKind::HostUnreachable => ReplyError::HostUnreachable,
+        ConnectErrorKind::NetworkUnreachable => ReplyError::NetworkUnreachable,
+        ConnectErrorKind::TimedOut => ReplyError::TtlExpired,
+        ConnectErrorKind::NotAllowed => ReplyError::ConnectionNotAllowed,
+        ConnectErrorKind::General => ReplyError::GeneralFailure,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::dns::DnsResolver;
+    use crate::protocols::tcp::DomainResolutionError;
+    use std::str::FromStr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_stream::StreamExt;
+    use url::Host;
+
+    async fn connect_reply(listener: &mut Socks5TunnelListener, server_addr: SocketAddr, err: anyhow::Error) -> u8 {
+        let client = tokio::spawn(async move {
+            let mut client = TcpStream::connect(server_addr).await.unwrap();
+            client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+            let mut method = [0u8; 2];
+            client.read_exact(&mut method).await.unwrap();
+            client
+                .write_all(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0x1f, 0x90])
+                .await
+                .unwrap();
+            let mut reply = [0u8; 10];
+            client.read_exact(&mut reply).await.unwrap();
+            reply
+        });
+
+        let ((rx, tx), _) = listener.next().await.unwrap().unwrap();
+        let err = TunnelConnectError::Destination {
+            kind: ConnectErrorKind::from_error(&err),
+            cause: err,
+        };
+        rx.close_on_connect_error(tx, &err, ConnectFailureBehavior::Abrupt)
+            .await;
+
+        let reply = client.await.unwrap();
+        assert_eq!(reply[0], 0x05);
+        reply[1]
+    }
+
+    #[tokio::test]
+    async fn test_socks5_reply_of_connect_errors() {
+        let server_addr = SocketAddr::from_str("127.0.0.1:1284").unwrap();
+        let mut listener = Socks5TunnelListener::new(server_addr, None, None).await.unwrap();
+
+        // Nobody listens anymore on the port
+        let closed_port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let refused = crate::protocols::tcp::connect(
+            &Host::Ipv4("127.0.0.1".parse().unwrap()),
+            closed_port,
+            None,
+            Duration::from_secs(1),
+            &DnsResolver::System { prefer_ipv6: false },
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(connect_reply(&mut listener, server_addr, refused).await, 0x05);
+
+        let unresolved = anyhow!("no record").context(DomainResolutionError("nowhere.invalid".to_string()));
+        assert_eq!(connect_reply(&mut listener, server_addr, unresolved).await, 0x04);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::protocols::stdio;
+use crate::tunnel::listeners::LocalConnection;
 use crate::tunnel::RemoteAddr;
 use crate::LocalProtocol;
 use anyhow::{anyhow, Context};
@@ -22,10 +23,7 @@ where
 pub async fn new_stdio_listener(
     dest: (Host, u16),
     proxy_protocol: bool,
-) -> anyhow::Result<(
-    StdioTunnelListener<impl AsyncRead + Send, impl AsyncWrite + Send>,
-    oneshot::Sender<()>,
-)> {
+) -> anyhow::Result<(StdioTunnelListener<stdio::WsStdin, stdio::WsStdout>, oneshot::Sender<()>)> {
     let (listener, handle) = stdio::run_server()
         .await
         .with_context(|| anyhow!("Cannot start STDIO server"))?;
@@ -39,6 +37,8 @@ pub async fn new_stdio_listener(
     ))
 }
 
+impl LocalConnection<stdio::WsStdout> for stdio::WsStdin {}
+
 impl<R, W> Stream for StdioTunnelListener<R, W>
 where
     R: AsyncRead + Send + 'static,
@@ -60,6 +60,8 @@ where
                         },
                         host,
                         port,
+                        source: None,
+                        request_id: None,
                     },
                 )))
             }
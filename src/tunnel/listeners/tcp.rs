@@ -1,32 +1,120 @@
-use crate::tunnel::RemoteAddr;
+use crate::protocols::tcp::{reset_connection, ConnectFailureBehavior, TcpSocketOptions};
+use crate::tunnel::listeners::LocalConnection;
+use crate::tunnel::{to_host_port, RemoteAddr, TunnelConnectError};
 use crate::{protocols, LocalProtocol};
 use anyhow::{anyhow, Context};
+use futures_util::future::BoxFuture;
+use futures_util::stream::FuturesUnordered;
+use futures_util::FutureExt;
+use log::warn;
+use socket2::SockRef;
 use std::net::SocketAddr;
 use std::pin::Pin;
-use std::task::{ready, Poll};
+use std::task::Poll;
+use std::time::Duration;
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
 use tokio_stream::wrappers::TcpListenerStream;
 use tokio_stream::Stream;
 use url::Host;
 
+const PROXY_PROTOCOL_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+type PendingHeader = BoxFuture<'static, anyhow::Result<(TcpStream, Option<SocketAddr>)>>;
+
 pub struct TcpTunnelListener {
     listener: TcpListenerStream,
     dest: (Host, u16),
-    proxy_protocol: bool,
+    protocol: LocalProtocol,
+    tcp_options: TcpSocketOptions,
+    expect_proxy_protocol: bool,
+    use_original_dst: bool,
+    // Accepted connections for which we are still waiting for the proxy protocol header
+    pending_headers: FuturesUnordered<PendingHeader>,
 }
 
 impl TcpTunnelListener {
-    pub async fn new(bind_addr: SocketAddr, dest: (Host, u16), proxy_protocol: bool) -> anyhow::Result<Self> {
-        let listener = protocols::tcp::run_server(bind_addr, false)
+    /// listen_backlog is the number of connections the kernel queues until they are accepted
+    pub async fn new(
+        bind_addr: SocketAddr,
+        dest: (Host, u16),
+        proxy_protocol: bool,
+        listen_backlog: u32,
+    ) -> anyhow::Result<Self> {
+        let listener = protocols::tcp::run_server(bind_addr, false, listen_backlog)
             .await
             .with_context(|| anyhow!("Cannot start TCP server on {}", bind_addr))?;
 
         Ok(Self {
             listener,
             dest,
-            proxy_protocol,
+            protocol: LocalProtocol::Tcp { proxy_protocol },
+            tcp_options: TcpSocketOptions::default(),
+            expect_proxy_protocol: false,
+            use_original_dst: false,
+            pending_headers: FuturesUnordered::new(),
         })
     }
+
+    /// Ask the server to forward the connections with this protocol instead of plain tcp (i.e: to a unix socket)
+    pub fn with_protocol(mut self, protocol: LocalProtocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// TCP options set on the accepted sockets (nodelay, keepalive)
+    pub fn with_tcp_options(mut self, tcp_options: TcpSocketOptions) -> Self {
+        self.tcp_options = tcp_options;
+        self
+    }
+
+    /// Expect the accepted connections to start with a PROXY protocol header (i.e: behind a load balancer).
+    /// The header is stripped and the source it announces is used as the source of the tunnel.
+    /// Connections with a missing or malformed header are rejected
+    pub fn with_expect_proxy_protocol(mut self, expect_proxy_protocol: bool) -> Self {
+        self.expect_proxy_protocol = expect_proxy_protocol;
+        self
+    }
+
+    /// Forward the connections to their destination before they were redirected to the listener,
+    /// by iptables/ip6tables REDIRECT, instead of the configured one. Linux only
+    pub fn with_use_original_dst(mut self, use_original_dst: bool) -> Self {
+        self.use_original_dst = use_original_dst;
+        self
+    }
+
+    fn to_item(&self, stream: TcpStream, source: Option<SocketAddr>) -> <Self as Stream>::Item {
+        let (host, port) = if self.use_original_dst {
+            let dest = protocols::tcp::original_destination(&stream)
+                .with_context(|| format!("cannot get original destination of connection from {:?}", source))?;
+            to_host_port(dest)
+        } else {
+            self.dest.clone()
+        };
+        Ok((
+            stream.into_split(),
+            RemoteAddr {
+                protocol: self.protocol.clone(),
+                host,
+                port,
+                source,
+                request_id: None,
+            },
+        ))
+    }
+}
+
+async fn read_proxy_protocol_header(mut stream: TcpStream) -> anyhow::Result<(TcpStream, Option<SocketAddr>)> {
+    let peer = stream.peer_addr()?;
+    let source = tokio::time::timeout(
+        PROXY_PROTOCOL_HEADER_TIMEOUT,
+        protocols::tcp::read_proxy_protocol_header(&mut stream),
+    )
+    .await
+    .map_err(|_| anyhow!("timeout while waiting for the proxy protocol header of {}", peer))?
+    .with_context(|| format!("rejecting connection from {}", peer))?;
+
+    Ok((stream, source.or(Some(peer))))
 }
 
 impl Stream for TcpTunnelListener {
@@ -34,24 +122,83 @@ impl Stream for TcpTunnelListener {
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Option<Self::Item>> {
         let this = self.get_mut();
-        let ret = ready!(Pin::new(&mut this.listener).poll_next(cx));
-        let ret = match ret {
-            Some(Ok(strean)) => {
-                let (host, port) = this.dest.clone();
-                Some(anyhow::Ok((
-                    strean.into_split(),
-                    RemoteAddr {
-                        protocol: LocalProtocol::Tcp {
-                            proxy_protocol: this.proxy_protocol,
-                        },
-                        host,
-                        port,
-                    },
-                )))
+
+        // Accept new connections. When a proxy protocol header is expected, the header is read
+        // in the background to not block the other connections on a slow peer
+        let listener_done = loop {
+            let stream = match Pin::new(&mut this.listener).poll_next(cx) {
+                Poll::Ready(Some(Ok(stream))) => stream,
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(anyhow::Error::new(err)))),
+                Poll::Ready(None) => break true,
+                Poll::Pending => break false,
+            };
+
+            if let Err(err) = protocols::tcp::configure_socket(SockRef::from(&stream), &None, &this.tcp_options) {
+                warn!("Error while configuring accepted socket {:?}", err);
+            }
+
+            if !this.expect_proxy_protocol {
+                let source = stream.peer_addr().ok();
+                return Poll::Ready(Some(this.to_item(stream, source)));
             }
-            Some(Err(err)) => Some(Err(anyhow::Error::new(err))),
-            None => None,
+            this.pending_headers.push(read_proxy_protocol_header(stream).boxed());
         };
-        Poll::Ready(ret)
+
+        match Pin::new(&mut this.pending_headers).poll_next(cx) {
+            Poll::Ready(Some(Ok((stream, source)))) => Poll::Ready(Some(this.to_item(stream, source))),
+            Poll::Ready(Some(Err(err))) => Poll::Ready(Some(Err(err))),
+            Poll::Ready(None) if listener_done => Poll::Ready(None),
+            Poll::Ready(None) | Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+// Also the connections of the http proxy and tproxy listeners
+impl LocalConnection<OwnedWriteHalf> for OwnedReadHalf {
+    async fn close_on_connect_error(
+        self,
+        tx: OwnedWriteHalf,
+        _err: &TunnelConnectError,
+        behavior: ConnectFailureBehavior,
+    ) {
+        if behavior == ConnectFailureBehavior::Abrupt {
+            if let Ok(stream) = self.reunite(tx) {
+                reset_connection(&stream);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::tcp::DEFAULT_LISTEN_BACKLOG;
+    use tokio_stream::StreamExt;
+
+    async fn accept(use_original_dst: bool) -> (SocketAddr, anyhow::Result<RemoteAddr>) {
+        let dest = (Host::Domain("example.com".to_string()), 443);
+        let listener = TcpTunnelListener::new("127.0.0.1:0".parse().unwrap(), dest, false, DEFAULT_LISTEN_BACKLOG)
+            .await
+            .unwrap();
+        let addr = listener.listener.as_ref().local_addr().unwrap();
+        let mut listener = listener.with_use_original_dst(use_original_dst);
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let ret = listener.next().await.unwrap().map(|(_, remote)| remote);
+        (addr, ret)
+    }
+
+    #[tokio::test]
+    async fn test_use_original_dst() {
+        let (_, remote) = accept(false).await;
+        let remote = remote.unwrap();
+        assert_eq!((remote.host, remote.port), (Host::Domain("example.com".to_string()), 443));
+
+        // Without any redirection, the original destination of a tracked connection is the listener itself.
+        // It cannot be found if the connection is not tracked (no conntrack), or on other systems than linux
+        let (addr, remote) = accept(true).await;
+        match remote {
+            Ok(remote) => assert_eq!((remote.host, remote.port), to_host_port(addr)),
+            Err(err) => assert!(format!("{:#}", err).contains("cannot get original destination"), "{:#}", err),
+        }
     }
 }
@@ -18,8 +18,8 @@ pub struct TproxyTcpTunnelListener {
 }
 
 impl TproxyTcpTunnelListener {
-    pub async fn new(bind_addr: SocketAddr, proxy_protocol: bool) -> anyhow::Result<Self> {
-        let listener = protocols::tcp::run_server(bind_addr, true)
+    pub async fn new(bind_addr: SocketAddr, proxy_protocol: bool, listen_backlog: u32) -> anyhow::Result<Self> {
+        let listener = protocols::tcp::run_server(bind_addr, true, listen_backlog)
             .await
             .with_context(|| anyhow!("Cannot start TProxy TCP server on {}", bind_addr))?;
 
@@ -38,7 +38,9 @@ impl Stream for TproxyTcpTunnelListener {
         let ret = ready!(Pin::new(&mut this.listener).poll_next(cx));
         let ret = match ret {
             Some(Ok(stream)) => {
+                // With TPROXY, the local address of the accepted socket is the original destination
                 let (host, port) = to_host_port(stream.local_addr().unwrap());
+                let source = stream.peer_addr().ok();
                 Some(anyhow::Ok((
                     stream.into_split(),
                     RemoteAddr {
@@ -47,6 +49,8 @@ impl Stream for TproxyTcpTunnelListener {
                         },
                         host,
                         port,
+                        source,
+                        request_id: None,
                     },
                 )))
             }
@@ -96,6 +100,8 @@ where
                         protocol: LocalProtocol::Udp { timeout: this.timeout },
                         host,
                         port,
+                        source: None,
+                        request_id: None,
                     },
                 )))
             }
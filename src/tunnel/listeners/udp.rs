@@ -1,5 +1,6 @@
 use crate::protocols::udp;
 use crate::protocols::udp::{UdpStream, UdpStreamWriter};
+use crate::tunnel::listeners::LocalConnection;
 use crate::tunnel::RemoteAddr;
 use crate::LocalProtocol;
 use anyhow::{anyhow, Context};
@@ -36,6 +37,9 @@ pub async fn new_udp_listener(
     })
 }
 
+// Also the datagrams of the tproxy udp listener. There is no connection to reset
+impl LocalConnection<UdpStreamWriter> for UdpStream {}
+
 impl<S> Stream for UdpTunnelListener<S>
 where
     S: Stream<Item = io::Result<UdpStream>>,
@@ -55,6 +59,8 @@ where
                         protocol: LocalProtocol::Udp { timeout: this.timeout },
                         host,
                         port,
+                        source: None,
+                        request_id: None,
                     },
                 )))
             }
@@ -1,5 +1,6 @@
 use crate::protocols::unix_sock;
 use crate::protocols::unix_sock::UnixListenerStream;
+use crate::tunnel::listeners::LocalConnection;
 use crate::tunnel::RemoteAddr;
 use crate::LocalProtocol;
 use anyhow::{anyhow, Context};
@@ -29,6 +30,8 @@ impl UnixTunnelListener {
         })
     }
 }
+impl LocalConnection<unix::OwnedWriteHalf> for unix::OwnedReadHalf {}
+
 impl Stream for UnixTunnelListener {
     type Item = anyhow::Result<((unix::OwnedReadHalf, unix::OwnedWriteHalf), RemoteAddr)>;
 
@@ -47,6 +50,8 @@ impl Stream for UnixTunnelListener {
                         },
                         host,
                         port,
+                        source: None,
+                        request_id: None,
                     },
                 )))
             }
@@ -0,0 +1,198 @@
+use anyhow::{anyhow, Context};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::level_filters::LevelFilter;
+use tracing::Subscriber;
+use tracing_subscriber::filter::Directive;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+/// Tracing subscriber for the logs of wstunnel, for programs embedding it that do not set up their own.
+/// Nothing is installed unless try_init is called, and it never replaces a subscriber the program already set
+pub struct LogConfig {
+    directives: String,
+    ansi: bool,
+    writer: Option<BoxMakeWriter>,
+    #[cfg(feature = "otlp")]
+    otlp_service_name: Option<String>,
+}
+
+impl LogConfig {
+    /// Log everything at level or above
+    pub fn new(level: LevelFilter) -> Self {
+        Self {
+            directives: level.to_string(),
+            ansi: true,
+            writer: None,
+            #[cfg(feature = "otlp")]
+            otlp_service_name: None,
+        }
+    }
+
+    /// Filter with directives in the syntax of RUST_LOG, i.e: info,wstunnel::tunnel::transport=warn
+    pub fn parse(directives: &str) -> anyhow::Result<Self> {
+        EnvFilter::builder()
+            .parse(directives)
+            .with_context(|| format!("invalid log directives {}", directives))?;
+        Ok(Self {
+            directives: directives.to_string(),
+            ansi: true,
+            writer: None,
+            #[cfg(feature = "otlp")]
+            otlp_service_name: None,
+        })
+    }
+
+    /// Log the target (a module path, i.e: wstunnel::tunnel::transport::io) and its children at another level
+    pub fn with_target(mut self, target: &str, level: LevelFilter) -> Self {
+        self.directives.push_str(&format!(",{}={}", target, level));
+        self
+    }
+
+    /// Color the logs, on by default
+    pub fn with_ansi(mut self, ansi: bool) -> Self {
+        self.ansi = ansi;
+        self
+    }
+
+    /// Write the logs there instead of stdout
+    pub fn with_writer(mut self, writer: impl Write + Send + 'static) -> Self {
+        self.writer = Some(BoxMakeWriter::new(Mutex::new(writer)));
+        self
+    }
+
+    /// Also export the spans to an OpenTelemetry collector as service_name, configured with the OTEL_EXPORTER_OTLP_*
+    /// environment variables (i.e: OTEL_EXPORTER_OTLP_ENDPOINT=http://localhost:4318).
+    /// The spans filtered out by the directives are not exported either. Call otlp::shutdown before exiting
+    #[cfg(feature = "otlp")]
+    pub fn with_otlp(mut self, service_name: &str) -> Self {
+        self.otlp_service_name = Some(service_name.to_string());
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<impl Subscriber + Send + Sync + 'static> {
+        let mut env_filter = EnvFilter::builder()
+            .parse(&self.directives)
+            .with_context(|| format!("invalid log directives {}", self.directives))?;
+        // Each http2 frame is logged, only when explicitly asked for
+        if !(self.directives.contains("h2::") || self.directives.contains("h2=")) {
+            env_filter = env_filter.add_directive("h2::codec=off".parse::<Directive>().expect("valid log directive"));
+        }
+
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(self.ansi)
+            .with_env_filter(env_filter)
+            .with_writer(self.writer.unwrap_or_else(|| BoxMakeWriter::new(std::io::stdout)))
+            .finish();
+
+        #[cfg(feature = "otlp")]
+        let subscriber = {
+            use tracing_subscriber::layer::SubscriberExt;
+
+            let otlp = self
+                .otlp_service_name
+                .map(|service_name| super::otlp::layer(&service_name))
+                .transpose()?;
+            subscriber.with(otlp)
+        };
+
+        Ok(subscriber)
+    }
+
+    /// Install the subscriber for the whole program, along with the one of the log crate. Fails if one is already set
+    pub fn try_init(self) -> anyhow::Result<()> {
+        self.build()?
+            .try_init()
+            .map_err(|_| anyhow!("a tracing subscriber is already set, wstunnel logs go to it"))
+    }
+}
+
+/// Let through a log repeated in a loop at most once per interval, counting the ones held back in between
+pub(crate) struct LogThrottle {
+    interval: Duration,
+    last_log: Option<Instant>,
+    suppressed: u64,
+}
+
+impl LogThrottle {
+    pub(crate) const fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_log: None,
+            suppressed: 0,
+        }
+    }
+
+    /// Some with the number of logs suppressed since the previous one if this one is to be logged, None otherwise
+    pub(crate) fn allow(&mut self) -> Option<u64> {
+        self.allow_at(Instant::now())
+    }
+
+    fn allow_at(&mut self, now: Instant) -> Option<u64> {
+        if self
+            .last_log
+            .is_some_and(|last_log| now.saturating_duration_since(last_log) < self.interval)
+        {
+            self.suppressed += 1;
+            return None;
+        }
+
+        self.last_log = Some(now);
+        Some(std::mem::take(&mut self.suppressed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tracing::{debug, info, warn};
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_log_config_filters_per_target() {
+        assert!(LogConfig::parse("info,wstunnel=nope").is_err());
+
+        let logs = SharedBuf::default();
+        let subscriber = LogConfig::new(LevelFilter::DEBUG)
+            .with_target("wstunnel::tunnel::logging::tests", LevelFilter::WARN)
+            .with_ansi(false)
+            .with_writer(logs.clone())
+            .build()
+            .unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            debug!("quiet");
+            info!("quiet too");
+            warn!("loud");
+        });
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("loud"));
+        assert!(!logs.contains("quiet"));
+    }
+
+    #[test]
+    fn test_log_throttle_counts_suppressed() {
+        let mut throttle = LogThrottle::new(Duration::from_secs(5));
+        let now = Instant::now();
+        assert_eq!(throttle.allow_at(now), Some(0));
+        assert_eq!(throttle.allow_at(now + Duration::from_secs(1)), None);
+        assert_eq!(throttle.allow_at(now + Duration::from_secs(4)), None);
+        assert_eq!(throttle.allow_at(now + Duration::from_secs(5)), Some(2));
+        assert_eq!(throttle.allow_at(now + Duration::from_secs(6)), None);
+    }
+}
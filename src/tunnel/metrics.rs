@@ -0,0 +1,120 @@
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    LocalToRemote,
+    RemoteToLocal,
+}
+
+/// How long each phase of connecting a tunnel to the server took.
+/// The connection may have been opened in advance by the pool, its phases are then not waited for by the tunnel.
+/// They are None when it is shared by several tunnels with http2 multiplexing. dns is None through an http proxy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectTiming {
+    pub dns: Option<Duration>,
+    pub tcp: Option<Duration>,
+    pub tls: Option<Duration>,
+    /// Websocket upgrade, http2 request or preamble of the raw transport, until the answer of the server
+    pub upgrade: Option<Duration>,
+}
+
+impl ConnectTiming {
+    pub fn total(&self) -> Duration {
+        [self.dns, self.tcp, self.tls, self.upgrade].iter().flatten().sum()
+    }
+}
+
+impl Display for ConnectTiming {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.total())?;
+        for (phase, duration) in [
+            ("dns", self.dns),
+            ("tcp", self.tcp),
+            ("tls", self.tls),
+            ("upgrade", self.upgrade),
+        ] {
+            if let Some(duration) = duration {
+                write!(f, " {}={:?}", phase, duration)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hooks called during the lifetime of every tunnel, to plug a metrics backend (i.e: prometheus).
+/// Methods are called from the IO path, so implementations must be cheap and never block
+pub trait TunnelMetrics: Send + Sync {
+    fn on_tunnel_open(&self) {}
+    fn on_bytes(&self, _direction: Direction, _nb_bytes: usize) {}
+    fn on_tunnel_close(&self, _duration: Duration) {}
+    /// Round trip time of a websocket ping, each time its pong is received
+    fn on_rtt(&self, _rtt: Duration) {}
+    /// Each time a tunnel is connected to the server, i.e: to feed a histogram per phase
+    fn on_connect(&self, _timing: &ConnectTiming) {}
+}
+
+/// Default recorder, does nothing
+pub struct NoopTunnelMetrics;
+
+impl TunnelMetrics for NoopTunnelMetrics {}
+
+/// Simple recorder keeping global counters, that can be read at any time
+#[derive(Default)]
+pub struct AtomicTunnelMetrics {
+    pub tunnels_opened: AtomicU64,
+    pub tunnels_closed: AtomicU64,
+    pub tunnels_duration_ms: AtomicU64,
+    pub bytes_local_to_remote: AtomicU64,
+    pub bytes_remote_to_local: AtomicU64,
+    pub last_rtt_us: AtomicU64,
+    pub last_connect_us: AtomicU64,
+}
+
+impl TunnelMetrics for AtomicTunnelMetrics {
+    fn on_tunnel_open(&self) {
+        self.tunnels_opened.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_bytes(&self, direction: Direction, nb_bytes: usize) {
+        let counter = match direction {
+            Direction::LocalToRemote => &self.bytes_local_to_remote,
+            Direction::RemoteToLocal => &self.bytes_remote_to_local,
+        };
+        counter.fetch_add(nb_bytes as u64, Ordering::Relaxed);
+    }
+
+    fn on_tunnel_close(&self, duration: Duration) {
+        self.tunnels_closed.fetch_add(1, Ordering::Relaxed);
+        self.tunnels_duration_ms
+            .fetch_add(duration.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn on_rtt(&self, rtt: Duration) {
+        self.last_rtt_us.store(rtt.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn on_connect(&self, timing: &ConnectTiming) {
+        self.last_connect_us
+            .store(timing.total().as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connect_timing_display() {
+        let timing = ConnectTiming {
+            dns: None,
+            tcp: Some(Duration::from_millis(10)),
+            tls: Some(Duration::from_millis(20)),
+            upgrade: Some(Duration::from_millis(5)),
+        };
+        assert_eq!(timing.total(), Duration::from_millis(35));
+        assert_eq!(timing.to_string(), "35ms tcp=10ms tls=20ms upgrade=5ms");
+        assert_eq!(ConnectTiming::default().to_string(), "0ns");
+    }
+}
@@ -1,25 +1,39 @@
 pub mod client;
+pub mod clock;
 pub mod connectors;
+mod error;
+pub mod jwt;
 pub mod listeners;
+pub mod logging;
+pub mod metrics;
+#[cfg(feature = "otlp")]
+pub mod otlp;
 pub mod server;
 mod tls_reloader;
 mod transport;
 
-use crate::{LocalProtocol, TlsClientConfig};
-use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+pub use error::{ConnectErrorKind, TunnelConnectError};
+pub use jwt::JWT_KEYS;
+pub use transport::http2::Http2MultiplexConfig;
+pub use transport::io::RateLimit;
+pub use transport::priority::TunnelPriority;
+pub use transport::{expand_env_vars, expand_vars, MIN_COPY_BUFFER_SIZE};
+
+use crate::tunnel::client::TlsClientConfig;
+use crate::LocalProtocol;
+use anyhow::{anyhow, Context as _};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
 use std::fmt::{Debug, Display, Formatter};
 use std::io::{Error, IoSlice};
-use std::net::{IpAddr, SocketAddr};
-use std::ops::Deref;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use std::pin::Pin;
 use std::str::FromStr;
 use std::task::{Context, Poll};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::TcpStream;
 use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::{DnsName, ServerName};
 use url::Host;
 use uuid::Uuid;
 
@@ -29,10 +43,14 @@ struct JwtTunnelConfig {
     pub p: LocalProtocol, // protocol to use
     pub r: String,        // remote host
     pub rp: u16,          // remote port
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub src: Option<SocketAddr>, // source of the tunnel, for the proxy protocol
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<String>, // opaque to wstunnel, i.e: tenant of the client, for the server logs and restrictions
 }
 
 impl JwtTunnelConfig {
-    fn new(request_id: Uuid, dest: &RemoteAddr) -> Self {
+    fn new(request_id: Uuid, dest: &RemoteAddr, metadata: Option<&str>) -> Self {
         Self {
             id: request_id.to_string(),
             p: match dest.protocol {
@@ -47,50 +65,71 @@ impl JwtTunnelConfig {
                 LocalProtocol::TProxyTcp => LocalProtocol::Tcp { proxy_protocol: false },
                 LocalProtocol::TProxyUdp { timeout } => LocalProtocol::Udp { timeout },
                 LocalProtocol::Unix { .. } => LocalProtocol::Tcp { proxy_protocol: false },
+                LocalProtocol::UnixSocket { .. } => dest.protocol.clone(),
+                LocalProtocol::Exec => dest.protocol.clone(),
+                LocalProtocol::TcpTls => dest.protocol.clone(),
                 LocalProtocol::ReverseUnix { .. } => dest.protocol.clone(),
                 LocalProtocol::ReverseHttpProxy { .. } => dest.protocol.clone(),
             },
             r: dest.host.to_string(),
             rp: dest.port,
+            src: match dest.protocol {
+                LocalProtocol::Tcp { proxy_protocol: true } => dest.source,
+                _ => None,
+            },
+            metadata: metadata.map(str::to_string),
         }
     }
 }
 
-fn tunnel_to_jwt_token(request_id: Uuid, tunnel: &RemoteAddr) -> String {
-    let cfg = JwtTunnelConfig::new(request_id, tunnel);
-    let (alg, secret) = JWT_KEY.deref();
-    jsonwebtoken::encode(alg, &cfg, secret).unwrap_or_default()
+fn tunnel_to_jwt_token(request_id: Uuid, tunnel: &RemoteAddr, metadata: Option<&str>) -> anyhow::Result<String> {
+    JWT_KEYS.encode(&JwtTunnelConfig::new(request_id, tunnel, metadata))
 }
 
-static JWT_HEADER_PREFIX: &str = "authorization.bearer.";
-static JWT_SECRET: &[u8; 15] = b"champignonfrais";
-static JWT_KEY: Lazy<(Header, EncodingKey)> =
-    Lazy::new(|| (Header::new(Algorithm::HS256), EncodingKey::from_secret(JWT_SECRET)));
-
-static JWT_DECODE: Lazy<(Validation, DecodingKey)> = Lazy::new(|| {
-    let mut validation = Validation::new(Algorithm::HS256);
-    validation.required_spec_claims = HashSet::with_capacity(0);
-    (validation, DecodingKey::from_secret(JWT_SECRET))
-});
+pub static JWT_HEADER_PREFIX: &str = "authorization.bearer.";
 
 #[derive(Debug, Clone)]
 pub struct RemoteAddr {
     pub protocol: LocalProtocol,
     pub host: Host,
     pub port: u16,
+    // Address of the peer that opened the tunnel, if known. Only forwarded to the server for the proxy protocol
+    pub source: Option<SocketAddr>,
+    // Id of the tunnel in its logs, to correlate them with the system that opened it. A uuid v7 is generated if None.
+    // Set it on the connections of a listener with listeners::with_request_ids.
+    // A reverse tunnel uses it for its first connection to the server only, the next ones get a generated id
+    pub request_id: Option<Uuid>,
 }
 
-#[derive(Copy, Clone, Debug)]
+impl RemoteAddr {
+    /// Reverse tunnel asking the server to listen on an ephemeral port. The server only binds it and answers
+    /// with the port it got, the client then asks for this port explicitly for the actual connections
+    pub const fn is_ephemeral_bind(&self) -> bool {
+        self.port == 0
+            && matches!(
+                self.protocol,
+                LocalProtocol::ReverseTcp
+                    | LocalProtocol::ReverseUdp { .. }
+                    | LocalProtocol::ReverseSocks5 { .. }
+                    | LocalProtocol::ReverseHttpProxy { .. }
+            )
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum TransportScheme {
     Ws,
     Wss,
     Http,
     Https,
+    // Tunnels straight over tcp, or tls, without any http upgrade. Only for trusted links, see transport::raw
+    Tcp,
+    Tls,
 }
 
 impl TransportScheme {
     pub const fn values() -> &'static [Self] {
-        &[Self::Ws, Self::Wss, Self::Http, Self::Https]
+        &[Self::Ws, Self::Wss, Self::Http, Self::Https, Self::Tcp, Self::Tls]
     }
     pub const fn to_str(self) -> &'static str {
         match self {
@@ -98,17 +137,52 @@ impl TransportScheme {
             Self::Wss => "wss",
             Self::Http => "http",
             Self::Https => "https",
+            Self::Tcp => "tcp",
+            Self::Tls => "tls",
         }
     }
 
     pub fn alpn_protocols(&self) -> Vec<Vec<u8>> {
+        self.alpn_protocol()
+            .map_or_else(Vec::new, |protocol| vec![protocol.as_bytes().to_vec()])
+    }
+
+    /// The protocol the transport speaks over TLS, and so the only one the server can negotiate with ALPN
+    pub const fn alpn_protocol(&self) -> Option<&'static str> {
         match self {
-            Self::Ws => vec![],
-            Self::Wss => vec![b"http/1.1".to_vec()],
-            Self::Http => vec![],
-            Self::Https => vec![b"h2".to_vec()],
+            Self::Ws | Self::Http | Self::Tcp | Self::Tls => None,
+            Self::Wss => Some("http/1.1"),
+            Self::Https => Some("h2"),
         }
     }
+
+    /// The server serves either the raw transport or the http based ones (websocket and http2), not both
+    pub const fn is_raw(&self) -> bool {
+        matches!(self, Self::Tcp | Self::Tls)
+    }
+
+    /// Check that an ALPN list overriding the default one still offers the protocol of the transport
+    pub fn check_alpn_protocols(&self, protocols: &[String]) -> anyhow::Result<()> {
+        let Some(expected) = self.alpn_protocol() else {
+            return Err(anyhow!(
+                "ALPN protocols are only used with the wss and https transports, not {}",
+                self
+            ));
+        };
+        if let Some(protocol) = protocols.iter().find(|p| p.is_empty() || p.len() > 255) {
+            return Err(anyhow!("invalid ALPN protocol {:?}, it must be 1 to 255 bytes long", protocol));
+        }
+        if !protocols.iter().any(|p| p == expected) {
+            return Err(anyhow!(
+                "ALPN protocols {:?} must contain {} to use the {} transport",
+                protocols,
+                expected,
+                self
+            ));
+        }
+
+        Ok(())
+    }
 }
 impl FromStr for TransportScheme {
     type Err = ();
@@ -119,6 +193,8 @@ impl FromStr for TransportScheme {
             "http" => Ok(Self::Http),
             "wss" => Ok(Self::Wss),
             "ws" => Ok(Self::Ws),
+            "tcp" => Ok(Self::Tcp),
+            "tls" => Ok(Self::Tls),
             _ => Err(()),
         }
     }
@@ -154,6 +230,17 @@ pub enum TransportAddr {
         host: Host,
         port: u16,
     },
+    Tls {
+        scheme: TransportScheme,
+        tls: TlsClientConfig,
+        host: Host,
+        port: u16,
+    },
+    Tcp {
+        scheme: TransportScheme,
+        host: Host,
+        port: u16,
+    },
 }
 
 impl Debug for TransportAddr {
@@ -187,8 +274,20 @@ impl TransportAddr {
                 host,
                 port,
             }),
+            TransportScheme::Tls => Some(Self::Tls {
+                scheme: TransportScheme::Tls,
+                tls: tls?,
+                host,
+                port,
+            }),
+            TransportScheme::Tcp => Some(Self::Tcp {
+                scheme: TransportScheme::Tcp,
+                host,
+                port,
+            }),
         }
     }
+
     pub const fn is_websocket(&self) -> bool {
         matches!(self, Self::Ws { .. } | Self::Wss { .. })
     }
@@ -197,12 +296,47 @@ impl TransportAddr {
         matches!(self, Self::Http { .. } | Self::Https { .. })
     }
 
+    pub fn tls_server_name(&self) -> ServerName<'static> {
+        static INVALID_DNS_NAME: Lazy<DnsName> = Lazy::new(|| DnsName::try_from("dns-name-invalid.com").unwrap());
+
+        self.tls().and_then(|tls| tls.tls_sni_override.as_ref()).map_or_else(
+            || match self.host() {
+                Host::Domain(domain) => {
+                    ServerName::DnsName(DnsName::try_from(domain.clone()).unwrap_or_else(|_| INVALID_DNS_NAME.clone()))
+                }
+                Host::Ipv4(ip) => ServerName::IpAddress(IpAddr::V4(*ip).into()),
+                Host::Ipv6(ip) => ServerName::IpAddress(IpAddr::V6(*ip).into()),
+            },
+            |sni_override| ServerName::DnsName(sni_override.clone()),
+        )
+    }
+
+    /// Name the server certificate is verified against: the explicit verification hostname, else the SNI override,
+    /// else the host of the server. None if the host is not a valid domain name
+    pub fn tls_verification_name(&self) -> Option<ServerName<'static>> {
+        let tls = self.tls()?;
+        if let Some(hostname) = &tls.tls_verify_hostname {
+            return Some(hostname.clone());
+        }
+        if let Some(sni_override) = &tls.tls_sni_override {
+            return Some(ServerName::DnsName(sni_override.clone()));
+        }
+
+        match self.host() {
+            Host::Domain(domain) => DnsName::try_from(domain.clone()).ok().map(ServerName::DnsName),
+            Host::Ipv4(ip) => Some(ServerName::IpAddress(IpAddr::V4(*ip).into())),
+            Host::Ipv6(ip) => Some(ServerName::IpAddress(IpAddr::V6(*ip).into())),
+        }
+    }
+
     pub const fn tls(&self) -> Option<&TlsClientConfig> {
         match self {
             Self::Wss { tls, .. } => Some(tls),
             Self::Https { tls, .. } => Some(tls),
+            Self::Tls { tls, .. } => Some(tls),
             Self::Ws { .. } => None,
             Self::Http { .. } => None,
+            Self::Tcp { .. } => None,
         }
     }
 
@@ -212,6 +346,8 @@ impl TransportAddr {
             Self::Ws { host, .. } => host,
             Self::Https { host, .. } => host,
             Self::Http { host, .. } => host,
+            Self::Tls { host, .. } => host,
+            Self::Tcp { host, .. } => host,
         }
     }
 
@@ -221,6 +357,8 @@ impl TransportAddr {
             Self::Ws { port, .. } => *port,
             Self::Https { port, .. } => *port,
             Self::Http { port, .. } => *port,
+            Self::Tls { port, .. } => *port,
+            Self::Tcp { port, .. } => *port,
         }
     }
 
@@ -230,6 +368,8 @@ impl TransportAddr {
             Self::Ws { scheme, .. } => scheme,
             Self::Https { scheme, .. } => scheme,
             Self::Http { scheme, .. } => scheme,
+            Self::Tls { scheme, .. } => scheme,
+            Self::Tcp { scheme, .. } => scheme,
         }
     }
 }
@@ -239,12 +379,15 @@ impl TryFrom<JwtTunnelConfig> for RemoteAddr {
     fn try_from(jwt: JwtTunnelConfig) -> anyhow::Result<Self> {
         Ok(Self {
             protocol: jwt.p,
-            host: Host::parse(&jwt.r)?,
+            host: parse_host(&jwt.r)?,
             port: jwt.rp,
+            source: jwt.src,
+            request_id: None,
         })
     }
 }
 
+#[allow(clippy::large_enum_variant)]
 pub enum TransportStream {
     Plain(TcpStream),
     Tls(TlsStream<TcpStream>),
@@ -306,3 +449,142 @@ pub fn to_host_port(addr: SocketAddr) -> (Host, u16) {
         IpAddr::V6(ip) => (Host::Ipv6(ip), addr.port()),
     }
 }
+
+/// Parse a host received from a peer (tunnel token, proxy request), an IPv6 being accepted with or without brackets.
+/// Host cannot hold the zone of a link local IPv6 (i.e: fe80::1%eth0), so it is dropped instead of failing
+pub fn parse_host(host: &str) -> anyhow::Result<Host> {
+    let unbracketed = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    if unbracketed.contains(':') {
+        let (ip, _zone) = unbracketed.split_once('%').unwrap_or((unbracketed, ""));
+        let ip = Ipv6Addr::from_str(ip).with_context(|| format!("invalid IPv6 address {}", host))?;
+        return Ok(Host::Ipv6(ip));
+    }
+
+    Ok(Host::parse(host)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_host_jwt_roundtrip() {
+        let hosts = [
+            Host::Ipv4(Ipv4Addr::new(192, 168, 1, 2)),
+            Host::Ipv6(Ipv6Addr::from_str("2001:db8::1").unwrap()),
+            Host::Ipv6(Ipv6Addr::LOCALHOST),
+            Host::Domain("example.com".to_string()),
+        ];
+        for host in hosts {
+            let remote = RemoteAddr {
+                protocol: LocalProtocol::Tcp { proxy_protocol: false },
+                host,
+                port: 443,
+                source: None,
+                request_id: None,
+            };
+            let token = tunnel_to_jwt_token(Uuid::now_v7(), &remote, None).unwrap();
+            let jwt = JWT_KEYS.decode::<JwtTunnelConfig>(&token).unwrap();
+            let decoded = RemoteAddr::try_from(jwt.claims).unwrap();
+            assert_eq!(decoded.host, remote.host);
+            assert_eq!(decoded.port, remote.port);
+        }
+    }
+
+    #[test]
+    fn test_parse_host() {
+        let ipv6 = |ip: &str| Host::<String>::Ipv6(Ipv6Addr::from_str(ip).unwrap());
+        assert_eq!(
+            parse_host("10.0.0.1").unwrap(),
+            Host::<String>::Ipv4(Ipv4Addr::new(10, 0, 0, 1))
+        );
+        assert_eq!(parse_host("[2001:db8::1]").unwrap(), ipv6("2001:db8::1"));
+        assert_eq!(parse_host("2001:db8::1").unwrap(), ipv6("2001:db8::1"));
+        assert_eq!(parse_host("[::ffff:10.0.0.1]").unwrap(), ipv6("::ffff:10.0.0.1"));
+        assert_eq!(parse_host("fe80::1%eth0").unwrap(), ipv6("fe80::1"));
+        assert_eq!(parse_host("[fe80::1%25eth0]").unwrap(), ipv6("fe80::1"));
+        assert_eq!(parse_host("Example.com").unwrap(), Host::Domain("example.com".to_string()));
+        assert!(parse_host("[2001:db8::1]:443").is_err());
+        assert!(parse_host("[not::an:ip:zz]").is_err());
+    }
+
+    #[test]
+    fn test_unix_socket_tunnel_jwt_roundtrip() {
+        let remote = RemoteAddr {
+            protocol: LocalProtocol::UnixSocket {
+                path: PathBuf::from("/var/run/docker.sock"),
+            },
+            host: Host::Domain("localhost".to_string()),
+            port: 0,
+            source: None,
+            request_id: None,
+        };
+
+        let token = tunnel_to_jwt_token(Uuid::now_v7(), &remote, None).unwrap();
+        let jwt = JWT_KEYS.decode::<JwtTunnelConfig>(&token).unwrap();
+        let decoded = RemoteAddr::try_from(jwt.claims).unwrap();
+        assert_eq!(decoded.protocol, remote.protocol);
+    }
+
+    #[test]
+    fn test_tunnel_source_only_sent_for_proxy_protocol() {
+        let mut remote = RemoteAddr {
+            protocol: LocalProtocol::Tcp { proxy_protocol: true },
+            host: Host::Domain("n.lan".to_string()),
+            port: 4,
+            source: Some("192.168.1.2:4242".parse().unwrap()),
+            request_id: None,
+        };
+
+        let token = tunnel_to_jwt_token(Uuid::now_v7(), &remote, None).unwrap();
+        let jwt = JWT_KEYS.decode::<JwtTunnelConfig>(&token).unwrap();
+        assert_eq!(RemoteAddr::try_from(jwt.claims).unwrap().source, remote.source);
+
+        remote.protocol = LocalProtocol::Tcp { proxy_protocol: false };
+        let token = tunnel_to_jwt_token(Uuid::now_v7(), &remote, None).unwrap();
+        let jwt = JWT_KEYS.decode::<JwtTunnelConfig>(&token).unwrap();
+        assert_eq!(RemoteAddr::try_from(jwt.claims).unwrap().source, None);
+    }
+
+    #[test]
+    fn test_tunnel_metadata_jwt_roundtrip() {
+        let remote = RemoteAddr {
+            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            host: Host::Domain("n.lan".to_string()),
+            port: 4,
+            source: None,
+            request_id: None,
+        };
+
+        let token = tunnel_to_jwt_token(Uuid::now_v7(), &remote, Some("tenant=acme; user=42")).unwrap();
+        let jwt = JWT_KEYS.decode::<JwtTunnelConfig>(&token).unwrap();
+        assert_eq!(jwt.claims.metadata.as_deref(), Some("tenant=acme; user=42"));
+
+        let token = tunnel_to_jwt_token(Uuid::now_v7(), &remote, None).unwrap();
+        let jwt = JWT_KEYS.decode::<JwtTunnelConfig>(&token).unwrap();
+        assert_eq!(jwt.claims.metadata, None);
+    }
+
+    #[test]
+    fn test_check_alpn_protocols() {
+        let protocols = |p: &[&str]| p.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        assert!(TransportScheme::Https.check_alpn_protocols(&protocols(&["h2"])).is_ok());
+        assert!(TransportScheme::Wss
+            .check_alpn_protocols(&protocols(&["h2", "http/1.1"]))
+            .is_ok());
+        assert!(TransportScheme::Https
+            .check_alpn_protocols(&protocols(&["http/1.1"]))
+            .is_err());
+        assert!(TransportScheme::Wss
+            .check_alpn_protocols(&protocols(&["http/1.1", ""]))
+            .is_err());
+        assert!(TransportScheme::Ws
+            .check_alpn_protocols(&protocols(&["http/1.1"]))
+            .is_err());
+    }
+}
@@ -0,0 +1,125 @@
+use std::sync::OnceLock;
+
+use anyhow::Context;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+use url::Url;
+
+// Kept to flush the spans still in the batch on exit
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// Tracing layer exporting the spans to an OpenTelemetry collector, with OTLP over http.
+/// Every tunnel is then a trace, with its connect and transfer spans as children.
+/// The exporter is configured with the standard environment variables, i.e: OTEL_EXPORTER_OTLP_ENDPOINT,
+/// OTEL_EXPORTER_OTLP_HEADERS or OTEL_BSP_SCHEDULE_DELAY. The spans are sent in batches from a thread of their own
+pub fn layer<S>(service_name: &str) -> anyhow::Result<OpenTelemetryLayer<S, SdkTracer>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    // The exporter silently falls back to localhost when they cannot be parsed
+    for var in ["OTEL_EXPORTER_OTLP_TRACES_ENDPOINT", "OTEL_EXPORTER_OTLP_ENDPOINT"] {
+        if let Ok(endpoint) = std::env::var(var) {
+            Url::parse(&endpoint).with_context(|| format!("invalid {} {}", var, endpoint))?;
+        }
+    }
+
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .build()
+        .context("cannot create otlp exporter")?;
+    let provider = tracer_provider(exporter, service_name);
+    let tracer = provider.tracer("wstunnel");
+    // Only the first one is flushed by shutdown, a program sets up its logging once
+    let _ = PROVIDER.set(provider);
+
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Export the spans still waiting in the batch, before the program exits. Blocks until the collector answers
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() {
+        let _ = provider.shutdown();
+    }
+}
+
+fn tracer_provider(exporter: SpanExporter, service_name: &str) -> SdkTracerProvider {
+    SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(service_name.to_string()).build())
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry_otlp::WithExportConfig;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use tracing::{info_span, Level};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    fn find(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|window| window == needle)
+    }
+
+    #[test]
+    fn test_spans_exported_as_traces() {
+        let collector = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/traces", collector.local_addr().unwrap());
+        let collector = std::thread::spawn(move || {
+            let (mut stream, _) = collector.accept().unwrap();
+            let mut request = vec![];
+            let mut buf = [0u8; 4096];
+            loop {
+                let n = stream.read(&mut buf).unwrap();
+                assert_ne!(n, 0, "collector request is incomplete");
+                request.extend_from_slice(&buf[..n]);
+                let Some(headers_end) = request.windows(4).position(|window| window == b"\r\n\r\n") else {
+                    continue;
+                };
+                let headers = String::from_utf8_lossy(&request[..headers_end]).to_lowercase();
+                let content_length: usize = headers
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length: "))
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                if request.len() >= headers_end + 4 + content_length {
+                    break;
+                }
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .unwrap();
+            request
+        });
+
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+            .unwrap();
+        let provider = tracer_provider(exporter, "wstunnel-test");
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("wstunnel")));
+        tracing::subscriber::with_default(subscriber, || {
+            let tunnel = info_span!("tunnel", id = "42");
+            tunnel.in_scope(|| {
+                let _connect = tracing::span!(Level::DEBUG, "connect").entered();
+            });
+        });
+        provider.shutdown().unwrap();
+
+        // The body is protobuf, where strings are written as is
+        let request = collector.join().unwrap();
+        assert!(String::from_utf8_lossy(&request).starts_with("POST /v1/traces HTTP/1.1"));
+        for expected in [&b"wstunnel-test"[..], b"tunnel", b"connect", b"42"] {
+            assert!(find(&request, expected), "{:?} not exported", String::from_utf8_lossy(expected));
+        }
+    }
+}
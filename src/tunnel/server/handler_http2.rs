@@ -1,8 +1,9 @@
 use crate::restrictions::types::RestrictionsRules;
-use crate::tunnel::server::utils::{bad_request, inject_cookie};
+use crate::tunnel::server::utils::{bad_request, health_check, inject_cookie, is_datagram_tunnel, spawn_server_tunnel};
 use crate::tunnel::server::WsServer;
-use crate::tunnel::transport;
-use crate::tunnel::transport::http2::{Http2TunnelRead, Http2TunnelWrite};
+use crate::tunnel::transport::datagram::{has_datagram_framing, set_datagram_framing};
+use crate::tunnel::transport::http2::{Http2TunnelRead, Http2TunnelWrite, HTTP2_COPY_BUFFER_SIZE};
+use crate::tunnel::transport::mux::set_reverse_multiplex;
 use bytes::Bytes;
 use futures_util::StreamExt;
 use http_body_util::combinators::BoxBody;
@@ -10,11 +11,11 @@ use http_body_util::{BodyStream, Either, StreamBody};
 use hyper::body::{Frame, Incoming};
 use hyper::header::CONTENT_TYPE;
 use hyper::{Request, Response, StatusCode};
+use std::future;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
-use tracing::{Instrument, Span};
 
 pub(super) async fn http_server_upgrade(
     server: WsServer,
@@ -23,13 +24,18 @@ pub(super) async fn http_server_upgrade(
     client_addr: SocketAddr,
     mut req: Request<Incoming>,
 ) -> Response<Either<String, BoxBody<Bytes, anyhow::Error>>> {
-    let (remote_addr, local_rx, local_tx, need_cookie) = match server
+    if let Some(response) = health_check(&server, &req) {
+        return response;
+    }
+
+    let (remote_addr, local_rx, local_tx, need_cookie, multiplexed, subject_tunnel) = match server
         .handle_tunnel_request(restrictions, restrict_path_prefix, client_addr, &req)
         .await
     {
         Ok(ret) => ret,
         Err(err) => return err,
     };
+    let length_prefixed = is_datagram_tunnel(&remote_addr) && has_datagram_framing(req.headers());
 
     let req_content_type = req.headers_mut().remove(CONTENT_TYPE);
     let ws_rx = BodyStream::new(req.into_body());
@@ -43,23 +49,26 @@ pub(super) async fn http_server_upgrade(
         .body(Either::Right(body))
         .expect("bug: failed to build response");
 
-    tokio::spawn(
-        async move {
-            let (close_tx, close_rx) = oneshot::channel::<()>();
-            tokio::task::spawn(
-                transport::io::propagate_remote_to_local(local_tx, Http2TunnelRead::new(ws_rx), close_rx)
-                    .instrument(Span::current()),
-            );
-
-            let _ =
-                transport::io::propagate_local_to_remote(local_rx, Http2TunnelWrite::new(ws_tx), close_tx, None).await;
-        }
-        .instrument(Span::current()),
+    let ws_rx = Http2TunnelRead::new(ws_rx);
+    let ws_tx = Http2TunnelWrite::new(ws_tx, HTTP2_COPY_BUFFER_SIZE);
+    spawn_server_tunnel(
+        &server,
+        local_rx,
+        local_tx,
+        future::ready(Some((ws_rx, ws_tx))),
+        subject_tunnel,
+        length_prefixed,
     );
 
     if need_cookie && inject_cookie(&mut response, &remote_addr).is_err() {
         return bad_request();
     }
+    if length_prefixed {
+        set_datagram_framing(response.headers_mut());
+    }
+    if multiplexed {
+        set_reverse_multiplex(response.headers_mut());
+    }
 
     if let Some(content_type) = req_content_type {
         response.headers_mut().insert(CONTENT_TYPE, content_type);
@@ -0,0 +1,120 @@
+use crate::restrictions::types::RestrictionsRules;
+use crate::tunnel::server::utils::{health_check, inject_cookie, is_datagram_tunnel, not_found, spawn_server_tunnel};
+use crate::tunnel::server::WsServer;
+use crate::tunnel::transport::datagram::{has_datagram_framing, set_datagram_framing};
+use crate::tunnel::transport::mux::set_reverse_multiplex;
+use crate::tunnel::transport::raw::{read_preamble, write_preamble, RawTunnelRead, RawTunnelWrite, PREAMBLE_TIMEOUT};
+use crate::tunnel::transport::MAX_PACKET_LENGTH;
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use std::future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader};
+use tracing::warn;
+
+/// The preamble of a raw client starts with its length, whose first byte is always 0 as it is far below 16MiB,
+/// while an http request starts with its method
+async fn is_http_request(stream: &mut (impl AsyncBufRead + Unpin)) -> bool {
+    matches!(
+        tokio::time::timeout(PREAMBLE_TIMEOUT, stream.fill_buf()).await,
+        Ok(Ok([first, ..])) if first.is_ascii_uppercase()
+    )
+}
+
+/// Answer the health checks of load balancers, which only speak http, on a connection of the raw transport
+async fn serve_health_check<S>(server: WsServer, stream: S)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |req: Request<Incoming>| {
+        future::ready(Ok::<_, Infallible>(health_check(&server, &req).unwrap_or_else(not_found)))
+    });
+    if let Err(err) = http1::Builder::new()
+        .serve_connection(TokioIo::new(stream), service)
+        .await
+    {
+        warn!("Error while serving health check: {}", err);
+    }
+}
+
+/// Serve a connection of the raw transport: read the preamble of the client, answer it and run the tunnel
+pub(super) async fn raw_server_tunnel<S>(
+    server: WsServer,
+    restrictions: Arc<RestrictionsRules>,
+    restrict_path_prefix: Option<String>,
+    client_addr: SocketAddr,
+    stream: S,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut stream = BufReader::new(stream);
+    if server.config.health_check_path.is_some() && is_http_request(&mut stream).await {
+        serve_health_check(server, stream).await;
+        return;
+    }
+
+    let (path, headers) = match tokio::time::timeout(PREAMBLE_TIMEOUT, read_preamble(&mut stream)).await {
+        Ok(Ok(preamble)) => preamble,
+        Ok(Err(err)) => {
+            warn!("Rejecting connection with bad preamble: {}", err);
+            return;
+        }
+        Err(_) => {
+            warn!("Rejecting connection without preamble after {:?}", PREAMBLE_TIMEOUT);
+            return;
+        }
+    };
+    let mut req = match Request::builder().uri(path).body(()) {
+        Ok(req) => req,
+        Err(err) => {
+            warn!("Rejecting connection with bad path in preamble: {}", err);
+            return;
+        }
+    };
+    *req.headers_mut() = headers;
+
+    let (remote_addr, local_rx, local_tx, need_cookie, multiplexed, subject_tunnel) = match server
+        .handle_tunnel_request(restrictions, restrict_path_prefix, client_addr, &req)
+        .await
+    {
+        Ok(ret) => ret,
+        Err(response) => {
+            let _ = write_preamble(&mut stream, response.status().as_str(), response.headers()).await;
+            return;
+        }
+    };
+    let length_prefixed = is_datagram_tunnel(&remote_addr) && has_datagram_framing(req.headers());
+
+    let mut response = Response::new(String::new());
+    if need_cookie && inject_cookie(&mut response, &remote_addr).is_err() {
+        let _ = write_preamble(&mut stream, StatusCode::BAD_REQUEST.as_str(), &Default::default()).await;
+        return;
+    }
+    if length_prefixed {
+        set_datagram_framing(response.headers_mut());
+    }
+    if multiplexed {
+        set_reverse_multiplex(response.headers_mut());
+    }
+    if let Err(err) = write_preamble(&mut stream, response.status().as_str(), response.headers()).await {
+        warn!("Cannot answer the preamble of the client: {}", err);
+        return;
+    }
+
+    let (ws_rx, ws_tx) = tokio::io::split(stream);
+    let ws_tx = RawTunnelWrite::new(Box::pin(ws_tx), MAX_PACKET_LENGTH);
+    let ws_rx = RawTunnelRead::new(Box::pin(ws_rx), &ws_tx, MAX_PACKET_LENGTH);
+    spawn_server_tunnel(
+        &server,
+        local_rx,
+        local_tx,
+        future::ready(Some((ws_rx, ws_tx))),
+        subject_tunnel,
+        length_prefixed,
+    );
+}
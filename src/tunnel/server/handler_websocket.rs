@@ -1,17 +1,19 @@
 use crate::restrictions::types::RestrictionsRules;
-use crate::tunnel::server::utils::{bad_request, inject_cookie};
+use crate::tunnel::server::utils::{bad_request, health_check, inject_cookie, is_datagram_tunnel, spawn_server_tunnel};
 use crate::tunnel::server::WsServer;
-use crate::tunnel::transport;
-use crate::tunnel::transport::websocket::{WebsocketTunnelRead, WebsocketTunnelWrite};
+use crate::tunnel::transport::datagram::{has_datagram_framing, set_datagram_framing};
+use crate::tunnel::transport::mux::set_reverse_multiplex;
+use crate::tunnel::transport::websocket::{accepted_subprotocol, WebsocketTunnelRead, WebsocketTunnelWrite};
+use crate::tunnel::transport::{CloseReason, MAX_PACKET_LENGTH};
 use bytes::Bytes;
+use fastwebsockets::Frame;
 use http_body_util::combinators::BoxBody;
 use http_body_util::Either;
 use hyper::body::Incoming;
-use hyper::header::{HeaderValue, SEC_WEBSOCKET_PROTOCOL};
+use hyper::header::SEC_WEBSOCKET_PROTOCOL;
 use hyper::{Request, Response};
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::oneshot;
 use tracing::{error, warn, Instrument, Span};
 
 pub(super) async fn ws_server_upgrade(
@@ -21,20 +23,30 @@ pub(super) async fn ws_server_upgrade(
     client_addr: SocketAddr,
     mut req: Request<Incoming>,
 ) -> Response<Either<String, BoxBody<Bytes, anyhow::Error>>> {
+    if let Some(response) = health_check(&server, &req) {
+        return response;
+    }
     if !fastwebsockets::upgrade::is_upgrade_request(&req) {
         warn!("Rejecting connection with bad upgrade request: {}", req.uri());
         return bad_request();
     }
 
     let mask_frame = server.config.websocket_mask_frame;
-    let (remote_addr, local_rx, local_tx, need_cookie) = match server
+    let (remote_addr, local_rx, local_tx, need_cookie, multiplexed, subject_tunnel) = match server
         .handle_tunnel_request(restrictions, restrict_path_prefix, client_addr, &req)
         .await
     {
         Ok(ret) => ret,
-        Err(err) => return err,
+        Err(err) => match err.extensions().get::<CloseReason>() {
+            Some(reason) => return close_after_upgrade(&server, req, reason.clone()),
+            None => return err,
+        },
     };
+    let length_prefixed = is_datagram_tunnel(&remote_addr) && has_datagram_framing(req.headers());
+    let subprotocol = accepted_subprotocol(req.headers());
 
+    // Sec-WebSocket-Extensions is ignored, so permessage-deflate is never negotiated and the client falls back to
+    // uncompressed frames as per RFC 7692. fastwebsockets does not support frames with RSV bits set
     let (response, fut) = match fastwebsockets::upgrade::upgrade(&mut req) {
         Ok(ret) => ret,
         Err(err) => {
@@ -43,38 +55,70 @@ pub(super) async fn ws_server_upgrade(
         }
     };
 
-    tokio::spawn(
+    let transport = async move {
+        let (ws_rx, mut ws_tx) = match fut.await {
+            Ok(ws) => ws.split(tokio::io::split),
+            Err(err) => {
+                error!("Error during http upgrade request: {:?}", err);
+                return None;
+            }
+        };
+        ws_tx.set_auto_apply_mask(mask_frame);
+        let ws_tx = WebsocketTunnelWrite::new(ws_tx, MAX_PACKET_LENGTH);
+        Some((WebsocketTunnelRead::new(ws_rx, &ws_tx), ws_tx))
+    };
+    spawn_server_tunnel(&server, local_rx, local_tx, transport, subject_tunnel, length_prefixed);
+
+    let mut response = Response::from_parts(response.into_parts().0, Either::Right(BoxBody::default()));
+    if need_cookie && inject_cookie(&mut response, &remote_addr).is_err() {
+        return bad_request();
+    }
+    if length_prefixed {
+        set_datagram_framing(response.headers_mut());
+    }
+    if multiplexed {
+        set_reverse_multiplex(response.headers_mut());
+    }
+
+    response.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, subprotocol);
+
+    response
+}
+
+// Accept the upgrade only to close the websocket right away, for the client to know why its tunnel is rejected
+fn close_after_upgrade(
+    server: &WsServer,
+    mut req: Request<Incoming>,
+    reason: CloseReason,
+) -> Response<Either<String, BoxBody<Bytes, anyhow::Error>>> {
+    let subprotocol = accepted_subprotocol(req.headers());
+    let (response, fut) = match fastwebsockets::upgrade::upgrade(&mut req) {
+        Ok(ret) => ret,
+        Err(err) => {
+            warn!("Rejecting connection with bad upgrade request: {} {}", err, req.uri());
+            return bad_request();
+        }
+    };
+
+    let mask_frame = server.config.websocket_mask_frame;
+    server.spawn_tunnel(
         async move {
-            let (ws_rx, mut ws_tx) = match fut.await {
-                Ok(ws) => ws.split(tokio::io::split),
+            let mut ws = match fut.await {
+                Ok(ws) => ws,
                 Err(err) => {
                     error!("Error during http upgrade request: {:?}", err);
                     return;
                 }
             };
-            let (close_tx, close_rx) = oneshot::channel::<()>();
-            ws_tx.set_auto_apply_mask(mask_frame);
-
-            tokio::task::spawn(
-                transport::io::propagate_remote_to_local(local_tx, WebsocketTunnelRead::new(ws_rx), close_rx)
-                    .instrument(Span::current()),
-            );
-
-            let _ =
-                transport::io::propagate_local_to_remote(local_rx, WebsocketTunnelWrite::new(ws_tx), close_tx, None)
-                    .await;
+            ws.set_auto_apply_mask(mask_frame);
+            let _ = ws
+                .write_frame(Frame::close(reason.code, reason.reason.as_bytes()))
+                .await;
         }
         .instrument(Span::current()),
     );
 
     let mut response = Response::from_parts(response.into_parts().0, Either::Right(BoxBody::default()));
-    if need_cookie && inject_cookie(&mut response, &remote_addr).is_err() {
-        return bad_request();
-    }
-
-    response
-        .headers_mut()
-        .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("v1"));
-
+    response.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, subprotocol);
     response
 }
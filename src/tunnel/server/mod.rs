@@ -1,9 +1,14 @@
 #![allow(clippy::module_inception)]
 mod handler_http2;
+mod handler_raw;
 mod handler_websocket;
+mod rate_limiter;
+mod replay_cache;
 mod server;
+mod subject_limits;
 mod utils;
 
 pub use server::TlsServerConfig;
 pub use server::WsServer;
 pub use server::WsServerConfig;
+pub use subject_limits::SubjectLimit;
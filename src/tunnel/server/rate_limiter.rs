@@ -0,0 +1,134 @@
+use ahash::{HashMap, HashMapExt};
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
+
+/// Buckets not touched for this long are full again, and are dropped to not keep every scanned key in memory
+const GC_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    last_gc: Instant,
+}
+
+/// Token bucket rate limiter, with a bucket per key (i.e: a destination, a client ip)
+pub struct KeyedRateLimiter {
+    rate_per_sec: f64,
+    burst: f64,
+    state: Mutex<Buckets>,
+}
+
+impl KeyedRateLimiter {
+    pub fn new(rate_per_sec: f64, burst: u32) -> Self {
+        Self {
+            rate_per_sec,
+            burst: f64::from(burst.max(1)),
+            state: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                last_gc: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take a token from the bucket of the key. Returns false if there is none left
+    pub fn try_acquire(&self, key: &str) -> bool {
+        self.try_acquire_at(key, Instant::now())
+    }
+
+    /// Whether the bucket of the key has no token left, without taking one
+    pub fn is_exhausted(&self, key: &str) -> bool {
+        self.is_exhausted_at(key, Instant::now())
+    }
+
+    fn is_exhausted_at(&self, key: &str, now: Instant) -> bool {
+        let state = self.state.lock();
+        state
+            .buckets
+            .get(key)
+            .is_some_and(|bucket| self.refill(bucket, now) < 1.0)
+    }
+
+    fn try_acquire_at(&self, key: &str, now: Instant) -> bool {
+        let mut state = self.state.lock();
+        if now.saturating_duration_since(state.last_gc) >= GC_INTERVAL {
+            state.last_gc = now;
+            state.buckets.retain(|_, bucket| self.refill(bucket, now) < self.burst);
+        }
+
+        let bucket = state.buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated_at: now,
+        });
+        let tokens = self.refill(bucket, now);
+        bucket.updated_at = now;
+        if tokens < 1.0 {
+            bucket.tokens = tokens;
+            return false;
+        }
+
+        bucket.tokens = tokens - 1.0;
+        true
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated_at).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate_per_sec).min(self.burst)
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.state.lock().buckets.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_then_refill() {
+        let limiter = KeyedRateLimiter::new(2.0, 3);
+        let now = Instant::now();
+
+        assert!(limiter.try_acquire_at("example.com:22", now));
+        assert!(limiter.try_acquire_at("example.com:22", now));
+        assert!(limiter.try_acquire_at("example.com:22", now));
+        assert!(!limiter.try_acquire_at("example.com:22", now));
+        // Other destinations have their own bucket
+        assert!(limiter.try_acquire_at("example.com:443", now));
+
+        assert!(limiter.try_acquire_at("example.com:22", now + Duration::from_millis(500)));
+        assert!(!limiter.try_acquire_at("example.com:22", now + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn test_is_exhausted_does_not_take_tokens() {
+        let limiter = KeyedRateLimiter::new(1.0, 2);
+        let now = Instant::now();
+
+        assert!(!limiter.is_exhausted_at("10.0.0.1", now));
+        assert!(limiter.try_acquire_at("10.0.0.1", now));
+        assert!(!limiter.is_exhausted_at("10.0.0.1", now));
+        assert!(!limiter.is_exhausted_at("10.0.0.1", now));
+        assert!(limiter.try_acquire_at("10.0.0.1", now));
+        assert!(limiter.is_exhausted_at("10.0.0.1", now));
+        assert!(!limiter.is_exhausted_at("10.0.0.1", now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_idle_buckets_are_dropped() {
+        let limiter = KeyedRateLimiter::new(1.0, 2);
+        let now = Instant::now();
+        for port in 0..100 {
+            assert!(limiter.try_acquire_at(&format!("10.0.0.1:{port}"), now));
+        }
+        assert_eq!(limiter.len(), 100);
+
+        assert!(limiter.try_acquire_at("10.0.0.2:22", now + GC_INTERVAL));
+        assert_eq!(limiter.len(), 1);
+    }
+}
@@ -0,0 +1,120 @@
+use ahash::{HashMap, HashMapExt};
+use parking_lot::Mutex;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::time::Duration;
+use tracing::warn;
+
+struct Entries {
+    // jti -> unix timestamp at which the token expires, and replaying it is rejected anyway
+    seen: HashMap<String, u64>,
+    // Soonest to expire first, to drop them without scanning all the entries
+    expirations: BinaryHeap<Reverse<(u64, String)>>,
+}
+
+/// Ids (jti) of the tunnel tokens already used, to reject a captured upgrade request replayed before it expires
+pub struct JtiReplayCache {
+    capacity: usize,
+    ttl: Duration,
+    state: Mutex<Entries>,
+}
+
+impl JtiReplayCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            ttl,
+            state: Mutex::new(Entries {
+                seen: HashMap::new(),
+                expirations: BinaryHeap::new(),
+            }),
+        }
+    }
+
+    /// Record the id of a token expiring at `exp` (+ leeway). Returns false if it has already been seen, or if the
+    /// cache is full. Ids are kept until their token expires, or for the ttl of the cache if it has no expiration
+    pub fn insert(&self, jti: &str, exp: Option<u64>, leeway: Duration) -> bool {
+        self.insert_at(jti, exp, leeway, jsonwebtoken::get_current_timestamp())
+    }
+
+    fn insert_at(&self, jti: &str, exp: Option<u64>, leeway: Duration, now: u64) -> bool {
+        let expires_at = exp.map_or(now + self.ttl.as_secs(), |exp| exp + leeway.as_secs());
+
+        let mut state = self.state.lock();
+        while let Some(Reverse((at, _))) = state.expirations.peek() {
+            if *at > now {
+                break;
+            }
+            let Reverse((_, id)) = state.expirations.pop().unwrap();
+            state.seen.remove(&id);
+        }
+
+        if state.seen.contains_key(jti) {
+            return false;
+        }
+        // Forgetting an id before its token expires would let it be replayed, i.e: after flooding the cache
+        if state.seen.len() >= self.capacity {
+            warn!(
+                "Too many tunnel token ids to remember, rejecting {} until some expire. Increase --jwt-replay-cache-size",
+                jti
+            );
+            return false;
+        }
+        state.seen.insert(jti.to_string(), expires_at);
+        state.expirations.push(Reverse((expires_at, jti.to_string())));
+        true
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.state.lock().seen.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replayed_ids_are_rejected_until_they_expire() {
+        let cache = JtiReplayCache::new(10, Duration::from_secs(120));
+        let leeway = Duration::from_secs(30);
+        let now = 1_000;
+
+        assert!(cache.insert_at("a", Some(now + 60), leeway, now));
+        assert!(!cache.insert_at("a", Some(now + 60), leeway, now + 10));
+        assert!(cache.insert_at("b", Some(now + 60), leeway, now + 10));
+        // Still rejected during the leeway, the token itself is accepted until then
+        assert!(!cache.insert_at("a", Some(now + 60), leeway, now + 89));
+        assert!(cache.insert_at("a", Some(now + 60), leeway, now + 90));
+
+        // Tokens expiring after the ttl of the cache are remembered until then
+        assert!(cache.insert_at("long", Some(now + 600), leeway, now));
+        assert!(!cache.insert_at("long", Some(now + 600), leeway, now + 629));
+        assert!(cache.insert_at("long", Some(now + 600), leeway, now + 630));
+
+        // Tokens without expiration are remembered for the ttl of the cache
+        assert!(cache.insert_at("c", None, leeway, now));
+        assert!(!cache.insert_at("c", None, leeway, now + 119));
+        assert!(cache.insert_at("c", None, leeway, now + 120));
+    }
+
+    #[test]
+    fn test_cache_is_bounded() {
+        let cache = JtiReplayCache::new(3, Duration::from_secs(120));
+        let now = 1_000;
+        for (ix, id) in ["a", "b", "c"].iter().enumerate() {
+            assert!(cache.insert_at(id, Some(now + 60 + ix as u64), Duration::ZERO, now));
+        }
+        assert_eq!(cache.len(), 3);
+
+        // Once full, new ids are rejected instead of forgetting the ones not expired yet
+        assert!(!cache.insert_at("d", Some(now + 60), Duration::ZERO, now));
+        assert!(!cache.insert_at("a", Some(now + 60), Duration::ZERO, now));
+        assert_eq!(cache.len(), 3);
+
+        // Until the soonest ones expire
+        assert!(cache.insert_at("d", Some(now + 120), Duration::ZERO, now + 60));
+        assert!(!cache.insert_at("b", Some(now + 61), Duration::ZERO, now + 60));
+    }
+}
@@ -1,5 +1,5 @@
 use ahash::{HashMap, HashMapExt};
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use futures_util::{pin_mut, FutureExt, StreamExt};
 use http_body_util::Either;
 use std::fmt;
@@ -8,14 +8,15 @@ use std::future::Future;
 
 use bytes::Bytes;
 use http_body_util::combinators::BoxBody;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::ops::Deref;
 use std::path::PathBuf;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
 use std::time::Duration;
 
-use crate::tunnel::RemoteAddr;
+use crate::tunnel::{ConnectErrorKind, RemoteAddr, JWT_KEYS};
 use crate::{protocols, LocalProtocol};
 use hyper::body::Incoming;
 use hyper::server::conn::{http1, http2};
@@ -27,26 +28,46 @@ use parking_lot::Mutex;
 use socket2::SockRef;
 
 use crate::protocols::dns::DnsResolver;
+use crate::protocols::geoip::GeoIpDatabase;
+use crate::protocols::tcp::{
+    ConnectFailureBehavior, ResetOnDropWriter, SocketBind, TcpSocketOptions, DEFAULT_LISTEN_BACKLOG,
+};
 use crate::protocols::tls;
+use crate::protocols::tls::TlsVersion;
 use crate::protocols::udp::{UdpStream, UdpStreamWriter};
 use crate::restrictions::config_reloader::RestrictionsRulesReloader;
 use crate::restrictions::types::{RestrictionConfig, RestrictionsRules};
-use crate::tunnel::connectors::{TcpTunnelConnector, TunnelConnector, UdpTunnelConnector};
+use crate::tunnel::clock::Clock;
+use crate::tunnel::connectors::{
+    CommandTunnelConnector, TcpTunnelConnector, TlsTunnelConnector, TunnelConnector, UdpTunnelConnector,
+};
 use crate::tunnel::listeners::{
     new_udp_listener, HttpProxyTunnelListener, Socks5TunnelListener, TcpTunnelListener, TunnelListener,
 };
 use crate::tunnel::server::handler_http2::http_server_upgrade;
+use crate::tunnel::server::handler_raw::raw_server_tunnel;
 use crate::tunnel::server::handler_websocket::ws_server_upgrade;
+use crate::tunnel::server::rate_limiter::KeyedRateLimiter;
+use crate::tunnel::server::replay_cache::JtiReplayCache;
+use crate::tunnel::server::subject_limits::{SubjectLimit, SubjectLimits, SubjectTunnel};
 use crate::tunnel::server::utils::{
-    bad_request, extract_path_prefix, extract_tunnel_info, extract_x_forwarded_for, find_mapped_port, validate_tunnel,
+    bad_gateway, bad_request, denied_cidrs, extract_path_prefix, extract_tunnel_info, extract_x_forwarded_for,
+    find_mapped_port, has_bearer_token, has_path_prefix, health_check, is_multiplexed_reverse_tunnel,
+    log_dropped_unauthorized, log_unauthorized, not_allowed, not_found, policy_violation, proxy_protocol_header,
+    service_unavailable, too_many_requests, unauthorized, validate_tunnel,
 };
 use crate::tunnel::tls_reloader::TlsReloader;
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use crate::tunnel::transport::http2::Http2MultiplexConfig;
+use crate::tunnel::transport::mux;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
 use tokio::net::TcpListener;
 use tokio::select;
 use tokio::sync::mpsc;
+use tokio::task::JoinSet;
 use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ClientConfig;
 use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
 use tracing::{error, info, span, warn, Instrument, Level, Span};
 use url::{Host, Url};
 
@@ -58,52 +79,203 @@ pub struct TlsServerConfig {
     pub tls_certificate_path: Option<PathBuf>,
     pub tls_key_path: Option<PathBuf>,
     pub tls_client_ca_certs_path: Option<PathBuf>,
+    pub tls_min_version: TlsVersion,
 }
 
 pub struct WsServerConfig {
     pub socket_so_mark: Option<u32>,
+    pub socket_bind_address: Option<IpAddr>,
+    pub socket_bind_device: Option<String>,
     pub bind: SocketAddr,
     pub websocket_ping_frequency: Option<Duration>,
     pub timeout_connect: Duration,
+    pub happy_eyeballs_delay: Duration,
+    pub connect_parallelism: usize,
+    pub tcp_options: TcpSocketOptions,
     pub websocket_mask_frame: bool,
     pub tls: Option<TlsServerConfig>,
     pub dns_resolver: DnsResolver,
     pub restriction_config: Option<PathBuf>,
     pub http_proxy: Option<Url>,
+    pub http_upgrade_path_prefix: Option<String>,
+    pub http_upgrade_bearer_token: Option<String>,
+    pub destination_rate_limit: Option<f64>,
+    pub destination_rate_limit_burst: u32,
+    // Http path answering 200 OK to the health checks of load balancers
+    pub health_check_path: Option<String>,
+    pub health_check_expose_version: bool,
+    // On shutdown, the tunnels in flight are given this long to finish, while new ones are refused
+    pub shutdown_grace_period: Duration,
+    // Tag the logs of the tunnels with the country of the clients
+    pub geoip_database: Option<Arc<GeoIpDatabase>>,
+    // Tunnels are closed once they transferred more bytes than this, counted separately for each direction
+    pub max_bytes_per_tunnel_upload: Option<u64>,
+    pub max_bytes_per_tunnel_download: Option<u64>,
+    // Flow control windows announced to the http2 clients, for the ones multiplexing their tunnels.
+    // The connections keep hyper default windows without it
+    pub http2_multiplex: Option<Http2MultiplexConfig>,
+    // Ids of the tunnel tokens already used, kept until they expire, to reject the replayed ones. 0 disables it.
+    // The ttl only applies to the tokens without expiration
+    pub jwt_replay_cache_size: usize,
+    pub jwt_replay_cache_ttl: Duration,
+    // Limits of the tunnels of each subject (the CN of the client certificate with mTLS, or else the client ip),
+    // and of the ones not listed
+    pub default_subject_limit: SubjectLimit,
+    pub subject_limits: Vec<(String, SubjectLimit)>,
+    // How the connections accepted by the reverse tcp tunnels are closed, when the client cannot connect them
+    pub connect_failure_behavior: ConnectFailureBehavior,
+    // Tell the clients why the destination of their tunnel cannot be reached, or is not allowed.
+    // Without it they only get a bad request, to not give a port scanner any hint
+    pub report_connect_errors: bool,
+    // Command run for each exec tunnel, with its stdio connected to the tunnel. Exec tunnels are refused if None
+    pub exec_command: Option<Vec<String>>,
+    // TLS of the connections to the destinations of the tcp+tls tunnels
+    pub destination_tls: Arc<ClientConfig>,
+    // Serve the raw transport (tcp:// or tls://) instead of websocket and http2, tunnels start with a preamble
+    pub raw_transport: bool,
+    // Clock of the timeouts and bandwidth limits of the tunnels, to control the time in tests
+    pub clock: Arc<dyn Clock>,
 }
 
+// Clients failing the bearer token check that often get their new connections closed without reading them
+const UNAUTHORIZED_RATE_PER_SEC: f64 = 1.0;
+const UNAUTHORIZED_BURST: u32 = 10;
+
 #[derive(Clone)]
 pub struct WsServer {
     pub config: Arc<WsServerConfig>,
+    destination_rate_limiter: Option<Arc<KeyedRateLimiter>>,
+    // Failed bearer token checks per client ip. Clients out of tokens are disconnected right after accept
+    unauthorized_limiter: Option<Arc<KeyedRateLimiter>>,
+    jti_replay_cache: Option<Arc<JtiReplayCache>>,
+    subject_limits: Option<Arc<SubjectLimits>>,
+    // Tunnels in flight, to let them finish on shutdown
+    tunnels: Arc<Mutex<JoinSet<()>>>,
+    draining: CancellationToken,
 }
 
 impl WsServer {
     pub fn new(config: WsServerConfig) -> Self {
+        let destination_rate_limiter = config
+            .destination_rate_limit
+            .map(|rate| Arc::new(KeyedRateLimiter::new(rate, config.destination_rate_limit_burst)));
+        let unauthorized_limiter = config
+            .http_upgrade_bearer_token
+            .as_ref()
+            .map(|_| Arc::new(KeyedRateLimiter::new(UNAUTHORIZED_RATE_PER_SEC, UNAUTHORIZED_BURST)));
+        let jti_replay_cache = (config.jwt_replay_cache_size > 0)
+            .then(|| Arc::new(JtiReplayCache::new(config.jwt_replay_cache_size, config.jwt_replay_cache_ttl)));
+        let subject_limits =
+            (config.default_subject_limit != SubjectLimit::default() || !config.subject_limits.is_empty()).then(|| {
+                Arc::new(SubjectLimits::new(
+                    config.default_subject_limit,
+                    config.subject_limits.iter().cloned(),
+                ))
+            });
         Self {
             config: Arc::new(config),
+            destination_rate_limiter,
+            unauthorized_limiter,
+            jti_replay_cache,
+            subject_limits,
+            tunnels: Arc::new(Mutex::new(JoinSet::new())),
+            draining: CancellationToken::new(),
+        }
+    }
+
+    fn record_country(&self, span: &Span, ip: IpAddr) {
+        let Some(geoip_database) = &self.config.geoip_database else {
+            return;
+        };
+        if let Some(country) = geoip_database.country(ip) {
+            span.record("country", country);
+        }
+    }
+
+    /// Whether the server is shutting down, and so refuses new tunnels
+    pub fn is_draining(&self) -> bool {
+        self.draining.is_cancelled()
+    }
+
+    pub(super) fn spawn_tunnel(&self, tunnel: impl Future<Output = ()> + Send + 'static) {
+        let mut tunnels = self.tunnels.lock();
+        // Reap the finished ones, to not keep them forever
+        while tunnels.try_join_next().is_some() {}
+        tunnels.spawn(tunnel);
+    }
+
+    // New tunnels are refused from now on, the ones in flight are given the grace period to finish before being aborted
+    async fn drain_tunnels(&self) {
+        let deadline = tokio::time::Instant::now() + self.config.shutdown_grace_period;
+        let mut check_interval = tokio::time::interval(Duration::from_millis(100));
+        loop {
+            let nb_tunnels = {
+                let mut tunnels = self.tunnels.lock();
+                while tunnels.try_join_next().is_some() {}
+                tunnels.len()
+            };
+            if nb_tunnels == 0 {
+                return;
+            }
+            if tokio::time::Instant::now() >= deadline {
+                warn!("Aborting {} tunnels still open after the shutdown grace period", nb_tunnels);
+                let mut tunnels = std::mem::take(&mut *self.tunnels.lock());
+                tunnels.shutdown().await;
+                return;
+            }
+            check_interval.tick().await;
         }
     }
 
-    pub(super) async fn handle_tunnel_request(
+    pub(super) async fn handle_tunnel_request<B>(
         &self,
         restrictions: Arc<RestrictionsRules>,
         restrict_path_prefix: Option<String>,
         mut client_addr: SocketAddr,
-        req: &Request<Incoming>,
+        req: &Request<B>,
     ) -> Result<
         (
             RemoteAddr,
             Pin<Box<dyn AsyncRead + Send>>,
             Pin<Box<dyn AsyncWrite + Send>>,
             bool,
+            bool,
+            Option<SubjectTunnel>,
         ),
         Response<Either<String, BoxBody<Bytes, anyhow::Error>>>,
     > {
+        if self.is_draining() {
+            return Err(service_unavailable());
+        }
+
+        // Do not give any hint to scanners that something is here, before looking at the tunnel info
+        if let Some(expected_prefix) = &self.config.http_upgrade_path_prefix {
+            if !has_path_prefix(req.uri().path(), expected_prefix) {
+                warn!("Rejecting connection with unknown upgrade path: {}", req.uri());
+                return Err(not_found());
+            }
+        }
+        if let Some(token) = &self.config.http_upgrade_bearer_token {
+            if !has_bearer_token(req, token) {
+                log_unauthorized(req, client_addr);
+                if let Some(limiter) = &self.unauthorized_limiter {
+                    limiter.try_acquire(&client_addr.ip().to_string());
+                }
+                return Err(unauthorized());
+            }
+        }
+
+        // The limits of the clients are keyed on who they are authenticated as, not on what their token claims.
+        // X-Forwarded-For is not used either, clients can send any
+        let subject = restrict_path_prefix
+            .clone()
+            .unwrap_or_else(|| client_addr.ip().to_string());
         match extract_x_forwarded_for(req) {
             Ok(Some((x_forward_for, x_forward_for_str))) => {
                 info!("Request X-Forwarded-For: {:?}", x_forward_for);
                 Span::current().record("forwarded_for", x_forward_for_str);
                 client_addr.set_ip(x_forward_for);
+                self.record_country(&Span::current(), x_forward_for);
             }
             Ok(_) => {}
             Err(_err) => return Err(bad_request()),
@@ -129,48 +301,144 @@ impl WsServer {
             Err(_err) => return Err(bad_request()),
         };
 
-        Span::current().record("id", &jwt.claims.id);
-        Span::current().record("remote", format!("{}:{}", jwt.claims.r, jwt.claims.rp));
-        let remote = match RemoteAddr::try_from(jwt.claims) {
+        if let Some(replay_cache) = &self.jti_replay_cache {
+            let validity = JWT_KEYS.validity();
+            let is_new = match &jwt.claims.jti {
+                Some(jti) => replay_cache.insert(jti, jwt.claims.exp, validity.leeway),
+                // Older clients don't send it, only accepted with --jwt-allow-missing-expiration
+                None => !validity.strict,
+            };
+            if !is_new {
+                warn!("Rejecting replayed or not unique tunnel token {:?}", jwt.claims.jti);
+                return Err(bad_request());
+            }
+        }
+
+        let jwt = jwt.claims.claims;
+        Span::current().record("id", &jwt.id);
+        if let Some(metadata) = &jwt.metadata {
+            Span::current().record("metadata", metadata);
+        }
+        let metadata = jwt.metadata.clone();
+        Span::current().record("remote", format!("{}:{}", jwt.r, jwt.rp));
+        let mut remote = match RemoteAddr::try_from(jwt) {
             Ok(remote) => remote,
             Err(err) => {
                 warn!("Rejecting connection with bad tunnel info: {} {}", err, req.uri());
                 return Err(bad_request());
             }
         };
+        // The source forwarded by the client is only trusted if it cannot be forged with the well known default secret,
+        // otherwise the peer of the tunnel is the source announced to the destination
+        if remote.source.is_none() || JWT_KEYS.accepts_default_secret() {
+            remote.source = Some(client_addr);
+        }
 
-        let restriction = match validate_tunnel(&remote, path_prefix, &restrictions) {
+        // The metadata is asserted by the client, the restrictions only match it when it cannot be forged with the
+        // well known default secret
+        let trusted_metadata = metadata.as_deref().filter(|_| !JWT_KEYS.accepts_default_secret());
+        let restriction = match validate_tunnel(&remote, path_prefix, trusted_metadata, &restrictions) {
             Ok(matched_restriction) => {
                 info!("Tunnel accepted due to matched restriction: {}", matched_restriction.name);
                 matched_restriction
             }
+            Err(_err) if self.config.report_connect_errors => return Err(not_allowed()),
             Err(_err) => return Err(bad_request()),
         };
 
+        if let Some(rate_limiter) = &self.destination_rate_limiter {
+            let destination = match &remote.protocol {
+                LocalProtocol::UnixSocket { path } => path.to_string_lossy().to_string(),
+                _ => format!("{}:{}", remote.host, remote.port),
+            };
+            if !remote.protocol.is_reverse_tunnel() && !rate_limiter.try_acquire(&destination) {
+                warn!(
+                    "Rejecting connection to {}, too many new tunnels to this destination",
+                    destination
+                );
+                // Slow down the client, it is likely scanning
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                return Err(too_many_requests());
+            }
+        }
+
+        let subject_tunnel = match &self.subject_limits {
+            Some(subject_limits) => match subject_limits.acquire(&subject) {
+                Ok(subject_tunnel) => Some(subject_tunnel),
+                Err(reason) => {
+                    warn!("Rejecting connection of {}, {}", subject, reason);
+                    return Err(policy_violation(reason));
+                }
+            },
+            None => None,
+        };
+
         let req_protocol = remote.protocol.clone();
-        let inject_cookie = matches!(
-            req_protocol,
-            LocalProtocol::ReverseSocks5 { .. } | LocalProtocol::ReverseHttpProxy { .. }
-        );
-        let tunnel = match self.exec_tunnel(restriction, remote, client_addr).await {
+        let inject_cookie = remote.is_ephemeral_bind()
+            || matches!(
+                req_protocol,
+                LocalProtocol::ReverseSocks5 { .. } | LocalProtocol::ReverseHttpProxy { .. }
+            );
+        let multiplexed = is_multiplexed_reverse_tunnel(&remote, req.headers());
+        let tunnel = match self.exec_tunnel(restriction, remote, multiplexed).await {
             Ok(ret) => ret,
-            Err(err) => {
+            // Reverse tunnels only fail to listen, the client has nothing to tell its peer about
+            Err(err) if req_protocol.is_reverse_tunnel() => {
                 warn!("Rejecting connection with bad upgrade request: {} {}", err, req.uri());
                 return Err(bad_request());
             }
+            Err(err) => {
+                warn!("Cannot connect to destination of tunnel: {:?} {}", err, req.uri());
+                if !self.config.report_connect_errors {
+                    return Err(bad_request());
+                }
+                return Err(bad_gateway(ConnectErrorKind::from_error(&err)));
+            }
         };
 
         let (remote_addr, local_rx, local_tx) = tunnel;
         info!("connected to {:?} {}:{}", req_protocol, remote_addr.host, remote_addr.port);
-        Ok((remote_addr, local_rx, local_tx, inject_cookie))
+        Ok((remote_addr, local_rx, local_tx, inject_cookie, multiplexed, subject_tunnel))
+    }
+
+    fn tcp_connector<'a>(&'a self, host: &'a Host, port: u16) -> TcpTunnelConnector<'a> {
+        TcpTunnelConnector::new(
+            host,
+            port,
+            self.config.socket_so_mark,
+            Duration::from_secs(10),
+            &self.config.dns_resolver,
+        )
+        .with_happy_eyeballs_delay(self.config.happy_eyeballs_delay)
+        .with_connect_parallelism(self.config.connect_parallelism)
+        .with_tcp_options(self.config.tcp_options)
+        .with_socket_bind(self.config.socket_bind())
     }
 
     async fn exec_tunnel(
         &self,
         restriction: &RestrictionConfig,
         remote: RemoteAddr,
-        client_address: SocketAddr,
+        multiplexed: bool,
     ) -> anyhow::Result<(RemoteAddr, Pin<Box<dyn AsyncRead + Send>>, Pin<Box<dyn AsyncWrite + Send>>)> {
+        // Only start the listener and report where it is, with a tunnel which ends right away
+        let ephemeral_bind = remote.is_ephemeral_bind().then(|| remote.protocol.clone());
+        let bound = |(host, port): (Host, u16), protocol: LocalProtocol| {
+            info!("Reverse tunnel listening on ephemeral port {}:{}", host, port);
+            let remote = RemoteAddr {
+                protocol,
+                host,
+                port,
+                source: None,
+                request_id: None,
+            };
+            (
+                remote,
+                Box::pin(tokio::io::empty()) as Pin<Box<dyn AsyncRead + Send>>,
+                Box::pin(tokio::io::sink()) as Pin<Box<dyn AsyncWrite + Send>>,
+            )
+        };
+
         match remote.protocol {
             LocalProtocol::Udp { timeout, .. } => {
                 let connector = UdpTunnelConnector::new(
@@ -179,7 +447,9 @@ impl WsServer {
                     self.config.socket_so_mark,
                     timeout.unwrap_or(Duration::from_secs(10)),
                     &self.config.dns_resolver,
-                );
+                )
+                .with_socket_bind(self.config.socket_bind())
+                .with_denied_cidrs(denied_cidrs(&remote, restriction));
                 let (rx, tx) = match &self.config.http_proxy {
                     None => connector.connect(&None).await?,
                     Some(_) => Err(anyhow!("UDP tunneling is not supported with HTTP proxy"))?,
@@ -188,27 +458,23 @@ impl WsServer {
                 Ok((remote, Box::pin(rx), Box::pin(tx)))
             }
             LocalProtocol::Tcp { proxy_protocol } => {
-                let connector = TcpTunnelConnector::new(
-                    &remote.host,
-                    remote.port,
-                    self.config.socket_so_mark,
-                    Duration::from_secs(10),
-                    &self.config.dns_resolver,
-                );
+                // The proxy resolves the destination itself, its addresses cannot be checked against the deny rules
+                let connector = self
+                    .tcp_connector(&remote.host, remote.port)
+                    .with_denied_cidrs(denied_cidrs(&remote, restriction));
                 let (rx, mut tx) = match &self.config.http_proxy {
                     None => connector.connect(&None).await?,
                     Some(proxy_url) => connector.connect_with_http_proxy(proxy_url, &None).await?,
                 };
 
                 if proxy_protocol {
-                    let header = ppp::v2::Builder::with_addresses(
-                        ppp::v2::Version::Two | ppp::v2::Command::Proxy,
-                        ppp::v2::Protocol::Stream,
-                        (client_address, tx.local_addr().unwrap()),
-                    )
-                        .build()
-                        .unwrap();
-                    let _ = tx.write_all(&header).await;
+                    let source = remote
+                        .source
+                        .context("missing source of the tunnel for the proxy protocol header")?;
+                    let header = proxy_protocol_header(source, tx.local_addr()?)?;
+                    tx.write_all(&header).await.with_context(|| {
+                        format!("cannot send proxy protocol header to {}:{}", remote.host, remote.port)
+                    })?;
                 }
 
                 Ok((remote, Box::pin(rx), Box::pin(tx)))
@@ -216,32 +482,63 @@ impl WsServer {
             LocalProtocol::ReverseTcp => {
                 type Item = <TcpTunnelListener as TunnelListener>::OkReturn;
                 #[allow(clippy::type_complexity)]
-                static SERVERS: Lazy<Mutex<HashMap<(Host<String>, u16), mpsc::Receiver<Item>>>> =
-                    Lazy::new(|| Mutex::new(HashMap::with_capacity(0)));
+                static SERVERS: Lazy<ListeningServers<Item>> = Lazy::new(|| Mutex::new(HashMap::with_capacity(0)));
 
-                let remote_port = find_mapped_port(remote.port, restriction);
+                let remote_port = match find_mapped_port(remote.port, restriction) {
+                    0 => ephemeral_port(&remote.host, SocketKind::Tcp)?,
+                    port => port,
+                };
                 let local_srv = (remote.host, remote_port);
                 let listening_server = async {
                     let bind = format!("{}:{}", local_srv.0, local_srv.1);
-                    TcpTunnelListener::new(bind.parse()?, local_srv.clone(), false).await
+                    let listener =
+                        TcpTunnelListener::new(bind.parse()?, local_srv.clone(), false, DEFAULT_LISTEN_BACKLOG).await?;
+                    Ok(listener.with_tcp_options(self.config.tcp_options))
                 };
+                if let Some(protocol) = ephemeral_bind {
+                    bind_listening_server(&local_srv, SERVERS.deref(), listening_server).await?;
+                    return Ok(bound(local_srv, protocol));
+                }
+                if multiplexed {
+                    let (local_rx, local_tx) =
+                        multiplex_listening_server(&local_srv, SERVERS.deref(), listening_server).await?;
+                    let remote = RemoteAddr {
+                        protocol: LocalProtocol::ReverseTcp,
+                        host: local_srv.0,
+                        port: local_srv.1,
+                        source: None,
+                        request_id: None,
+                    };
+                    return Ok((remote, Box::pin(local_rx), Box::pin(local_tx)));
+                }
                 let ((local_rx, local_tx), remote) =
                     run_listening_server(&local_srv, SERVERS.deref(), listening_server).await?;
+                // Reset the connection if the tunnel ends with an error, i.e: the client could not connect to its
+                // destination, instead of closing it as if the destination had nothing to say
+                if self.config.connect_failure_behavior == ConnectFailureBehavior::Abrupt {
+                    return Ok((remote, Box::pin(local_rx), Box::pin(ResetOnDropWriter::new(local_tx))));
+                }
 
                 Ok((remote, Box::pin(local_rx), Box::pin(local_tx)))
             }
             LocalProtocol::ReverseUdp { timeout } => {
                 type Item = ((UdpStream, UdpStreamWriter), RemoteAddr);
                 #[allow(clippy::type_complexity)]
-                static SERVERS: Lazy<Mutex<HashMap<(Host<String>, u16), mpsc::Receiver<Item>>>> =
-                    Lazy::new(|| Mutex::new(HashMap::with_capacity(0)));
+                static SERVERS: Lazy<ListeningServers<Item>> = Lazy::new(|| Mutex::new(HashMap::with_capacity(0)));
 
-                let remote_port = find_mapped_port(remote.port, restriction);
+                let remote_port = match find_mapped_port(remote.port, restriction) {
+                    0 => ephemeral_port(&remote.host, SocketKind::Udp)?,
+                    port => port,
+                };
                 let local_srv = (remote.host, remote_port);
                 let listening_server = async {
                     let bind = format!("{}:{}", local_srv.0, local_srv.1);
                     new_udp_listener(bind.parse()?, local_srv.clone(), timeout).await
                 };
+                if let Some(protocol) = ephemeral_bind {
+                    bind_listening_server(&local_srv, SERVERS.deref(), listening_server).await?;
+                    return Ok(bound(local_srv, protocol));
+                }
                 let ((local_rx, local_tx), remote) =
                     run_listening_server(&local_srv, SERVERS.deref(), listening_server).await?;
                 Ok((remote, Box::pin(local_rx), Box::pin(local_tx)))
@@ -249,15 +546,21 @@ impl WsServer {
             LocalProtocol::ReverseSocks5 { timeout, credentials } => {
                 type Item = <Socks5TunnelListener as TunnelListener>::OkReturn;
                 #[allow(clippy::type_complexity)]
-                static SERVERS: Lazy<Mutex<HashMap<(Host<String>, u16), mpsc::Receiver<Item>>>> =
-                    Lazy::new(|| Mutex::new(HashMap::with_capacity(0)));
+                static SERVERS: Lazy<ListeningServers<Item>> = Lazy::new(|| Mutex::new(HashMap::with_capacity(0)));
 
-                let remote_port = find_mapped_port(remote.port, restriction);
+                let remote_port = match find_mapped_port(remote.port, restriction) {
+                    0 => ephemeral_port(&remote.host, SocketKind::Tcp)?,
+                    port => port,
+                };
                 let local_srv = (remote.host, remote_port);
                 let listening_server = async {
                     let bind = format!("{}:{}", local_srv.0, local_srv.1);
                     Socks5TunnelListener::new(bind.parse()?, timeout, credentials).await
                 };
+                if let Some(protocol) = ephemeral_bind {
+                    bind_listening_server(&local_srv, SERVERS.deref(), listening_server).await?;
+                    return Ok(bound(local_srv, protocol));
+                }
                 let ((local_rx, local_tx), remote) =
                     run_listening_server(&local_srv, SERVERS.deref(), listening_server).await?;
 
@@ -266,15 +569,21 @@ impl WsServer {
             LocalProtocol::ReverseHttpProxy { timeout, credentials } => {
                 type Item = <HttpProxyTunnelListener as TunnelListener>::OkReturn;
                 #[allow(clippy::type_complexity)]
-                static SERVERS: Lazy<Mutex<HashMap<(Host<String>, u16), mpsc::Receiver<Item>>>> =
-                    Lazy::new(|| Mutex::new(HashMap::with_capacity(0)));
+                static SERVERS: Lazy<ListeningServers<Item>> = Lazy::new(|| Mutex::new(HashMap::with_capacity(0)));
 
-                let remote_port = find_mapped_port(remote.port, restriction);
+                let remote_port = match find_mapped_port(remote.port, restriction) {
+                    0 => ephemeral_port(&remote.host, SocketKind::Tcp)?,
+                    port => port,
+                };
                 let local_srv = (remote.host, remote_port);
                 let listening_server = async {
                     let bind = format!("{}:{}", local_srv.0, local_srv.1);
                     HttpProxyTunnelListener::new(bind.parse()?, timeout, credentials, false).await
                 };
+                if let Some(protocol) = ephemeral_bind {
+                    bind_listening_server(&local_srv, SERVERS.deref(), listening_server).await?;
+                    return Ok(bound(local_srv, protocol));
+                }
                 let ((local_rx, local_tx), remote) =
                     run_listening_server(&local_srv, SERVERS.deref(), listening_server).await?;
 
@@ -285,8 +594,7 @@ impl WsServer {
                 use crate::tunnel::listeners::UnixTunnelListener;
                 type Item = <UnixTunnelListener as TunnelListener>::OkReturn;
                 #[allow(clippy::type_complexity)]
-                static SERVERS: Lazy<Mutex<HashMap<(Host<String>, u16), mpsc::Receiver<Item>>>> =
-                    Lazy::new(|| Mutex::new(HashMap::with_capacity(0)));
+                static SERVERS: Lazy<ListeningServers<Item>> = Lazy::new(|| Mutex::new(HashMap::with_capacity(0)));
 
                 let remote_port = find_mapped_port(remote.port, restriction);
                 let local_srv = (remote.host, remote_port);
@@ -296,8 +604,40 @@ impl WsServer {
 
                 Ok((remote, Box::pin(local_rx), Box::pin(local_tx)))
             }
+            #[cfg(unix)]
+            LocalProtocol::UnixSocket { ref path } => {
+                use crate::tunnel::connectors::UnixSocketTunnelConnector;
+                let (rx, tx) = UnixSocketTunnelConnector::new(path).connect(&None).await?;
+
+                Ok((remote, Box::pin(rx), Box::pin(tx)))
+            }
+            LocalProtocol::TcpTls => {
+                let connector = TlsTunnelConnector::new(
+                    self.tcp_connector(&remote.host, remote.port)
+                        .with_denied_cidrs(denied_cidrs(&remote, restriction)),
+                    &remote.host,
+                    remote.port,
+                    self.config.destination_tls.clone(),
+                );
+                let (rx, tx) = match &self.config.http_proxy {
+                    None => connector.connect(&None).await?,
+                    Some(proxy_url) => connector.connect_with_http_proxy(proxy_url, &None).await?,
+                };
+
+                Ok((remote, Box::pin(rx), Box::pin(tx)))
+            }
+            LocalProtocol::Exec => {
+                let Some(command) = &self.config.exec_command else {
+                    return Err(anyhow!("Exec tunnels are not enabled on this server (--exec-command)"));
+                };
+                let (rx, tx) = CommandTunnelConnector::new(command)
+                    .connect(&Some(remote.clone()))
+                    .await?;
+
+                Ok((remote, Box::pin(rx), Box::pin(tx)))
+            }
             #[cfg(not(unix))]
-            LocalProtocol::ReverseUnix { .. } => {
+            LocalProtocol::ReverseUnix { .. } | LocalProtocol::UnixSocket { .. } => {
                 error!("Received an unsupported target protocol {:?}", remote);
                 Err(anyhow::anyhow!("Invalid upgrade request"))
             }
@@ -313,8 +653,21 @@ impl WsServer {
         }
     }
 
-    pub async fn serve(self, restrictions: RestrictionsRules) -> anyhow::Result<()> {
-        info!("Starting wstunnel server listening on {}", self.config.bind);
+    /// Serve until shutdown is cancelled. The server then keeps answering requests while draining, to refuse new tunnels
+    /// and fail the health checks, until the tunnels in flight are finished or the shutdown grace period is elapsed
+    pub async fn serve(self, restrictions: RestrictionsRules, shutdown: CancellationToken) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(&self.config.bind).await?;
+        self.serve_listener(listener, restrictions, shutdown).await
+    }
+
+    /// Same as serve, with the connections accepted from this listener instead of one bound to the address of the config
+    pub async fn serve_listener(
+        self,
+        listener: TcpListener,
+        restrictions: RestrictionsRules,
+        shutdown: CancellationToken,
+    ) -> anyhow::Result<()> {
+        info!("Starting wstunnel server listening on {}", listener.local_addr()?);
 
         // setup upgrade request handler
         let mk_websocket_upgrade_fn = |server: WsServer,
@@ -346,7 +699,9 @@ impl WsServer {
                 let restrictions = restrictions.clone();
                 let restrict_path = restrict_path.clone();
                 async move {
-                    if fastwebsockets::upgrade::is_upgrade_request(&req) {
+                    if let Some(response) = health_check(&server, &req) {
+                        Ok(response)
+                    } else if fastwebsockets::upgrade::is_upgrade_request(&req) {
                         ws_server_upgrade(server.clone(), restrictions.clone(), restrict_path, client_addr, req)
                             .map::<anyhow::Result<_>, _>(Ok)
                             .await
@@ -389,12 +744,23 @@ impl WsServer {
         // Bind server and run forever to serve incoming connections.
         let mut restrictions = RestrictionsRulesReloader::new(restrictions, self.config.restriction_config.clone())?;
         let mut await_config_reload = Box::pin(restrictions.reload_notifier());
-        let listener = TcpListener::bind(&self.config.bind).await?;
+        let drained = async {
+            shutdown.cancelled().await;
+            info!(
+                "Shutting down, waiting up to {:?} for tunnels in flight to finish",
+                self.config.shutdown_grace_period
+            );
+            self.draining.cancel();
+            self.drain_tunnels().await;
+        };
+        pin_mut!(drained);
 
         loop {
             let cnx = select! {
                 biased;
 
+                _ = &mut drained => return Ok(()),
+
                 _ = &mut await_config_reload => {
                     drop(await_config_reload);
                     restrictions.reload_restrictions_config();
@@ -413,7 +779,17 @@ impl WsServer {
                 }
             };
 
-            if let Err(err) = protocols::tcp::configure_socket(SockRef::from(&stream), &None) {
+            if self
+                .unauthorized_limiter
+                .as_ref()
+                .is_some_and(|limiter| limiter.is_exhausted(&peer_addr.ip().to_string()))
+            {
+                log_dropped_unauthorized(peer_addr);
+                continue;
+            }
+
+            if let Err(err) = protocols::tcp::configure_socket(SockRef::from(&stream), &None, &self.config.tcp_options)
+            {
                 warn!("Error while configuring server socket {:?}", err);
             }
 
@@ -423,8 +799,11 @@ impl WsServer {
                 id = tracing::field::Empty,
                 remote = tracing::field::Empty,
                 peer = peer_addr.to_string(),
-                forwarded_for = tracing::field::Empty
+                forwarded_for = tracing::field::Empty,
+                country = tracing::field::Empty,
+                metadata = tracing::field::Empty
             );
+            self.record_country(&span, peer_addr.ip());
 
             info!("Accepting connection");
             let server = self.clone();
@@ -451,10 +830,22 @@ impl WsServer {
                             .peer_certificates()
                             .and_then(tls::find_leaf_certificate)
                             .and_then(|c| tls::cn_from_certificate(&c));
+                        if server.config.raw_transport {
+                            let tls_stream = tls_stream.into_inner();
+                            raw_server_tunnel(server, restrictions, restrict_path, peer_addr, tls_stream).await;
+                            return;
+                        }
                         match tls_ctx.alpn_protocol() {
                             // http2
                             Some(b"h2") => {
                                 let mut conn_builder = http2::Builder::new(TokioExecutor::new());
+                                // Clients multiplexing their tunnels must not have one stalled stream block the others
+                                if let Some(multiplex) = &server.config.http2_multiplex {
+                                    conn_builder
+                                        .initial_stream_window_size(multiplex.stream_window)
+                                        .initial_connection_window_size(multiplex.connection_window())
+                                        .max_concurrent_streams(multiplex.max_concurrent_streams());
+                                }
                                 if let Some(ping) = server.config.websocket_ping_frequency {
                                     conn_builder.keep_alive_interval(ping);
                                 }
@@ -486,10 +877,21 @@ impl WsServer {
                     // Normal
                 }
                 // HTTP without TLS
+                None if self.config.raw_transport => {
+                    let fut = raw_server_tunnel(server, restrictions, None, peer_addr, stream).instrument(span);
+                    tokio::spawn(fut);
+                }
                 None => {
                     let fut = async move {
                         let stream = hyper_util::rt::TokioIo::new(stream);
                         let mut conn_fut = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new());
+                        if let Some(multiplex) = &server.config.http2_multiplex {
+                            conn_fut
+                                .http2()
+                                .initial_stream_window_size(multiplex.stream_window)
+                                .initial_connection_window_size(multiplex.connection_window())
+                                .max_concurrent_streams(multiplex.max_concurrent_streams());
+                        }
                         if let Some(ping) = server.config.websocket_ping_frequency {
                             conn_fut.http2().keep_alive_interval(ping);
                         }
@@ -511,15 +913,48 @@ impl WsServer {
     }
 }
 
+impl WsServerConfig {
+    fn socket_bind(&self) -> SocketBind {
+        SocketBind {
+            address: self.socket_bind_address,
+            device: self.socket_bind_device.clone(),
+        }
+    }
+}
+
 impl Debug for WsServerConfig {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         f.debug_struct("WsServerConfig")
             .field("socket_so_mark", &self.socket_so_mark)
+            .field("socket_bind_address", &self.socket_bind_address)
+            .field("socket_bind_device", &self.socket_bind_device)
             .field("bind", &self.bind)
             .field("websocket_ping_frequency", &self.websocket_ping_frequency)
             .field("timeout_connect", &self.timeout_connect)
+            .field("happy_eyeballs_delay", &self.happy_eyeballs_delay)
+            .field("connect_parallelism", &self.connect_parallelism)
+            .field("tcp_options", &self.tcp_options)
             .field("websocket_mask_frame", &self.websocket_mask_frame)
             .field("restriction_config", &self.restriction_config)
+            .field("http_upgrade_path_prefix", &self.http_upgrade_path_prefix)
+            .field("destination_rate_limit", &self.destination_rate_limit)
+            .field("destination_rate_limit_burst", &self.destination_rate_limit_burst)
+            .field("health_check_path", &self.health_check_path)
+            .field("geoip_database", &self.geoip_database.is_some())
+            .field("max_bytes_per_tunnel_upload", &self.max_bytes_per_tunnel_upload)
+            .field("max_bytes_per_tunnel_download", &self.max_bytes_per_tunnel_download)
+            .field("http2_multiplex", &self.http2_multiplex)
+            .field("jwt_replay_cache_size", &self.jwt_replay_cache_size)
+            .field("jwt_replay_cache_ttl", &self.jwt_replay_cache_ttl)
+            .field("default_subject_limit", &self.default_subject_limit)
+            .field("subject_limits", &self.subject_limits)
+            .field("connect_failure_behavior", &self.connect_failure_behavior)
+            .field("report_connect_errors", &self.report_connect_errors)
+            .field("raw_transport", &self.raw_transport)
+            .field(
+                "http_upgrade_bearer_token",
+                &self.http_upgrade_bearer_token.as_ref().map(|_| "<redacted>"),
+            )
             .field("tls", &self.tls.is_some())
             .field(
                 "mTLS",
@@ -552,28 +987,110 @@ impl TlsContext<'_> {
     }
 }
 
-#[allow(clippy::type_complexity)]
-async fn run_listening_server<T>(
-    local_srv: &(Host, u16),
-    servers: &Mutex<
-        HashMap<
-            (Host<String>, u16),
-            mpsc::Receiver<((<T as TunnelListener>::Reader, <T as TunnelListener>::Writer), RemoteAddr)>,
-        >,
-    >,
-    gen_listening_server: impl Future<Output = anyhow::Result<T>>,
-) -> anyhow::Result<((<T as TunnelListener>::Reader, <T as TunnelListener>::Writer), RemoteAddr)>
+type ListenerItem<T> = ((<T as TunnelListener>::Reader, <T as TunnelListener>::Writer), RemoteAddr);
+// Reverse listeners of a protocol by their address. A listener is started by the first tunnel asking for it,
+// the concurrent ones wait for it instead of trying to bind the same port
+type ListeningServers<I> = Mutex<HashMap<(Host<String>, u16), Arc<tokio::sync::OnceCell<ReverseListener<I>>>>>;
+
+// A listener no tunnel waited on for this long is closed. Its client is gone, or never came back for the ephemeral
+// port it got. The next tunnel asking for its port starts it again
+const REVERSE_LISTENER_IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 3);
+
+// Connections of a reverse listener, picked in turn by the tunnels asking for its port
+struct ReverseListener<I> {
+    connections: Arc<tokio::sync::Mutex<mpsc::Receiver<I>>>,
+    activity: Arc<ListenerActivity>,
+}
+
+impl<I> Clone for ReverseListener<I> {
+    fn clone(&self) -> Self {
+        Self {
+            connections: self.connections.clone(),
+            activity: self.activity.clone(),
+        }
+    }
+}
+
+// Tunnels waiting for the connections of a listener, to close it once none did for a while
+struct ListenerActivity {
+    waiters: AtomicUsize,
+    last_waited: Mutex<tokio::time::Instant>,
+}
+
+// A tunnel waiting for the connections of a listener, until dropped
+struct ListenerWaiter(Arc<ListenerActivity>);
+
+impl ListenerActivity {
+    fn new() -> Self {
+        Self {
+            waiters: AtomicUsize::new(0),
+            last_waited: Mutex::new(tokio::time::Instant::now()),
+        }
+    }
+
+    fn waiter(self: &Arc<Self>) -> ListenerWaiter {
+        self.waiters.fetch_add(1, Ordering::Relaxed);
+        ListenerWaiter(self.clone())
+    }
+
+    // Resolve once no tunnel waited for the connections for the timeout
+    async fn idle(&self, timeout: Duration) {
+        loop {
+            let wake_at = if self.waiters.load(Ordering::Relaxed) > 0 {
+                tokio::time::Instant::now() + timeout
+            } else {
+                *self.last_waited.lock() + timeout
+            };
+            if self.waiters.load(Ordering::Relaxed) == 0 && tokio::time::Instant::now() >= wake_at {
+                return;
+            }
+            tokio::time::sleep_until(wake_at).await;
+        }
+    }
+}
+
+impl Drop for ListenerWaiter {
+    fn drop(&mut self) {
+        *self.0.last_waited.lock() = tokio::time::Instant::now();
+        self.0.waiters.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Copy, Clone)]
+enum SocketKind {
+    Tcp,
+    Udp,
+}
+
+// The listeners are registered under their port, so an ephemeral one is picked before starting the listener
+// instead of letting it bind to port 0. The port could be taken in between, in which case the bind fails
+// and the client asks again for a new one
+fn ephemeral_port(host: &Host, kind: SocketKind) -> anyhow::Result<u16> {
+    let bind: SocketAddr = format!("{}:0", host).parse()?;
+    let port = match kind {
+        SocketKind::Tcp => std::net::TcpListener::bind(bind)?.local_addr()?.port(),
+        SocketKind::Udp => std::net::UdpSocket::bind(bind)?.local_addr()?.port(),
+    };
+
+    Ok(port)
+}
+
+// Accept the connections of the listener until it is closed or idle, then remove it from the listeners of its protocol
+fn start_listening_server<T>(
+    listening_server: T,
+    local_srv: (Host, u16),
+    servers: &'static ListeningServers<ListenerItem<T>>,
+    entry: Weak<tokio::sync::OnceCell<ReverseListener<ListenerItem<T>>>>,
+) -> ReverseListener<ListenerItem<T>>
 where
     T: TunnelListener + Send + 'static,
 {
-    let listening_server = servers.lock().remove(local_srv);
-    let mut listening_server = if let Some(listening_server) = listening_server {
-        listening_server
-    } else {
-        let listening_server = gen_listening_server.await?;
-        let send_timeout = Duration::from_secs(60 * 3);
-        let (tx, rx) = mpsc::channel(1);
-        let fut = async move {
+    let send_timeout = Duration::from_secs(60 * 3);
+    let (tx, rx) = mpsc::channel(1);
+    let activity = Arc::new(ListenerActivity::new());
+    let fut = {
+        let activity = activity.clone();
+        async move {
             pin_mut!(listening_server);
             loop {
                 select! {
@@ -597,19 +1114,1000 @@ where
                     _ = tx.closed() => {
                         break;
                     }
+
+                    _ = activity.idle(REVERSE_LISTENER_IDLE_TIMEOUT) => {
+                        info!("No client asked for the connections of the reverse tunnel server for {}s. Closing it", REVERSE_LISTENER_IDLE_TIMEOUT.as_secs());
+                        break;
+                    }
                 }
             }
             info!("Stopping listening reverse server");
-        };
 
-        tokio::spawn(fut.instrument(Span::current()));
-        rx
+            // Only remove its own entry, the port may already be used by a new listener
+            let mut servers = servers.lock();
+            if let Some(entry) = entry.upgrade() {
+                if servers
+                    .get(&local_srv)
+                    .is_some_and(|current| Arc::ptr_eq(current, &entry))
+                {
+                    servers.remove(&local_srv);
+                }
+            }
+        }
     };
 
-    let cnx = listening_server
+    tokio::spawn(fut.instrument(Span::current()));
+    ReverseListener {
+        connections: Arc::new(tokio::sync::Mutex::new(rx)),
+        activity,
+    }
+}
+
+// Listener of the address, started if no tunnel did yet
+async fn get_listening_server<T>(
+    local_srv: &(Host, u16),
+    servers: &'static ListeningServers<ListenerItem<T>>,
+    gen_listening_server: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<ReverseListener<ListenerItem<T>>>
+where
+    T: TunnelListener + Send + 'static,
+{
+    let entry = servers.lock().entry(local_srv.clone()).or_default().clone();
+    let listener = entry
+        .get_or_try_init(|| async {
+            let listening_server = gen_listening_server.await?;
+            Ok(start_listening_server(
+                listening_server,
+                local_srv.clone(),
+                servers,
+                Arc::downgrade(&entry),
+            ))
+        })
+        .await;
+
+    match listener {
+        Ok(listener) => Ok(listener.clone()),
+        Err(err) => {
+            // Let the next tunnel try to start it again
+            let mut servers = servers.lock();
+            if servers
+                .get(local_srv)
+                .is_some_and(|current| Arc::ptr_eq(current, &entry) && !current.initialized())
+            {
+                servers.remove(local_srv);
+            }
+            Err(err)
+        }
+    }
+}
+
+async fn run_listening_server<T>(
+    local_srv: &(Host, u16),
+    servers: &'static ListeningServers<ListenerItem<T>>,
+    gen_listening_server: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<ListenerItem<T>>
+where
+    T: TunnelListener + Send + 'static,
+{
+    let listener = get_listening_server(local_srv, servers, gen_listening_server).await?;
+    let _waiter = listener.activity.waiter();
+    let cnx = listener
+        .connections
+        .lock()
+        .await
         .recv()
         .await
         .ok_or_else(|| anyhow!("listening reverse server stopped"))?;
-    servers.lock().insert(local_srv.clone(), listening_server);
     Ok(cnx)
 }
+
+// Start the listener without waiting for a connection, the tunnels asking for its port later on pick them
+async fn bind_listening_server<T>(
+    local_srv: &(Host, u16),
+    servers: &'static ListeningServers<ListenerItem<T>>,
+    gen_listening_server: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<()>
+where
+    T: TunnelListener + Send + 'static,
+{
+    get_listening_server(local_srv, servers, gen_listening_server).await?;
+    Ok(())
+}
+
+// Hand over all the connections of the listener to a single tunnel, each of them as a flow of the multiplexer.
+// The other tunnels asking for its port wait for this one to be closed
+async fn multiplex_listening_server<T>(
+    local_srv: &(Host, u16),
+    servers: &'static ListeningServers<ListenerItem<T>>,
+    gen_listening_server: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<(ReadHalf<DuplexStream>, WriteHalf<DuplexStream>)>
+where
+    T: TunnelListener + Send + 'static,
+{
+    let listener = get_listening_server(local_srv, servers, gen_listening_server).await?;
+    let (tunnel_pipe, mux_pipe) = tokio::io::duplex(mux::MUX_PIPE_SIZE);
+    let fut = async move {
+        let _waiter = listener.activity.waiter();
+        let mut connections = listener.connections.lock().await;
+        mux::serve(mux_pipe, &mut connections).await;
+    };
+    tokio::spawn(fut.instrument(Span::current()));
+
+    Ok(tokio::io::split(tunnel_pipe))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::clock::TokioClock;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpStream;
+
+    // Plain server on a random port of localhost, without restrictions on the tunnels
+    fn server_config() -> WsServerConfig {
+        WsServerConfig {
+            socket_so_mark: None,
+            socket_bind_address: None,
+            socket_bind_device: None,
+            bind: "127.0.0.1:0".parse().unwrap(),
+            websocket_ping_frequency: None,
+            timeout_connect: Duration::from_secs(1),
+            happy_eyeballs_delay: protocols::tcp::DEFAULT_HAPPY_EYEBALLS_DELAY,
+            connect_parallelism: protocols::tcp::DEFAULT_CONNECT_PARALLELISM,
+            tcp_options: TcpSocketOptions::default(),
+            websocket_mask_frame: false,
+            tls: None,
+            dns_resolver: DnsResolver::System { prefer_ipv6: true },
+            restriction_config: None,
+            http_proxy: None,
+            http_upgrade_path_prefix: None,
+            http_upgrade_bearer_token: None,
+            destination_rate_limit: None,
+            destination_rate_limit_burst: 1,
+            jwt_replay_cache_size: 0,
+            jwt_replay_cache_ttl: Duration::from_secs(90),
+            default_subject_limit: SubjectLimit::default(),
+            subject_limits: vec![],
+            connect_failure_behavior: ConnectFailureBehavior::Graceful,
+            report_connect_errors: false,
+            exec_command: None,
+            destination_tls: tls::tls_client_config(tls::TlsClientOptions::default()).unwrap(),
+            raw_transport: false,
+            clock: Arc::new(TokioClock),
+            health_check_path: None,
+            health_check_expose_version: false,
+            shutdown_grace_period: Duration::from_secs(1),
+            geoip_database: None,
+            max_bytes_per_tunnel_upload: None,
+            max_bytes_per_tunnel_download: None,
+            http2_multiplex: None,
+        }
+    }
+
+    // The listener is bound before returning, so the server can be contacted right away on the returned port
+    async fn spawn_server(
+        server: WsServer,
+        shutdown: CancellationToken,
+    ) -> (u16, tokio::task::JoinHandle<anyhow::Result<()>>) {
+        let listener = TcpListener::bind(server.config.bind).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let restrictions = RestrictionsRules::from_path_prefix(&[], &[]).unwrap();
+        (port, tokio::spawn(server.serve_listener(listener, restrictions, shutdown)))
+    }
+
+    async fn upgrade_status(port: u16, path: &str) -> String {
+        upgrade_status_with_headers(port, path, "").await
+    }
+
+    async fn upgrade_status_with_headers(port: u16, path: &str, headers: &str) -> String {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let req = format!(
+            "GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: upgrade\r\nUpgrade: websocket\r\n{headers}\
+             Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Protocol: v1, authorization.bearer.not-a-jwt\r\n\r\n"
+        );
+        stream.write_all(req.as_bytes()).await.unwrap();
+
+        let mut buf = [0u8; 128];
+        let n = stream.read(&mut buf).await.unwrap();
+        String::from_utf8_lossy(&buf[..n])
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    // Settings announced by the server in the first frame of a cleartext http2 connection
+    async fn http2_server_settings(port: u16) -> Vec<(u16, u32)> {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        // Client preface, followed by an empty SETTINGS frame
+        stream
+            .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\x00\x00\x00\x04\x00\x00\x00\x00\x00")
+            .await
+            .unwrap();
+
+        let mut header = [0u8; 9];
+        stream.read_exact(&mut header).await.unwrap();
+        assert_eq!(header[3], 0x4, "the first frame of the server must be SETTINGS");
+        let mut payload = vec![0u8; u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize];
+        stream.read_exact(&mut payload).await.unwrap();
+        payload
+            .chunks(6)
+            .map(|s| (u16::from_be_bytes([s[0], s[1]]), u32::from_be_bytes([s[2], s[3], s[4], s[5]])))
+            .collect()
+    }
+
+    #[test]
+    fn test_ephemeral_port_is_free() {
+        let host = Host::Ipv4(std::net::Ipv4Addr::LOCALHOST);
+        for kind in [SocketKind::Tcp, SocketKind::Udp] {
+            let port = ephemeral_port(&host, kind).unwrap();
+            assert_ne!(port, 0);
+            match kind {
+                SocketKind::Tcp => drop(std::net::TcpListener::bind(("127.0.0.1", port)).unwrap()),
+                SocketKind::Udp => drop(std::net::UdpSocket::bind(("127.0.0.1", port)).unwrap()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http2_multiplex_windows_are_opt_in() {
+        const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+        const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+
+        let (port, server) = spawn_server(WsServer::new(server_config()), CancellationToken::new()).await;
+        let settings = http2_server_settings(port).await;
+        assert!(!settings.contains(&(SETTINGS_MAX_CONCURRENT_STREAMS, 7)));
+        assert!(!settings.contains(&(SETTINGS_INITIAL_WINDOW_SIZE, 128 * 1024)));
+        server.abort();
+
+        let server = WsServer::new(WsServerConfig {
+            http2_multiplex: Some(Http2MultiplexConfig {
+                max_streams: 7,
+                stream_window: 128 * 1024,
+            }),
+            ..server_config()
+        });
+        let (port, server) = spawn_server(server, CancellationToken::new()).await;
+        let settings = http2_server_settings(port).await;
+        assert!(settings.contains(&(SETTINGS_MAX_CONCURRENT_STREAMS, 7)));
+        assert!(settings.contains(&(SETTINGS_INITIAL_WINDOW_SIZE, 128 * 1024)));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_wrong_upgrade_path_prefix_is_not_found() {
+        let server = WsServer::new(WsServerConfig {
+            http_upgrade_path_prefix: Some("api/v2/stream".to_string()),
+            ..server_config()
+        });
+        let (port, server) = spawn_server(server, CancellationToken::new()).await;
+
+        // The jwt is invalid, so reaching it would give a 400 instead of a 404
+        assert_eq!(upgrade_status(port, "/v1/events").await, "HTTP/1.1 404 Not Found");
+        assert_eq!(upgrade_status(port, "/api/v2/streaming/events").await, "HTTP/1.1 404 Not Found");
+        assert_eq!(upgrade_status(port, "/api/v2/stream/events").await, "HTTP/1.1 400 Bad Request");
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_wrong_bearer_token_is_unauthorized() {
+        let server = WsServer::new(WsServerConfig {
+            http_upgrade_bearer_token: Some("s3cr3t".to_string()),
+            ..server_config()
+        });
+        let (port, server) = spawn_server(server, CancellationToken::new()).await;
+
+        assert_eq!(upgrade_status(port, "/v1/events").await, "HTTP/1.1 401 Unauthorized");
+        let wrong_token = "Authorization: Bearer s3cr3T\r\n";
+        assert_eq!(
+            upgrade_status_with_headers(port, "/v1/events", wrong_token).await,
+            "HTTP/1.1 401 Unauthorized"
+        );
+        let basic_auth = "Authorization: Basic czNjcjN0\r\n";
+        assert_eq!(
+            upgrade_status_with_headers(port, "/v1/events", basic_auth).await,
+            "HTTP/1.1 401 Unauthorized"
+        );
+
+        // The jwt is invalid, so the request goes through the token check to fail later
+        let token = "Authorization: Bearer s3cr3t\r\n";
+        assert_eq!(
+            upgrade_status_with_headers(port, "/v1/events", token).await,
+            "HTTP/1.1 400 Bad Request"
+        );
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_unauthorized_clients_are_disconnected_at_accept() {
+        let server = WsServer::new(WsServerConfig {
+            http_upgrade_bearer_token: Some("s3cr3t".to_string()),
+            ..server_config()
+        });
+        let (port, server) = spawn_server(server, CancellationToken::new()).await;
+
+        for _ in 0..UNAUTHORIZED_BURST {
+            assert_eq!(upgrade_status(port, "/v1/events").await, "HTTP/1.1 401 Unauthorized");
+        }
+
+        // Out of tries, the connection is closed before any request is read, even with the right token
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let mut buf = [0u8; 128];
+        let ret = stream.read(&mut buf).await;
+        assert!(matches!(ret, Ok(0) | Err(_)), "{:?}", ret);
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_health_check_path() {
+        let server = WsServer::new(WsServerConfig {
+            http_upgrade_bearer_token: Some("s3cr3t".to_string()),
+            health_check_path: Some("/healthz".to_string()),
+            ..server_config()
+        });
+        let (port, server) = spawn_server(server, CancellationToken::new()).await;
+
+        // A plain GET, without upgrade nor bearer token
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stream
+            .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0u8; 256];
+        let n = stream.read(&mut buf).await.unwrap();
+        let response = String::from_utf8_lossy(&buf[..n]);
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("\r\n\r\nOK"));
+        assert!(!response.contains(env!("CARGO_PKG_VERSION")));
+
+        // Other paths still go through the tunnel request handling
+        assert_eq!(upgrade_status(port, "/healthz").await, "HTTP/1.1 200 OK");
+        assert_eq!(upgrade_status(port, "/healthz/v1/events").await, "HTTP/1.1 401 Unauthorized");
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_tunnels() {
+        let server = WsServer::new(WsServerConfig {
+            health_check_path: Some("/healthz".to_string()),
+            shutdown_grace_period: Duration::from_millis(500),
+            ..server_config()
+        });
+        // Stand-in for a tunnel in flight, which never finishes on its own
+        server.spawn_tunnel(std::future::pending());
+
+        let shutdown = CancellationToken::new();
+        let (port, serve) = spawn_server(server.clone(), shutdown.clone()).await;
+        assert_eq!(upgrade_status(port, "/healthz").await, "HTTP/1.1 200 OK");
+        assert_eq!(upgrade_status(port, "/v1/events").await, "HTTP/1.1 400 Bad Request");
+
+        shutdown.cancel();
+        server.draining.cancelled().await;
+        assert!(!serve.is_finished());
+        assert_eq!(upgrade_status(port, "/healthz").await, "HTTP/1.1 503 Service Unavailable");
+        assert_eq!(upgrade_status(port, "/v1/events").await, "HTTP/1.1 503 Service Unavailable");
+
+        // The tunnel is aborted once the grace period is elapsed
+        tokio::time::timeout(Duration::from_secs(2), serve)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(server.tunnels.lock().is_empty());
+    }
+
+    type TestCnx = anyhow::Result<((DuplexStream, DuplexStream), RemoteAddr)>;
+    type TestListener = tokio_stream::wrappers::ReceiverStream<TestCnx>;
+    type TestItem = ListenerItem<TestListener>;
+
+    // Listener whose connections are pushed by the test
+    fn test_listener() -> (mpsc::Sender<TestCnx>, TestListener) {
+        let (tx, rx) = mpsc::channel(8);
+        (tx, tokio_stream::wrappers::ReceiverStream::new(rx))
+    }
+
+    fn test_cnx(port: u16) -> TestCnx {
+        let remote = RemoteAddr {
+            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            host: Host::Domain("localhost".to_string()),
+            port,
+            source: None,
+            request_id: None,
+        };
+        Ok((tokio::io::duplex(8), remote))
+    }
+
+    #[tokio::test]
+    async fn test_reverse_listener_is_started_once() {
+        static SERVERS: Lazy<ListeningServers<TestItem>> = Lazy::new(|| Mutex::new(HashMap::with_capacity(0)));
+        let local_srv = (Host::Domain("localhost".to_string()), 1);
+        let (cnx_tx, listener) = test_listener();
+        let listener = Mutex::new(Some(listener));
+        let nb_binds = AtomicUsize::new(0);
+        let gen_listener = || async {
+            nb_binds.fetch_add(1, Ordering::Relaxed);
+            tokio::task::yield_now().await;
+            listener.lock().take().ok_or_else(|| anyhow!("port already in use"))
+        };
+
+        // Tunnels asking for the same port at the same time share the listener, instead of binding it again
+        let first = run_listening_server(&local_srv, &SERVERS, gen_listener());
+        let second = run_listening_server(&local_srv, &SERVERS, gen_listener());
+        cnx_tx.send(test_cnx(1)).await.unwrap();
+        cnx_tx.send(test_cnx(2)).await.unwrap();
+        let (first, second) = tokio::join!(first, second);
+        let mut ports = [first.unwrap().1.port, second.unwrap().1.port];
+        ports.sort();
+        assert_eq!(ports, [1, 2]);
+        assert_eq!(nb_binds.load(Ordering::Relaxed), 1);
+
+        // A failed bind is not kept, the next tunnel tries again
+        let other_srv = (Host::Domain("localhost".to_string()), 2);
+        let err = bind_listening_server(&other_srv, &SERVERS, gen_listener()).await;
+        assert!(err.is_err());
+        assert!(!SERVERS.lock().contains_key(&other_srv));
+        assert!(SERVERS.lock().contains_key(&local_srv));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_reverse_listener_is_removed() {
+        static SERVERS: Lazy<ListeningServers<TestItem>> = Lazy::new(|| Mutex::new(HashMap::with_capacity(0)));
+        let local_srv = (Host::Domain("localhost".to_string()), 1);
+        let (_cnx_tx, listener) = test_listener();
+
+        // Only bound, as for an ephemeral port the client never comes back for
+        bind_listening_server(&local_srv, &SERVERS, async { Ok(listener) })
+            .await
+            .unwrap();
+        tokio::time::sleep(REVERSE_LISTENER_IDLE_TIMEOUT - Duration::from_secs(1)).await;
+        assert!(SERVERS.lock().contains_key(&local_srv));
+
+        // Kept while a tunnel waits for a connection, however long it takes
+        let waiting = tokio::spawn(async move {
+            let (_cnx_tx, listener) = test_listener();
+            run_listening_server(&local_srv, &SERVERS, async { Ok(listener) }).await
+        });
+        tokio::time::sleep(REVERSE_LISTENER_IDLE_TIMEOUT * 2).await;
+        let local_srv = (Host::Domain("localhost".to_string()), 1);
+        assert!(SERVERS.lock().contains_key(&local_srv));
+
+        waiting.abort();
+        let _ = waiting.await;
+        tokio::time::sleep(REVERSE_LISTENER_IDLE_TIMEOUT + Duration::from_secs(1)).await;
+        assert!(!SERVERS.lock().contains_key(&local_srv));
+    }
+
+    #[tokio::test]
+    async fn test_reverse_tunnel_on_ephemeral_port() {
+        use crate::tunnel::client::{TunnelEvent, WsClient, WsClientConfigBuilder};
+        use crate::tunnel::connectors::TcpTunnelConnector;
+        use crate::tunnel::{TransportAddr, TransportScheme};
+        use tokio::io::AsyncReadExt;
+
+        let shutdown = CancellationToken::new();
+        let (port, _serve) = spawn_server(WsServer::new(server_config()), shutdown.clone()).await;
+
+        // Local service the connections of the reverse tunnel are forwarded to
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let service_port = service.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = service.accept().await {
+                tokio::spawn(async move {
+                    let (mut rx, mut tx) = stream.split();
+                    let _ = tokio::io::copy(&mut rx, &mut tx).await;
+                });
+            }
+        });
+
+        let localhost = Host::Ipv4("127.0.0.1".parse().unwrap());
+        let config =
+            WsClientConfigBuilder::new(TransportAddr::new(TransportScheme::Ws, localhost.clone(), port, None).unwrap())
+                .build()
+                .unwrap();
+        let client = WsClient::new(config, 0, Duration::from_secs(1)).await.unwrap();
+        let (events_tx, mut events_rx) = mpsc::channel(16);
+        let dns_resolver = DnsResolver::System { prefer_ipv6: false };
+        let remote_addr = RemoteAddr {
+            protocol: LocalProtocol::ReverseTcp,
+            host: localhost.clone(),
+            port: 0,
+            source: None,
+            request_id: None,
+        };
+        let tunnel = {
+            let shutdown = shutdown.clone();
+            let localhost = localhost.clone();
+            async move {
+                let connector =
+                    TcpTunnelConnector::new(&localhost, service_port, None, Duration::from_secs(1), &dns_resolver);
+                client
+                    .run_reverse_tunnel(remote_addr, connector, Some(events_tx), shutdown)
+                    .await
+            }
+        };
+        let tunnel = tokio::spawn(tunnel);
+
+        let bound = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(TunnelEvent::Bound { addr }) = events_rx.recv().await {
+                    break addr;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_ne!(bound.port, 0);
+
+        // The connections to the port the server got reach the local service through the client
+        let mut stream = TcpStream::connect(("127.0.0.1", bound.port)).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(5), tunnel)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tunnels_over_subject_limit_are_closed_with_policy_violation() {
+        use crate::tunnel::client::{WsClient, WsClientConfigBuilder};
+        use crate::tunnel::{TransportAddr, TransportScheme};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let server = WsServer::new(WsServerConfig {
+            default_subject_limit: SubjectLimit {
+                max_tunnels: Some(1),
+                max_bytes: None,
+            },
+            ..server_config()
+        });
+        let shutdown = CancellationToken::new();
+        let (port, _serve) = spawn_server(server, shutdown.clone()).await;
+
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destination_port = destination.local_addr().unwrap().port();
+        let nb_connections = Arc::new(AtomicUsize::new(0));
+        tokio::spawn({
+            let nb_connections = nb_connections.clone();
+            async move {
+                while let Ok((mut stream, _)) = destination.accept().await {
+                    nb_connections.fetch_add(1, Ordering::SeqCst);
+                    tokio::spawn(async move {
+                        let (mut rx, mut tx) = stream.split();
+                        let _ = tokio::io::copy(&mut rx, &mut tx).await;
+                    });
+                }
+            }
+        });
+
+        let localhost = Host::Ipv4("127.0.0.1".parse().unwrap());
+        let config =
+            WsClientConfigBuilder::new(TransportAddr::new(TransportScheme::Ws, localhost.clone(), port, None).unwrap())
+                .build()
+                .unwrap();
+        let client = WsClient::new(config, 0, Duration::from_secs(1)).await.unwrap();
+        let remote_addr = RemoteAddr {
+            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            host: localhost,
+            port: destination_port,
+            source: None,
+            request_id: None,
+        };
+
+        let open_tunnel = |local| {
+            let (client, remote_addr) = (client.clone(), remote_addr.clone());
+            tokio::spawn(async move { client.open_tunnel(&remote_addr, tokio::io::split(local)).await })
+        };
+
+        let (local, mut first) = tokio::io::duplex(1024);
+        let _first_tunnel = open_tunnel(local);
+        first.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        tokio::time::timeout(Duration::from_secs(5), first.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // The second tunnel of the same client ip is accepted only to be closed, without reaching the destination
+        let (local, mut second) = tokio::io::duplex(1024);
+        let second_tunnel = open_tunnel(local);
+        let read = tokio::time::timeout(Duration::from_secs(5), second.read(&mut buf))
+            .await
+            .unwrap();
+        assert_eq!(read.unwrap(), 0);
+        tokio::time::timeout(Duration::from_secs(5), second_tunnel)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(nb_connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_handles_of_the_accepted_connections() {
+        use crate::tunnel::client::{WsClient, WsClientConfigBuilder};
+        use crate::tunnel::metrics::AtomicTunnelMetrics;
+        use crate::tunnel::{TransportAddr, TransportScheme};
+        use std::sync::atomic::Ordering;
+
+        let (port, _serve) = spawn_server(WsServer::new(server_config()), CancellationToken::new()).await;
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destination_port = destination.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = destination.accept().await {
+                tokio::spawn(async move {
+                    let (mut rx, mut tx) = stream.split();
+                    let _ = tokio::io::copy(&mut rx, &mut tx).await;
+                });
+            }
+        });
+
+        let localhost = Host::Ipv4("127.0.0.1".parse().unwrap());
+        let metrics = Arc::new(AtomicTunnelMetrics::default());
+        let config =
+            WsClientConfigBuilder::new(TransportAddr::new(TransportScheme::Ws, localhost.clone(), port, None).unwrap())
+                .with_metrics(metrics.clone())
+                .build()
+                .unwrap();
+        let client = WsClient::new(config, 0, Duration::from_secs(1)).await.unwrap();
+        let remote_addr = RemoteAddr {
+            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            host: localhost,
+            port: destination_port,
+            source: None,
+            request_id: None,
+        };
+        let (first_local, mut first) = tokio::io::duplex(1024);
+        let (second_local, mut second) = tokio::io::duplex(1024);
+        let listener = tokio_stream::iter([
+            anyhow::Ok((tokio::io::split(first_local), remote_addr.clone())),
+            anyhow::Ok((tokio::io::split(second_local), remote_addr)),
+        ])
+        .chain(tokio_stream::pending());
+        let tunnels = client.tunnels(listener, CancellationToken::new());
+        tokio::pin!(tunnels);
+
+        async fn echo(peer: &mut tokio::io::DuplexStream) {
+            let mut buf = [0u8; 5];
+            peer.write_all(b"hello").await.unwrap();
+            tokio::time::timeout(Duration::from_secs(5), peer.read_exact(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf, b"hello");
+        }
+        let first_tunnel = tunnels.next().await.unwrap().unwrap();
+        let second_tunnel = tunnels.next().await.unwrap().unwrap();
+        assert_eq!(first_tunnel.remote_addr().port, destination_port);
+        assert_ne!(first_tunnel.request_id(), second_tunnel.request_id());
+        echo(&mut first).await;
+        echo(&mut second).await;
+        assert_eq!(first_tunnel.bytes_received(), 5);
+        assert_eq!(metrics.tunnels_opened.load(Ordering::Relaxed), 2);
+
+        // A cancelled tunnel is accounted as closed, as the ones that end by themselves
+        second_tunnel.cancel();
+        assert!(second_tunnel.await.unwrap_err().is_cancelled());
+        assert_eq!(metrics.tunnels_closed.load(Ordering::Relaxed), 1);
+
+        drop(first);
+        tokio::time::timeout(Duration::from_secs(5), first_tunnel)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(metrics.tunnels_closed.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_open_tunnel_of_a_duplex_stream() {
+        use crate::tunnel::client::{WsClient, WsClientConfigBuilder};
+        use crate::tunnel::{TransportAddr, TransportScheme};
+
+        let shutdown = CancellationToken::new();
+        let (port, serve) = spawn_server(WsServer::new(server_config()), shutdown.clone()).await;
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destination_port = destination.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = destination.accept().await {
+                tokio::spawn(async move {
+                    let (mut rx, mut tx) = stream.split();
+                    let _ = tokio::io::copy(&mut rx, &mut tx).await;
+                });
+            }
+        });
+
+        let localhost = Host::Ipv4("127.0.0.1".parse().unwrap());
+        let config =
+            WsClientConfigBuilder::new(TransportAddr::new(TransportScheme::Ws, localhost.clone(), port, None).unwrap())
+                .build()
+                .unwrap();
+        let client = WsClient::new(config, 0, Duration::from_secs(1)).await.unwrap();
+        let remote_addr = RemoteAddr {
+            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            host: localhost,
+            port: destination_port,
+            source: None,
+            request_id: None,
+        };
+
+        // A stream from the program embedding wstunnel, not accepted by any listener
+        let (local, mut peer) = tokio::io::duplex(1024);
+        let tunnel = tokio::spawn({
+            let client = client.clone();
+            let remote_addr = remote_addr.clone();
+            async move { client.open_tunnel(&remote_addr, tokio::io::split(local)).await }
+        });
+        peer.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        tokio::time::timeout(Duration::from_secs(5), peer.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // Returns once the tunnel is closed
+        peer.shutdown().await.unwrap();
+        assert_eq!(peer.read(&mut buf).await.unwrap(), 0);
+        tokio::time::timeout(Duration::from_secs(5), tunnel)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        // Failing to reach the server is returned to the caller
+        shutdown.cancel();
+        serve.await.unwrap().unwrap();
+        let (local, _peer) = tokio::io::duplex(1024);
+        let ret = tokio::time::timeout(
+            Duration::from_secs(5),
+            client.open_tunnel(&remote_addr, tokio::io::split(local)),
+        )
+        .await
+        .unwrap();
+        assert!(ret.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_errors_are_only_reported_when_enabled() {
+        use crate::tunnel::client::{WsClient, WsClientConfigBuilder};
+        use crate::tunnel::{ConnectErrorKind, TransportAddr, TransportScheme, TunnelConnectError};
+
+        // Nobody listens anymore on the port
+        let closed_port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        async fn connect_error(report_connect_errors: bool, destination_port: u16) -> anyhow::Error {
+            let server = WsServer::new(WsServerConfig {
+                report_connect_errors,
+                ..server_config()
+            });
+            let (port, _serve) = spawn_server(server, CancellationToken::new()).await;
+            let localhost = Host::Ipv4("127.0.0.1".parse().unwrap());
+            let config = WsClientConfigBuilder::new(
+                TransportAddr::new(TransportScheme::Ws, localhost.clone(), port, None).unwrap(),
+            )
+            .build()
+            .unwrap();
+            let client = WsClient::new(config, 0, Duration::from_secs(1)).await.unwrap();
+            let remote_addr = RemoteAddr {
+                protocol: LocalProtocol::Tcp { proxy_protocol: false },
+                host: localhost,
+                port: destination_port,
+                source: None,
+                request_id: None,
+            };
+            let (local, _peer) = tokio::io::duplex(1024);
+            client
+                .open_tunnel(&remote_addr, tokio::io::split(local))
+                .await
+                .unwrap_err()
+        }
+
+        // By default the client cannot tell a closed port from any other rejection
+        let err = connect_error(false, closed_port).await;
+        let err = err.downcast_ref::<TunnelConnectError>().unwrap();
+        assert!(matches!(
+            err,
+            TunnelConnectError::HttpUpgrade {
+                status: Some(StatusCode::BAD_REQUEST),
+                ..
+            }
+        ));
+
+        let err = connect_error(true, closed_port).await;
+        let err = err.downcast_ref::<TunnelConnectError>().unwrap();
+        assert!(matches!(
+            err,
+            TunnelConnectError::Destination {
+                kind: ConnectErrorKind::Refused,
+                ..
+            }
+        ));
+        // The server is fine, trying again would only hit the same closed port
+        assert!(!err.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn test_transport_fallback_when_http2_is_blocked() {
+        use crate::tunnel::client::{WsClient, WsClientConfigBuilder};
+        use crate::tunnel::{TransportAddr, TransportScheme};
+        use tokio::io::AsyncReadExt;
+
+        let shutdown = CancellationToken::new();
+        let (port, _serve) = spawn_server(WsServer::new(server_config()), shutdown.clone()).await;
+
+        // Middlebox in front of the server, resetting the connections starting with the http2 preface
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_port = proxy.local_addr().unwrap().port();
+        let nb_http2_attempts = Arc::new(AtomicUsize::new(0));
+        tokio::spawn({
+            let nb_http2_attempts = nb_http2_attempts.clone();
+            async move {
+                while let Ok((mut stream, _)) = proxy.accept().await {
+                    let nb_http2_attempts = nb_http2_attempts.clone();
+                    tokio::spawn(async move {
+                        let mut preface = [0u8; 3];
+                        if stream.read_exact(&mut preface).await.is_err() {
+                            return;
+                        }
+                        if &preface == b"PRI" {
+                            nb_http2_attempts.fetch_add(1, Ordering::Relaxed);
+                            let _ = stream.set_linger(Some(Duration::ZERO));
+                            return;
+                        }
+                        let mut server = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+                        server.write_all(&preface).await.unwrap();
+                        let _ = tokio::io::copy_bidirectional(&mut stream, &mut server).await;
+                    });
+                }
+            }
+        });
+
+        // Destination of the tunnels
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let service_port = service.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = service.accept().await {
+                tokio::spawn(async move {
+                    let (mut rx, mut tx) = stream.split();
+                    let _ = tokio::io::copy(&mut rx, &mut tx).await;
+                });
+            }
+        });
+
+        let localhost = Host::Ipv4("127.0.0.1".parse().unwrap());
+        let config = WsClientConfigBuilder::new(
+            TransportAddr::new(TransportScheme::Http, localhost.clone(), proxy_port, None).unwrap(),
+        )
+        .with_transport_fallback(true)
+        .build()
+        .unwrap();
+        let client = WsClient::new(config, 0, Duration::from_secs(1)).await.unwrap();
+        let remote_addr = RemoteAddr {
+            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            host: localhost,
+            port: service_port,
+            source: None,
+            request_id: None,
+        };
+
+        for _ in 0..2 {
+            let (mut local, tunnel_end) = tokio::io::duplex(1024);
+            let tunnel = {
+                let client = client.clone();
+                let remote_addr = remote_addr.clone();
+                tokio::spawn(async move { client.open_tunnel(&remote_addr, tokio::io::split(tunnel_end)).await })
+            };
+            local.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            tokio::time::timeout(Duration::from_secs(5), local.read_exact(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf, b"hello");
+            drop(local);
+            tokio::time::timeout(Duration::from_secs(5), tunnel)
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+        }
+
+        // The second tunnel went straight to websocket
+        assert_eq!(nb_http2_attempts.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_raw_transport_tunnel_and_health_check() {
+        use crate::tunnel::client::{WsClient, WsClientConfigBuilder};
+        use crate::tunnel::{TransportAddr, TransportScheme};
+
+        let server = WsServer::new(WsServerConfig {
+            raw_transport: true,
+            health_check_path: Some("/healthz".to_string()),
+            ..server_config()
+        });
+        let (port, _serve) = spawn_server(server, CancellationToken::new()).await;
+
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destination_port = destination.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = destination.accept().await {
+                tokio::spawn(async move {
+                    let (mut rx, mut tx) = stream.split();
+                    let _ = tokio::io::copy(&mut rx, &mut tx).await;
+                });
+            }
+        });
+
+        // The tunnel is closed as soon as a ping misses its pong, so it only stays up if the server answers them
+        let localhost = Host::Ipv4("127.0.0.1".parse().unwrap());
+        let config = WsClientConfigBuilder::new(
+            TransportAddr::new(TransportScheme::Tcp, localhost.clone(), port, None).unwrap(),
+        )
+        .with_websocket_ping_frequency(Duration::from_millis(50))
+        .with_missed_pong_limit(Some(1))
+        .build()
+        .unwrap();
+        let client = WsClient::new(config, 0, Duration::from_secs(1)).await.unwrap();
+        let remote_addr = RemoteAddr {
+            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            host: localhost,
+            port: destination_port,
+            source: None,
+            request_id: None,
+        };
+        let (local, mut peer) = tokio::io::duplex(1024);
+        let tunnel = tokio::spawn(async move { client.open_tunnel(&remote_addr, tokio::io::split(local)).await });
+
+        let mut buf = [0u8; 5];
+        for msg in [b"hello", b"world"] {
+            peer.write_all(msg).await.unwrap();
+            tokio::time::timeout(Duration::from_secs(5), peer.read_exact(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf, msg);
+            tokio::time::sleep(Duration::from_millis(300)).await;
+        }
+
+        // The end of the local stream is forwarded up to the destination, which closes its side in turn
+        peer.shutdown().await.unwrap();
+        let read = tokio::time::timeout(Duration::from_secs(5), peer.read(&mut buf))
+            .await
+            .unwrap();
+        assert_eq!(read.unwrap(), 0);
+        tokio::time::timeout(Duration::from_secs(5), tunnel)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        // Load balancers still get their health check, over http
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stream
+            .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0u8; 256];
+        let n = stream.read(&mut buf).await.unwrap();
+        assert!(String::from_utf8_lossy(&buf[..n]).starts_with("HTTP/1.1 200 OK"));
+    }
+}
@@ -0,0 +1,201 @@
+use crate::tunnel::transport::io::ByteBudget;
+use ahash::{HashMap, HashMapExt};
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Subjects without any tunnel for this long are forgotten, with the bytes they used
+const SUBJECT_IDLE_TIMEOUT: Duration = Duration::from_secs(3600);
+// Idle subjects are looked for at most this often, to not go through all of them for every new tunnel
+const SUBJECT_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Limits of the tunnels of a subject, i.e: the authenticated identity of the client. Unlimited if None
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SubjectLimit {
+    // Tunnels opened at the same time
+    pub max_tunnels: Option<usize>,
+    // Bytes transferred by all the tunnels, in both directions, until the subject has no tunnel for an hour
+    pub max_bytes: Option<u64>,
+}
+
+struct SubjectUsage {
+    nb_tunnels: usize,
+    budget: Option<Arc<ByteBudget>>,
+    // When its last tunnel was opened or closed
+    last_active: Instant,
+}
+
+struct UsagesState {
+    subjects: HashMap<String, SubjectUsage>,
+    last_sweep: Instant,
+}
+
+type Usages = Arc<Mutex<UsagesState>>;
+
+/// Accounting of the tunnels opened by each subject, to not let one of them use the whole server
+pub struct SubjectLimits {
+    default: SubjectLimit,
+    overrides: HashMap<String, SubjectLimit>,
+    usages: Usages,
+}
+
+impl SubjectLimits {
+    pub fn new(default: SubjectLimit, overrides: impl IntoIterator<Item = (String, SubjectLimit)>) -> Self {
+        let mut limits = HashMap::new();
+        limits.extend(overrides);
+        Self {
+            default,
+            overrides: limits,
+            usages: Arc::new(Mutex::new(UsagesState {
+                subjects: HashMap::new(),
+                last_sweep: Instant::now(),
+            })),
+        }
+    }
+
+    /// Count a new tunnel of the subject, until the returned guard is dropped.
+    /// Returns an error with the reason if the subject cannot open one more
+    pub fn acquire(&self, subject: &str) -> Result<SubjectTunnel, String> {
+        self.acquire_at(subject, Instant::now())
+    }
+
+    fn acquire_at(&self, subject: &str, now: Instant) -> Result<SubjectTunnel, String> {
+        let limit = self.overrides.get(subject).unwrap_or(&self.default);
+        let mut usages = self.usages.lock();
+        if now.saturating_duration_since(usages.last_sweep) >= SUBJECT_SWEEP_INTERVAL {
+            usages.last_sweep = now;
+            usages.subjects.retain(|_, usage| {
+                usage.nb_tunnels > 0 || now.saturating_duration_since(usage.last_active) < SUBJECT_IDLE_TIMEOUT
+            });
+        }
+
+        let usage = usages
+            .subjects
+            .entry(subject.to_string())
+            .or_insert_with(|| SubjectUsage {
+                nb_tunnels: 0,
+                budget: limit.max_bytes.map(|max_bytes| Arc::new(ByteBudget::new(max_bytes))),
+                last_active: now,
+            });
+
+        if let Some(max_tunnels) = limit.max_tunnels {
+            if usage.nb_tunnels >= max_tunnels {
+                return Err(format!("more than {} tunnels opened at the same time", max_tunnels));
+            }
+        }
+        if usage.budget.as_ref().is_some_and(|budget| budget.is_exhausted()) {
+            return Err(format!("budget of {} bytes exhausted", limit.max_bytes.unwrap_or_default()));
+        }
+
+        usage.nb_tunnels += 1;
+        usage.last_active = now;
+        Ok(SubjectTunnel {
+            subject: subject.to_string(),
+            budget: usage.budget.clone(),
+            usages: self.usages.clone(),
+        })
+    }
+
+    #[cfg(test)]
+    fn nb_subjects(&self) -> usize {
+        self.usages.lock().subjects.len()
+    }
+}
+
+/// A tunnel counted in the usage of its subject, until it is dropped
+pub struct SubjectTunnel {
+    subject: String,
+    budget: Option<Arc<ByteBudget>>,
+    usages: Usages,
+}
+
+impl SubjectTunnel {
+    pub fn budget(&self) -> Option<Arc<ByteBudget>> {
+        self.budget.clone()
+    }
+}
+
+impl Drop for SubjectTunnel {
+    fn drop(&mut self) {
+        let mut usages = self.usages.lock();
+        let Some(usage) = usages.subjects.get_mut(&self.subject) else {
+            return;
+        };
+        usage.nb_tunnels = usage.nb_tunnels.saturating_sub(1);
+        usage.last_active = Instant::now();
+        // The budget must outlive the tunnels, for a subject to not get a new one by closing them all
+        if usage.nb_tunnels == 0 && usage.budget.is_none() {
+            usages.subjects.remove(&self.subject);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_max_tunnels_per_subject() {
+        let default = SubjectLimit {
+            max_tunnels: Some(1),
+            max_bytes: None,
+        };
+        let acme = SubjectLimit {
+            max_tunnels: Some(2),
+            max_bytes: None,
+        };
+        let limits = SubjectLimits::new(default, [("acme".to_string(), acme)]);
+
+        let other = limits.acquire("other").unwrap();
+        assert!(limits.acquire("other").is_err());
+        let acme_1 = limits.acquire("acme").unwrap();
+        let _acme_2 = limits.acquire("acme").unwrap();
+        assert!(limits.acquire("acme").is_err());
+
+        drop(other);
+        drop(acme_1);
+        assert!(limits.acquire("other").is_ok());
+        assert!(limits.acquire("acme").is_ok());
+        // Subjects without budget are forgotten once they have no tunnel left
+        assert_eq!(limits.nb_subjects(), 1);
+    }
+
+    #[test]
+    fn test_bytes_budget_per_subject() {
+        let default = SubjectLimit {
+            max_tunnels: None,
+            max_bytes: Some(100),
+        };
+        let limits = SubjectLimits::new(default, []);
+
+        let tunnel = limits.acquire("acme").unwrap();
+        assert!(tunnel.budget().unwrap().consume(100));
+        drop(tunnel);
+        assert!(limits.acquire("acme").is_err());
+        assert!(limits.acquire("other").is_ok());
+    }
+
+    #[test]
+    fn test_idle_subjects_are_forgotten() {
+        let default = SubjectLimit {
+            max_tunnels: None,
+            max_bytes: Some(100),
+        };
+        let limits = SubjectLimits::new(default, []);
+        let now = Instant::now();
+
+        let active = limits.acquire_at("active", now).unwrap();
+        let idle = limits.acquire_at("idle", now).unwrap();
+        assert!(idle.budget().unwrap().consume(100));
+        drop(idle);
+        assert!(limits.acquire_at("idle", now + SUBJECT_SWEEP_INTERVAL).is_err());
+        assert_eq!(limits.nb_subjects(), 2);
+
+        // Only the subjects without tunnel are forgotten, their budget with them
+        let later = Instant::now() + SUBJECT_IDLE_TIMEOUT + SUBJECT_SWEEP_INTERVAL;
+        assert!(limits.acquire_at("other", later).is_ok());
+        assert_eq!(limits.nb_subjects(), 2);
+        assert!(limits.acquire_at("idle", later).is_ok());
+        assert!(active.budget().is_some());
+    }
+}
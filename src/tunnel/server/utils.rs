@@ -1,18 +1,35 @@
 use crate::restrictions::types::{
-    AllowConfig, MatchConfig, RestrictionConfig, RestrictionsRules, ReverseTunnelConfigProtocol, TunnelConfigProtocol,
+    AllowConfig, DenyTunnelConfig, MatchConfig, RestrictionConfig, RestrictionsRules, ReverseTunnelConfigProtocol,
+    TunnelConfigProtocol,
 };
-use crate::tunnel::{tunnel_to_jwt_token, JwtTunnelConfig, RemoteAddr, JWT_DECODE, JWT_HEADER_PREFIX};
+use crate::tunnel::error::set_connect_error;
+use crate::tunnel::jwt::ClaimsWithId;
+use crate::tunnel::logging::LogThrottle;
+use crate::tunnel::server::subject_limits::SubjectTunnel;
+use crate::tunnel::server::WsServer;
+use crate::tunnel::transport;
+use crate::tunnel::transport::datagram::{DatagramTunnelRead, DatagramTunnelWrite};
+use crate::tunnel::transport::mux::has_reverse_multiplex;
+use crate::tunnel::transport::{CloseReason, TunnelRead, TunnelWrite};
+use crate::tunnel::{tunnel_to_jwt_token, ConnectErrorKind, JwtTunnelConfig, RemoteAddr, JWT_HEADER_PREFIX, JWT_KEYS};
+use crate::LocalProtocol;
 use bytes::Bytes;
 use http_body_util::combinators::BoxBody;
 use http_body_util::Either;
-use hyper::body::{Body, Incoming};
-use hyper::header::{HeaderValue, COOKIE, SEC_WEBSOCKET_PROTOCOL};
-use hyper::{http, Request, Response, StatusCode};
+use hyper::body::Body;
+use hyper::header::{HeaderMap, HeaderValue, AUTHORIZATION, CACHE_CONTROL, COOKIE, SEC_WEBSOCKET_PROTOCOL};
+use hyper::{http, Method, Request, Response, StatusCode};
+use ipnet::IpNet;
 use jsonwebtoken::TokenData;
+use parking_lot::Mutex;
 use std::cmp::min;
-use std::net::IpAddr;
-use std::ops::Deref;
-use tracing::{error, info, warn};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::oneshot;
+use tracing::{error, info, warn, Instrument, Span};
 use url::Host;
 use uuid::Uuid;
 
@@ -23,6 +40,198 @@ pub(super) fn bad_request() -> Response<Either<String, BoxBody<Bytes, anyhow::Er
         .unwrap()
 }
 
+/// Forward a tunnel between its local side and the transport of the client, from a task of the server.
+/// The transport is only awaited in the task, as i.e: a websocket is upgraded once its response has been sent
+pub(super) fn spawn_server_tunnel<R, W>(
+    server: &WsServer,
+    local_rx: Pin<Box<dyn AsyncRead + Send>>,
+    local_tx: Pin<Box<dyn AsyncWrite + Send>>,
+    transport: impl Future<Output = Option<(R, W)>> + Send + 'static,
+    subject_tunnel: Option<SubjectTunnel>,
+    length_prefixed: bool,
+) where
+    R: TunnelRead,
+    W: TunnelWrite,
+{
+    // Upload is what the client sends to the destination, so what goes from the remote to the local side of the server
+    let byte_limits = transport::io::ByteLimits::new(
+        server.config.max_bytes_per_tunnel_download,
+        server.config.max_bytes_per_tunnel_upload,
+    )
+    .with_budget(
+        subject_tunnel
+            .as_ref()
+            .and_then(|subject_tunnel| subject_tunnel.budget()),
+    );
+    let options = transport::io::PropagateOptions::new(server.config.clock.clone()).with_byte_limits(byte_limits);
+    server.spawn_tunnel(
+        async move {
+            // Counted in the tunnels of its subject until it ends, whatever the way
+            let _subject_tunnel = subject_tunnel;
+            let Some((ws_rx, ws_tx)) = transport.await else {
+                return;
+            };
+            let (close_tx, close_rx) = oneshot::channel::<()>();
+            let started_at = Instant::now();
+            let remote_to_local = tokio::task::spawn(
+                transport::io::propagate_remote_to_local(
+                    local_tx,
+                    DatagramTunnelRead::new(ws_rx, length_prefixed),
+                    close_rx,
+                    options.clone(),
+                )
+                .instrument(Span::current()),
+            );
+
+            let local_to_remote = transport::io::propagate_local_to_remote(
+                local_rx,
+                DatagramTunnelWrite::new(ws_tx, length_prefixed),
+                close_tx,
+                options,
+            )
+            .await;
+            let remote_to_local = remote_to_local.await.unwrap_or_default();
+            transport::io::log_tunnel_closed(&local_to_remote, &remote_to_local, started_at.elapsed());
+        }
+        .instrument(Span::current()),
+    );
+}
+
+/// The tunnel is refused by the restrictions, the client is told so to not take it for a connection failure
+pub(super) fn not_allowed() -> Response<Either<String, BoxBody<Bytes, anyhow::Error>>> {
+    let mut response = bad_request();
+    set_connect_error(response.headers_mut(), ConnectErrorKind::NotAllowed);
+    response
+}
+
+/// The destination of the tunnel cannot be reached, with the reason for the client to forward it to its peer
+pub(super) fn bad_gateway(kind: ConnectErrorKind) -> Response<Either<String, BoxBody<Bytes, anyhow::Error>>> {
+    let mut response = http::Response::builder()
+        .status(StatusCode::BAD_GATEWAY)
+        .body(Either::Left("Bad Gateway".to_string()))
+        .unwrap();
+    set_connect_error(response.headers_mut(), kind);
+    response
+}
+
+/// Answer the health checks of load balancers, before any processing of the request as a tunnel one
+/// Report unhealthy while draining, for the load balancers to stop sending new connections
+pub(super) fn health_check<B>(
+    server: &WsServer,
+    req: &Request<B>,
+) -> Option<Response<Either<String, BoxBody<Bytes, anyhow::Error>>>> {
+    let config = &server.config;
+    let path = config.health_check_path.as_deref()?;
+    if req.uri().path() != path || !matches!(*req.method(), Method::GET | Method::HEAD) {
+        return None;
+    }
+    if server.is_draining() {
+        return Some(service_unavailable());
+    }
+
+    let body = if config.health_check_expose_version {
+        format!("OK wstunnel v{}", env!("CARGO_PKG_VERSION"))
+    } else {
+        "OK".to_string()
+    };
+    Some(
+        http::Response::builder()
+            .status(StatusCode::OK)
+            .header(CACHE_CONTROL, "no-store")
+            .body(Either::Left(body))
+            .unwrap(),
+    )
+}
+
+pub(super) fn not_found() -> Response<Either<String, BoxBody<Bytes, anyhow::Error>>> {
+    http::Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Either::Left("Not Found".to_string()))
+        .unwrap()
+}
+
+pub(super) fn unauthorized() -> Response<Either<String, BoxBody<Bytes, anyhow::Error>>> {
+    http::Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .body(Either::Left("Unauthorized".to_string()))
+        .unwrap()
+}
+
+pub(super) fn service_unavailable() -> Response<Either<String, BoxBody<Bytes, anyhow::Error>>> {
+    http::Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .body(Either::Left("Service Unavailable".to_string()))
+        .unwrap()
+}
+
+pub(super) fn too_many_requests() -> Response<Either<String, BoxBody<Bytes, anyhow::Error>>> {
+    http::Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .body(Either::Left("Too Many Requests".to_string()))
+        .unwrap()
+}
+
+/// Rejection of a tunnel going over the limits of its client. Transports able to tell why a tunnel is closed accept it
+/// and close it right away with the policy violation code (1008) and the reason given in the extensions of the response
+pub(super) fn policy_violation(reason: impl Into<String>) -> Response<Either<String, BoxBody<Bytes, anyhow::Error>>> {
+    let mut response = too_many_requests();
+    response
+        .extensions_mut()
+        .insert(CloseReason::new(CloseReason::POLICY_VIOLATION, reason));
+    response
+}
+
+/// Checks that the request carries the expected bearer token, in constant time to not leak it through timings
+pub(super) fn has_bearer_token<B>(req: &Request<B>, token: &str) -> bool {
+    let Some(provided) = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|header| header.as_bytes().strip_prefix(b"Bearer "))
+    else {
+        return false;
+    };
+
+    provided.len() == token.len()
+        && provided
+            .iter()
+            .zip(token.as_bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+// Scanners can trigger the unauthorized logs in a loop, at most one of them is logged per second
+static UNAUTHORIZED_LOG: Mutex<LogThrottle> = parking_lot::const_mutex(LogThrottle::new(Duration::from_secs(1)));
+
+/// Log rejected unauthorized requests, throttled to not flood the logs when scanned
+pub(super) fn log_unauthorized<B>(req: &Request<B>, client_addr: SocketAddr) {
+    if let Some(suppressed) = UNAUTHORIZED_LOG.lock().allow() {
+        warn!(
+            "Rejecting unauthorized connection from {} to {} ({} more since last log)",
+            client_addr,
+            req.uri(),
+            suppressed
+        );
+    }
+}
+
+/// Log connections closed right after accept for failing the bearer token check too often, throttled as well
+pub(super) fn log_dropped_unauthorized(client_addr: SocketAddr) {
+    if let Some(suppressed) = UNAUTHORIZED_LOG.lock().allow() {
+        warn!(
+            "Closing connection from {}, too many unauthorized requests ({} more since last log)",
+            client_addr, suppressed
+        );
+    }
+}
+
+/// Checks if the request path starts with the given prefix, on a path segment boundary
+#[inline]
+pub(super) fn has_path_prefix(path: &str, prefix: &str) -> bool {
+    path.strip_prefix('/')
+        .and_then(|path| path.strip_prefix(prefix.trim_matches('/')))
+        .is_some_and(|rest| rest.starts_with('/'))
+}
+
 /// Checks if the requested (remote) port has been mapped in the configuration to another port.
 /// If it is not mapped the original port number is returned.
 #[inline]
@@ -47,7 +256,7 @@ pub(super) fn find_mapped_port(req_port: u16, restriction: &RestrictionConfig) -
 }
 
 #[inline]
-pub(super) fn extract_x_forwarded_for(req: &Request<Incoming>) -> Result<Option<(IpAddr, &str)>, ()> {
+pub(super) fn extract_x_forwarded_for<B>(req: &Request<B>) -> Result<Option<(IpAddr, &str)>, ()> {
     let Some(x_forward_for) = req.headers().get("X-Forwarded-For") else {
         return Ok(None);
     };
@@ -60,7 +269,7 @@ pub(super) fn extract_x_forwarded_for(req: &Request<Incoming>) -> Result<Option<
 }
 
 #[inline]
-pub(super) fn extract_path_prefix(req: &Request<Incoming>) -> Result<&str, ()> {
+pub(super) fn extract_path_prefix<B>(req: &Request<B>) -> Result<&str, ()> {
     let path = req.uri().path();
     let min_len = min(path.len(), 1);
     if &path[0..min_len] != "/" {
@@ -68,7 +277,8 @@ pub(super) fn extract_path_prefix(req: &Request<Incoming>) -> Result<&str, ()> {
         return Err(());
     }
 
-    let Some((l, r)) = path[min_len..].split_once('/') else {
+    // The prefix can span multiple path segments, i.e: /api/v2/stream/events
+    let Some((l, r)) = path[min_len..].rsplit_once('/') else {
         warn!("Rejecting connection with bad upgrade request: {}", req.uri());
         return Err(());
     };
@@ -82,7 +292,7 @@ pub(super) fn extract_path_prefix(req: &Request<Incoming>) -> Result<&str, ()> {
 }
 
 #[inline]
-pub(super) fn extract_tunnel_info(req: &Request<Incoming>) -> Result<TokenData<JwtTunnelConfig>, ()> {
+pub(super) fn extract_tunnel_info<B>(req: &Request<B>) -> Result<TokenData<ClaimsWithId<JwtTunnelConfig>>, ()> {
     let jwt = req
         .headers()
         .get(SEC_WEBSOCKET_PROTOCOL)
@@ -92,8 +302,7 @@ pub(super) fn extract_tunnel_info(req: &Request<Incoming>) -> Result<TokenData<J
         .or_else(|| req.headers().get(COOKIE).and_then(|header| header.to_str().ok()))
         .unwrap_or_default();
 
-    let (validation, decode_key) = JWT_DECODE.deref();
-    let jwt = match jsonwebtoken::decode(jwt, decode_key, validation) {
+    let jwt: TokenData<ClaimsWithId<JwtTunnelConfig>> = match JWT_KEYS.decode(jwt) {
         Ok(jwt) => jwt,
         err => {
             warn!(
@@ -105,6 +314,18 @@ pub(super) fn extract_tunnel_info(req: &Request<Incoming>) -> Result<TokenData<J
         }
     };
 
+    let (nbf, exp) = (jwt.claims.nbf, jwt.claims.exp);
+    if !JWT_KEYS
+        .validity()
+        .accepts_lifetime(nbf, exp, jsonwebtoken::get_current_timestamp())
+    {
+        warn!(
+            "Rejecting tunnel info valid for too long, from {:?} to {:?}. Check --jwt-max-ttl-sec",
+            nbf, exp
+        );
+        return Err(());
+    }
+
     Ok(jwt)
 }
 
@@ -112,16 +333,26 @@ pub(super) fn extract_tunnel_info(req: &Request<Incoming>) -> Result<TokenData<J
 pub(super) fn validate_tunnel<'a>(
     remote: &RemoteAddr,
     path_prefix: &str,
+    metadata: Option<&str>,
     restrictions: &'a RestrictionsRules,
 ) -> Result<&'a RestrictionConfig, ()> {
     for restriction in &restrictions.restrictions {
         if !restriction.r#match.iter().all(|m| match m {
             MatchConfig::Any => true,
             MatchConfig::PathPrefix(path) => path.is_match(path_prefix),
+            MatchConfig::Metadata(regex) => metadata.is_some_and(|metadata| regex.is_match(metadata)),
         }) {
             continue;
         }
 
+        if let Some(deny) = restriction.deny.iter().find(|deny| is_denied(remote, deny)) {
+            warn!(
+                "Rejecting connection to {:?}, denied by restriction '{}': {:?}",
+                remote, restriction.name, deny
+            );
+            return Err(());
+        }
+
         for allow in &restriction.allow {
             match allow {
                 AllowConfig::ReverseTunnel(allow) => {
@@ -167,6 +398,20 @@ pub(super) fn validate_tunnel<'a>(
                         continue;
                     }
 
+                    // Host and port are meaningless for a unix socket, the host regex is matched against its path
+                    if let LocalProtocol::UnixSocket { path } = &remote.protocol {
+                        if allow.protocol.contains(&TunnelConfigProtocol::Unix)
+                            && allow.host.is_match(&path.to_string_lossy())
+                        {
+                            return Ok(restriction);
+                        }
+                        continue;
+                    }
+
+                    if remote.protocol == LocalProtocol::Exec && !allow.protocol.contains(&TunnelConfigProtocol::Exec) {
+                        continue;
+                    }
+
                     if !allow.port.is_empty() && !allow.port.iter().any(|range| range.contains(&remote.port)) {
                         continue;
                     }
@@ -209,8 +454,89 @@ pub(super) fn validate_tunnel<'a>(
     Err(())
 }
 
+fn is_denied(remote: &RemoteAddr, deny: &DenyTunnelConfig) -> bool {
+    if remote.protocol.is_reverse_tunnel() {
+        return false;
+    }
+
+    if !deny.protocol.is_empty() && !deny.protocol.contains(&TunnelConfigProtocol::from(&remote.protocol)) {
+        return false;
+    }
+
+    if let LocalProtocol::UnixSocket { path } = &remote.protocol {
+        return deny
+            .host
+            .as_ref()
+            .is_none_or(|host| host.is_match(&path.to_string_lossy()));
+    }
+
+    if !is_denied_port(remote, deny) {
+        return false;
+    }
+
+    if deny.host.is_none() && deny.cidr.is_empty() {
+        return true;
+    }
+
+    match &remote.host {
+        Host::Domain(host) => deny.host.as_ref().is_some_and(|regex| regex.is_match(host)),
+        Host::Ipv4(ip) => deny.cidr.iter().any(|cidr| cidr.contains(&IpAddr::V4(*ip))),
+        Host::Ipv6(ip) => deny.cidr.iter().any(|cidr| cidr.contains(&IpAddr::V6(*ip))),
+    }
+}
+
+fn is_denied_port(remote: &RemoteAddr, deny: &DenyTunnelConfig) -> bool {
+    deny.port.is_empty() || deny.port.iter().any(|range| range.contains(&remote.port))
+}
+
+/// Networks the addresses of the destination must not be in, according to the deny rules of the restriction.
+/// A hostname passes the rules checked against the tunnel, but may resolve to a denied ip once connecting
+pub(super) fn denied_cidrs(remote: &RemoteAddr, restriction: &RestrictionConfig) -> Vec<IpNet> {
+    if remote.protocol.is_reverse_tunnel() || matches!(remote.protocol, LocalProtocol::UnixSocket { .. }) {
+        return vec![];
+    }
+
+    restriction
+        .deny
+        .iter()
+        .filter(|deny| {
+            deny.protocol.is_empty() || deny.protocol.contains(&TunnelConfigProtocol::from(&remote.protocol))
+        })
+        .filter(|deny| is_denied_port(remote, deny))
+        .flat_map(|deny| deny.cidr.iter().copied())
+        .collect()
+}
+
+/// Build a PROXY protocol v2 header announcing the source of the tunnel
+pub(super) fn proxy_protocol_header(source: SocketAddr, destination: SocketAddr) -> anyhow::Result<Vec<u8>> {
+    use ppp::v2::{Builder, Command, Protocol, Version};
+
+    // Both addresses must be of the same family, use IPv4-mapped IPv6 addresses if they don't match
+    let to_v6 = |addr: SocketAddr| match addr {
+        SocketAddr::V4(addr) => SocketAddr::new(IpAddr::V6(addr.ip().to_ipv6_mapped()), addr.port()),
+        SocketAddr::V6(_) => addr,
+    };
+    let addresses = if source.is_ipv4() == destination.is_ipv4() {
+        (source, destination)
+    } else {
+        (to_v6(source), to_v6(destination))
+    };
+
+    Ok(Builder::with_addresses(Version::Two | Command::Proxy, Protocol::Stream, addresses).build()?)
+}
+
 pub(super) fn inject_cookie(response: &mut http::Response<impl Body>, remote_addr: &RemoteAddr) -> Result<(), ()> {
-    let Ok(header_val) = HeaderValue::from_str(&tunnel_to_jwt_token(Uuid::from_u128(0), remote_addr)) else {
+    let jwt = match tunnel_to_jwt_token(Uuid::from_u128(0), remote_addr, None) {
+        Ok(jwt) => jwt,
+        Err(err) => {
+            error!(
+                "Cannot sign the tunnel information sent back for reverse socks5/http: {:?}",
+                err
+            );
+            return Err(());
+        }
+    };
+    let Ok(header_val) = HeaderValue::from_str(&jwt) else {
         error!("Bad header value for reverse socks5: {} {}", remote_addr.host, remote_addr.port);
         return Err(());
     };
@@ -218,3 +544,158 @@ pub(super) fn inject_cookie(response: &mut http::Response<impl Body>, remote_add
 
     Ok(())
 }
+
+/// Tunnels carrying udp datagrams, which need framing to keep their boundaries over the transport
+pub(super) const fn is_datagram_tunnel(remote_addr: &RemoteAddr) -> bool {
+    matches!(remote_addr.protocol, LocalProtocol::Udp { .. })
+}
+
+/// Reverse tunnels whose client asked to receive all the connections of the listener through a single tunnel
+pub(super) fn is_multiplexed_reverse_tunnel(remote_addr: &RemoteAddr, headers: &HeaderMap) -> bool {
+    matches!(remote_addr.protocol, LocalProtocol::ReverseTcp)
+        && !remote_addr.is_ephemeral_bind()
+        && has_reverse_multiplex(headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_deny_rules_take_precedence_over_allow() {
+        let config = r#"
+restrictions:
+  - name: "no internal network"
+    match:
+      - !Any
+    allow:
+      - !Tunnel
+    deny:
+      - cidr:
+          - 10.0.0.0/8
+      - host: ^.*\.internal$
+      - protocol:
+          - Udp
+        port:
+          - 53
+"#;
+        let restrictions: RestrictionsRules = serde_yaml::from_str(config).unwrap();
+        let tcp = |host: Host, port: u16| RemoteAddr {
+            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            host,
+            port,
+            source: None,
+            request_id: None,
+        };
+
+        assert!(validate_tunnel(&tcp(Host::Domain("example.com".to_string()), 443), "v1", None, &restrictions).is_ok());
+        assert!(validate_tunnel(&tcp(Host::Ipv4(Ipv4Addr::new(1, 1, 1, 1)), 53), "v1", None, &restrictions).is_ok());
+        assert!(validate_tunnel(&tcp(Host::Ipv4(Ipv4Addr::new(10, 1, 2, 3)), 443), "v1", None, &restrictions).is_err());
+        assert!(
+            validate_tunnel(&tcp(Host::Domain("db.internal".to_string()), 5432), "v1", None, &restrictions).is_err()
+        );
+
+        let udp_dns = RemoteAddr {
+            protocol: LocalProtocol::Udp { timeout: None },
+            host: Host::Ipv4(Ipv4Addr::new(1, 1, 1, 1)),
+            port: 53,
+            source: None,
+            request_id: None,
+        };
+        assert!(validate_tunnel(&udp_dns, "v1", None, &restrictions).is_err());
+
+        // A domain passes, but cannot resolve to a denied network
+        let restriction = &restrictions.restrictions[0];
+        let domain = tcp(Host::Domain("example.com".to_string()), 443);
+        assert_eq!(denied_cidrs(&domain, restriction), vec!["10.0.0.0/8".parse::<IpNet>().unwrap()]);
+    }
+
+    #[test]
+    fn test_restriction_matching_metadata() {
+        let config = r#"
+restrictions:
+  - name: "acme"
+    match:
+      - !Metadata "^tenant=acme$"
+    allow:
+      - !Tunnel
+        port:
+          - 443
+"#;
+        let restrictions: RestrictionsRules = serde_yaml::from_str(config).unwrap();
+        let remote = RemoteAddr {
+            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            host: Host::Domain("example.com".to_string()),
+            port: 443,
+            source: None,
+            request_id: None,
+        };
+
+        assert!(validate_tunnel(&remote, "v1", Some("tenant=acme"), &restrictions).is_ok());
+        assert!(validate_tunnel(&remote, "v1", Some("tenant=other"), &restrictions).is_err());
+        assert!(validate_tunnel(&remote, "v1", None, &restrictions).is_err());
+    }
+
+    #[test]
+    fn test_exec_tunnels_must_be_explicitly_allowed() {
+        let config = r#"
+restrictions:
+  - name: "any"
+    match:
+      - !Any
+    allow:
+      - !Tunnel
+        port:
+          - 22
+  - name: "exec"
+    match:
+      - !PathPrefix "^exec-"
+    allow:
+      - !Tunnel
+        protocol:
+          - Exec
+        port:
+          - 22
+"#;
+        let restrictions: RestrictionsRules = serde_yaml::from_str(config).unwrap();
+        let exec = |port: u16| RemoteAddr {
+            protocol: LocalProtocol::Exec,
+            host: Host::Domain("n.lan".to_string()),
+            port,
+            source: None,
+            request_id: None,
+        };
+
+        assert!(validate_tunnel(&exec(22), "v1", None, &restrictions).is_err());
+        assert!(validate_tunnel(&exec(22), "v1", Some("admin"), &restrictions).is_err());
+        assert!(validate_tunnel(&exec(22), "exec-v1", None, &restrictions).is_ok());
+        assert!(validate_tunnel(&exec(80), "exec-v1", None, &restrictions).is_err());
+    }
+
+    #[test]
+    fn test_proxy_protocol_header() {
+        use ppp::v2::{Addresses, Command, Header, IPv4, IPv6};
+
+        let destination: SocketAddr = "10.0.0.1:443".parse().unwrap();
+        let header = proxy_protocol_header("192.168.1.2:4242".parse().unwrap(), destination).unwrap();
+        let header = Header::try_from(header.as_slice()).unwrap();
+        assert_eq!(header.command, Command::Proxy);
+        assert_eq!(
+            header.addresses,
+            Addresses::IPv4(IPv4::new(Ipv4Addr::new(192, 168, 1, 2), Ipv4Addr::new(10, 0, 0, 1), 4242, 443))
+        );
+
+        let header = proxy_protocol_header("[fd00::2]:4242".parse().unwrap(), destination).unwrap();
+        let header = Header::try_from(header.as_slice()).unwrap();
+        assert_eq!(
+            header.addresses,
+            Addresses::IPv6(IPv6::new(
+                "fd00::2".parse::<std::net::Ipv6Addr>().unwrap(),
+                Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped(),
+                4242,
+                443
+            ))
+        );
+    }
+}
@@ -286,21 +286,25 @@ impl TlsReloader {
                     tls::load_private_key_from_file(&this.key_path),
                 ) {
                     (Ok(tls_certs), Ok(tls_key)) => {
-                        let tls_connector = tls::tls_connector(
-                            tls.tls_verify_certificate,
-                            this.client_config.remote_addr.scheme().alpn_protocols(),
-                            !tls.tls_sni_disabled,
-                            Some(tls_certs),
-                            Some(tls_key),
-                        );
-                        let tls_connector = match tls_connector {
-                            Ok(cn) => cn,
+                        let tls_client_config = tls::tls_client_config(tls::TlsClientOptions {
+                            tls_verify_certificate: tls.tls_verify_certificate,
+                            alpn_protocols: this.client_config.alpn_protocols(),
+                            enable_sni: !tls.tls_sni_disabled,
+                            tls_verify_hostname: tls.tls_verify_hostname.clone(),
+                            tls_certificate_pins: tls.tls_certificate_pins.clone(),
+                            root_store: tls.tls_root_store.clone(),
+                            tls_client_certificate: Some(tls_certs),
+                            tls_client_key: Some(tls_key),
+                            tls_min_version: tls.tls_min_version,
+                        });
+                        let tls_client_config = match tls_client_config {
+                            Ok(cfg) => cfg,
                             Err(err) => {
                                 error!("Error while creating TLS connector {:?}", err);
                                 return;
                             }
                         };
-                        *tls.tls_connector.write() = tls_connector;
+                        *tls.tls_client_config.write() = tls_client_config;
                         this.tls_reload_certificate.store(true, Ordering::Relaxed);
                     }
                     (Err(err), _) | (_, Err(err)) => {
@@ -329,21 +333,25 @@ impl TlsReloader {
                     tls::load_private_key_from_file(&this.key_path),
                 ) {
                     (Ok(tls_certs), Ok(tls_key)) => {
-                        let tls_connector = tls::tls_connector(
-                            tls.tls_verify_certificate,
-                            this.client_config.remote_addr.scheme().alpn_protocols(),
-                            !tls.tls_sni_disabled,
-                            Some(tls_certs),
-                            Some(tls_key),
-                        );
-                        let tls_connector = match tls_connector {
-                            Ok(cn) => cn,
+                        let tls_client_config = tls::tls_client_config(tls::TlsClientOptions {
+                            tls_verify_certificate: tls.tls_verify_certificate,
+                            alpn_protocols: this.client_config.alpn_protocols(),
+                            enable_sni: !tls.tls_sni_disabled,
+                            tls_verify_hostname: tls.tls_verify_hostname.clone(),
+                            tls_certificate_pins: tls.tls_certificate_pins.clone(),
+                            root_store: tls.tls_root_store.clone(),
+                            tls_client_certificate: Some(tls_certs),
+                            tls_client_key: Some(tls_key),
+                            tls_min_version: tls.tls_min_version,
+                        });
+                        let tls_client_config = match tls_client_config {
+                            Ok(cfg) => cfg,
                             Err(err) => {
                                 error!("Error while creating TLS connector {:?}", err);
                                 return;
                             }
                         };
-                        *tls.tls_connector.write() = tls_connector;
+                        *tls.tls_client_config.write() = tls_client_config;
                         this.tls_reload_certificate.store(true, Ordering::Relaxed);
                     }
                     (Err(err), _) | (_, Err(err)) => {
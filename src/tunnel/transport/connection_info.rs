@@ -0,0 +1,68 @@
+use crate::tunnel::{TransportAddr, TransportScheme, TransportStream};
+use std::fmt::{Display, Formatter};
+use tokio_rustls::rustls::{CipherSuite, ProtocolVersion};
+use x509_parser::parse_x509_certificate;
+
+/// What was negotiated with the server when establishing the connection carrying a tunnel.
+/// Available in the extensions of the response to the upgrade request
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub scheme: TransportScheme,
+    pub server: String,
+    /// None when the server did not pick any of the protocols we offered, or without tls
+    pub alpn: Option<String>,
+    pub tls_version: Option<ProtocolVersion>,
+    pub cipher_suite: Option<CipherSuite>,
+    /// Subject of the leaf certificate presented by the server
+    pub peer_certificate_subject: Option<String>,
+}
+
+impl ConnectionInfo {
+    pub fn new(server: &TransportAddr, stream: &TransportStream) -> Self {
+        let mut info = Self {
+            scheme: *server.scheme(),
+            server: format!("{}:{}", server.host(), server.port()),
+            alpn: None,
+            tls_version: None,
+            cipher_suite: None,
+            peer_certificate_subject: None,
+        };
+
+        let TransportStream::Tls(stream) = stream else {
+            return info;
+        };
+        let (_, tls) = stream.get_ref();
+        info.alpn = tls
+            .alpn_protocol()
+            .map(|alpn| String::from_utf8_lossy(alpn).to_string());
+        info.tls_version = tls.protocol_version();
+        info.cipher_suite = tls.negotiated_cipher_suite().map(|suite| suite.suite());
+        info.peer_certificate_subject = tls
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| parse_x509_certificate(cert).ok())
+            .map(|(_, cert)| cert.subject().to_string());
+
+        info
+    }
+}
+
+impl Display for ConnectionInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}://{}", self.scheme, self.server)?;
+        if let Some(tls_version) = &self.tls_version {
+            write!(f, " {:?}", tls_version)?;
+        }
+        if let Some(cipher_suite) = &self.cipher_suite {
+            write!(f, " {:?}", cipher_suite)?;
+        }
+        if let Some(alpn) = &self.alpn {
+            write!(f, " alpn={}", alpn)?;
+        }
+        if let Some(subject) = &self.peer_certificate_subject {
+            write!(f, " certificate subject=\"{}\"", subject)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,222 @@
+use crate::tunnel::transport::{CloseReason, TunnelRead, TunnelWrite};
+use bytes::BytesMut;
+use hyper::http::{HeaderMap, HeaderName, HeaderValue};
+use std::io;
+use std::io::ErrorKind;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tracing::warn;
+
+/// Biggest payload of an UDP datagram over IPv4
+pub const MAX_DATAGRAM_SIZE: usize = 65507;
+
+const LENGTH_PREFIX_SIZE: usize = 2;
+
+// The client announces with this header that it understands length prefixed datagrams, and the server answers
+// with it when it enables the framing for the tunnel. Peers of older versions keep one message per datagram
+static DATAGRAM_FRAMING_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-datagram-framing");
+static DATAGRAM_FRAMING_LENGTH_PREFIX: HeaderValue = HeaderValue::from_static("u16-length-prefix");
+
+/// Ask for the framing (client), or confirm that it is used (server)
+pub fn set_datagram_framing(headers: &mut HeaderMap) {
+    headers.insert(DATAGRAM_FRAMING_HEADER.clone(), DATAGRAM_FRAMING_LENGTH_PREFIX.clone());
+}
+
+pub fn has_datagram_framing(headers: &HeaderMap) -> bool {
+    headers.get(&DATAGRAM_FRAMING_HEADER) == Some(&DATAGRAM_FRAMING_LENGTH_PREFIX)
+}
+
+/// Prefix every datagram with its length as an u16 big endian, for the boundaries to survive transports
+/// that split or merge messages (i.e: http2 data frames are at most 16Kb by default).
+/// Data is forwarded as is when the framing is not enabled.
+pub struct DatagramTunnelWrite<W> {
+    inner: W,
+    length_prefixed: bool,
+}
+
+impl<W: TunnelWrite> DatagramTunnelWrite<W> {
+    pub fn new(mut inner: W, length_prefixed: bool) -> Self {
+        // Reserve room for the length of the next datagram, to not have to move it once it is read
+        if length_prefixed {
+            inner.buf_mut().extend_from_slice(&[0; LENGTH_PREFIX_SIZE]);
+        }
+
+        Self { inner, length_prefixed }
+    }
+}
+
+impl<W: TunnelWrite> TunnelWrite for DatagramTunnelWrite<W> {
+    fn buf_mut(&mut self) -> &mut BytesMut {
+        self.inner.buf_mut()
+    }
+
+    async fn write(&mut self) -> Result<(), io::Error> {
+        if !self.length_prefixed {
+            return self.inner.write().await;
+        }
+
+        let buf = self.inner.buf_mut();
+        let len = buf.len() - LENGTH_PREFIX_SIZE;
+        if len > MAX_DATAGRAM_SIZE {
+            // As the network would do, drop it instead of killing the whole tunnel
+            warn!("dropping datagram of {} bytes, above the maximum of {}", len, MAX_DATAGRAM_SIZE);
+            buf.truncate(LENGTH_PREFIX_SIZE);
+            return Ok(());
+        }
+
+        buf[..LENGTH_PREFIX_SIZE].copy_from_slice(&(len as u16).to_be_bytes());
+        self.inner.write().await?;
+        self.inner.buf_mut().extend_from_slice(&[0; LENGTH_PREFIX_SIZE]);
+        Ok(())
+    }
+
+    async fn ping(&mut self) -> Result<(), io::Error> {
+        self.inner.ping().await
+    }
+
+    async fn close(&mut self, reason: &CloseReason) -> Result<(), io::Error> {
+        self.inner.close(reason).await
+    }
+}
+
+/// Reassemble the datagrams written by DatagramTunnelWrite, whatever the way the transport chunked them
+pub struct DatagramTunnelRead<R> {
+    inner: R,
+    length_prefixed: bool,
+    pending: Vec<u8>,
+}
+
+impl<R: TunnelRead> DatagramTunnelRead<R> {
+    pub const fn new(inner: R, length_prefixed: bool) -> Self {
+        Self {
+            inner,
+            length_prefixed,
+            pending: Vec::new(),
+        }
+    }
+
+    // Length of the first datagram if it has been entirely received
+    fn next_datagram_len(&self) -> Result<Option<usize>, io::Error> {
+        let [hi, lo, ref datagram @ ..] = self.pending[..] else {
+            return Ok(None);
+        };
+
+        let len = u16::from_be_bytes([hi, lo]) as usize;
+        if len > MAX_DATAGRAM_SIZE {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("received datagram of {} bytes, above the maximum of {}", len, MAX_DATAGRAM_SIZE),
+            ));
+        }
+
+        Ok((datagram.len() >= len).then_some(len))
+    }
+}
+
+impl<R: TunnelRead> TunnelRead for DatagramTunnelRead<R> {
+    async fn copy(&mut self, mut writer: impl AsyncWrite + Unpin + Send) -> Result<usize, io::Error> {
+        if !self.length_prefixed {
+            return self.inner.copy(writer).await;
+        }
+
+        loop {
+            if let Some(len) = self.next_datagram_len()? {
+                let datagram = &self.pending[LENGTH_PREFIX_SIZE..LENGTH_PREFIX_SIZE + len];
+                if let Err(err) = writer.write_all(datagram).await {
+                    return Err(io::Error::new(ErrorKind::ConnectionAborted, err));
+                }
+                self.pending.drain(..LENGTH_PREFIX_SIZE + len);
+                return Ok(len);
+            }
+
+            self.inner.copy(&mut self.pending).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BufMut;
+    use std::collections::VecDeque;
+
+    struct VecTunnelWrite {
+        buf: BytesMut,
+        written: Vec<u8>,
+    }
+
+    impl TunnelWrite for VecTunnelWrite {
+        fn buf_mut(&mut self) -> &mut BytesMut {
+            &mut self.buf
+        }
+
+        async fn write(&mut self) -> Result<(), io::Error> {
+            self.written.extend_from_slice(&self.buf.split());
+            Ok(())
+        }
+
+        async fn ping(&mut self) -> Result<(), io::Error> {
+            Ok(())
+        }
+
+        async fn close(&mut self, _reason: &CloseReason) -> Result<(), io::Error> {
+            Ok(())
+        }
+    }
+
+    struct ChunksTunnelRead {
+        chunks: VecDeque<Vec<u8>>,
+    }
+
+    impl TunnelRead for ChunksTunnelRead {
+        async fn copy(&mut self, mut writer: impl AsyncWrite + Unpin + Send) -> Result<usize, io::Error> {
+            let chunk = self
+                .chunks
+                .pop_front()
+                .ok_or_else(|| io::Error::from(ErrorKind::BrokenPipe))?;
+            writer.write_all(&chunk).await?;
+            Ok(chunk.len())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_datagram_framing_round_trip() {
+        let sizes = [1, MAX_DATAGRAM_SIZE, 512, 16 * 1024 + 1, 3, MAX_DATAGRAM_SIZE, 1400];
+        let datagrams: Vec<Vec<u8>> = sizes
+            .iter()
+            .enumerate()
+            .map(|(ix, size)| (0..*size).map(|i| (i + ix) as u8).collect())
+            .collect();
+
+        let inner = VecTunnelWrite {
+            buf: BytesMut::with_capacity(MAX_DATAGRAM_SIZE + LENGTH_PREFIX_SIZE),
+            written: vec![],
+        };
+        let mut tx = DatagramTunnelWrite::new(inner, true);
+        for datagram in &datagrams {
+            tx.buf_mut().put_slice(datagram);
+            tx.write().await.unwrap();
+        }
+        tx.buf_mut().put_slice(&vec![0; MAX_DATAGRAM_SIZE + 1]);
+        tx.write().await.unwrap();
+
+        // Chunk the stream with a size unrelated to the datagrams, as a transport could
+        let written = tx.inner.written;
+        assert_eq!(written.len(), sizes.iter().sum::<usize>() + sizes.len() * LENGTH_PREFIX_SIZE);
+        let chunks = written.chunks(16 * 1024).map(|c| c.to_vec()).collect();
+        let mut rx = DatagramTunnelRead::new(ChunksTunnelRead { chunks }, true);
+        for datagram in &datagrams {
+            let mut received = vec![];
+            assert_eq!(rx.copy(&mut received).await.unwrap(), datagram.len());
+            assert_eq!(&received, datagram);
+        }
+        assert!(rx.copy(&mut vec![]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_datagram_framing_rejects_oversized_length() {
+        let chunks = VecDeque::from([vec![0xff, 0xff, 0x00]]);
+        let mut rx = DatagramTunnelRead::new(ChunksTunnelRead { chunks }, true);
+        let err = rx.copy(&mut vec![]).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -1,18 +1,30 @@
 use crate::tunnel::client::WsClient;
-use crate::tunnel::transport::{headers_from_file, TunnelRead, TunnelWrite, MAX_PACKET_LENGTH};
-use crate::tunnel::{tunnel_to_jwt_token, RemoteAddr, TransportScheme};
+use crate::tunnel::error::connect_error;
+use crate::tunnel::metrics::ConnectTiming;
+use crate::tunnel::transport::connection_info::ConnectionInfo;
+use crate::tunnel::transport::{
+    copy_buffer_size, datagram, headers_from_file, mux, order_http_headers, parse_retry_after, set_http_headers,
+    CloseReason, TunnelRead, TunnelWrite, MAX_PACKET_LENGTH,
+};
+use crate::tunnel::{tunnel_to_jwt_token, RemoteAddr, TransportScheme, TunnelConnectError};
 use anyhow::{anyhow, Context};
 use bytes::{Bytes, BytesMut};
+use http_body_util::combinators::UnsyncBoxBody;
 use http_body_util::{BodyExt, BodyStream, StreamBody};
 use hyper::body::{Frame, Incoming};
-use hyper::header::{AUTHORIZATION, CONTENT_TYPE, COOKIE};
+use hyper::client::conn::http2::SendRequest;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, COOKIE, USER_AGENT};
 use hyper::http::response::Parts;
 use hyper::Request;
 use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
 use log::{debug, error, warn};
+use parking_lot::Mutex;
 use std::io;
 use std::io::ErrorKind;
 use std::ops::DerefMut;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
@@ -21,22 +33,32 @@ use uuid::Uuid;
 
 pub struct Http2TunnelRead {
     inner: BodyStream<Incoming>,
+    _stream_slot: Option<StreamSlot>,
 }
 
 impl Http2TunnelRead {
     pub const fn new(inner: BodyStream<Incoming>) -> Self {
-        Self { inner }
+        Self {
+            inner,
+            _stream_slot: None,
+        }
+    }
+
+    /// Keep the slot of the stream on its shared connection taken for as long as the tunnel lives
+    pub fn with_stream_slot(mut self, stream_slot: Option<StreamSlot>) -> Self {
+        self._stream_slot = stream_slot;
+        self
     }
 }
 
 impl TunnelRead for Http2TunnelRead {
-    async fn copy(&mut self, mut writer: impl AsyncWrite + Unpin + Send) -> Result<(), io::Error> {
+    async fn copy(&mut self, mut writer: impl AsyncWrite + Unpin + Send) -> Result<usize, io::Error> {
         loop {
             match self.inner.next().await {
                 Some(Ok(frame)) => match frame.into_data() {
                     Ok(data) => {
                         return match writer.write_all(data.as_ref()).await {
-                            Ok(_) => Ok(()),
+                            Ok(_) => Ok(data.len()),
                             Err(err) => Err(io::Error::new(ErrorKind::ConnectionAborted, err)),
                         }
                     }
@@ -57,13 +79,15 @@ impl TunnelRead for Http2TunnelRead {
 pub struct Http2TunnelWrite {
     inner: mpsc::Sender<Bytes>,
     buf: BytesMut,
+    min_capacity: usize,
 }
 
 impl Http2TunnelWrite {
-    pub fn new(inner: mpsc::Sender<Bytes>) -> Self {
+    pub fn new(inner: mpsc::Sender<Bytes>, buffer_size: usize) -> Self {
         Self {
             inner,
-            buf: BytesMut::with_capacity(MAX_PACKET_LENGTH * 20), // ~ 1Mb
+            buf: BytesMut::with_capacity(buffer_size),
+            min_capacity: buffer_size.min(MAX_PACKET_LENGTH),
         }
     }
 }
@@ -80,9 +104,9 @@ impl TunnelWrite for Http2TunnelWrite {
             Err(err) => Err(io::Error::new(ErrorKind::ConnectionAborted, err)),
         };
 
-        if self.buf.capacity() < MAX_PACKET_LENGTH {
+        if self.buf.capacity() < self.min_capacity {
             //info!("read {} Kb {} Kb", self.buf.capacity() / 1024, old_capa / 1024);
-            self.buf.reserve(MAX_PACKET_LENGTH)
+            self.buf.reserve(self.min_capacity)
         }
 
         ret
@@ -92,20 +116,235 @@ impl TunnelWrite for Http2TunnelWrite {
         Ok(())
     }
 
-    async fn close(&mut self) -> Result<(), io::Error> {
+    async fn close(&mut self, _reason: &CloseReason) -> Result<(), io::Error> {
         Ok(())
     }
 }
 
+type Http2Body = UnsyncBoxBody<Bytes, anyhow::Error>;
+
+// Data is only sent once the buffer is flushed, so it must hold enough to keep the stream window busy
+pub const HTTP2_COPY_BUFFER_SIZE: usize = 20 * 64 * 1024; // ~ 1Mb
+
+// Smallest and biggest flow control windows allowed by http2 (RFC 9113 section 6.9.1), the smallest one being its default
+pub const MIN_WINDOW_SIZE: u32 = 65_535;
+pub const MAX_WINDOW_SIZE: u32 = (1 << 31) - 1;
+
+/// Flow control of the http2 connections carrying multiplexed tunnels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Http2MultiplexConfig {
+    // hyper does not expose the SETTINGS_MAX_CONCURRENT_STREAMS of the server, and queues the streams above it
+    // instead of failing them. So the client opens a new connection past this number, and the server announces it
+    pub max_streams: usize,
+    // A tunnel whose local end does not read holds up to a stream window of the connection window.
+    // The connection window is large enough for all of them, so a stalled tunnel never starves the others
+    pub stream_window: u32,
+}
+
+impl Default for Http2MultiplexConfig {
+    fn default() -> Self {
+        Self {
+            // The minimum the RFC recommends for servers
+            max_streams: 100,
+            stream_window: 2 * 1024 * 1024,
+        }
+    }
+}
+
+impl Http2MultiplexConfig {
+    pub fn max_concurrent_streams(&self) -> u32 {
+        u32::try_from(self.max_streams).unwrap_or(u32::MAX)
+    }
+
+    pub fn connection_window(&self) -> u32 {
+        self.stream_window
+            .saturating_mul(self.max_concurrent_streams())
+            .min(MAX_WINDOW_SIZE)
+    }
+}
+
+/// Http2 connections to the servers shared by the tunnels, when http2_multiplex is enabled
+#[derive(Default)]
+pub struct Http2Multiplexer {
+    connections: Mutex<Vec<SharedConnection>>,
+}
+
+struct SharedConnection {
+    request_sender: SendRequest<Http2Body>,
+    server_ix: usize,
+    connection_info: ConnectionInfo,
+    nb_streams: Arc<AtomicUsize>,
+}
+
+/// Slot of a stream on a shared connection, released when the tunnel is dropped
+pub struct StreamSlot(Arc<AtomicUsize>);
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Http2Multiplexer {
+    fn acquire(&self, max_streams: usize) -> Option<(SendRequest<Http2Body>, usize, ConnectionInfo, StreamSlot)> {
+        let mut connections = self.connections.lock();
+        connections.retain(|cnx| !cnx.request_sender.is_closed());
+        let cnx = connections
+            .iter()
+            .find(|cnx| cnx.nb_streams.load(Ordering::Relaxed) < max_streams)?;
+
+        cnx.nb_streams.fetch_add(1, Ordering::Relaxed);
+        Some((
+            cnx.request_sender.clone(),
+            cnx.server_ix,
+            cnx.connection_info.clone(),
+            StreamSlot(cnx.nb_streams.clone()),
+        ))
+    }
+
+    fn register(
+        &self,
+        request_sender: SendRequest<Http2Body>,
+        server_ix: usize,
+        connection_info: ConnectionInfo,
+    ) -> StreamSlot {
+        let nb_streams = Arc::new(AtomicUsize::new(1));
+        self.connections.lock().push(SharedConnection {
+            request_sender,
+            server_ix,
+            connection_info,
+            nb_streams: nb_streams.clone(),
+        });
+
+        StreamSlot(nb_streams)
+    }
+}
+
+// The connection is reset or closed while starting http2, or what answers does not speak it.
+// Likely a middlebox only letting http/1.1 through, as opposed to the server refusing the tunnel
+fn is_http2_blocked(err: &hyper::Error) -> bool {
+    let is_blocked_io = |err: &io::Error| {
+        matches!(
+            err.kind(),
+            ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::BrokenPipe
+                | ErrorKind::UnexpectedEof
+        )
+    };
+    if err.is_closed() || err.is_incomplete_message() {
+        return true;
+    }
+
+    let mut source = std::error::Error::source(err);
+    while let Some(cause) = source {
+        if let Some(err) = cause.downcast_ref::<io::Error>() {
+            return is_blocked_io(err);
+        }
+        if let Some(err) = cause.downcast_ref::<h2::Error>() {
+            if let Some(err) = err.get_io() {
+                return is_blocked_io(err);
+            }
+            return matches!(
+                err.reason(),
+                Some(h2::Reason::PROTOCOL_ERROR | h2::Reason::FRAME_SIZE_ERROR | h2::Reason::HTTP_1_1_REQUIRED)
+            );
+        }
+        source = cause.source();
+    }
+
+    false
+}
+
+fn http2_error(client: &WsClient, server_ix: usize, blocked: bool, cause: anyhow::Error) -> TunnelConnectError {
+    if !blocked {
+        return TunnelConnectError::HttpUpgrade {
+            status: None,
+            retry_after: None,
+            cause,
+        };
+    }
+
+    if client.config.transport_fallback {
+        client.servers.mark_http2_unavailable(server_ix);
+    }
+    TunnelConnectError::Http2Unavailable(cause)
+}
+
+async fn handshake(
+    client: &WsClient,
+) -> Result<(SendRequest<Http2Body>, usize, ConnectionInfo, ConnectTiming), TunnelConnectError> {
+    let mut pooled_cnx = client.get_server_connection().await?;
+    let (transport, server_ix, mut timing) = pooled_cnx.deref_mut().take().unwrap();
+    let server = client.servers.get(server_ix);
+    let connection_info = ConnectionInfo::new(server, &transport);
+    // Without h2, the server answers with http/1.1 and the http2 handshake can only fail
+    if client.config.transport_fallback && server.tls().is_some() && connection_info.alpn.as_deref() != Some("h2") {
+        client.servers.mark_http2_unavailable(server_ix);
+        return Err(TunnelConnectError::Http2Unavailable(anyhow!(
+            "server {:?} negotiated ALPN protocol {:?} instead of h2",
+            server,
+            connection_info.alpn
+        )));
+    }
+
+    let mut builder = hyper::client::conn::http2::Builder::new(TokioExecutor::new());
+    builder
+        .timer(TokioTimer::new())
+        .keep_alive_interval(client.config.websocket_ping_frequency)
+        .keep_alive_while_idle(false);
+    if let Some(multiplex) = &client.config.http2_multiplex {
+        builder
+            .initial_stream_window_size(multiplex.stream_window)
+            .initial_connection_window_size(multiplex.connection_window());
+    } else {
+        builder.adaptive_window(true);
+    }
+
+    let handshake_started_at = Instant::now();
+    let (request_sender, cnx) = builder.handshake(TokioIo::new(transport)).await.map_err(|err| {
+        let blocked = is_http2_blocked(&err);
+        let cause = anyhow!(err).context(format!("failed to do http2 handshake with the server {:?}", server));
+        http2_error(client, server_ix, blocked, cause)
+    })?;
+    timing.upgrade = Some(handshake_started_at.elapsed());
+    tokio::spawn(async move {
+        if let Err(err) = cnx.await {
+            error!("{:?}", err)
+        }
+    });
+
+    Ok((request_sender, server_ix, connection_info, timing))
+}
+
 pub async fn connect(
     request_id: Uuid,
     client: &WsClient,
     dest_addr: &RemoteAddr,
-) -> anyhow::Result<(Http2TunnelRead, Http2TunnelWrite, Parts)> {
-    let mut pooled_cnx = match client.cnx_pool.get().await {
-        Ok(cnx) => Ok(cnx),
-        Err(err) => Err(anyhow!("failed to get a connection to the server from the pool: {err:?}")),
-    }?;
+) -> Result<(Http2TunnelRead, Http2TunnelWrite, Parts), TunnelConnectError> {
+    // Open a new stream on a shared connection if any has room left, otherwise a new connection
+    let (mut request_sender, server_ix, connection_info, stream_slot, timing) = match client
+        .config
+        .http2_multiplex
+        .and_then(|multiplex| client.http2_connections.acquire(multiplex.max_streams))
+    {
+        Some((request_sender, server_ix, connection_info, stream_slot)) => {
+            (request_sender, server_ix, connection_info, Some(stream_slot), None)
+        }
+        None => {
+            let (request_sender, server_ix, connection_info, timing) = handshake(client).await?;
+            let stream_slot = client.config.http2_multiplex.map(|_| {
+                client
+                    .http2_connections
+                    .register(request_sender.clone(), server_ix, connection_info.clone())
+            });
+            (request_sender, server_ix, connection_info, stream_slot, Some(timing))
+        }
+    };
+    let server = client.servers.get(server_ix);
+
+    let jwt = tunnel_to_jwt_token(request_id, dest_addr, client.config.tunnel_metadata.as_deref())
+        .map_err(TunnelConnectError::JwtRejected)?;
 
     // In http2 HOST header does not exist, it is explicitly set in the authority from the request uri
     let (headers_file, authority) =
@@ -116,7 +355,7 @@ pub async fn connect(
             .map_or((None, None), |headers_file_path| {
                 let (host, headers) = headers_from_file(headers_file_path);
                 let host = if let Some((_, v)) = host {
-                    match (client.config.remote_addr.scheme(), client.config.remote_addr.port()) {
+                    match (server.scheme(), server.port()) {
                         (TransportScheme::Http, 80) | (TransportScheme::Https, 443) => {
                             Some(v.to_str().unwrap_or("").to_string())
                         }
@@ -133,21 +372,28 @@ pub async fn connect(
         .method("POST")
         .uri(format!(
             "{}://{}/{}/events",
-            client.config.remote_addr.scheme(),
-            authority
-                .as_deref()
-                .unwrap_or_else(|| client.config.http_header_host.to_str().unwrap_or("")),
+            server.scheme(),
+            authority.unwrap_or_else(|| client
+                .config
+                .http_header_host_for(server)
+                .to_str()
+                .unwrap_or("")
+                .to_string()),
             &client.config.http_upgrade_path_prefix
         ))
-        .header(COOKIE, tunnel_to_jwt_token(request_id, dest_addr))
+        .header(COOKIE, jwt)
         .header(CONTENT_TYPE, "application/json")
         .version(hyper::Version::HTTP_2);
 
     let headers = req.headers_mut().unwrap();
-    for (k, v) in &client.config.http_headers {
-        let _ = headers.remove(k);
-        headers.append(k, v.clone());
+    datagram::set_datagram_framing(headers);
+    if client.config.multiplexes_reverse_tunnel(dest_addr) {
+        mux::set_reverse_multiplex(headers);
+    }
+    if let Some(user_agent) = &client.config.user_agent {
+        headers.insert(USER_AGENT, user_agent.clone());
     }
+    set_http_headers(headers, &client.config.http_headers);
 
     if let Some(auth) = &client.config.http_upgrade_credentials {
         let _ = headers.remove(AUTHORIZATION);
@@ -155,49 +401,115 @@ pub async fn connect(
     }
 
     if let Some(headers_file) = headers_file {
-        for (k, v) in headers_file {
-            let _ = headers.remove(&k);
-            headers.append(k, v);
-        }
+        set_http_headers(headers, &headers_file);
     }
+    order_http_headers(headers, &client.config.http_headers_order);
 
     let (tx, rx) = mpsc::channel::<Bytes>(1024);
     let body = StreamBody::new(ReceiverStream::new(rx).map(|s| -> anyhow::Result<Frame<Bytes>> { Ok(Frame::data(s)) }));
-    let req = req.body(body).with_context(|| {
-        format!(
-            "failed to build HTTP request to contact the server {:?}",
-            client.config.remote_addr
-        )
-    })?;
+    let upgrade_error = |cause| TunnelConnectError::HttpUpgrade {
+        status: None,
+        retry_after: None,
+        cause,
+    };
+    let req = req
+        .body(body.boxed_unsync())
+        .with_context(|| format!("failed to build HTTP request to contact the server {:?}", server))
+        .map_err(upgrade_error)?;
     debug!("with HTTP upgrade request {:?}", req);
-    let transport = pooled_cnx.deref_mut().take().unwrap();
-    let (mut request_sender, cnx) = hyper::client::conn::http2::Builder::new(TokioExecutor::new())
-        .timer(TokioTimer::new())
-        .adaptive_window(true)
-        .keep_alive_interval(client.config.websocket_ping_frequency)
-        .keep_alive_while_idle(false)
-        .handshake(TokioIo::new(transport))
-        .await
-        .with_context(|| format!("failed to do http2 handshake with the server {:?}", client.config.remote_addr))?;
-    tokio::spawn(async move {
-        if let Err(err) = cnx.await {
-            error!("{:?}", err)
-        }
-    });
 
-    let response = request_sender
-        .send_request(req)
-        .await
-        .with_context(|| format!("failed to send http2 request with the server {:?}", client.config.remote_addr))?;
+    // On a new connection, the first request is what tells whether the server and everything in between speak http2
+    let is_new_connection = timing.is_some();
+    let request_started_at = Instant::now();
+    let response = request_sender.send_request(req).await.map_err(|err| {
+        let blocked = is_new_connection && is_http2_blocked(&err);
+        let cause = anyhow!(err).context(format!("failed to send http2 request with the server {:?}", server));
+        http2_error(client, server_ix, blocked, cause)
+    })?;
 
     if !response.status().is_success() {
-        return Err(anyhow!(
-            "Http2 server rejected the connection: {:?}: {:?}",
-            response.status(),
-            String::from_utf8(response.into_body().collect().await?.to_bytes().to_vec()).unwrap_or_default()
-        ));
+        let status = response.status();
+        let retry_after = parse_retry_after(response.headers(), SystemTime::now());
+        let destination_error = connect_error(response.headers());
+        let body = match response.into_body().collect().await {
+            Ok(body) => String::from_utf8(body.to_bytes().to_vec()).unwrap_or_default(),
+            Err(_) => String::new(),
+        };
+        let cause = anyhow!("Http2 server rejected the connection: {:?}: {:?}", status, body);
+        if let Some(kind) = destination_error {
+            return Err(TunnelConnectError::Destination { kind, cause });
+        }
+        return Err(TunnelConnectError::HttpUpgrade {
+            status: Some(status),
+            retry_after,
+            cause,
+        });
     }
 
-    let (parts, body) = response.into_parts();
-    Ok((Http2TunnelRead::new(BodyStream::new(body)), Http2TunnelWrite::new(tx), parts))
+    // A stream on a shared connection only waits for its request
+    let mut timing = timing.unwrap_or_default();
+    timing.upgrade = Some(timing.upgrade.unwrap_or_default() + request_started_at.elapsed());
+    debug!("Connected to the server in {}", timing);
+    client.config.metrics.on_connect(&timing);
+
+    let (mut parts, body) = response.into_parts();
+    parts.extensions.insert(connection_info);
+    Ok((
+        Http2TunnelRead::new(BodyStream::new(body)).with_stream_slot(stream_slot),
+        Http2TunnelWrite::new(
+            tx,
+            copy_buffer_size(
+                client.config.copy_buffer_size.unwrap_or(HTTP2_COPY_BUFFER_SIZE),
+                &dest_addr.protocol,
+            ),
+        ),
+        parts,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multiplex_connection_window() {
+        assert_eq!(Http2MultiplexConfig::default().connection_window(), 200 * 1024 * 1024);
+
+        // The window of the connection is capped by the protocol, instead of overflowing
+        let config = Http2MultiplexConfig {
+            max_streams: 10_000,
+            stream_window: MAX_WINDOW_SIZE,
+        };
+        assert_eq!(config.connection_window(), MAX_WINDOW_SIZE);
+    }
+
+    #[tokio::test]
+    async fn test_multiplexer_spills_past_max_streams() {
+        let (io, _server_io) = tokio::io::duplex(64 * 1024);
+        let (request_sender, cnx) = hyper::client::conn::http2::Builder::new(TokioExecutor::new())
+            .handshake::<_, Http2Body>(TokioIo::new(io))
+            .await
+            .unwrap();
+        tokio::spawn(cnx);
+        let connection_info = ConnectionInfo {
+            scheme: TransportScheme::Http,
+            server: "localhost:8080".to_string(),
+            alpn: None,
+            tls_version: None,
+            cipher_suite: None,
+            peer_certificate_subject: None,
+        };
+
+        let multiplexer = Http2Multiplexer::default();
+        let first = multiplexer.register(request_sender, 0, connection_info);
+        let (_, _, _, second) = multiplexer.acquire(2).unwrap();
+        assert!(multiplexer.acquire(2).is_none());
+
+        // A tunnel closing frees its stream for the next one
+        drop(first);
+        let third = multiplexer.acquire(2);
+        assert!(third.is_some());
+        assert!(multiplexer.acquire(2).is_none());
+        drop(second);
+    }
 }
@@ -1,100 +1,937 @@
-use crate::tunnel::transport::{TunnelRead, TunnelWrite};
+use crate::tunnel::clock::Clock;
+use crate::tunnel::metrics::{Direction, NoopTunnelMetrics, TunnelMetrics};
+use crate::tunnel::transport::{CloseReason, TunnelRead, TunnelWrite, MIN_COPY_BUFFER_SIZE};
 use bytes::BufMut;
 use futures_util::{pin_mut, FutureExt};
+use parking_lot::Mutex;
+use std::fmt::{Display, Formatter};
+use std::future::pending;
+use std::io;
+use std::io::ErrorKind;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::Duration;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::select;
 use tokio::sync::oneshot;
 use tokio::time::Instant;
 use tracing::log::debug;
 use tracing::{error, info, warn};
 
+/// Track the last time bytes were transferred in either direction of a tunnel.
+/// Clones share the same state, so give one to each propagate function of the tunnel.
+#[derive(Clone)]
+pub struct IdleTimeout {
+    timeout: Duration,
+    clock: Arc<dyn Clock>,
+    start: Instant,
+    last_activity_ms: Arc<AtomicU64>,
+}
+
+impl IdleTimeout {
+    pub fn new(timeout: Duration, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            timeout,
+            start: clock.now(),
+            clock,
+            last_activity_ms: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.clock.now().saturating_duration_since(self.start)
+    }
+
+    fn touch(&self) {
+        let elapsed = self.elapsed().as_millis() as u64;
+        self.last_activity_ms.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        let last_activity = Duration::from_millis(self.last_activity_ms.load(Ordering::Relaxed));
+        self.elapsed().saturating_sub(last_activity)
+    }
+}
+
+// Resolve only once no bytes have been transferred for the whole timeout, never if there is no timeout.
+// A single future can be kept for the lifetime of the tunnel, as it re-checks the last activity when it wakes up
+async fn wait_idle(idle_timeout: Option<IdleTimeout>) {
+    let Some(idle_timeout) = idle_timeout else {
+        return pending().await;
+    };
+
+    loop {
+        let idle_for = idle_timeout.idle_for();
+        if idle_for >= idle_timeout.timeout {
+            return;
+        }
+        idle_timeout.clock.sleep(idle_timeout.timeout - idle_for).await;
+    }
+}
+
+/// Caps of the total bytes transferred by each direction of a tunnel, unlimited if None.
+/// Clones share the same state, so give one to each propagate function of the tunnel.
+#[derive(Clone, Default)]
+pub struct ByteLimits {
+    pub local_to_remote: Option<u64>,
+    pub remote_to_local: Option<u64>,
+    // Shared with other tunnels, counting the bytes of both directions
+    budget: Option<Arc<ByteBudget>>,
+    // Only the local => remote direction can send the close frame, the other one leaves it the reason to send
+    exceeded: Arc<Mutex<Option<CloseReason>>>,
+}
+
+impl ByteLimits {
+    pub fn new(local_to_remote: Option<u64>, remote_to_local: Option<u64>) -> Self {
+        Self {
+            local_to_remote,
+            remote_to_local,
+            budget: None,
+            exceeded: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn with_budget(mut self, budget: Option<Arc<ByteBudget>>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    // Take the bytes out of the shared budget, and return the close reason of the tunnel if there was not enough
+    fn consume_budget(&self, nb_bytes: usize) -> Option<CloseReason> {
+        let budget = self.budget.as_ref()?;
+        if budget.consume(nb_bytes as u64) {
+            return None;
+        }
+
+        warn!("closing tunnel, its budget of {} bytes is exhausted", budget.max_bytes);
+        let reason = CloseReason::new(CloseReason::POLICY_VIOLATION, "bytes budget exhausted");
+        *self.exceeded.lock() = Some(reason.clone());
+        Some(reason)
+    }
+
+    // Return the close reason of the tunnel if the bytes went over the cap of the direction
+    fn check(&self, cap: Option<u64>, nb_bytes: u64, direction: &str) -> Option<CloseReason> {
+        let cap = cap?;
+        if nb_bytes <= cap {
+            return None;
+        }
+
+        warn!("closing tunnel, more than {} bytes transferred {}", cap, direction);
+        let reason = CloseReason::new(CloseReason::POLICY_VIOLATION, format!("max bytes {} exceeded", direction));
+        *self.exceeded.lock() = Some(reason.clone());
+        Some(reason)
+    }
+}
+
+// Local side of the remote => local direction, refusing the bytes that would go over the caps of the tunnel.
+// The tunnel messages are written to it as they are read, so the caps must be checked before they reach it
+struct CappedWrite<'a, W> {
+    inner: W,
+    byte_limits: &'a ByteLimits,
+    // Bytes transferred so far by the direction
+    nb_bytes: u64,
+    exceeded: Option<CloseReason>,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CappedWrite<'_, W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let nb_bytes = buf.len();
+        let exceeded = this
+            .byte_limits
+            .check(
+                this.byte_limits.remote_to_local,
+                this.nb_bytes + nb_bytes as u64,
+                "local <= remote",
+            )
+            .or_else(|| this.byte_limits.consume_budget(nb_bytes));
+        if let Some(reason) = exceeded {
+            this.exceeded = Some(reason);
+            return Poll::Ready(Err(io::Error::other("max bytes exceeded")));
+        }
+
+        let ret = Pin::new(&mut this.inner).poll_write(cx, buf);
+        let written = match &ret {
+            Poll::Ready(Ok(written)) => *written,
+            _ => 0,
+        };
+        this.nb_bytes += written as u64;
+        if let Some(budget) = &this.byte_limits.budget {
+            budget.release((nb_bytes - written) as u64);
+        }
+        ret
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Total bytes several tunnels can transfer together, in both directions
+pub struct ByteBudget {
+    max_bytes: u64,
+    used: AtomicU64,
+}
+
+impl ByteBudget {
+    pub fn new(max_bytes: u64) -> Self {
+        Self {
+            max_bytes,
+            used: AtomicU64::new(0),
+        }
+    }
+
+    /// Count the bytes as used. Returns false, without counting them, if they go over the budget
+    pub fn consume(&self, nb_bytes: u64) -> bool {
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(nb_bytes).filter(|used| *used <= self.max_bytes)
+            })
+            .is_ok()
+    }
+
+    // Give back bytes counted as used but that have not been transferred
+    fn release(&self, nb_bytes: u64) {
+        self.used.fetch_sub(nb_bytes, Ordering::Relaxed);
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.used.load(Ordering::Relaxed) >= self.max_bytes
+    }
+}
+
+// Bytes allowed to go at once, as a duration at the limited rate
+const RATE_LIMIT_BURST: Duration = Duration::from_millis(100);
+
+/// Token bucket pacing a flow of bytes to bytes_per_sec. Only 100ms worth of bytes can go in a burst,
+/// so the copy loops are slowed down regularly instead of bursting at full speed and then stalling for a long time.
+/// It can be shared by several tunnels: each chunk reserves its own slot of time, in the order they arrive.
+/// A tunnel waits for its slot before reading its next chunk, so it never holds more than one slot in advance,
+/// and a long transfer cannot starve the tunnels coming after it
+pub struct RateLimit {
+    bytes_per_sec: u64,
+    clock: Arc<dyn Clock>,
+    // Theoretical time at which all the bytes reserved so far are sent at the limited rate
+    reserved_until: Mutex<Instant>,
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_sec: u64, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec.max(1),
+            reserved_until: Mutex::new(clock.now()),
+            clock,
+        }
+    }
+
+    /// Biggest chunk to read at once, to not reserve a long slot with a single read
+    fn max_chunk_size(&self) -> usize {
+        ((self.bytes_per_sec as f64 * RATE_LIMIT_BURST.as_secs_f64()) as usize).max(MIN_COPY_BUFFER_SIZE)
+    }
+
+    /// Reserve a slot for nb_bytes, and wait for it if it is beyond the allowed burst
+    async fn consume(&self, nb_bytes: usize) {
+        let wait_until = {
+            let mut reserved_until = self.reserved_until.lock();
+            let now = self.clock.now();
+            *reserved_until =
+                (*reserved_until).max(now) + Duration::from_secs_f64(nb_bytes as f64 / self.bytes_per_sec as f64);
+            *reserved_until - RATE_LIMIT_BURST
+        };
+
+        self.clock.sleep_until(wait_until).await;
+    }
+}
+
+/// Bandwidth limits applied to one direction of a tunnel
+#[derive(Clone, Default)]
+pub struct BandwidthLimit {
+    /// Limit of this tunnel alone
+    pub per_tunnel: Option<u64>,
+    /// Limit shared with the other tunnels
+    pub global: Option<Arc<RateLimit>>,
+}
+
+impl BandwidthLimit {
+    fn into_rate_limits(self, clock: &Arc<dyn Clock>) -> Vec<Arc<RateLimit>> {
+        let per_tunnel = self
+            .per_tunnel
+            .map(|bytes_per_sec| Arc::new(RateLimit::new(bytes_per_sec, clock.clone())));
+        per_tunnel.into_iter().chain(self.global).collect()
+    }
+}
+
+async fn consume(rate_limits: &[Arc<RateLimit>], nb_bytes: usize) {
+    for rate_limit in rate_limits {
+        rate_limit.consume(nb_bytes).await;
+    }
+}
+
+/// Why one direction of a tunnel stopped
+#[derive(Debug, Default)]
+pub enum DisconnectReason {
+    /// The local side ended its stream
+    LocalEof,
+    /// The remote end ended the tunnel without a close frame
+    RemoteEof,
+    LocalError(io::Error),
+    RemoteError(io::Error),
+    /// No data transferred for the idle timeout, or max duration of the tunnel reached
+    Timeout,
+    /// The remote end closed the tunnel with a close frame (websocket only)
+    CloseFrame(CloseReason),
+    /// The tunnel transferred more bytes than it is allowed to
+    LimitReached(CloseReason),
+    /// Stopped because the other direction of the tunnel ended, or its task did not finish
+    #[default]
+    Stopped,
+}
+
+impl DisconnectReason {
+    pub const fn is_failure(&self) -> bool {
+        match self {
+            Self::LocalError(_) | Self::RemoteError(_) | Self::LimitReached(_) => true,
+            Self::CloseFrame(close_reason) => !close_reason.is_normal(),
+            Self::LocalEof | Self::RemoteEof | Self::Timeout | Self::Stopped => false,
+        }
+    }
+}
+
+impl Display for DisconnectReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::LocalEof => write!(f, "local end closed"),
+            Self::RemoteEof => write!(f, "remote end closed"),
+            Self::LocalError(err) => write!(f, "local error: {}", err),
+            Self::RemoteError(err) => write!(f, "remote error: {}", err),
+            Self::Timeout => write!(f, "timeout"),
+            Self::CloseFrame(close_reason) => write!(f, "closed by remote with {}", close_reason),
+            Self::LimitReached(close_reason) => write!(f, "{}", close_reason.reason),
+            Self::Stopped => write!(f, "stopped"),
+        }
+    }
+}
+
+/// What went through one direction of a tunnel. Returned even when it ended on an error, for the accounting
+#[derive(Debug, Default)]
+pub struct Propagated {
+    pub nb_bytes: u64,
+    /// Close reason sent to the remote end, or received from it
+    pub close_reason: Option<CloseReason>,
+    pub disconnect: DisconnectReason,
+}
+
+/// Summary of a tunnel once both of its directions are done, logged within the span of the tunnel.
+/// A failure of either direction is a warning, as it is why the tunnel ended
+pub fn log_tunnel_closed(local_to_remote: &Propagated, remote_to_local: &Propagated, duration: Duration) {
+    let reasons = [&local_to_remote.disconnect, &remote_to_local.disconnect];
+    let reason = reasons
+        .iter()
+        .find(|reason| reason.is_failure())
+        .or_else(|| {
+            reasons
+                .iter()
+                .find(|reason| !matches!(reason, DisconnectReason::Stopped))
+        })
+        .unwrap_or(&reasons[0]);
+
+    if reason.is_failure() {
+        warn!(
+            bytes_sent = local_to_remote.nb_bytes,
+            bytes_received = remote_to_local.nb_bytes,
+            duration_ms = duration.as_millis() as u64,
+            reason = %reason,
+            "Tunnel closed"
+        );
+    } else {
+        info!(
+            bytes_sent = local_to_remote.nb_bytes,
+            bytes_received = remote_to_local.nb_bytes,
+            duration_ms = duration.as_millis() as u64,
+            reason = %reason,
+            "Tunnel closed"
+        );
+    }
+}
+
+/// What a propagate function checks and measures while forwarding one direction of a tunnel.
+/// The pings and the max duration are only handled by the local => remote direction, that stops the other one
+#[derive(Clone)]
+pub struct PropagateOptions {
+    pub ping_frequency: Option<Duration>,
+    /// Only ping when nothing has been written for the whole ping frequency
+    pub adaptive_ping: bool,
+    pub idle_timeout: Option<IdleTimeout>,
+    pub max_duration: Option<Duration>,
+    pub byte_limits: ByteLimits,
+    pub bandwidth_limit: BandwidthLimit,
+    pub metrics: Arc<dyn TunnelMetrics>,
+    pub clock: Arc<dyn Clock>,
+}
+
+impl PropagateOptions {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            ping_frequency: None,
+            adaptive_ping: false,
+            idle_timeout: None,
+            max_duration: None,
+            byte_limits: ByteLimits::default(),
+            bandwidth_limit: BandwidthLimit::default(),
+            metrics: Arc::new(NoopTunnelMetrics),
+            clock,
+        }
+    }
+
+    pub fn with_ping(mut self, ping_frequency: Option<Duration>, adaptive_ping: bool) -> Self {
+        self.ping_frequency = ping_frequency;
+        self.adaptive_ping = adaptive_ping;
+        self
+    }
+
+    pub fn with_idle_timeout(mut self, idle_timeout: Option<IdleTimeout>) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    pub fn with_max_duration(mut self, max_duration: Option<Duration>) -> Self {
+        self.max_duration = max_duration;
+        self
+    }
+
+    pub fn with_byte_limits(mut self, byte_limits: ByteLimits) -> Self {
+        self.byte_limits = byte_limits;
+        self
+    }
+
+    pub fn with_bandwidth_limit(mut self, bandwidth_limit: BandwidthLimit) -> Self {
+        self.bandwidth_limit = bandwidth_limit;
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<dyn TunnelMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+}
+
 pub async fn propagate_local_to_remote(
     local_rx: impl AsyncRead,
     mut ws_tx: impl TunnelWrite,
     mut close_tx: oneshot::Sender<()>,
-    ping_frequency: Option<Duration>,
-) -> anyhow::Result<()> {
+    options: PropagateOptions,
+) -> Propagated {
+    let PropagateOptions {
+        ping_frequency,
+        adaptive_ping,
+        idle_timeout,
+        max_duration,
+        byte_limits,
+        bandwidth_limit,
+        metrics,
+        clock,
+    } = options;
     let _guard = scopeguard::guard((), |_| {
         info!("Closing local => remote tunnel");
     });
 
-    static MAX_PACKET_LENGTH: usize = 64 * 1024;
-
-    // We do our own pin_mut! to avoid shadowing timeout and be able to reset it, on next loop iteration
-    // We reuse the future to avoid creating a timer in the tight loop
+    // The ping timer is only re-armed when it fires, not after every write, to not create a timer in the tight loop.
+    // With adaptive ping, it then sleeps again until the frequency has elapsed since the last write
     let frequency = ping_frequency.unwrap_or(Duration::from_secs(3600 * 24));
-    let start_at = Instant::now().checked_add(frequency).unwrap_or_else(Instant::now);
-    let timeout = tokio::time::interval_at(start_at, frequency);
+    let mut last_write = clock.now();
+    let mut ping_timer = clock.sleep(frequency).fuse();
     let should_close = close_tx.closed().fuse();
+    let is_idle = wait_idle(idle_timeout.clone()).fuse();
+    // Stopping this direction drops close_tx, which stops the other one too
+    let is_expired = match max_duration {
+        Some(max_duration) => clock.sleep(max_duration),
+        None => pending().boxed(),
+    }
+    .fuse();
 
-    pin_mut!(timeout);
     pin_mut!(should_close);
+    pin_mut!(is_idle);
+    pin_mut!(is_expired);
     pin_mut!(local_rx);
-    loop {
+    let rate_limits = bandwidth_limit.into_rate_limits(&clock);
+    let max_read = rate_limits
+        .iter()
+        .map(|r| r.max_chunk_size() as u64)
+        .min()
+        .unwrap_or(u64::MAX);
+    let mut nb_bytes = 0;
+    let (close_reason, disconnect) = loop {
         debug_assert!(
-            ws_tx.buf_mut().chunk_mut().len() >= MAX_PACKET_LENGTH,
+            ws_tx.buf_mut().chunk_mut().len() >= MIN_COPY_BUFFER_SIZE,
             "buffer must be large enough to receive a whole packet length"
         );
 
+        let mut local_rx = local_rx.as_mut().take(max_read);
         let read_len = select! {
             biased;
 
             read_len = local_rx.read_buf(ws_tx.buf_mut()) => read_len,
 
-            _ = &mut should_close => break,
+            _ = &mut should_close => match byte_limits.exceeded.lock().take() {
+                Some(reason) => break (reason.clone(), DisconnectReason::LimitReached(reason)),
+                None => break (CloseReason::normal(), DisconnectReason::Stopped),
+            },
+
+            _ = &mut is_idle => {
+                info!("closing tunnel, no data transferred for {:?}", idle_timeout.as_ref().map(|t| t.timeout).unwrap_or_default());
+                break (CloseReason::new(CloseReason::NORMAL, "idle timeout"), DisconnectReason::Timeout);
+            }
+
+            _ = &mut is_expired => {
+                info!("closing tunnel, it reached its max duration of {:?}", max_duration.unwrap_or_default());
+                break (CloseReason::expired(), DisconnectReason::Timeout);
+            }
+
+            _ = &mut ping_timer, if ping_frequency.is_some() => {
+                let now = clock.now();
+                if let Some(ping_at) = last_write.checked_add(frequency).filter(|ping_at| *ping_at > now) {
+                    ping_timer = clock.sleep_until(ping_at).fuse();
+                    continue;
+                }
+                ping_timer = clock.sleep(frequency).fuse();
 
-            _ = timeout.tick(), if ping_frequency.is_some() => {
                 debug!("sending ping to keep connection alive");
-                ws_tx.ping().await?;
+                if let Err(err) = ws_tx.ping().await {
+                    warn!("error while sending ping to tx tunnel {}", err);
+                    break (CloseReason::new(CloseReason::INTERNAL_ERROR, "tunnel write error"), DisconnectReason::RemoteError(err));
+                }
                 continue;
             }
         };
 
-        let _read_len = match read_len {
-            Ok(0) => break,
+        let read_len = match read_len {
+            Ok(0) => break (CloseReason::normal(), DisconnectReason::LocalEof),
             Ok(read_len) => read_len,
             Err(err) => {
                 warn!("error while reading incoming bytes from local tx tunnel: {}", err);
-                break;
+                break (
+                    CloseReason::new(CloseReason::INTERNAL_ERROR, "local read error"),
+                    DisconnectReason::LocalError(err),
+                );
             }
         };
 
+        if let Some(reason) = byte_limits
+            .check(byte_limits.local_to_remote, nb_bytes + read_len as u64, "local => remote")
+            .or_else(|| byte_limits.consume_budget(read_len))
+        {
+            break (reason.clone(), DisconnectReason::LimitReached(reason));
+        }
+
         //debug!("read {} wasted {}% usable {} capa {}", read_len, 100 - (read_len * 100 / buffer.capacity()), buffer.as_slice().len(), buffer.capacity());
         if let Err(err) = ws_tx.write().await {
             warn!("error while writing to tx tunnel {}", err);
-            break;
+            break (
+                CloseReason::new(CloseReason::INTERNAL_ERROR, "tunnel write error"),
+                DisconnectReason::RemoteError(err),
+            );
         }
-    }
+        metrics.on_bytes(Direction::LocalToRemote, read_len);
+        nb_bytes += read_len as u64;
+        consume(&rate_limits, read_len).await;
 
-    // Send normal close
-    let _ = ws_tx.close().await;
+        // Data frames already prove the connection is alive, only ping when nothing has been sent for a while
+        if adaptive_ping {
+            last_write = clock.now();
+        }
 
-    Ok(())
+        if let Some(idle_timeout) = &idle_timeout {
+            idle_timeout.touch();
+        }
+    };
+
+    // Tell the remote end why we are closing: normal on local EOF, internal error if the local side failed
+    let _ = ws_tx.close(&close_reason).await;
+
+    Propagated {
+        nb_bytes,
+        close_reason: Some(close_reason),
+        disconnect,
+    }
 }
 
 pub async fn propagate_remote_to_local(
     local_tx: impl AsyncWrite + Send,
     mut ws_rx: impl TunnelRead,
     mut close_rx: oneshot::Receiver<()>,
-) -> anyhow::Result<()> {
+    options: PropagateOptions,
+) -> Propagated {
+    let PropagateOptions {
+        idle_timeout,
+        byte_limits,
+        bandwidth_limit,
+        metrics,
+        clock,
+        ..
+    } = options;
     let _guard = scopeguard::guard((), |_| {
         info!("Closing local <= remote tunnel");
     });
 
+    let is_idle = wait_idle(idle_timeout.clone()).fuse();
+    pin_mut!(is_idle);
     pin_mut!(local_tx);
-    loop {
+    // The size of the messages is decided by the remote, so the pacing is only as fine as them
+    let rate_limits = bandwidth_limit.into_rate_limits(&clock);
+    let mut nb_bytes = 0;
+    // The local side is shut down cleanly unless the tunnel failed, or the remote could not connect to the
+    // destination, else it is just dropped. i.e: to be reset by a ResetOnDropWriter
+    let mut failed = false;
+    let (close_reason, disconnect) = loop {
+        let mut capped_tx = CappedWrite {
+            inner: &mut local_tx,
+            byte_limits: &byte_limits,
+            nb_bytes,
+            exceeded: None,
+        };
         let msg = select! {
             biased;
-            msg = ws_rx.copy(&mut local_tx) => msg,
-            _ = &mut close_rx => break,
+            msg = ws_rx.copy(&mut capped_tx) => msg,
+            _ = &mut close_rx => break (None, DisconnectReason::Stopped),
+            _ = &mut is_idle => break (None, DisconnectReason::Timeout),
         };
+        if let Some(reason) = capped_tx.exceeded {
+            break (Some(reason.clone()), DisconnectReason::LimitReached(reason));
+        }
+
+        let msg_len = match msg {
+            Ok(msg_len) => msg_len,
+            Err(err) => match err.get_ref().and_then(|err| err.downcast_ref::<CloseReason>()) {
+                Some(close_reason) if close_reason.is_normal() => {
+                    debug!("tunnel closed by remote with {}", close_reason);
+                    break (Some(close_reason.clone()), DisconnectReason::CloseFrame(close_reason.clone()));
+                }
+                Some(close_reason) => {
+                    warn!("tunnel closed by remote with {}", close_reason);
+                    failed = close_reason.is_connect_failed();
+                    break (Some(close_reason.clone()), DisconnectReason::CloseFrame(close_reason.clone()));
+                }
+                // Transports without close frames just end the stream
+                None if err.kind() == ErrorKind::BrokenPipe => {
+                    debug!("tunnel closed by remote {}", err);
+                    break (None, DisconnectReason::RemoteEof);
+                }
+                None => {
+                    error!("error while reading from tunnel rx {}", err);
+                    failed = true;
+                    break (None, DisconnectReason::RemoteError(err));
+                }
+            },
+        };
+        metrics.on_bytes(Direction::RemoteToLocal, msg_len);
+        nb_bytes += msg_len as u64;
+        consume(&rate_limits, msg_len).await;
+
+        if let Some(idle_timeout) = &idle_timeout {
+            idle_timeout.touch();
+        }
+    };
+
+    if !failed {
+        let _ = local_tx.shutdown().await;
+    }
+
+    Propagated {
+        nb_bytes,
+        close_reason,
+        disconnect,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::clock::TokioClock;
+    use crate::tunnel::transport::raw::{RawTunnelRead, RawTunnelWrite};
+    use futures_util::future::BoxFuture;
+    use futures_util::FutureExt;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limit_paces_to_bytes_per_sec() {
+        // The bucket starts full with 100ms worth of bytes, the remaining 200ms must be waited
+        let rate_limit = RateLimit::new(100_000, Arc::new(TokioClock));
+        let started_at = Instant::now();
+        for _ in 0..10 {
+            rate_limit.consume(1_000).await;
+        }
+        assert_eq!(started_at.elapsed(), Duration::ZERO);
+        for _ in 0..20 {
+            rate_limit.consume(1_000).await;
+        }
+        assert_eq!(started_at.elapsed(), Duration::from_millis(200));
+
+        // Paced chunk by chunk, instead of bursting then stalling
+        let started_at = Instant::now();
+        rate_limit.consume(1_000).await;
+        assert_eq!(started_at.elapsed(), Duration::from_millis(10));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_follows_the_clock() {
+        // Frozen in time, the rate limit can only know how long to wait from the clock it was given
+        struct FrozenClock {
+            now: Instant,
+            deadlines: Mutex<Vec<Instant>>,
+        }
+
+        impl Clock for FrozenClock {
+            fn now(&self) -> Instant {
+                self.now
+            }
+
+            fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+                self.deadlines.lock().push(deadline);
+                futures_util::future::ready(()).boxed()
+            }
+        }
 
-        if let Err(err) = msg {
-            error!("error while reading from tunnel rx {}", err);
-            break;
+        let now = Instant::now() + Duration::from_secs(3600);
+        let clock = Arc::new(FrozenClock {
+            now,
+            deadlines: Mutex::new(Vec::new()),
+        });
+        let rate_limit = RateLimit::new(100_000, clock.clone());
+        for _ in 0..30 {
+            rate_limit.consume(1_000).await;
         }
+        assert_eq!(clock.deadlines.lock().last(), Some(&(now + Duration::from_millis(200))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shared_rate_limit_is_fair() {
+        // A transfer already using the whole limit must not delay a new one by more than its own chunk
+        let rate_limit = Arc::new(RateLimit::new(100_000, Arc::new(TokioClock)));
+        let bulk = tokio::spawn({
+            let rate_limit = rate_limit.clone();
+            async move {
+                for _ in 0..100 {
+                    rate_limit.consume(5_000).await;
+                }
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        // At most the slot the bulk transfer reserved in advance (50ms), then its own (10ms)
+        let started_at = Instant::now();
+        rate_limit.consume(1_000).await;
+        let elapsed = started_at.elapsed();
+        assert!(elapsed <= Duration::from_millis(60), "{:?}", elapsed);
+        assert!(!bulk.is_finished());
+        bulk.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_timeout_follows_the_clock() {
+        let idle_timeout = IdleTimeout::new(Duration::from_secs(60), Arc::new(TokioClock));
+        let is_idle = wait_idle(Some(idle_timeout.clone()));
+        pin_mut!(is_idle);
+
+        tokio::time::advance(Duration::from_secs(50)).await;
+        idle_timeout.touch();
+        tokio::time::advance(Duration::from_secs(59)).await;
+        assert!(futures_util::poll!(is_idle.as_mut()).is_pending());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(futures_util::poll!(is_idle.as_mut()).is_ready());
+    }
+
+    #[test]
+    fn test_byte_limits_are_per_direction() {
+        let byte_limits = ByteLimits::new(Some(100), None);
+        let remote_to_local = byte_limits.clone();
+        assert_eq!(
+            remote_to_local.check(remote_to_local.remote_to_local, u64::MAX, "local <= remote"),
+            None
+        );
+        assert_eq!(byte_limits.check(byte_limits.local_to_remote, 100, "local => remote"), None);
+
+        // The direction going over its cap leaves the reason for the one sending the close frame
+        let reason = byte_limits.check(byte_limits.local_to_remote, 101, "local => remote");
+        assert_eq!(reason.as_ref().map(|r| r.code), Some(CloseReason::POLICY_VIOLATION));
+        assert_eq!(remote_to_local.exceeded.lock().take(), reason);
     }
 
-    Ok(())
+    #[test]
+    fn test_byte_budget_is_shared() {
+        let budget = Arc::new(ByteBudget::new(100));
+        let tunnel_1 = ByteLimits::default().with_budget(Some(budget.clone()));
+        let tunnel_2 = ByteLimits::default().with_budget(Some(budget.clone()));
+
+        assert_eq!(tunnel_1.consume_budget(60), None);
+        assert!(!budget.is_exhausted());
+        assert_eq!(tunnel_2.consume_budget(40), None);
+        assert!(budget.is_exhausted());
+        let reason = tunnel_1.consume_budget(1);
+        assert_eq!(reason.as_ref().map(|r| r.code), Some(CloseReason::POLICY_VIOLATION));
+        assert_eq!(tunnel_2.exceeded.lock().take(), None);
+    }
+
+    // Data frame of the raw transport
+    const HELLO_FRAME: &[u8] = b"\0\0\0\0\x05hello";
+
+    #[tokio::test]
+    async fn test_disconnect_reason_of_each_direction() {
+        let (close_tx, close_rx) = oneshot::channel::<()>();
+        let local_to_remote = propagate_local_to_remote(
+            &b"hello"[..],
+            RawTunnelWrite::new(Box::pin(Vec::new()), MIN_COPY_BUFFER_SIZE),
+            close_tx,
+            PropagateOptions::new(Arc::new(TokioClock)).with_byte_limits(ByteLimits::new(Some(4), None)),
+        )
+        .await;
+        assert!(matches!(local_to_remote.disconnect, DisconnectReason::LimitReached(_)));
+        assert!(local_to_remote.disconnect.is_failure());
+
+        let remote_to_local = propagate_remote_to_local(
+            Vec::new(),
+            RawTunnelRead::new(
+                Box::pin(HELLO_FRAME),
+                &RawTunnelWrite::new(Box::pin(tokio::io::sink()), MIN_COPY_BUFFER_SIZE),
+                MIN_COPY_BUFFER_SIZE,
+            ),
+            close_rx,
+            PropagateOptions::new(Arc::new(TokioClock)),
+        )
+        .await;
+        assert_eq!(remote_to_local.nb_bytes, 5);
+        assert!(matches!(remote_to_local.disconnect, DisconnectReason::RemoteEof));
+        assert!(!remote_to_local.disconnect.is_failure());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tunnel_is_closed_after_max_duration() {
+        // Neither side sends anything, only the max duration can end the tunnel
+        let (local_rx, _local) = tokio::io::duplex(1024);
+        let (remote_tx, remote_rx) = tokio::io::duplex(1024);
+        let (close_tx, mut close_rx) = oneshot::channel::<()>();
+        let local_to_remote = tokio::spawn(propagate_local_to_remote(
+            local_rx,
+            RawTunnelWrite::new(Box::pin(remote_tx), MIN_COPY_BUFFER_SIZE),
+            close_tx,
+            PropagateOptions::new(Arc::new(TokioClock)).with_max_duration(Some(Duration::from_secs(60))),
+        ));
+
+        tokio::time::sleep(Duration::from_secs(59)).await;
+        assert!(!local_to_remote.is_finished());
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let propagated = local_to_remote.await.unwrap();
+        assert_eq!(propagated.close_reason, Some(CloseReason::expired()));
+        assert!(matches!(propagated.disconnect, DisconnectReason::Timeout));
+
+        // The remote gets the reason in a close frame, and the other direction is stopped
+        let mut remote = RawTunnelRead::new(
+            Box::pin(remote_rx),
+            &RawTunnelWrite::new(Box::pin(tokio::io::sink()), MIN_COPY_BUFFER_SIZE),
+            MIN_COPY_BUFFER_SIZE,
+        );
+        let err = remote.copy(Vec::new()).await.unwrap_err();
+        assert_eq!(
+            err.get_ref().and_then(|err| err.downcast_ref::<CloseReason>()),
+            Some(&CloseReason::expired())
+        );
+        assert_eq!(close_rx.try_recv(), Err(oneshot::error::TryRecvError::Closed));
+    }
+
+    #[tokio::test]
+    async fn test_byte_limits_are_checked_before_writing_locally() {
+        async fn remote_to_local(local_tx: &mut Vec<u8>, byte_limits: ByteLimits) -> Propagated {
+            let (_close_tx, close_rx) = oneshot::channel::<()>();
+            propagate_remote_to_local(
+                local_tx,
+                RawTunnelRead::new(
+                    Box::pin(HELLO_FRAME),
+                    &RawTunnelWrite::new(Box::pin(tokio::io::sink()), MIN_COPY_BUFFER_SIZE),
+                    MIN_COPY_BUFFER_SIZE,
+                ),
+                close_rx,
+                PropagateOptions::new(Arc::new(TokioClock)).with_byte_limits(byte_limits),
+            )
+            .await
+        }
+
+        // Nothing of a message going over the cap reaches the local side
+        let mut local = Vec::new();
+        let propagated = remote_to_local(&mut local, ByteLimits::new(None, Some(4))).await;
+        assert!(matches!(propagated.disconnect, DisconnectReason::LimitReached(_)));
+        assert!(local.is_empty());
+
+        let budget = Arc::new(ByteBudget::new(4));
+        let mut local = Vec::new();
+        let propagated = remote_to_local(&mut local, ByteLimits::default().with_budget(Some(budget.clone()))).await;
+        assert!(matches!(propagated.disconnect, DisconnectReason::LimitReached(_)));
+        assert!(local.is_empty());
+        assert!(budget.consume(4));
+
+        let mut local = Vec::new();
+        let propagated = remote_to_local(&mut local, ByteLimits::new(None, Some(5))).await;
+        assert!(matches!(propagated.disconnect, DisconnectReason::RemoteEof));
+        assert_eq!(local, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_local_side_is_shut_down_unless_the_tunnel_failed() {
+        struct ClosedTunnelRead(Option<io::Error>);
+
+        impl TunnelRead for ClosedTunnelRead {
+            async fn copy(&mut self, _writer: impl AsyncWrite + Unpin + Send) -> io::Result<usize> {
+                Err(self.0.take().unwrap())
+            }
+        }
+
+        #[derive(Default)]
+        struct LocalWriter {
+            is_shutdown: bool,
+        }
+
+        impl AsyncWrite for LocalWriter {
+            fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+                Poll::Ready(Ok(buf.len()))
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                self.is_shutdown = true;
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        async fn is_shut_down(err: io::Error) -> bool {
+            let (_close_tx, close_rx) = oneshot::channel::<()>();
+            let mut local_tx = LocalWriter::default();
+            propagate_remote_to_local(
+                &mut local_tx,
+                ClosedTunnelRead(Some(err)),
+                close_rx,
+                PropagateOptions::new(Arc::new(TokioClock)),
+            )
+            .await;
+            local_tx.is_shutdown
+        }
+
+        let closed = |close_reason: CloseReason| io::Error::new(ErrorKind::NotConnected, close_reason);
+        assert!(is_shut_down(closed(CloseReason::normal())).await);
+        assert!(is_shut_down(closed(CloseReason::new(CloseReason::INTERNAL_ERROR, "boom"))).await);
+        assert!(is_shut_down(closed(CloseReason::new(CloseReason::POLICY_VIOLATION, "too many tunnels"))).await);
+        assert!(!is_shut_down(closed(CloseReason::connect_failed())).await);
+
+        // The end of stream of the transports without close frames is not a failure, the local peer must get a FIN
+        assert!(is_shut_down(io::Error::new(ErrorKind::BrokenPipe, "end of stream")).await);
+        assert!(!is_shut_down(io::Error::new(ErrorKind::ConnectionReset, "reset")).await);
+    }
 }
@@ -1,45 +1,146 @@
 use crate::tunnel::transport::http2::{Http2TunnelRead, Http2TunnelWrite};
+use crate::tunnel::transport::prefetch::PrefetchTunnelRead;
+use crate::tunnel::transport::raw::{RawTunnelRead, RawTunnelWrite};
 use crate::tunnel::transport::websocket::{WebsocketTunnelRead, WebsocketTunnelWrite};
+use crate::LocalProtocol;
+use anyhow::anyhow;
 use bytes::BytesMut;
-use hyper::http::{HeaderName, HeaderValue};
+use hyper::header::RETRY_AFTER;
+use hyper::http::{HeaderMap, HeaderName, HeaderValue};
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
 use std::future::Future;
 use std::io::{BufRead, BufReader};
 use std::path::Path;
 use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
 use tokio::io::AsyncWrite;
 use tracing::error;
 
+pub mod connection_info;
+pub mod datagram;
 pub mod http2;
 pub mod io;
+pub mod mux;
+pub mod prefetch;
+pub mod priority;
+pub mod raw;
 pub mod websocket;
 
-static MAX_PACKET_LENGTH: usize = 64 * 1024;
+pub static MAX_PACKET_LENGTH: usize = 64 * 1024;
+
+/// Smallest buffer allowed to read from the local side, below that the syscalls overhead kills the throughput
+pub const MIN_COPY_BUFFER_SIZE: usize = 4 * 1024;
+
+/// Size of the buffer used to read from the local side of a tunnel.
+/// Datagram protocols always get room for a whole packet, as a truncated datagram cannot be completed by a later read
+pub fn copy_buffer_size(buffer_size: usize, protocol: &LocalProtocol) -> usize {
+    let buffer_size = buffer_size.max(MIN_COPY_BUFFER_SIZE);
+    match protocol {
+        LocalProtocol::Udp { .. }
+        | LocalProtocol::TProxyUdp { .. }
+        | LocalProtocol::ReverseUdp { .. }
+        | LocalProtocol::ReverseSocks5 { .. } => buffer_size.max(MAX_PACKET_LENGTH),
+        _ => buffer_size,
+    }
+}
+
+/// Code and reason of a websocket close frame (RFC 6455 section 7.4).
+/// Http2 transport has no such thing, the stream is simply ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseReason {
+    pub code: u16,
+    pub reason: String,
+}
+
+impl CloseReason {
+    pub const NORMAL: u16 = 1000;
+    pub const NO_STATUS: u16 = 1005;
+    pub const POLICY_VIOLATION: u16 = 1008;
+    pub const INTERNAL_ERROR: u16 = 1011;
+    // Bad gateway, the other end could not connect to the destination
+    pub const CONNECT_FAILED: u16 = 1014;
+
+    pub fn new(code: u16, reason: impl Into<String>) -> Self {
+        Self {
+            code,
+            reason: reason.into(),
+        }
+    }
+
+    pub fn normal() -> Self {
+        Self::new(Self::NORMAL, "")
+    }
+
+    /// The tunnel lived longer than the max duration it was given
+    pub fn expired() -> Self {
+        Self::new(Self::NORMAL, "max tunnel duration reached")
+    }
+
+    pub fn connect_failed() -> Self {
+        Self::new(Self::CONNECT_FAILED, "cannot connect to destination")
+    }
+
+    pub const fn is_normal(&self) -> bool {
+        matches!(self.code, Self::NORMAL | Self::NO_STATUS)
+    }
+
+    pub const fn is_connect_failed(&self) -> bool {
+        self.code == Self::CONNECT_FAILED
+    }
+
+    /// Parse the payload of a close frame, an empty payload means that no code was given
+    pub fn from_payload(payload: &[u8]) -> Self {
+        match payload {
+            [hi, lo, reason @ ..] => Self::new(u16::from_be_bytes([*hi, *lo]), String::from_utf8_lossy(reason)),
+            _ => Self::new(Self::NO_STATUS, ""),
+        }
+    }
+}
+
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.reason.is_empty() {
+            write!(f, "close code {}", self.code)
+        } else {
+            write!(f, "close code {} ({})", self.code, self.reason)
+        }
+    }
+}
+
+impl std::error::Error for CloseReason {}
 
 pub trait TunnelWrite: Send + 'static {
     fn buf_mut(&mut self) -> &mut BytesMut;
     fn write(&mut self) -> impl Future<Output = Result<(), std::io::Error>> + Send;
     fn ping(&mut self) -> impl Future<Output = Result<(), std::io::Error>> + Send;
-    fn close(&mut self) -> impl Future<Output = Result<(), std::io::Error>> + Send;
+    fn close(&mut self, reason: &CloseReason) -> impl Future<Output = Result<(), std::io::Error>> + Send;
 }
 
 pub trait TunnelRead: Send + 'static {
+    /// Copy the next data frame into the writer, and return the number of bytes written.
+    /// When the remote end closes the tunnel, the returned error wraps its CloseReason if it gave one
     fn copy(
         &mut self,
         writer: impl AsyncWrite + Unpin + Send,
-    ) -> impl Future<Output = Result<(), std::io::Error>> + Send;
+    ) -> impl Future<Output = Result<usize, std::io::Error>> + Send;
 }
 
 pub enum TunnelReader {
     Websocket(WebsocketTunnelRead),
     Http2(Http2TunnelRead),
+    Raw(RawTunnelRead),
+    Prefetched(PrefetchTunnelRead),
 }
 
 impl TunnelRead for TunnelReader {
-    async fn copy(&mut self, writer: impl AsyncWrite + Unpin + Send) -> Result<(), std::io::Error> {
+    async fn copy(&mut self, writer: impl AsyncWrite + Unpin + Send) -> Result<usize, std::io::Error> {
         match self {
             Self::Websocket(s) => s.copy(writer).await,
             Self::Http2(s) => s.copy(writer).await,
+            Self::Raw(s) => s.copy(writer).await,
+            Self::Prefetched(s) => s.copy(writer).await,
         }
     }
 }
@@ -47,6 +148,7 @@ impl TunnelRead for TunnelReader {
 pub enum TunnelWriter {
     Websocket(WebsocketTunnelWrite),
     Http2(Http2TunnelWrite),
+    Raw(RawTunnelWrite),
 }
 
 impl TunnelWrite for TunnelWriter {
@@ -54,6 +156,7 @@ impl TunnelWrite for TunnelWriter {
         match self {
             Self::Websocket(s) => s.buf_mut(),
             Self::Http2(s) => s.buf_mut(),
+            Self::Raw(s) => s.buf_mut(),
         }
     }
 
@@ -61,6 +164,7 @@ impl TunnelWrite for TunnelWriter {
         match self {
             Self::Websocket(s) => s.write().await,
             Self::Http2(s) => s.write().await,
+            Self::Raw(s) => s.write().await,
         }
     }
 
@@ -68,13 +172,15 @@ impl TunnelWrite for TunnelWriter {
         match self {
             Self::Websocket(s) => s.ping().await,
             Self::Http2(s) => s.ping().await,
+            Self::Raw(s) => s.ping().await,
         }
     }
 
-    async fn close(&mut self) -> Result<(), std::io::Error> {
+    async fn close(&mut self, reason: &CloseReason) -> Result<(), std::io::Error> {
         match self {
-            Self::Websocket(s) => s.close().await,
-            Self::Http2(s) => s.close().await,
+            Self::Websocket(s) => s.close(reason).await,
+            Self::Http2(s) => s.close(reason).await,
+            Self::Raw(s) => s.close(reason).await,
         }
     }
 }
@@ -99,7 +205,13 @@ pub fn headers_from_file(path: &Path) -> (Option<(HeaderName, HeaderValue)>, Vec
             let line = line.ok()?;
             let (header, value) = line.split_once(':')?;
             let header = HeaderName::from_str(header.trim()).ok()?;
-            let value = HeaderValue::from_str(value.trim()).ok()?;
+            let value = match expand_env_vars(value.trim()) {
+                Ok(value) => HeaderValue::from_str(&value).ok()?,
+                Err(err) => {
+                    error!("Cannot read header {} from file {:?}: {:?}", header, path, err);
+                    return None;
+                }
+            };
             if header == HOST_HEADER {
                 host_header = Some((header, value));
                 return None;
@@ -110,3 +222,177 @@ pub fn headers_from_file(path: &Path) -> (Option<(HeaderName, HeaderValue)>, Vec
 
     (host_header, headers)
 }
+
+/// Add the headers to the request, replacing the ones already there with the same name.
+/// A header given multiple times is sent multiple times
+pub fn set_http_headers<'a>(
+    headers: &mut HeaderMap,
+    new_headers: impl IntoIterator<Item = &'a (HeaderName, HeaderValue)>,
+) {
+    let mut replaced: Vec<&HeaderName> = vec![];
+    for (k, v) in new_headers {
+        if !replaced.contains(&k) {
+            let _ = headers.remove(k);
+            replaced.push(k);
+        }
+        headers.append(k, v.clone());
+    }
+}
+
+/// Rebuild the headers for the listed ones to come first, in the given order. The others follow in their current order.
+/// Hyper writes the headers of an http1 request in the order of the map
+pub fn order_http_headers(headers: &mut HeaderMap, order: &[HeaderName]) {
+    if order.is_empty() {
+        return;
+    }
+
+    let unordered = std::mem::take(headers);
+    for (ix, name) in order.iter().enumerate() {
+        if order[..ix].contains(name) {
+            continue;
+        }
+        for value in unordered.get_all(name) {
+            headers.append(name, value.clone());
+        }
+    }
+    for (name, value) in unordered.iter().filter(|(name, _)| !order.contains(name)) {
+        headers.append(name, value.clone());
+    }
+}
+
+/// Delay asked by the server in the Retry-After header of a response (RFC 9110 section 10.2.3),
+/// given either as a number of seconds or as an http date
+pub fn parse_retry_after(headers: &HeaderMap, now: SystemTime) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(now).unwrap_or(Duration::ZERO))
+}
+
+/// Replace every ${VAR} in the value by the content of the environment variable VAR, to avoid having secrets in the
+/// command line. Fails if the variable is not set
+pub fn expand_env_vars(value: &str) -> anyhow::Result<Cow<'_, str>> {
+    expand_vars(value, |var| std::env::var(var))
+}
+
+/// Same as expand_env_vars, with the variables read by lookup
+pub fn expand_vars(
+    value: &str,
+    lookup: impl Fn(&str) -> Result<String, std::env::VarError>,
+) -> anyhow::Result<Cow<'_, str>> {
+    if !value.contains("${") {
+        return Ok(Cow::Borrowed(value));
+    }
+
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start + 2..].find('}') else {
+            return Err(anyhow!("unterminated environment variable in {value}"));
+        };
+        let var = &rest[start + 2..start + 2 + len];
+        let var_value = lookup(var).map_err(|err| anyhow!("cannot read environment variable {var}: {err}"))?;
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&var_value);
+        rest = &rest[start + 2 + len + 1..];
+    }
+    expanded.push_str(rest);
+
+    Ok(Cow::Owned(expanded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_777);
+        let retry_after = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(RETRY_AFTER, HeaderValue::from_str(value).unwrap());
+            parse_retry_after(&headers, now)
+        };
+
+        assert_eq!(parse_retry_after(&HeaderMap::new(), now), None);
+        assert_eq!(retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(retry_after(" 0 "), Some(Duration::ZERO));
+        // now is Sun, 06 Nov 1994 08:49:37 GMT
+        assert_eq!(retry_after("Sun, 06 Nov 1994 08:50:07 GMT"), Some(Duration::from_secs(30)));
+        assert_eq!(retry_after("Sun, 06 Nov 1994 08:00:00 GMT"), Some(Duration::ZERO));
+        assert_eq!(retry_after("-5"), None);
+        assert_eq!(retry_after("soon"), None);
+    }
+
+    #[test]
+    fn test_order_http_headers() {
+        let name = |name: &'static str| HeaderName::from_static(name);
+        let mut headers = HeaderMap::new();
+        headers.insert(name("host"), HeaderValue::from_static("example.com"));
+        headers.insert(name("upgrade"), HeaderValue::from_static("websocket"));
+        headers.append(name("x-a"), HeaderValue::from_static("1"));
+        headers.append(name("x-a"), HeaderValue::from_static("2"));
+        headers.insert(name("user-agent"), HeaderValue::from_static("ua"));
+
+        order_http_headers(
+            &mut headers,
+            &[name("user-agent"), name("x-a"), name("origin"), name("user-agent")],
+        );
+        let ordered: Vec<_> = headers.iter().map(|(k, v)| (k.as_str(), v.to_str().unwrap())).collect();
+        assert_eq!(
+            ordered,
+            [
+                ("user-agent", "ua"),
+                ("x-a", "1"),
+                ("x-a", "2"),
+                ("host", "example.com"),
+                ("upgrade", "websocket")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_close_reason_from_payload() {
+        assert_eq!(CloseReason::from_payload(&[]), CloseReason::new(CloseReason::NO_STATUS, ""));
+        assert_eq!(CloseReason::from_payload(&[0x03, 0xe8]), CloseReason::normal());
+        let close = CloseReason::from_payload(b"\x03\xf0bad token");
+        assert_eq!(close, CloseReason::new(1008, "bad token"));
+        assert!(!close.is_normal());
+        assert_eq!(close.to_string(), "close code 1008 (bad token)");
+    }
+
+    #[test]
+    fn test_expand_env_vars() {
+        let expand = |value| {
+            expand_vars(value, |var| match var {
+                "WSTUNNEL_TEST_SECRET" => Ok("s3cr3t".to_string()),
+                _ => Err(std::env::VarError::NotPresent),
+            })
+        };
+        assert_eq!(expand("Bearer ${WSTUNNEL_TEST_SECRET}").unwrap(), "Bearer s3cr3t");
+        assert_eq!(
+            expand("${WSTUNNEL_TEST_SECRET}:${WSTUNNEL_TEST_SECRET}").unwrap(),
+            "s3cr3t:s3cr3t"
+        );
+        assert_eq!(expand("no $variable").unwrap(), "no $variable");
+        assert!(expand("${WSTUNNEL_TEST_NOT_SET}").is_err());
+        assert!(expand("${WSTUNNEL_TEST_SECRET").is_err());
+    }
+
+    #[test]
+    fn test_set_http_headers_keeps_duplicates() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("1.1.1.1"));
+        let name = HeaderName::from_static("x-forwarded-for");
+        let new_headers = vec![
+            (name.clone(), HeaderValue::from_static("2.2.2.2")),
+            (name.clone(), HeaderValue::from_static("3.3.3.3")),
+        ];
+
+        set_http_headers(&mut headers, &new_headers);
+        assert_eq!(headers.get_all(&name).iter().collect::<Vec<_>>(), vec!["2.2.2.2", "3.3.3.3"]);
+    }
+}
@@ -0,0 +1,568 @@
+use crate::tunnel::connectors::TunnelConnector;
+use crate::tunnel::RemoteAddr;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures_util::stream::FuturesUnordered;
+use futures_util::{pin_mut, StreamExt};
+use hyper::http::{HeaderMap, HeaderName, HeaderValue};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::io;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio::sync::{mpsc, Semaphore};
+use tracing::{debug, warn};
+
+// The client asks with this header to keep the reverse tunnel open and receive all the flows through it,
+// and the server answers with it when it does so. Servers of older versions keep one flow per tunnel.
+// v1 had no flow control, it is not supported anymore and such peers fall back to one flow per tunnel
+static REVERSE_MULTIPLEX_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-reverse-multiplex");
+static REVERSE_MULTIPLEX_V2: HeaderValue = HeaderValue::from_static("v2");
+
+/// Size of the in memory pipe between the tunnel and the multiplexer
+pub const MUX_PIPE_SIZE: usize = 256 * 1024;
+
+// stream id u32 + kind u8 + payload length u16, all big endian
+const FRAME_HEADER_SIZE: usize = 7;
+const MAX_FRAME_PAYLOAD: usize = 16 * 1024;
+// Frames waiting to be written to the tunnel
+const TUNNEL_BUFFERED_FRAMES: usize = 32;
+// Bytes a flow can send before the peer acknowledges having written them to its local side.
+// A slow local side only stalls its own flow, the tunnel keeps dispatching the frames of the others
+const FLOW_WINDOW: usize = 256 * 1024;
+// Acknowledged bytes are batched in a single credit frame, unless the flow has nothing more to write
+const FLOW_CREDIT_BATCH: usize = FLOW_WINDOW / 4;
+
+/// Ask for the multiplexed mode (client), or confirm that it is used (server)
+pub fn set_reverse_multiplex(headers: &mut HeaderMap) {
+    headers.insert(REVERSE_MULTIPLEX_HEADER.clone(), REVERSE_MULTIPLEX_V2.clone());
+}
+
+pub fn has_reverse_multiplex(headers: &HeaderMap) -> bool {
+    headers.get(&REVERSE_MULTIPLEX_HEADER) == Some(&REVERSE_MULTIPLEX_V2)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FrameKind {
+    // Sent by the server for each connection accepted by the reverse listener
+    Open = 0,
+    Data = 1,
+    // The sender will not send anything more on this flow
+    Fin = 2,
+    // The sender wrote this many bytes of the flow to its local side, and can receive as many more.
+    // Payload is a u32 big endian
+    Credit = 3,
+}
+
+#[derive(Debug)]
+struct Frame {
+    stream_id: u32,
+    kind: FrameKind,
+    payload: Bytes,
+}
+
+impl Frame {
+    const fn new(stream_id: u32, kind: FrameKind) -> Self {
+        Self {
+            stream_id,
+            kind,
+            payload: Bytes::new(),
+        }
+    }
+
+    fn credit(stream_id: u32, nb_bytes: usize) -> Self {
+        Self {
+            stream_id,
+            kind: FrameKind::Credit,
+            payload: Bytes::copy_from_slice(&(nb_bytes as u32).to_be_bytes()),
+        }
+    }
+
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.reserve(FRAME_HEADER_SIZE + self.payload.len());
+        buf.put_u32(self.stream_id);
+        buf.put_u8(self.kind as u8);
+        buf.put_u16(self.payload.len() as u16);
+        buf.put_slice(&self.payload);
+    }
+
+    // None when the peer closed the pipe in between two frames
+    async fn read(reader: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<Self>> {
+        let mut header = [0; FRAME_HEADER_SIZE];
+        match reader.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+
+        let [a, b, c, d, kind, hi, lo] = header;
+        let kind = match kind {
+            0 => FrameKind::Open,
+            1 => FrameKind::Data,
+            2 => FrameKind::Fin,
+            3 => FrameKind::Credit,
+            _ => return Err(io::Error::new(ErrorKind::InvalidData, format!("unknown frame kind {}", kind))),
+        };
+        let len = u16::from_be_bytes([hi, lo]) as usize;
+        if len > MAX_FRAME_PAYLOAD {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("received frame of {} bytes, above the maximum of {}", len, MAX_FRAME_PAYLOAD),
+            ));
+        }
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload).await?;
+
+        Ok(Some(Self {
+            stream_id: u32::from_be_bytes([a, b, c, d]),
+            kind,
+            payload: Bytes::from(payload),
+        }))
+    }
+}
+
+// State of a flow, as seen by the task dispatching the frames received from the tunnel
+struct Flow {
+    // None once the peer sent everything it had for this flow
+    data_tx: Option<mpsc::UnboundedSender<Bytes>>,
+    // Received but not yet written to the local side, bounded by the window granted to the peer
+    buffered: Arc<AtomicUsize>,
+    // Bytes the flow can still send to the peer
+    credit: Arc<Semaphore>,
+}
+
+// End of a flow handed over to the tasks forwarding it to its local side
+struct FlowHandle {
+    data_rx: mpsc::UnboundedReceiver<Bytes>,
+    buffered: Arc<AtomicUsize>,
+    credit: Arc<Semaphore>,
+}
+
+// Flows carried by the tunnel, until both their directions are done
+struct Flows {
+    frames_tx: mpsc::Sender<Frame>,
+    flows: Mutex<HashMap<u32, Flow>>,
+}
+
+impl Flows {
+    fn new(pipe_tx: WriteHalf<DuplexStream>) -> Arc<Self> {
+        let (frames_tx, frames_rx) = mpsc::channel(TUNNEL_BUFFERED_FRAMES);
+        tokio::spawn(write_frames(pipe_tx, frames_rx));
+
+        Arc::new(Self {
+            frames_tx,
+            flows: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn register(&self, stream_id: u32) -> FlowHandle {
+        let (data_tx, data_rx) = mpsc::unbounded_channel();
+        let buffered = Arc::new(AtomicUsize::new(0));
+        let credit = Arc::new(Semaphore::new(FLOW_WINDOW));
+        let flow = Flow {
+            data_tx: Some(data_tx),
+            buffered: buffered.clone(),
+            credit: credit.clone(),
+        };
+        self.flows.lock().insert(stream_id, flow);
+
+        FlowHandle {
+            data_rx,
+            buffered,
+            credit,
+        }
+    }
+
+    // Forward the flow in both directions, until each of them reaches its end
+    fn forward<R, W>(self: &Arc<Self>, stream_id: u32, flow: FlowHandle, local_rx: R, local_tx: W)
+    where
+        R: AsyncRead + Send + 'static,
+        W: AsyncWrite + Send + 'static,
+    {
+        let FlowHandle {
+            mut data_rx,
+            buffered,
+            credit,
+        } = flow;
+
+        let frames_tx = self.frames_tx.clone();
+        let local_to_tunnel = async move {
+            pin_mut!(local_rx);
+            let mut buf = vec![0; MAX_FRAME_PAYLOAD];
+            loop {
+                let len = match local_rx.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(len) => len,
+                };
+                // Closed along with the tunnel
+                let Ok(permits) = credit.acquire_many(len as u32).await else {
+                    return;
+                };
+                permits.forget();
+                let frame = Frame {
+                    stream_id,
+                    kind: FrameKind::Data,
+                    payload: Bytes::copy_from_slice(&buf[..len]),
+                };
+                if frames_tx.send(frame).await.is_err() {
+                    return;
+                }
+            }
+            let _ = frames_tx.send(Frame::new(stream_id, FrameKind::Fin)).await;
+        };
+
+        let frames_tx = self.frames_tx.clone();
+        let tunnel_to_local = async move {
+            pin_mut!(local_tx);
+            let mut failed = false;
+            let mut written = 0;
+            while let Some(data) = data_rx.recv().await {
+                // Once the local side is gone, what the flow still receives is dropped. It is acknowledged anyway,
+                // for the peer to get to the end of the flow
+                if !failed && local_tx.write_all(&data).await.is_err() {
+                    failed = true;
+                }
+                buffered.fetch_sub(data.len(), Ordering::Relaxed);
+                written += data.len();
+                if written >= FLOW_CREDIT_BATCH || data_rx.is_empty() {
+                    if frames_tx.send(Frame::credit(stream_id, written)).await.is_err() {
+                        break;
+                    }
+                    written = 0;
+                }
+            }
+            if !failed {
+                let _ = local_tx.shutdown().await;
+            }
+        };
+
+        let flows = self.clone();
+        tokio::spawn(async move {
+            futures_util::future::join(local_to_tunnel, tunnel_to_local).await;
+            flows.flows.lock().remove(&stream_id);
+        });
+    }
+
+    async fn close(&self, stream_id: u32) {
+        self.flows.lock().remove(&stream_id);
+        let _ = self.frames_tx.send(Frame::new(stream_id, FrameKind::Fin)).await;
+    }
+
+    // Dispatch the frames received from the tunnel to their flow, until the tunnel is closed.
+    // Open frames are handed over to opened_tx, only the client accepts them.
+    // This never waits on a flow, the peer is not allowed to send more than the window of each of them
+    async fn read_frames(
+        &self,
+        mut pipe_rx: ReadHalf<DuplexStream>,
+        opened_tx: Option<mpsc::UnboundedSender<(u32, FlowHandle)>>,
+    ) -> io::Result<()> {
+        let ret = loop {
+            let frame = match Frame::read(&mut pipe_rx).await {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break Ok(()),
+                Err(err) => break Err(err),
+            };
+
+            match (frame.kind, &opened_tx) {
+                (FrameKind::Open, Some(opened_tx)) => {
+                    let flow = self.register(frame.stream_id);
+                    let _ = opened_tx.send((frame.stream_id, flow));
+                }
+                (FrameKind::Open, None) => {
+                    break Err(io::Error::new(ErrorKind::InvalidData, "only the server can open flows"));
+                }
+                (FrameKind::Data, _) => {
+                    // A flow already closed silently drops what it still receives
+                    let flows = self.flows.lock();
+                    let Some(flow) = flows.get(&frame.stream_id) else {
+                        continue;
+                    };
+                    let Some(data_tx) = &flow.data_tx else {
+                        continue;
+                    };
+                    let buffered = flow.buffered.fetch_add(frame.payload.len(), Ordering::Relaxed);
+                    if buffered + frame.payload.len() > FLOW_WINDOW {
+                        break Err(io::Error::new(
+                            ErrorKind::InvalidData,
+                            format!("flow {} sent more than its window", frame.stream_id),
+                        ));
+                    }
+                    let _ = data_tx.send(frame.payload);
+                }
+                (FrameKind::Fin, _) => {
+                    if let Some(flow) = self.flows.lock().get_mut(&frame.stream_id) {
+                        flow.data_tx = None;
+                    }
+                }
+                (FrameKind::Credit, _) => {
+                    let Ok(nb_bytes) = <[u8; 4]>::try_from(frame.payload.as_ref()) else {
+                        break Err(io::Error::new(ErrorKind::InvalidData, "invalid credit frame"));
+                    };
+                    let nb_bytes = u32::from_be_bytes(nb_bytes) as usize;
+                    if let Some(flow) = self.flows.lock().get(&frame.stream_id) {
+                        if flow.credit.available_permits() + nb_bytes > FLOW_WINDOW {
+                            break Err(io::Error::new(
+                                ErrorKind::InvalidData,
+                                format!("flow {} was granted more than its window", frame.stream_id),
+                            ));
+                        }
+                        flow.credit.add_permits(nb_bytes);
+                    }
+                }
+            }
+        };
+
+        // Let the local side of the flows know that nothing more will come, and stop sending what they read
+        for (_, flow) in self.flows.lock().drain() {
+            flow.credit.close();
+        }
+        ret
+    }
+}
+
+async fn write_frames(mut pipe_tx: WriteHalf<DuplexStream>, mut frames_rx: mpsc::Receiver<Frame>) {
+    let mut buf = BytesMut::with_capacity(FRAME_HEADER_SIZE + MAX_FRAME_PAYLOAD);
+    while let Some(frame) = frames_rx.recv().await {
+        frame.encode(&mut buf);
+        // Batch the frames already queued, to write them to the tunnel in one message
+        while buf.len() < MUX_PIPE_SIZE / 4 {
+            let Ok(frame) = frames_rx.try_recv() else {
+                break;
+            };
+            frame.encode(&mut buf);
+        }
+
+        if pipe_tx.write_all(&buf).await.is_err() {
+            return;
+        }
+        buf.clear();
+    }
+}
+
+/// Server side of a multiplexed reverse tunnel: push each connection accepted by the reverse listener
+/// to the client as a new flow, until the tunnel is closed or the listener stops.
+/// The pipe is the other end of the one forwarded through the tunnel
+pub async fn serve<R, W>(pipe: DuplexStream, listener: &mut mpsc::Receiver<((R, W), RemoteAddr)>)
+where
+    R: AsyncRead + Send + 'static,
+    W: AsyncWrite + Send + 'static,
+{
+    let (pipe_rx, pipe_tx) = tokio::io::split(pipe);
+    let flows = Flows::new(pipe_tx);
+    let read_frames = flows.read_frames(pipe_rx, None);
+    pin_mut!(read_frames);
+
+    let mut next_stream_id: u32 = 0;
+    loop {
+        tokio::select! {
+            ret = &mut read_frames => {
+                if let Err(err) = ret {
+                    warn!("Multiplexed reverse tunnel failed: {:?}", err);
+                }
+                break;
+            }
+            cnx = listener.recv() => {
+                let Some(((local_rx, local_tx), remote)) = cnx else {
+                    break;
+                };
+                let stream_id = next_stream_id;
+                next_stream_id = next_stream_id.wrapping_add(1);
+                debug!("Opening flow {} for {:?}", stream_id, remote.source);
+
+                let flow = flows.register(stream_id);
+                if flows.frames_tx.send(Frame::new(stream_id, FrameKind::Open)).await.is_err() {
+                    break;
+                }
+                flows.forward(stream_id, flow, local_rx, local_tx);
+            }
+        }
+    }
+}
+
+/// Client side of a multiplexed reverse tunnel: connect to the local endpoint for each flow opened by the server,
+/// until the tunnel is closed.
+/// The pipe is the other end of the one forwarded through the tunnel
+pub async fn run_client(pipe: DuplexStream, connector: &impl TunnelConnector) {
+    let (pipe_rx, pipe_tx) = tokio::io::split(pipe);
+    let flows = Flows::new(pipe_tx);
+    let (opened_tx, mut opened_rx) = mpsc::unbounded_channel();
+    let read_frames = flows.read_frames(pipe_rx, Some(opened_tx));
+    pin_mut!(read_frames);
+
+    // The data of a flow is buffered while connecting to the local endpoint
+    let mut connecting = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            ret = &mut read_frames => {
+                if let Err(err) = ret {
+                    warn!("Multiplexed reverse tunnel failed: {:?}", err);
+                }
+                break;
+            }
+            Some((stream_id, flow)) = opened_rx.recv() => {
+                debug!("Flow {} opened by the server", stream_id);
+                connecting.push(async move { (stream_id, flow, connector.connect(&None).await) });
+            }
+            Some((stream_id, flow, cnx)) = connecting.next() => {
+                match cnx {
+                    Ok((local_rx, local_tx)) => flows.forward(stream_id, flow, local_rx, local_tx),
+                    Err(err) => {
+                        warn!("Closing flow {}, cannot connect to local endpoint: {:?}", stream_id, err);
+                        flows.close(stream_id).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LocalProtocol;
+    use tokio::io::DuplexStream;
+    use url::{Host, Url};
+
+    // Hand over the endpoint side of each connection to the test
+    struct PipeConnector {
+        endpoints: mpsc::UnboundedSender<DuplexStream>,
+    }
+
+    impl TunnelConnector for PipeConnector {
+        type Reader = ReadHalf<DuplexStream>;
+        type Writer = WriteHalf<DuplexStream>;
+
+        async fn connect(&self, _: &Option<RemoteAddr>) -> anyhow::Result<(Self::Reader, Self::Writer)> {
+            let (local, endpoint) = tokio::io::duplex(1024);
+            self.endpoints.send(endpoint)?;
+            Ok(tokio::io::split(local))
+        }
+
+        async fn connect_with_http_proxy(
+            &self,
+            _: &Url,
+            remote: &Option<RemoteAddr>,
+        ) -> anyhow::Result<(Self::Reader, Self::Writer)> {
+            self.connect(remote).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flows_are_multiplexed_over_one_pipe() {
+        let (server_pipe, client_pipe) = tokio::io::duplex(MUX_PIPE_SIZE);
+        let (cnx_tx, mut cnx_rx) = mpsc::channel(4);
+        tokio::spawn(async move { serve(server_pipe, &mut cnx_rx).await });
+        let (endpoints_tx, mut endpoints_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            run_client(
+                client_pipe,
+                &PipeConnector {
+                    endpoints: endpoints_tx,
+                },
+            )
+            .await
+        });
+
+        let mut flows = vec![];
+        for _ in 0..3 {
+            let (accepted, remote_client) = tokio::io::duplex(1024);
+            let remote = RemoteAddr {
+                protocol: LocalProtocol::ReverseTcp,
+                host: Host::Ipv4("127.0.0.1".parse().unwrap()),
+                port: 0,
+                source: None,
+                request_id: None,
+            };
+            cnx_tx.send((tokio::io::split(accepted), remote)).await.unwrap();
+            let endpoint = endpoints_rx.recv().await.unwrap();
+            flows.push((remote_client, endpoint));
+        }
+
+        // Interleave the writes of the flows, each one must only see its own data
+        let payload = vec![7; 3 * MAX_FRAME_PAYLOAD];
+        for (ix, (remote_client, endpoint)) in flows.iter_mut().enumerate() {
+            remote_client.write_all(&[ix as u8; 10]).await.unwrap();
+            endpoint.write_all(&[ix as u8 + 100; 5]).await.unwrap();
+        }
+        for (ix, (remote_client, endpoint)) in flows.iter_mut().enumerate() {
+            let mut buf = [0; 10];
+            endpoint.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [ix as u8; 10]);
+            let mut buf = [0; 5];
+            remote_client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [ix as u8 + 100; 5]);
+        }
+
+        // Payloads bigger than a frame are split and reassembled, and the end of a flow is propagated
+        let (mut remote_client, mut endpoint) = flows.remove(1);
+        let writer = tokio::spawn(async move {
+            remote_client.write_all(&payload).await.unwrap();
+            remote_client.shutdown().await.unwrap();
+            remote_client
+        });
+        let mut received = vec![];
+        endpoint.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, vec![7; 3 * MAX_FRAME_PAYLOAD]);
+        drop(writer.await.unwrap());
+
+        // The other flows are still up
+        let (remote_client, endpoint) = &mut flows[1];
+        endpoint.write_all(b"ok").await.unwrap();
+        let mut buf = [0; 2];
+        remote_client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ok");
+    }
+
+    #[tokio::test]
+    async fn test_stalled_flow_does_not_block_the_others() {
+        let (server_pipe, client_pipe) = tokio::io::duplex(MUX_PIPE_SIZE);
+        let (cnx_tx, mut cnx_rx) = mpsc::channel(4);
+        tokio::spawn(async move { serve(server_pipe, &mut cnx_rx).await });
+        let (endpoints_tx, mut endpoints_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            run_client(
+                client_pipe,
+                &PipeConnector {
+                    endpoints: endpoints_tx,
+                },
+            )
+            .await
+        });
+
+        let mut flows = vec![];
+        for _ in 0..2 {
+            let (accepted, remote_client) = tokio::io::duplex(1024);
+            let remote = RemoteAddr {
+                protocol: LocalProtocol::ReverseTcp,
+                host: Host::Ipv4("127.0.0.1".parse().unwrap()),
+                port: 0,
+                source: None,
+                request_id: None,
+            };
+            cnx_tx.send((tokio::io::split(accepted), remote)).await.unwrap();
+            let endpoint = endpoints_rx.recv().await.unwrap();
+            flows.push((remote_client, endpoint));
+        }
+
+        // The endpoint of the first flow never reads, far more than its window is pushed to it
+        let (mut stalled_client, _stalled_endpoint) = flows.remove(0);
+        let stalled = tokio::spawn(async move { stalled_client.write_all(&vec![1; 8 * FLOW_WINDOW]).await });
+
+        let (mut remote_client, mut endpoint) = flows.remove(0);
+        let payload = vec![2; 4 * FLOW_WINDOW];
+        let writer = tokio::spawn(async move {
+            remote_client.write_all(&payload).await.unwrap();
+            remote_client.shutdown().await.unwrap();
+            remote_client
+        });
+        let mut received = vec![];
+        tokio::time::timeout(std::time::Duration::from_secs(5), endpoint.read_to_end(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received.len(), 4 * FLOW_WINDOW);
+        drop(writer.await.unwrap());
+        assert!(!stalled.is_finished());
+    }
+}
@@ -0,0 +1,106 @@
+use crate::tunnel::transport::TunnelRead;
+use bytes::Bytes;
+use std::io;
+use std::io::ErrorKind;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+// Frames read ahead before the reading task waits for them to be consumed
+const PREFETCH_QUEUE_SIZE: usize = 16;
+
+/// Read a tunnel from a task of its own, even when no one consumes it yet.
+/// An idle tunnel stays alive this way: its pongs are received and the pings of the other end answered,
+/// and its death is noticed. The data received meanwhile is queued, one frame per item, and given back in order
+pub struct PrefetchTunnelRead {
+    rx: mpsc::Receiver<Result<Bytes, io::Error>>,
+    task: JoinHandle<()>,
+}
+
+impl PrefetchTunnelRead {
+    pub fn spawn(mut reader: impl TunnelRead) -> Self {
+        let (tx, rx) = mpsc::channel(PREFETCH_QUEUE_SIZE);
+        let task = tokio::spawn(async move {
+            let mut buf = Vec::new();
+            loop {
+                let ret = reader
+                    .copy(&mut buf)
+                    .await
+                    .map(|_| Bytes::from(std::mem::take(&mut buf)));
+                let is_err = ret.is_err();
+                if tx.send(ret).await.is_err() || is_err {
+                    return;
+                }
+            }
+        });
+
+        Self { rx, task }
+    }
+
+    /// The tunnel has been closed, or considered dead, while being read ahead
+    pub fn is_closed(&self) -> bool {
+        self.task.is_finished()
+    }
+}
+
+impl Drop for PrefetchTunnelRead {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl TunnelRead for PrefetchTunnelRead {
+    async fn copy(&mut self, mut writer: impl AsyncWrite + Unpin + Send) -> Result<usize, io::Error> {
+        let data = match self.rx.recv().await {
+            Some(ret) => ret?,
+            None => return Err(io::Error::new(ErrorKind::ConnectionAborted, "tunnel reader stopped")),
+        };
+
+        match writer.write_all(&data).await {
+            Ok(_) => Ok(data.len()),
+            Err(err) => Err(io::Error::new(ErrorKind::ConnectionAborted, err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    struct ChunksTunnelRead {
+        chunks: VecDeque<Vec<u8>>,
+    }
+
+    impl TunnelRead for ChunksTunnelRead {
+        async fn copy(&mut self, mut writer: impl AsyncWrite + Unpin + Send) -> Result<usize, io::Error> {
+            let chunk = self
+                .chunks
+                .pop_front()
+                .ok_or_else(|| io::Error::from(ErrorKind::NotConnected))?;
+            writer.write_all(&chunk).await?;
+            Ok(chunk.len())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_keeps_frames_and_close() {
+        let chunks = VecDeque::from([b"banner".to_vec(), b"more".to_vec()]);
+        let mut rx = PrefetchTunnelRead::spawn(ChunksTunnelRead { chunks });
+
+        // The reading task goes until the end of the tunnel, without anyone consuming it
+        tokio::time::timeout(std::time::Duration::from_secs(1), async {
+            while !rx.is_closed() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let mut received = vec![];
+        assert_eq!(rx.copy(&mut received).await.unwrap(), 6);
+        assert_eq!(rx.copy(&mut received).await.unwrap(), 4);
+        assert_eq!(received, b"bannermore");
+        assert_eq!(rx.copy(&mut received).await.unwrap_err().kind(), ErrorKind::NotConnected);
+    }
+}
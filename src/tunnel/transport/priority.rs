@@ -0,0 +1,273 @@
+use crate::tunnel::transport::{CloseReason, TunnelRead, TunnelWrite};
+use bytes::BytesMut;
+use parking_lot::Mutex;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWrite;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+// A priority is considered active for this long after one of its tunnels last transferred data
+const ACTIVITY_WINDOW: Duration = Duration::from_millis(100);
+
+// How far, in weighted bytes, a priority can get ahead of the least served active one before waiting for it
+const SCHEDULING_QUANTUM: u64 = 64 * 1024;
+
+// Longest wait of a chunk for its turn. Interactive tunnels stay active while idle between keystrokes,
+// so without it they would stall the lower priorities instead of only slowing them down
+const MAX_TURN_WAIT: Duration = Duration::from_millis(10);
+
+/// Scheduling hint of the tunnels of a listener, when several of them share the same uplink
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TunnelPriority {
+    /// Latency sensitive traffic (i.e: ssh), gets the biggest share
+    Interactive,
+    #[default]
+    Normal,
+    /// Throughput oriented traffic (i.e: downloads, backups), gets the smallest share
+    Bulk,
+}
+
+impl TunnelPriority {
+    // Share of the bytes transferred while several priorities are active
+    const fn weight(self) -> u64 {
+        match self {
+            Self::Interactive => 16,
+            Self::Normal => 4,
+            Self::Bulk => 1,
+        }
+    }
+
+    const fn index(self) -> usize {
+        match self {
+            Self::Interactive => 0,
+            Self::Normal => 1,
+            Self::Bulk => 2,
+        }
+    }
+}
+
+impl FromStr for TunnelPriority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "interactive" => Ok(Self::Interactive),
+            "normal" => Ok(Self::Normal),
+            "bulk" => Ok(Self::Bulk),
+            _ => Err(format!("invalid priority {}, expected interactive, normal or bulk", s)),
+        }
+    }
+}
+
+impl Display for TunnelPriority {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Interactive => "interactive",
+            Self::Normal => "normal",
+            Self::Bulk => "bulk",
+        })
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct PriorityState {
+    // Bytes transferred by the tunnels of this priority, divided by its weight
+    served: u64,
+    last_active: Option<Instant>,
+}
+
+impl PriorityState {
+    fn is_active(&self, now: Instant) -> bool {
+        self.last_active
+            .is_some_and(|last_active| now < last_active + ACTIVITY_WINDOW)
+    }
+}
+
+/// Weighted fair sharing of the uplink between the priorities, shared by all the tunnels of a client.
+/// Each priority accounts the bytes of its tunnels divided by its weight. A chunk waits for its turn while its priority
+/// is more than a quantum ahead of the least served active one, so busy priorities share the bytes by their weights,
+/// and an idle one does not hold back the others.
+/// hyper does not support http2 stream priorities (deprecated by RFC 9113), so this is done when propagating the data.
+/// Delaying the reads of lower priority tunnels also slows down what the server sends them, through flow control
+#[derive(Default)]
+pub struct PriorityScheduler {
+    priorities: Mutex<[PriorityState; 3]>,
+    served_changed: Notify,
+}
+
+impl PriorityScheduler {
+    // Weighted bytes of the least served active priority, and when the next active one becomes inactive
+    fn least_served(priorities: &[PriorityState; 3], now: Instant) -> (Option<u64>, Option<Instant>) {
+        let active = priorities.iter().filter(|state| state.is_active(now));
+        let least_served = active.clone().map(|state| state.served).min();
+        let next_inactive = active.filter_map(|state| state.last_active).min();
+        (least_served, next_inactive.map(|last_active| last_active + ACTIVITY_WINDOW))
+    }
+
+    /// Account nb_bytes to the priority, and wait until it is its turn to transfer them
+    pub async fn wait_turn(&self, priority: TunnelPriority, nb_bytes: usize) {
+        let deadline = Instant::now() + MAX_TURN_WAIT;
+        let start = {
+            let mut priorities = self.priorities.lock();
+            let now = Instant::now();
+            let (least_served, _) = Self::least_served(&priorities, now);
+            let state = &mut priorities[priority.index()];
+            // A priority coming back after being idle starts level with the others, it does not bank its idle time
+            if !state.is_active(now) {
+                state.served = state.served.max(least_served.unwrap_or(0));
+            }
+            state.last_active = Some(now);
+            let start = state.served;
+            state.served += (nb_bytes as u64).div_ceil(priority.weight());
+            start
+        };
+        self.served_changed.notify_waiters();
+
+        loop {
+            let served_changed = self.served_changed.notified();
+            let wake_at = {
+                let priorities = self.priorities.lock();
+                let (least_served, next_inactive) = Self::least_served(&priorities, Instant::now());
+                if start <= least_served.unwrap_or(start) + SCHEDULING_QUANTUM {
+                    return;
+                }
+                next_inactive.map_or(deadline, |next_inactive| next_inactive.min(deadline))
+            };
+
+            tokio::select! {
+                _ = served_changed => {},
+                _ = tokio::time::sleep_until(wake_at) => {},
+            }
+            if Instant::now() >= deadline {
+                return;
+            }
+        }
+    }
+}
+
+/// Wait for the turn of the tunnel before each write to the remote
+pub struct PrioritizedTunnelWrite<W> {
+    inner: W,
+    priority: TunnelPriority,
+    scheduler: Arc<PriorityScheduler>,
+}
+
+impl<W: TunnelWrite> PrioritizedTunnelWrite<W> {
+    pub const fn new(inner: W, priority: TunnelPriority, scheduler: Arc<PriorityScheduler>) -> Self {
+        Self {
+            inner,
+            priority,
+            scheduler,
+        }
+    }
+}
+
+impl<W: TunnelWrite> TunnelWrite for PrioritizedTunnelWrite<W> {
+    fn buf_mut(&mut self) -> &mut BytesMut {
+        self.inner.buf_mut()
+    }
+
+    async fn write(&mut self) -> Result<(), io::Error> {
+        let nb_bytes = self.inner.buf_mut().len();
+        self.scheduler.wait_turn(self.priority, nb_bytes).await;
+        self.inner.write().await
+    }
+
+    async fn ping(&mut self) -> Result<(), io::Error> {
+        self.inner.ping().await
+    }
+
+    async fn close(&mut self, reason: &CloseReason) -> Result<(), io::Error> {
+        self.inner.close(reason).await
+    }
+}
+
+/// Wait for the turn of the tunnel after each read from the remote, before reading the next chunk
+pub struct PrioritizedTunnelRead<R> {
+    inner: R,
+    priority: TunnelPriority,
+    scheduler: Arc<PriorityScheduler>,
+}
+
+impl<R: TunnelRead> PrioritizedTunnelRead<R> {
+    pub const fn new(inner: R, priority: TunnelPriority, scheduler: Arc<PriorityScheduler>) -> Self {
+        Self {
+            inner,
+            priority,
+            scheduler,
+        }
+    }
+}
+
+impl<R: TunnelRead> TunnelRead for PrioritizedTunnelRead<R> {
+    async fn copy(&mut self, writer: impl AsyncWrite + Unpin + Send) -> Result<usize, io::Error> {
+        // The size of a chunk is only known once read
+        let nb_bytes = self.inner.copy(writer).await?;
+        self.scheduler.wait_turn(self.priority, nb_bytes).await;
+        Ok(nb_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_single_priority_never_waits() {
+        let scheduler = PriorityScheduler::default();
+        let started_at = Instant::now();
+        for _ in 0..100 {
+            scheduler.wait_turn(TunnelPriority::Bulk, 1024 * 1024).await;
+        }
+        assert_eq!(started_at.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_busy_priorities_share_by_weight() {
+        let scheduler = Arc::new(PriorityScheduler::default());
+        let transfer = |priority| {
+            let scheduler = scheduler.clone();
+            tokio::spawn(async move {
+                let mut nb_bytes = 0;
+                let started_at = Instant::now();
+                while started_at.elapsed() < Duration::from_secs(1) {
+                    scheduler.wait_turn(priority, 16 * 1024).await;
+                    // Time to send the chunk on the uplink
+                    tokio::time::sleep(Duration::from_micros(100)).await;
+                    nb_bytes += 16 * 1024;
+                }
+                nb_bytes
+            })
+        };
+        let normal = transfer(TunnelPriority::Normal);
+        let bulk = transfer(TunnelPriority::Bulk);
+        let (normal, bulk) = (normal.await.unwrap(), bulk.await.unwrap());
+
+        let ratio = normal as f64 / bulk as f64;
+        assert!((3.5..=4.5).contains(&ratio), "{} / {} = {}", normal, bulk, ratio);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_priority_only_slows_down_the_others() {
+        let scheduler = PriorityScheduler::default();
+        scheduler.wait_turn(TunnelPriority::Interactive, 1).await;
+        scheduler
+            .wait_turn(TunnelPriority::Bulk, SCHEDULING_QUANTUM as usize * 2)
+            .await;
+
+        // An interactive tunnel waiting for keystrokes holds the bulk ones for at most the turn wait
+        let started_at = Instant::now();
+        scheduler.wait_turn(TunnelPriority::Bulk, 1024).await;
+        assert_eq!(started_at.elapsed(), MAX_TURN_WAIT);
+
+        // Then not at all, once it has been idle for long enough to not be active anymore
+        tokio::time::sleep(ACTIVITY_WINDOW).await;
+        let started_at = Instant::now();
+        scheduler.wait_turn(TunnelPriority::Bulk, 1024).await;
+        assert_eq!(started_at.elapsed(), Duration::ZERO);
+    }
+}
@@ -0,0 +1,454 @@
+use crate::tunnel::client::WsClient;
+use crate::tunnel::error::connect_error;
+use crate::tunnel::transport::connection_info::ConnectionInfo;
+use crate::tunnel::transport::websocket::{wait_dead, PingTracker, PongSender};
+use crate::tunnel::transport::{
+    copy_buffer_size, datagram, headers_from_file, mux, parse_retry_after, set_http_headers, CloseReason, TunnelRead,
+    TunnelWrite, MAX_PACKET_LENGTH,
+};
+use crate::tunnel::{tunnel_to_jwt_token, RemoteAddr, TunnelConnectError};
+use anyhow::anyhow;
+use bytes::{Buf, Bytes, BytesMut};
+use hyper::header::{AUTHORIZATION, COOKIE, USER_AGENT};
+use hyper::http::response::Parts;
+use hyper::http::{HeaderMap, HeaderName, HeaderValue};
+use hyper::{Response, StatusCode};
+use std::io;
+use std::io::ErrorKind;
+use std::ops::DerefMut;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::select;
+use tracing::{debug, trace};
+use uuid::Uuid;
+
+// Tunnels straight over a tcp (or tls) connection, without any http upgrade, for trusted links where the http
+// layer is only overhead. The client sends a preamble with the same request as the other transports (its path and
+// headers, the tunnel token being in the cookie header), the server answers with the status and headers of its
+// response, then both ends exchange frames: an opcode, the length of the payload as an u32 big endian, then the
+// payload. Besides the data, the frames carry pings and pongs, to keep the connection alive and detect dead peers
+// even through a proxy, and a close code like the websocket close frame.
+//
+// As it is not http, it does not go through http (reverse) proxies, CDN or load balancers. Only a proxy doing
+// CONNECT, that does not look at what goes through it, can be in between
+
+/// Biggest preamble accepted, for a peer to not make us buffer an unbounded amount of data
+const MAX_PREAMBLE_SIZE: usize = 64 * 1024;
+
+/// The server closes the connections that do not send their preamble in time
+pub const PREAMBLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+const OPCODE_DATA: u8 = 0;
+const OPCODE_PING: u8 = 1;
+const OPCODE_PONG: u8 = 2;
+const OPCODE_CLOSE: u8 = 3;
+
+const FRAME_HEADER_SIZE: usize = 5;
+
+/// Biggest frame accepted, the same as the biggest websocket message of fastwebsockets
+const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+// Shared with the task answering the pings of the other end
+type RawWriter = Arc<tokio::sync::Mutex<Pin<Box<dyn AsyncWrite + Send>>>>;
+
+async fn write_frame(writer: &mut Pin<Box<dyn AsyncWrite + Send>>, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut header = [opcode; FRAME_HEADER_SIZE];
+    header[1..].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+    // In a single write when the stream supports it, to not send the header in a packet of its own
+    writer
+        .write_all_buf(&mut Buf::chain(header.as_slice(), payload))
+        .await?;
+    // With tls, the data stays in the session until flushed
+    writer.flush().await
+}
+
+/// Read a frame into payload and return its opcode, or None if the stream ended cleanly before it
+async fn read_frame(reader: &mut (impl AsyncRead + Unpin), payload: &mut BytesMut) -> io::Result<Option<u8>> {
+    let opcode = match reader.read_u8().await {
+        Ok(opcode) => opcode,
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let len = reader.read_u32().await? as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("frame of {} bytes, above the maximum of {}", len, MAX_FRAME_SIZE),
+        ));
+    }
+    payload.clear();
+    payload.resize(len, 0);
+    reader.read_exact(payload).await?;
+
+    Ok(Some(opcode))
+}
+
+/// Write a preamble: its length as an u32 big endian, then its first line and one header per line
+pub async fn write_preamble(
+    mut writer: impl AsyncWrite + Unpin,
+    first_line: &str,
+    headers: &HeaderMap,
+) -> Result<(), io::Error> {
+    let mut preamble = Vec::with_capacity(256);
+    preamble.extend_from_slice(first_line.as_bytes());
+    for (name, value) in headers {
+        preamble.push(b'\n');
+        preamble.extend_from_slice(name.as_str().as_bytes());
+        preamble.extend_from_slice(b": ");
+        preamble.extend_from_slice(value.as_bytes());
+    }
+    if preamble.len() > MAX_PREAMBLE_SIZE {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "preamble of {} bytes, above the maximum of {}",
+                preamble.len(),
+                MAX_PREAMBLE_SIZE
+            ),
+        ));
+    }
+
+    writer.write_all(&(preamble.len() as u32).to_be_bytes()).await?;
+    writer.write_all(&preamble).await?;
+    writer.flush().await
+}
+
+/// Read a preamble written by write_preamble, and return its first line and headers
+pub async fn read_preamble(mut reader: impl AsyncRead + Unpin) -> Result<(String, HeaderMap), io::Error> {
+    let len = reader.read_u32().await? as usize;
+    if len > MAX_PREAMBLE_SIZE {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("preamble of {} bytes, above the maximum of {}", len, MAX_PREAMBLE_SIZE),
+        ));
+    }
+    let mut preamble = vec![0; len];
+    reader.read_exact(&mut preamble).await?;
+
+    let invalid = |what| io::Error::new(ErrorKind::InvalidData, format!("invalid {} in preamble", what));
+    let mut lines = preamble.split(|c| *c == b'\n');
+    let first_line = lines.next().unwrap_or_default();
+    let first_line = String::from_utf8(first_line.to_vec()).map_err(|_| invalid("first line"))?;
+    let mut headers = HeaderMap::new();
+    for line in lines {
+        let Some(ix) = line.windows(2).position(|sep| sep == b": ") else {
+            return Err(invalid("header"));
+        };
+        let name = HeaderName::from_bytes(&line[..ix]).map_err(|_| invalid("header name"))?;
+        let value = HeaderValue::from_bytes(&line[ix + 2..]).map_err(|_| invalid("header value"))?;
+        headers.append(name, value);
+    }
+
+    Ok((first_line, headers))
+}
+
+pub struct RawTunnelRead {
+    inner: BufReader<Pin<Box<dyn AsyncRead + Send>>>,
+    pong_sender: PongSender,
+    buf: BytesMut,
+    ping_tracker: Option<PingTracker>,
+}
+
+impl RawTunnelRead {
+    /// The pings of the other end are answered through the write half of the same connection
+    pub fn new(inner: Pin<Box<dyn AsyncRead + Send>>, write_half: &RawTunnelWrite, buffer_size: usize) -> Self {
+        let writer = write_half.inner.clone();
+        let pong_sender = PongSender::spawn(move |payload| {
+            let writer = writer.clone();
+            async move { write_frame(&mut *writer.lock().await, OPCODE_PONG, &payload).await }
+        });
+        Self {
+            inner: BufReader::with_capacity(buffer_size, inner),
+            pong_sender,
+            buf: BytesMut::with_capacity(buffer_size),
+            ping_tracker: None,
+        }
+    }
+
+    pub fn with_ping_tracker(mut self, ping_tracker: PingTracker) -> Self {
+        self.ping_tracker = Some(ping_tracker);
+        self
+    }
+}
+
+impl TunnelRead for RawTunnelRead {
+    async fn copy(&mut self, mut writer: impl AsyncWrite + Unpin + Send) -> Result<usize, io::Error> {
+        loop {
+            let frame = select! {
+                biased;
+
+                frame = read_frame(&mut self.inner, &mut self.buf) => frame,
+
+                reason = wait_dead(&self.ping_tracker) => return Err(io::Error::new(ErrorKind::TimedOut, reason)),
+            };
+            let opcode = match frame {
+                Ok(Some(opcode)) => opcode,
+                Ok(None) => return Err(io::Error::new(ErrorKind::BrokenPipe, "closed")),
+                Err(err) => return Err(io::Error::new(ErrorKind::ConnectionAborted, err)),
+            };
+
+            trace!("receive raw frame {} of {} bytes", opcode, self.buf.len());
+            match opcode {
+                OPCODE_DATA => {
+                    return match writer.write_all(&self.buf).await {
+                        Ok(_) => Ok(self.buf.len()),
+                        Err(err) => Err(io::Error::new(ErrorKind::ConnectionAborted, err)),
+                    }
+                }
+                // Not waiting for the write half, which may be blocked until we read what the other end sends
+                OPCODE_PING => self.pong_sender.send(Bytes::copy_from_slice(&self.buf)),
+                OPCODE_PONG => {
+                    if let Some(ping_tracker) = &self.ping_tracker {
+                        ping_tracker.on_pong(&self.buf);
+                    }
+                }
+                OPCODE_CLOSE => {
+                    return Err(io::Error::new(ErrorKind::NotConnected, CloseReason::from_payload(&self.buf)))
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("unknown opcode {} of raw frame", opcode),
+                    ))
+                }
+            }
+        }
+    }
+}
+
+pub struct RawTunnelWrite {
+    inner: RawWriter,
+    buf: BytesMut,
+    ping_tracker: Option<PingTracker>,
+}
+
+impl RawTunnelWrite {
+    pub fn new(inner: Pin<Box<dyn AsyncWrite + Send>>, buffer_size: usize) -> Self {
+        Self {
+            inner: Arc::new(tokio::sync::Mutex::new(inner)),
+            buf: BytesMut::with_capacity(buffer_size),
+            ping_tracker: None,
+        }
+    }
+
+    pub fn with_ping_tracker(mut self, ping_tracker: PingTracker) -> Self {
+        self.ping_tracker = Some(ping_tracker);
+        self
+    }
+}
+
+impl TunnelWrite for RawTunnelWrite {
+    fn buf_mut(&mut self) -> &mut BytesMut {
+        &mut self.buf
+    }
+
+    async fn write(&mut self) -> Result<(), io::Error> {
+        let ret = write_frame(&mut *self.inner.lock().await, OPCODE_DATA, &self.buf).await;
+        self.buf.clear();
+
+        ret.map_err(|err| io::Error::new(ErrorKind::ConnectionAborted, err))
+    }
+
+    async fn ping(&mut self) -> Result<(), io::Error> {
+        let nonce = self.ping_tracker.as_ref().map(PingTracker::on_ping);
+        let payload = nonce.as_ref().map(|n| n.as_slice()).unwrap_or_default();
+        write_frame(&mut *self.inner.lock().await, OPCODE_PING, payload)
+            .await
+            .map_err(|err| io::Error::new(ErrorKind::BrokenPipe, err))
+    }
+
+    async fn close(&mut self, reason: &CloseReason) -> Result<(), io::Error> {
+        let mut payload = Vec::with_capacity(2 + reason.reason.len());
+        payload.extend_from_slice(&reason.code.to_be_bytes());
+        payload.extend_from_slice(reason.reason.as_bytes());
+
+        let mut inner = self.inner.lock().await;
+        write_frame(&mut inner, OPCODE_CLOSE, &payload).await?;
+        inner.shutdown().await
+    }
+}
+
+pub async fn connect(
+    request_id: Uuid,
+    client: &WsClient,
+    dest_addr: &RemoteAddr,
+) -> Result<(RawTunnelRead, RawTunnelWrite, Parts), TunnelConnectError> {
+    let mut pooled_cnx = client.get_server_connection().await?;
+    let (mut transport, server_ix, mut timing) = pooled_cnx.deref_mut().take().unwrap();
+    let client_cfg = &client.config;
+    let server = client.servers.get(server_ix);
+    let connection_info = ConnectionInfo::new(server, &transport);
+
+    let mut headers = HeaderMap::new();
+    let jwt = tunnel_to_jwt_token(request_id, dest_addr, client_cfg.tunnel_metadata.as_deref())
+        .map_err(TunnelConnectError::JwtRejected)?;
+    headers.insert(COOKIE, HeaderValue::from_str(&jwt).expect("jwt is a valid header value"));
+    datagram::set_datagram_framing(&mut headers);
+    if client_cfg.multiplexes_reverse_tunnel(dest_addr) {
+        mux::set_reverse_multiplex(&mut headers);
+    }
+    if let Some(user_agent) = &client_cfg.user_agent {
+        headers.insert(USER_AGENT, user_agent.clone());
+    }
+    set_http_headers(&mut headers, &client_cfg.http_headers);
+    if let Some(auth) = &client_cfg.http_upgrade_credentials {
+        let _ = headers.remove(AUTHORIZATION);
+        headers.append(AUTHORIZATION, auth.clone());
+    }
+    if let Some(headers_file_path) = &client_cfg.http_headers_file {
+        // There is no host to override, without http
+        let (_host, headers_file) = headers_from_file(headers_file_path);
+        set_http_headers(&mut headers, &headers_file);
+    }
+
+    let path = format!("/{}/events", &client_cfg.http_upgrade_path_prefix);
+    debug!("with raw preamble {} {:?}", path, headers);
+    let upgrade_started_at = Instant::now();
+    let upgrade_error = |cause| TunnelConnectError::HttpUpgrade {
+        status: None,
+        retry_after: None,
+        cause,
+    };
+    write_preamble(&mut transport, &path, &headers)
+        .await
+        .map_err(|err| upgrade_error(anyhow!("failed to send preamble to the server {:?}: {}", server, err)))?;
+    let (status, headers) = read_preamble(&mut transport)
+        .await
+        .map_err(|err| upgrade_error(anyhow!("failed to read preamble of the server {:?}: {}", server, err)))?;
+    let status = StatusCode::from_bytes(status.as_bytes())
+        .map_err(|_| upgrade_error(anyhow!("invalid status {:?} from the server {:?}", status, server)))?;
+    timing.upgrade = Some(upgrade_started_at.elapsed());
+
+    if !status.is_success() {
+        let cause = anyhow!("Raw tunnel server {:?} rejected the connection: {:?}", server, status);
+        if let Some(kind) = connect_error(&headers) {
+            return Err(TunnelConnectError::Destination { kind, cause });
+        }
+        return Err(TunnelConnectError::HttpUpgrade {
+            status: Some(status),
+            retry_after: parse_retry_after(&headers, SystemTime::now()),
+            cause,
+        });
+    }
+
+    debug!("Connected to the server in {}", timing);
+    client_cfg.metrics.on_connect(&timing);
+
+    let (mut parts, _) = Response::builder()
+        .status(status)
+        .body(())
+        .expect("bug: failed to build response")
+        .into_parts();
+    parts.headers = headers;
+    parts.extensions.insert(connection_info);
+
+    let ping_tracker = PingTracker::new(
+        client_cfg.websocket_pong_timeout,
+        client_cfg.missed_pong_limit,
+        client_cfg.metrics.clone(),
+        client_cfg.clock.clone(),
+    );
+    let buffer_size = copy_buffer_size(client_cfg.copy_buffer_size.unwrap_or(MAX_PACKET_LENGTH), &dest_addr.protocol);
+    let (rx, tx) = tokio::io::split(transport);
+    let tx = RawTunnelWrite::new(Box::pin(tx), buffer_size).with_ping_tracker(ping_tracker.clone());
+    let rx = RawTunnelRead::new(Box::pin(rx), &tx, buffer_size).with_ping_tracker(ping_tracker);
+    Ok((rx, tx, parts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::clock::TokioClock;
+    use crate::tunnel::metrics::NoopTunnelMetrics;
+    use futures_util::FutureExt;
+
+    #[tokio::test]
+    async fn test_preamble_roundtrip() {
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_static("xxx.yyy.zzz"));
+        headers.append("x-custom", HeaderValue::from_static("a: b"));
+        headers.append("x-custom", HeaderValue::from_static("c"));
+
+        let mut preamble = Vec::new();
+        write_preamble(&mut preamble, "/v1/events", &headers).await.unwrap();
+        // The stream of the tunnel follows right after it
+        preamble.extend_from_slice(b"data");
+
+        let mut reader = preamble.as_slice();
+        let (first_line, read_headers) = read_preamble(&mut reader).await.unwrap();
+        assert_eq!(first_line, "/v1/events");
+        assert_eq!(read_headers, headers);
+        assert_eq!(reader, b"data");
+
+        let mut reader: &[u8] = &[0, 0, 0, 4, b'2', b'0', b'0', b'\n'];
+        assert!(read_preamble(&mut reader).await.is_err());
+        let mut reader: &[u8] = &u32::MAX.to_be_bytes();
+        assert_eq!(read_preamble(&mut reader).await.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_frames_roundtrip() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (client_rx, client_tx) = tokio::io::split(client);
+        let (server_rx, server_tx) = tokio::io::split(server);
+        // Dead as soon as two pings are waiting for their pong
+        let ping_tracker = PingTracker::new(None, Some(1), Arc::new(NoopTunnelMetrics), Arc::new(TokioClock));
+        let mut client_tx = RawTunnelWrite::new(Box::pin(client_tx), 1024).with_ping_tracker(ping_tracker.clone());
+        let mut client_rx = RawTunnelRead::new(Box::pin(client_rx), &client_tx, 1024).with_ping_tracker(ping_tracker);
+        let mut server_tx = RawTunnelWrite::new(Box::pin(server_tx), 1024);
+        let mut server_rx = RawTunnelRead::new(Box::pin(server_rx), &server_tx, 1024);
+
+        client_tx.ping().await.unwrap();
+        client_tx.ping().await.unwrap();
+        assert!(wait_dead(&client_rx.ping_tracker).now_or_never().is_some());
+        client_tx.buf_mut().extend_from_slice(b"hello");
+        client_tx.write().await.unwrap();
+
+        // The pings are answered while reading the data behind them
+        let mut data = Vec::new();
+        assert_eq!(server_rx.copy(&mut data).await.unwrap(), 5);
+        assert_eq!(data, b"hello");
+
+        // The pongs are sent by a task of their own, read them before closing
+        while wait_dead(&client_rx.ping_tracker).now_or_never().is_some() {
+            tokio::task::yield_now().await;
+            let _ = client_rx.copy(&mut data).now_or_never();
+        }
+
+        server_tx.close(&CloseReason::connect_failed()).await.unwrap();
+        let err = client_rx.copy(&mut data).await.unwrap_err();
+        assert_eq!(
+            err.get_ref().and_then(|err| err.downcast_ref::<CloseReason>()),
+            Some(&CloseReason::connect_failed())
+        );
+        assert!(wait_dead(&client_rx.ping_tracker).now_or_never().is_none());
+        assert_eq!(data, b"hello");
+
+        // The close frame is followed by the end of the stream
+        assert_eq!(client_rx.copy(&mut data).await.unwrap_err().kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[tokio::test]
+    async fn test_ping_does_not_wait_for_the_write_half() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (_client_rx, client_tx) = tokio::io::split(client);
+        let (server_rx, server_tx) = tokio::io::split(server);
+        let mut client_tx = RawTunnelWrite::new(Box::pin(client_tx), 1024);
+        let server_tx = RawTunnelWrite::new(Box::pin(server_tx), 1024);
+        let mut server_rx = RawTunnelRead::new(Box::pin(server_rx), &server_tx, 1024);
+
+        // i.e: a write blocked until the other end reads, while it is itself waiting for us to read
+        let blocked_write = server_tx.inner.lock().await;
+        client_tx.ping().await.unwrap();
+        client_tx.buf_mut().extend_from_slice(b"hello");
+        client_tx.write().await.unwrap();
+
+        let mut data = Vec::new();
+        let copied = tokio::time::timeout(Duration::from_secs(1), server_rx.copy(&mut data)).await;
+        assert_eq!(copied.unwrap().unwrap(), 5);
+        assert_eq!(data, b"hello");
+        drop(blocked_write);
+    }
+}
@@ -1,37 +1,208 @@
 use crate::tunnel::client::WsClient;
-use crate::tunnel::transport::{headers_from_file, TunnelRead, TunnelWrite, MAX_PACKET_LENGTH};
-use crate::tunnel::{tunnel_to_jwt_token, RemoteAddr, JWT_HEADER_PREFIX};
+use crate::tunnel::clock::Clock;
+use crate::tunnel::error::connect_error;
+use crate::tunnel::metrics::{ConnectTiming, TunnelMetrics};
+use crate::tunnel::transport::connection_info::ConnectionInfo;
+use crate::tunnel::transport::{
+    copy_buffer_size, datagram, headers_from_file, mux, order_http_headers, parse_retry_after, set_http_headers,
+    CloseReason, TunnelRead, TunnelWrite, MAX_PACKET_LENGTH,
+};
+use crate::tunnel::{tunnel_to_jwt_token, RemoteAddr, TransportStream, TunnelConnectError, JWT_HEADER_PREFIX};
 use anyhow::{anyhow, Context};
 use bytes::{Bytes, BytesMut};
-use fastwebsockets::{Frame, OpCode, Payload, WebSocketRead, WebSocketWrite};
+use fastwebsockets::{Frame, OpCode, Payload, Role, WebSocket, WebSocketError, WebSocketRead, WebSocketWrite};
 use http_body_util::Empty;
-use hyper::header::{AUTHORIZATION, SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION, UPGRADE};
-use hyper::header::{CONNECTION, HOST, SEC_WEBSOCKET_KEY};
+use hyper::header::{AUTHORIZATION, SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION, UPGRADE};
+use hyper::header::{CONNECTION, HOST, SEC_WEBSOCKET_KEY, USER_AGENT};
 use hyper::http::response::Parts;
+use hyper::http::{HeaderMap, HeaderValue};
 use hyper::upgrade::Upgraded;
-use hyper::Request;
-use hyper_util::rt::TokioExecutor;
+use hyper::{Request, StatusCode};
 use hyper_util::rt::TokioIo;
 use log::debug;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::future::{pending, ready, Future};
 use std::io;
 use std::io::ErrorKind;
 use std::ops::DerefMut;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use tokio::io::{AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::select;
+use tokio::sync::{watch, Notify};
+use tokio::time::Instant;
 use tracing::trace;
 use uuid::Uuid;
 
+// Shared with the task answering the pings of the other end
+type WebsocketWriter = Arc<tokio::sync::Mutex<WebSocketWrite<WriteHalf<TokioIo<Upgraded>>>>>;
+
+/// Pings still waiting for their pong, past it the oldest ones are forgotten
+pub const MAX_PINGS_IN_FLIGHT: usize = 64;
+
+/// Match the pongs received to the pings sent, to measure the round trip time of the tunnel and detect when the
+/// other end stopped answering. Clones share the same state, so give one to each half of the websocket
+#[derive(Clone)]
+pub struct PingTracker {
+    inner: Arc<PingTrackerInner>,
+}
+
+struct PingTrackerInner {
+    pong_timeout: Option<Duration>,
+    missed_pong_limit: Option<usize>,
+    metrics: Arc<dyn TunnelMetrics>,
+    clock: Arc<dyn Clock>,
+    next_nonce: AtomicU64,
+    in_flight: Mutex<VecDeque<(u64, Instant)>>,
+    ping_sent: Notify,
+}
+
+impl PingTracker {
+    pub fn new(
+        pong_timeout: Option<Duration>,
+        missed_pong_limit: Option<usize>,
+        metrics: Arc<dyn TunnelMetrics>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Self {
+            inner: Arc::new(PingTrackerInner {
+                pong_timeout,
+                missed_pong_limit,
+                metrics,
+                clock,
+                next_nonce: AtomicU64::new(0),
+                in_flight: Mutex::new(VecDeque::new()),
+                ping_sent: Notify::new(),
+            }),
+        }
+    }
+
+    // Payload of the next ping, a nonce echoed back in its pong
+    pub(super) fn on_ping(&self) -> [u8; 8] {
+        let nonce = self.inner.next_nonce.fetch_add(1, Ordering::Relaxed);
+        let mut in_flight = self.inner.in_flight.lock();
+        if in_flight.len() >= MAX_PINGS_IN_FLIGHT {
+            in_flight.pop_front();
+        }
+        in_flight.push_back((nonce, self.inner.clock.now()));
+        self.inner.ping_sent.notify_one();
+
+        nonce.to_be_bytes()
+    }
+
+    pub(super) fn on_pong(&self, payload: &[u8]) {
+        let Ok(nonce) = <[u8; 8]>::try_from(payload).map(u64::from_be_bytes) else {
+            return;
+        };
+
+        let mut in_flight = self.inner.in_flight.lock();
+        let Some(ix) = in_flight.iter().position(|(n, _)| *n == nonce) else {
+            return;
+        };
+        // Pongs come back in order, the pings sent before this one will never get theirs
+        let (_, sent_at) = in_flight[ix];
+        in_flight.drain(..=ix);
+        drop(in_flight);
+
+        let rtt = self.inner.clock.now().duration_since(sent_at);
+        debug!("websocket ping round trip time {:?}", rtt);
+        self.inner.metrics.on_rtt(rtt);
+    }
+
+    // Resolve with the reason once the other end is considered gone, because a ping did not get its pong in time
+    // or too many pings in a row got none
+    async fn wait_dead(&self) -> String {
+        if self.inner.pong_timeout.is_none() && self.inner.missed_pong_limit.is_none() {
+            return pending().await;
+        }
+
+        loop {
+            let (oldest, nb_in_flight) = {
+                let in_flight = self.inner.in_flight.lock();
+                (in_flight.front().map(|(_, sent_at)| *sent_at), in_flight.len())
+            };
+
+            // The last ping sent may still get its pong, only the ones before it are missed
+            let missed = nb_in_flight.saturating_sub(1);
+            if self.inner.missed_pong_limit.is_some_and(|limit| missed >= limit) {
+                return format!("no pong received for the last {} pings", missed);
+            }
+
+            match (oldest, self.inner.pong_timeout) {
+                (Some(sent_at), Some(pong_timeout))
+                    if self.inner.clock.now().duration_since(sent_at) >= pong_timeout =>
+                {
+                    return format!("no pong received within {:?}", pong_timeout);
+                }
+                (Some(sent_at), Some(pong_timeout)) => {
+                    select! {
+                        _ = self.inner.clock.sleep_until(sent_at + pong_timeout) => {},
+                        _ = self.inner.ping_sent.notified() => {},
+                    }
+                }
+                _ => self.inner.ping_sent.notified().await,
+            }
+        }
+    }
+}
+
+/// Answer the pings of the other end from a task of its own, that waits for the write half to be free.
+/// The read half must never wait on the write half: with both ends sending in bulk, each would stop reading while
+/// its writes are blocked by the other. Only the last ping not answered yet gets its pong, as RFC 6455 allows
+pub(super) struct PongSender {
+    pending: watch::Sender<Option<Bytes>>,
+}
+
+impl PongSender {
+    pub(super) fn spawn<F, Fut>(send_pong: F) -> Self
+    where
+        F: Fn(Bytes) -> Fut + Send + 'static,
+        Fut: Future<Output = io::Result<()>> + Send,
+    {
+        let (pending, mut rx) = watch::channel(None);
+        // Ends with the read half, once the sender is dropped
+        tokio::spawn(async move {
+            while rx.changed().await.is_ok() {
+                let Some(payload) = rx.borrow_and_update().clone() else {
+                    continue;
+                };
+                if let Err(err) = send_pong(payload).await {
+                    debug!("cannot send pong: {:?}", err);
+                    return;
+                }
+            }
+        });
+
+        Self { pending }
+    }
+
+    pub(super) fn send(&self, payload: Bytes) {
+        self.pending.send_replace(Some(payload));
+    }
+}
+
 pub struct WebsocketTunnelWrite {
-    inner: WebSocketWrite<WriteHalf<TokioIo<Upgraded>>>,
+    inner: WebsocketWriter,
     buf: BytesMut,
+    ping_tracker: Option<PingTracker>,
 }
 
 impl WebsocketTunnelWrite {
-    pub fn new(ws: WebSocketWrite<WriteHalf<TokioIo<Upgraded>>>) -> Self {
+    /// buffer_size is only the initial size, the buffer grows when a read fills it entirely
+    pub fn new(ws: WebSocketWrite<WriteHalf<TokioIo<Upgraded>>>, buffer_size: usize) -> Self {
         Self {
-            inner: ws,
-            buf: BytesMut::with_capacity(MAX_PACKET_LENGTH),
+            inner: Arc::new(tokio::sync::Mutex::new(ws)),
+            buf: BytesMut::with_capacity(buffer_size),
+            ping_tracker: None,
         }
     }
+
+    pub fn with_ping_tracker(mut self, ping_tracker: PingTracker) -> Self {
+        self.ping_tracker = Some(ping_tracker);
+        self
+    }
 }
 
 impl TunnelWrite for WebsocketTunnelWrite {
@@ -45,6 +216,8 @@ impl TunnelWrite for WebsocketTunnelWrite {
 
         let ret = self
             .inner
+            .lock()
+            .await
             .write_frame(Frame::binary(Payload::BorrowedMut(&mut buf[..read_len])))
             .await;
 
@@ -74,9 +247,13 @@ impl TunnelWrite for WebsocketTunnelWrite {
     }
 
     async fn ping(&mut self) -> Result<(), io::Error> {
+        let mut nonce = self.ping_tracker.as_ref().map(PingTracker::on_ping);
+        let payload = nonce.as_mut().map(|n| n.as_mut_slice()).unwrap_or_default();
         if let Err(err) = self
             .inner
-            .write_frame(Frame::new(true, OpCode::Ping, None, Payload::BorrowedMut(&mut [])))
+            .lock()
+            .await
+            .write_frame(Frame::new(true, OpCode::Ping, None, Payload::BorrowedMut(payload)))
             .await
         {
             return Err(io::Error::new(ErrorKind::BrokenPipe, err));
@@ -85,8 +262,14 @@ impl TunnelWrite for WebsocketTunnelWrite {
         Ok(())
     }
 
-    async fn close(&mut self) -> Result<(), io::Error> {
-        if let Err(err) = self.inner.write_frame(Frame::close(1000, &[])).await {
+    async fn close(&mut self, reason: &CloseReason) -> Result<(), io::Error> {
+        if let Err(err) = self
+            .inner
+            .lock()
+            .await
+            .write_frame(Frame::close(reason.code, reason.reason.as_bytes()))
+            .await
+        {
             return Err(io::Error::new(ErrorKind::BrokenPipe, err));
         }
 
@@ -96,23 +279,65 @@ impl TunnelWrite for WebsocketTunnelWrite {
 
 pub struct WebsocketTunnelRead {
     inner: WebSocketRead<ReadHalf<TokioIo<Upgraded>>>,
+    pong_sender: PongSender,
+    ping_tracker: Option<PingTracker>,
 }
 
 impl WebsocketTunnelRead {
-    pub const fn new(ws: WebSocketRead<ReadHalf<TokioIo<Upgraded>>>) -> Self {
-        Self { inner: ws }
+    /// The pings of the other end are answered through the write half of the same websocket
+    pub fn new(ws: WebSocketRead<ReadHalf<TokioIo<Upgraded>>>, write_half: &WebsocketTunnelWrite) -> Self {
+        let writer = write_half.inner.clone();
+        let pong_sender = PongSender::spawn(move |payload| {
+            let writer = writer.clone();
+            async move {
+                writer
+                    .lock()
+                    .await
+                    .write_frame(Frame::pong(Payload::Owned(payload.to_vec())))
+                    .await
+                    .map_err(|err| io::Error::new(ErrorKind::BrokenPipe, err))
+            }
+        });
+        Self {
+            inner: ws,
+            pong_sender,
+            ping_tracker: None,
+        }
+    }
+
+    pub fn with_ping_tracker(mut self, ping_tracker: PingTracker) -> Self {
+        self.ping_tracker = Some(ping_tracker);
+        self
     }
 }
 
-fn frame_reader(_: Frame<'_>) -> futures_util::future::Ready<anyhow::Result<()>> {
-    //error!("frame {:?} {:?}", x.opcode, x.payload);
-    futures_util::future::ready(anyhow::Ok(()))
+pub(super) async fn wait_dead(ping_tracker: &Option<PingTracker>) -> String {
+    match ping_tracker {
+        Some(ping_tracker) => ping_tracker.wait_dead().await,
+        None => pending().await,
+    }
 }
 
 impl TunnelRead for WebsocketTunnelRead {
-    async fn copy(&mut self, mut writer: impl AsyncWrite + Unpin + Send) -> Result<(), io::Error> {
+    async fn copy(&mut self, mut writer: impl AsyncWrite + Unpin + Send) -> Result<usize, io::Error> {
+        // fastwebsockets gives back the frames it must send, only the pongs are sent, we close the tunnel ourselves
+        let pong_sender = &self.pong_sender;
+        let mut frame_sender = |frame: Frame<'static>| {
+            if frame.opcode == OpCode::Pong {
+                pong_sender.send(Bytes::copy_from_slice(&frame.payload));
+            }
+            ready(Ok::<_, WebSocketError>(()))
+        };
+
         loop {
-            let msg = match self.inner.read_frame(&mut frame_reader).await {
+            let msg = select! {
+                biased;
+
+                msg = self.inner.read_frame(&mut frame_sender) => msg,
+
+                reason = wait_dead(&self.ping_tracker) => return Err(io::Error::new(ErrorKind::TimedOut, reason)),
+            };
+            let msg = match msg {
                 Ok(msg) => msg,
                 Err(err) => return Err(io::Error::new(ErrorKind::ConnectionAborted, err)),
             };
@@ -121,48 +346,101 @@ impl TunnelRead for WebsocketTunnelRead {
             match msg.opcode {
                 OpCode::Continuation | OpCode::Text | OpCode::Binary => {
                     return match writer.write_all(msg.payload.as_ref()).await {
-                        Ok(_) => Ok(()),
+                        Ok(_) => Ok(msg.payload.len()),
                         Err(err) => Err(io::Error::new(ErrorKind::ConnectionAborted, err)),
                     }
                 }
-                OpCode::Close => return Err(io::Error::new(ErrorKind::NotConnected, "websocket close")),
+                OpCode::Close => {
+                    return Err(io::Error::new(
+                        ErrorKind::NotConnected,
+                        CloseReason::from_payload(msg.payload.as_ref()),
+                    ))
+                }
                 OpCode::Ping => continue,
-                OpCode::Pong => continue,
+                OpCode::Pong => {
+                    if let Some(ping_tracker) = &self.ping_tracker {
+                        ping_tracker.on_pong(msg.payload.as_ref());
+                    }
+                    continue;
+                }
             };
         }
     }
 }
 
+/// Subprotocol offered along the tunnel token when none is configured
+pub const DEFAULT_SUBPROTOCOL: &str = "v1";
+
+/// Subprotocol the server accepts: the first one offered by the client that is not the tunnel token
+pub fn accepted_subprotocol(headers: &HeaderMap) -> HeaderValue {
+    headers
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(','))
+        .map(str::trim)
+        .find(|protocol| !protocol.is_empty() && !protocol.starts_with(JWT_HEADER_PREFIX))
+        .and_then(|protocol| HeaderValue::from_str(protocol).ok())
+        .unwrap_or_else(|| HeaderValue::from_static(DEFAULT_SUBPROTOCOL))
+}
+
 pub async fn connect(
     request_id: Uuid,
     client: &WsClient,
     dest_addr: &RemoteAddr,
-) -> anyhow::Result<(WebsocketTunnelRead, WebsocketTunnelWrite, Parts)> {
+) -> Result<(WebsocketTunnelRead, WebsocketTunnelWrite, Parts), TunnelConnectError> {
+    let mut pooled_cnx = client.get_server_connection().await?;
+    let (transport, server_ix, timing) = pooled_cnx.deref_mut().take().unwrap();
+    upgrade(request_id, client, dest_addr, transport, server_ix, timing).await
+}
+
+/// Do the websocket upgrade over a connection already established with the server at server_ix
+pub async fn upgrade(
+    request_id: Uuid,
+    client: &WsClient,
+    dest_addr: &RemoteAddr,
+    transport: TransportStream,
+    server_ix: usize,
+    mut timing: ConnectTiming,
+) -> Result<(WebsocketTunnelRead, WebsocketTunnelWrite, Parts), TunnelConnectError> {
+    let upgrade_started_at = Instant::now();
     let client_cfg = &client.config;
-    let mut pooled_cnx = match client.cnx_pool.get().await {
-        Ok(cnx) => Ok(cnx),
-        Err(err) => Err(anyhow!("failed to get a connection to the server from the pool: {err:?}")),
-    }?;
+    let server = client.servers.get(server_ix);
+    let connection_info = ConnectionInfo::new(server, &transport);
+    let jwt = tunnel_to_jwt_token(request_id, dest_addr, client_cfg.tunnel_metadata.as_deref())
+        .map_err(TunnelConnectError::JwtRejected)?;
 
     let mut req = Request::builder()
         .method("GET")
         .uri(format!("/{}/events", &client_cfg.http_upgrade_path_prefix))
-        .header(HOST, &client_cfg.http_header_host)
+        .header(HOST, client_cfg.http_header_host_for(server))
         .header(UPGRADE, "websocket")
         .header(CONNECTION, "upgrade")
         .header(SEC_WEBSOCKET_KEY, fastwebsockets::handshake::generate_key())
         .header(SEC_WEBSOCKET_VERSION, "13")
         .header(
             SEC_WEBSOCKET_PROTOCOL,
-            format!("v1, {}{}", JWT_HEADER_PREFIX, tunnel_to_jwt_token(request_id, dest_addr)),
+            format!(
+                "{}, {}{}",
+                client_cfg
+                    .websocket_subprotocol
+                    .as_deref()
+                    .unwrap_or(DEFAULT_SUBPROTOCOL),
+                JWT_HEADER_PREFIX,
+                jwt
+            ),
         )
         .version(hyper::Version::HTTP_11);
 
     let headers = req.headers_mut().unwrap();
-    for (k, v) in &client_cfg.http_headers {
-        let _ = headers.remove(k);
-        headers.append(k, v.clone());
+    datagram::set_datagram_framing(headers);
+    if client_cfg.multiplexes_reverse_tunnel(dest_addr) {
+        mux::set_reverse_multiplex(headers);
     }
+    if let Some(user_agent) = &client_cfg.user_agent {
+        headers.insert(USER_AGENT, user_agent.clone());
+    }
+    set_http_headers(headers, &client_cfg.http_headers);
 
     if let Some(auth) = &client_cfg.http_upgrade_credentials {
         let _ = headers.remove(AUTHORIZATION);
@@ -171,35 +449,268 @@ pub async fn connect(
 
     if let Some(headers_file_path) = &client_cfg.http_headers_file {
         let (host, headers_file) = headers_from_file(headers_file_path);
-        for (k, v) in headers_file {
-            let _ = headers.remove(&k);
-            headers.append(k, v);
-        }
+        set_http_headers(headers, &headers_file);
         if let Some((host, val)) = host {
             let _ = headers.remove(&host);
             headers.append(host, val);
         }
     }
+    order_http_headers(headers, &client_cfg.http_headers_order);
 
-    let req = req.body(Empty::<Bytes>::new()).with_context(|| {
-        format!(
-            "failed to build HTTP request to contact the server {:?}",
-            client_cfg.remote_addr
-        )
-    })?;
+    let req = req
+        .body(Empty::<Bytes>::new())
+        .with_context(|| format!("failed to build HTTP request to contact the server {:?}", server))
+        .map_err(|cause| TunnelConnectError::HttpUpgrade {
+            status: None,
+            retry_after: None,
+            cause,
+        })?;
     debug!("with HTTP upgrade request {:?}", req);
-    let transport = pooled_cnx.deref_mut().take().unwrap();
-    let (mut ws, response) = fastwebsockets::handshake::client(&TokioExecutor::new(), req, transport)
+    // Same handshake as fastwebsockets::handshake::client, which drops the response when the upgrade is rejected.
+    // Keep it to honor the Retry-After header of an overloaded server
+    let upgrade_error = |cause| TunnelConnectError::HttpUpgrade {
+        status: None,
+        retry_after: None,
+        cause,
+    };
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(transport))
+        .await
+        .with_context(|| format!("failed to do websocket handshake with the server {:?}", server))
+        .map_err(upgrade_error)?;
+    tokio::spawn(async move {
+        if let Err(err) = conn.with_upgrades().await {
+            debug!("Error while polling websocket upgrade connection: {:?}", err);
+        }
+    });
+
+    let mut response = sender
+        .send_request(req)
         .await
-        .with_context(|| format!("failed to do websocket handshake with the server {:?}", client_cfg.remote_addr))?;
+        .with_context(|| format!("failed to do websocket handshake with the server {:?}", server))
+        .map_err(upgrade_error)?;
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        let status = response.status();
+        let cause = anyhow!(
+            "failed to do websocket handshake with the server {:?}: {}",
+            server,
+            WebSocketError::InvalidStatusCode(status.as_u16())
+        );
+        if let Some(kind) = connect_error(response.headers()) {
+            return Err(TunnelConnectError::Destination { kind, cause });
+        }
+        return Err(TunnelConnectError::HttpUpgrade {
+            status: Some(status),
+            retry_after: parse_retry_after(response.headers(), SystemTime::now()),
+            cause,
+        });
+    }
+    if let Err(err) = verify_upgrade_headers(response.headers()) {
+        let cause = anyhow!(err).context(format!("failed to do websocket handshake with the server {:?}", server));
+        return Err(upgrade_error(cause));
+    }
 
+    // Proxies enforcing a subprotocol policy may rewrite it, do not go on with one the server did not agree on
+    if let Some(subprotocol) = &client_cfg.websocket_subprotocol {
+        let accepted = response.headers().get(SEC_WEBSOCKET_PROTOCOL);
+        if accepted.and_then(|h| h.to_str().ok()) != Some(subprotocol.as_str()) {
+            let cause = anyhow!(
+                "server {:?} accepted websocket subprotocol {:?} instead of the requested {}",
+                server,
+                accepted,
+                subprotocol
+            );
+            return Err(upgrade_error(cause));
+        }
+    }
+
+    let upgraded = hyper::upgrade::on(&mut response)
+        .await
+        .with_context(|| format!("failed to do websocket handshake with the server {:?}", server))
+        .map_err(upgrade_error)?;
+    let mut ws = WebSocket::after_handshake(TokioIo::new(upgraded), Role::Client);
+    timing.upgrade = Some(upgrade_started_at.elapsed());
+    debug!("Connected to the server in {}", timing);
+    client_cfg.metrics.on_connect(&timing);
+
+    // Websocket extensions (i.e: permessage-deflate) are not supported, fastwebsockets rejects frames with RSV bits set.
+    // wstunnel servers never negotiate them, but a middlebox in front of it could if the user forced the header.
+    if let Some(extensions) = response.headers().get(SEC_WEBSOCKET_EXTENSIONS) {
+        let cause = anyhow!(
+            "server {:?} negotiated unsupported websocket extensions {:?}, do not request them with custom headers",
+            server,
+            extensions
+        );
+        return Err(TunnelConnectError::HttpUpgrade {
+            status: None,
+            retry_after: None,
+            cause,
+        });
+    }
+
+    // Masked by default as RFC 6455 requires. wstunnel servers also accept unmasked frames, which avoids the overhead
     ws.set_auto_apply_mask(client_cfg.websocket_mask_frame);
 
     let (ws_rx, ws_tx) = ws.split(tokio::io::split);
 
-    Ok((
-        WebsocketTunnelRead::new(ws_rx),
-        WebsocketTunnelWrite::new(ws_tx),
-        response.into_parts().0,
-    ))
+    let (mut parts, _) = response.into_parts();
+    parts.extensions.insert(connection_info);
+    let ping_tracker = PingTracker::new(
+        client_cfg.websocket_pong_timeout,
+        client_cfg.missed_pong_limit,
+        client_cfg.metrics.clone(),
+        client_cfg.clock.clone(),
+    );
+    let ws_tx = WebsocketTunnelWrite::new(
+        ws_tx,
+        copy_buffer_size(client.config.copy_buffer_size.unwrap_or(MAX_PACKET_LENGTH), &dest_addr.protocol),
+    )
+    .with_ping_tracker(ping_tracker.clone());
+    let ws_rx = WebsocketTunnelRead::new(ws_rx, &ws_tx).with_ping_tracker(ping_tracker);
+    Ok((ws_rx, ws_tx, parts))
+}
+
+// The headers fastwebsockets::handshake::client requires on the answer of the server to switch to websocket
+fn verify_upgrade_headers(headers: &HeaderMap) -> Result<(), WebSocketError> {
+    let is_websocket_upgrade = headers
+        .get(UPGRADE)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| h.eq_ignore_ascii_case("websocket"));
+    if !is_websocket_upgrade {
+        return Err(WebSocketError::InvalidUpgradeHeader);
+    }
+
+    // A list of options, i.e: "keep-alive, Upgrade"
+    let is_connection_upgrade = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .any(|option| option.trim().eq_ignore_ascii_case("upgrade"));
+    if !is_connection_upgrade {
+        return Err(WebSocketError::InvalidConnectionHeader);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::clock::TokioClock;
+    use crate::tunnel::metrics::AtomicTunnelMetrics;
+    use futures_util::pin_mut;
+
+    #[tokio::test]
+    async fn test_pong_sender_does_not_wait_for_the_writer() {
+        // The write half is busy, i.e: blocked on a full socket while sending a data frame
+        let writer = Arc::new(tokio::sync::Mutex::new(Vec::<Bytes>::new()));
+        let busy = writer.clone().lock_owned().await;
+        let pong_sender = PongSender::spawn({
+            let writer = writer.clone();
+            move |payload| {
+                let writer = writer.clone();
+                async move {
+                    writer.lock().await.push(payload);
+                    Ok(())
+                }
+            }
+        });
+
+        pong_sender.send(Bytes::from_static(b"1"));
+        tokio::task::yield_now().await;
+        pong_sender.send(Bytes::from_static(b"2"));
+        pong_sender.send(Bytes::from_static(b"3"));
+        drop(busy);
+
+        // The pong being sent when the writer got busy, then only the last of the ones queued meanwhile
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while writer.lock().await.len() < 2 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(*writer.lock().await, [Bytes::from_static(b"1"), Bytes::from_static(b"3")]);
+    }
+
+    #[tokio::test]
+    async fn test_ping_tracker() {
+        let metrics = Arc::new(AtomicTunnelMetrics::default());
+        let tracker = PingTracker::new(Some(Duration::from_millis(100)), None, metrics.clone(), Arc::new(TokioClock));
+
+        // The pong of the last ping answers for the ones before it
+        let _ = tracker.on_ping();
+        let nonce = tracker.on_ping();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        tracker.on_pong(b"garbage");
+        tracker.on_pong(&nonce);
+        assert!(metrics.last_rtt_us.load(Ordering::Relaxed) >= 10_000);
+        assert!(tracker.inner.in_flight.lock().is_empty());
+
+        let started_at = Instant::now();
+        let _ = tracker.on_ping();
+        assert_eq!(tracker.wait_dead().await, "no pong received within 100ms");
+        assert!(started_at.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn test_ping_tracker_missed_pong_limit() {
+        let tracker = PingTracker::new(None, Some(2), Arc::new(AtomicTunnelMetrics::default()), Arc::new(TokioClock));
+        let _ = tracker.on_ping();
+        let nonce = tracker.on_ping();
+        tracker.on_pong(&nonce);
+
+        // A pong resets the count of the pings missed in a row
+        for _ in 0..2 {
+            let _ = tracker.on_ping();
+        }
+        let dead = tracker.wait_dead();
+        pin_mut!(dead);
+        assert!(futures_util::poll!(dead.as_mut()).is_pending());
+
+        let _ = tracker.on_ping();
+        assert_eq!(dead.await, "no pong received for the last 2 pings");
+    }
+
+    #[test]
+    fn test_accepted_subprotocol() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(accepted_subprotocol(&headers), DEFAULT_SUBPROTOCOL);
+
+        headers.insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static("v1, authorization.bearer.xxx.yyy.zzz"),
+        );
+        assert_eq!(accepted_subprotocol(&headers), "v1");
+
+        headers.insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static("authorization.bearer.xxx.yyy.zzz, chat.example.com"),
+        );
+        assert_eq!(accepted_subprotocol(&headers), "chat.example.com");
+    }
+
+    #[test]
+    fn test_verify_upgrade_headers() {
+        let mut headers = HeaderMap::new();
+        assert!(matches!(
+            verify_upgrade_headers(&headers),
+            Err(WebSocketError::InvalidUpgradeHeader)
+        ));
+
+        headers.insert(UPGRADE, HeaderValue::from_static("WebSocket"));
+        assert!(matches!(
+            verify_upgrade_headers(&headers),
+            Err(WebSocketError::InvalidConnectionHeader)
+        ));
+
+        headers.insert(CONNECTION, HeaderValue::from_static("close"));
+        assert!(matches!(
+            verify_upgrade_headers(&headers),
+            Err(WebSocketError::InvalidConnectionHeader)
+        ));
+
+        headers.insert(CONNECTION, HeaderValue::from_static("keep-alive, Upgrade"));
+        assert!(verify_upgrade_headers(&headers).is_ok());
+    }
 }